@@ -0,0 +1,753 @@
+//! Parsing of the BBCode used in Steam announcement bodies.
+//!
+//! A body is parsed into a tree of `Node`s once and then rendered, so nested, unclosed and
+//! unknown tags are all handled by the parser instead of by the order of string replacements.
+
+use crate::message_part::escape_markdown_v2;
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+
+/// Shown in place of an embedded YouTube video.
+const VIDEO_NOTE: &str =
+    "(This update contains video. To watch the video, go to the official website.)";
+
+/// Text of the link that replaces a `[previewyoutube]` tag with a video id.
+const VIDEO_LINK_TEXT: &str = "▶️ Watch the video on YouTube";
+
+/// Steam writes image URLs relative to this placeholder.
+const CLAN_IMAGE_PLACEHOLDER: &str = "{STEAM_CLAN_IMAGE}";
+
+/// Where `CLAN_IMAGE_PLACEHOLDER` points.
+const CLAN_IMAGE_BASE: &str = "https://clan.akamai.steamstatic.com/images";
+
+/// A piece of a parsed BBCode body.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Node {
+    Text(String),
+    /// A tag like `[url=https://www.dota2.com]...[/url]`. The name is lowercase, and `args` is
+    /// what follows the name after `=` or a space.
+    Tag {
+        name: String,
+        args: Option<String>,
+        children: Vec<Node>,
+    },
+}
+
+/// A tag whose closing tag hasn't been parsed yet.
+struct Open {
+    name: String,
+    args: Option<String>,
+    /// The tag as written, kept in case it is never closed.
+    raw: String,
+    children: Vec<Node>,
+}
+
+/// A tag recognized at the start of the input.
+enum Token {
+    Open { name: String, args: Option<String> },
+    Close(String),
+}
+
+/// Parses a BBCode body.
+///
+/// A closing tag closes the nearest open tag of the same name along with everything opened
+/// inside it; a closing tag with no matching open tag is kept as text. List items (`[*]`) end at
+/// the next item or at the end of their list. Any other tag left unclosed is kept as text, with
+/// its content parsed as usual. Closing tags may be written as `[/b]` or `[\/b]`.
+pub fn parse(input: &str) -> Vec<Node> {
+    let mut stack = vec![Open {
+        name: String::new(),
+        args: None,
+        raw: String::new(),
+        children: Vec::new(),
+    }];
+    let mut rest = input;
+    while let Some(start) = rest.find('[') {
+        push_text(&mut stack, &rest[..start]);
+        rest = &rest[start..];
+        let Some((token, len)) = token_at(rest) else {
+            push_text(&mut stack, "[");
+            rest = &rest[1..];
+            continue;
+        };
+        let raw = &rest[..len];
+        rest = &rest[len..];
+        match token {
+            Token::Open { name, args } => {
+                if name == "*" && stack.last().is_some_and(|open| open.name == "*") {
+                    close_top(&mut stack, true);
+                }
+                stack.push(Open {
+                    name,
+                    args,
+                    raw: raw.to_string(),
+                    children: Vec::new(),
+                });
+            }
+            Token::Close(name) => match stack.iter().rposition(|open| open.name == name) {
+                Some(index) if index > 0 => {
+                    while stack.len() > index + 1 {
+                        close_top(&mut stack, false);
+                    }
+                    close_top(&mut stack, true);
+                }
+                _ => push_text(&mut stack, raw),
+            },
+        }
+    }
+    push_text(&mut stack, rest);
+    while stack.len() > 1 {
+        close_top(&mut stack, false);
+    }
+    stack.pop().map(|root| root.children).unwrap_or_default()
+}
+
+/// Recognizes a tag at the start of `input`, which starts with `[`. Returns the tag and its
+/// length, or `None` if the bracket doesn't start a tag.
+fn token_at(input: &str) -> Option<(Token, usize)> {
+    let end = input.find(']')?;
+    let inner = &input[1..end];
+    if inner.contains('[') {
+        return None;
+    }
+    let is_name = |name: &str| {
+        name == "*"
+            || name.starts_with(|c: char| c.is_ascii_alphabetic())
+                && name.chars().all(|c| c.is_ascii_alphanumeric())
+    };
+    let token = match inner
+        .strip_prefix('/')
+        .or_else(|| inner.strip_prefix("\\/"))
+    {
+        Some(name) if is_name(name) => Token::Close(name.to_lowercase()),
+        Some(_) => return None,
+        None => {
+            let (name, args) = match inner.find(['=', ' ']) {
+                Some(i) => (&inner[..i], Some(inner[i + 1..].to_string())),
+                None => (inner, None),
+            };
+            if !is_name(name) {
+                return None;
+            }
+            Token::Open {
+                name: name.to_lowercase(),
+                args,
+            }
+        }
+    };
+    Some((token, end + 1))
+}
+
+/// Appends text to the innermost open tag, merging it with preceding text.
+fn push_text(stack: &mut [Open], text: &str) {
+    if text.is_empty() {
+        return;
+    }
+    let children = &mut stack.last_mut().expect("the root is never closed").children;
+    match children.last_mut() {
+        Some(Node::Text(last)) => last.push_str(text),
+        _ => children.push(Node::Text(text.to_string())),
+    }
+}
+
+/// Closes the innermost open tag. A tag that wasn't `closed` explicitly is turned back into
+/// text, except for list items, which never have a closing tag.
+fn close_top(stack: &mut Vec<Open>, closed: bool) {
+    let open = stack.pop().expect("the root is never closed");
+    if closed || open.name == "*" {
+        stack
+            .last_mut()
+            .expect("the root is never closed")
+            .children
+            .push(Node::Tag {
+                name: open.name,
+                args: open.args,
+                children: open.children,
+            });
+        return;
+    }
+    push_text(stack, &open.raw);
+    for child in open.children {
+        match child {
+            Node::Text(text) => push_text(stack, &text),
+            tag => stack
+                .last_mut()
+                .expect("the root is never closed")
+                .children
+                .push(tag),
+        }
+    }
+}
+
+/// Returns the text of the nodes without any markup.
+fn text_of(nodes: &[Node]) -> String {
+    nodes
+        .iter()
+        .map(|node| match node {
+            Node::Text(text) => text.clone(),
+            Node::Tag { children, .. } => text_of(children),
+        })
+        .collect()
+}
+
+/// The markup a message is written in, one of Telegram's parse modes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Markup {
+    #[default]
+    MarkdownV2,
+    Html,
+    /// Plain text without any formatting.
+    Plain,
+}
+
+impl FromStr for Markup {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "markdownv2" | "markdown" => Ok(Markup::MarkdownV2),
+            "html" => Ok(Markup::Html),
+            "plain" => Ok(Markup::Plain),
+            other => Err(format!("Unknown parse mode: {}", other)),
+        }
+    }
+}
+
+impl Markup {
+    /// Renders parsed BBCode in this markup. See `render_markdown_v2()`, `render_html()` and
+    /// `render_plain()`.
+    pub fn render(self, nodes: &[Node]) -> String {
+        match self {
+            Markup::MarkdownV2 => render_markdown_v2(nodes),
+            Markup::Html => render_html(nodes),
+            Markup::Plain => render_plain(nodes),
+        }
+    }
+
+    /// Escapes text so it is shown as written.
+    pub fn escape(self, text: &str) -> String {
+        match self {
+            Markup::MarkdownV2 => escape_markdown_v2(text),
+            Markup::Html => escape_html(text),
+            Markup::Plain => text.to_string(),
+        }
+    }
+
+    /// Escapes a URL for use as a link target.
+    pub fn escape_url(self, url: &str) -> String {
+        match self {
+            Markup::MarkdownV2 => url.replace('\\', "\\\\").replace(')', "\\)"),
+            Markup::Html => escape_html(url),
+            Markup::Plain => url.to_string(),
+        }
+    }
+
+    /// Builds a link to `url` with already escaped `text`.
+    pub fn link(self, text: &str, url: &str) -> String {
+        match self {
+            Markup::MarkdownV2 => format!("[{}]({})", text, self.escape_url(url)),
+            Markup::Html => format!("<a href=\"{}\">{}</a>", self.escape_url(url), text),
+            Markup::Plain if text == url => url.to_string(),
+            Markup::Plain => format!("{} ({})", text, url),
+        }
+    }
+
+    /// Returns the markers that open and close `style`.
+    fn markers(self, style: Style) -> (&'static str, &'static str) {
+        match (self, style) {
+            (Markup::MarkdownV2, Style::Bold) => ("*", "*"),
+            (Markup::MarkdownV2, Style::Italic) => ("_", "_"),
+            (Markup::MarkdownV2, Style::Strike) => ("~", "~"),
+            (Markup::MarkdownV2, Style::Spoiler) => ("||", "||"),
+            (Markup::Html, Style::Bold) => ("<b>", "</b>"),
+            (Markup::Html, Style::Italic) => ("<i>", "</i>"),
+            (Markup::Html, Style::Strike) => ("<s>", "</s>"),
+            (Markup::Html, Style::Spoiler) => ("<tg-spoiler>", "</tg-spoiler>"),
+            (Markup::Plain, _) => ("", ""),
+        }
+    }
+}
+
+/// Escapes the characters Telegram's HTML parse mode reserves.
+pub fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Renders parsed BBCode as Telegram Markdown V2.
+///
+/// Text is escaped, headings and `[b]` become bold, `[i]` italic, `[strike]` strikethrough and
+/// `[spoiler]` a spoiler. A style nested inside the same style isn't repeated, since Markdown V2
+/// would read the inner markers as closing the outer ones. `[quote]` becomes a block quote and
+/// `[code]` a code block whose content is shown as written. Tables and images are dropped,
+/// YouTube previews replaced with a link to the video, and list items marked with an emoji.
+/// Unknown tags are rendered as their content.
+pub fn render_markdown_v2(nodes: &[Node]) -> String {
+    Renderer::new(Markup::MarkdownV2).render_to_string(nodes)
+}
+
+/// Renders parsed BBCode as Telegram HTML, the same way as `render_markdown_v2()` but with HTML
+/// tags and escaping.
+pub fn render_html(nodes: &[Node]) -> String {
+    Renderer::new(Markup::Html).render_to_string(nodes)
+}
+
+/// Renders parsed BBCode as plain text: the content of `render_markdown_v2()` without styles,
+/// links written out after their text, and quoted lines starting with `> `.
+pub fn render_plain(nodes: &[Node]) -> String {
+    Renderer::new(Markup::Plain).render_to_string(nodes)
+}
+
+/// Reads the video id from the argument of a `[previewyoutube=ID;full]` tag. The part after `;`,
+/// `full` or `raw`, only says how Steam lays the preview out.
+fn youtube_id(args: &str) -> Option<&str> {
+    let id = args.split(';').next()?.trim().trim_matches(['"', '\'']);
+    let valid = !id.is_empty()
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    valid.then_some(id)
+}
+
+/// Returns the short link to the YouTube video with the given id.
+fn youtube_link(id: &str) -> String {
+    format!("https://youtu.be/{}", id)
+}
+
+/// Returns the URLs of the `[img]` tags in parsed BBCode, in order.
+///
+/// The URL is the tag's content, or its `src` argument for tags like `[img src="..."][/img]`.
+/// Links relative to Steam's clan image placeholder are made absolute, and anything that isn't an
+/// `http` or `https` URL is skipped, since Telegram can't fetch it.
+pub fn image_urls(nodes: &[Node]) -> Vec<String> {
+    let mut urls = Vec::new();
+    for node in nodes {
+        let Node::Tag {
+            name,
+            args,
+            children,
+        } = node
+        else {
+            continue;
+        };
+        if name != "img" {
+            urls.extend(image_urls(children));
+            continue;
+        }
+        let src = args.as_deref().and_then(|args| {
+            let (_, rest) = args.split_once("src=")?;
+            let rest = rest.trim_start_matches(['"', '\'']);
+            Some(
+                rest.split(['"', '\'', ' '])
+                    .next()
+                    .unwrap_or_default()
+                    .to_string(),
+            )
+        });
+        let url = src.unwrap_or_else(|| text_of(children));
+        let url = url.trim().replace(CLAN_IMAGE_PLACEHOLDER, CLAN_IMAGE_BASE);
+        if url.starts_with("https://") || url.starts_with("http://") {
+            urls.push(url);
+        }
+    }
+    urls
+}
+
+/// A text style a tag can apply.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Style {
+    Bold,
+    Italic,
+    Strike,
+    Spoiler,
+}
+
+/// Rendering state: the target markup, the styles of the enclosing tags and whether they include
+/// a quote.
+struct Renderer {
+    markup: Markup,
+    styles: Vec<Style>,
+    quoted: bool,
+}
+
+impl Renderer {
+    fn new(markup: Markup) -> Self {
+        Self {
+            markup,
+            styles: Vec::new(),
+            quoted: false,
+        }
+    }
+
+    fn render_to_string(mut self, nodes: &[Node]) -> String {
+        let mut out = String::new();
+        self.render(nodes, &mut out);
+        out
+    }
+
+    fn render(&mut self, nodes: &[Node], out: &mut String) {
+        for node in nodes {
+            let (name, args, children) = match node {
+                Node::Text(text) => {
+                    out.push_str(&self.markup.escape(text));
+                    continue;
+                }
+                Node::Tag {
+                    name,
+                    args,
+                    children,
+                } => (name.as_str(), args, children),
+            };
+            match name {
+                "b" | "h1" | "h2" | "h3" | "h4" | "h5" | "h6" => {
+                    self.styled(Style::Bold, children, out)
+                }
+                "i" => self.styled(Style::Italic, children, out),
+                "strike" => self.styled(Style::Strike, children, out),
+                "spoiler" => self.styled(Style::Spoiler, children, out),
+                "url" => self.link(args.as_deref(), children, out),
+                "quote" => self.quote(children, out),
+                "code" => self.code(children, out),
+                "*" => {
+                    let bold =
+                        matches!(children.first(), Some(Node::Tag { name, .. }) if name == "b");
+                    out.push_str(if bold { "🔸" } else { "📌" });
+                    self.render(children, out);
+                }
+                "table" | "img" => {}
+                "previewyoutube" => match args.as_deref().and_then(youtube_id) {
+                    Some(id) => out.push_str(
+                        &self
+                            .markup
+                            .link(&self.markup.escape(VIDEO_LINK_TEXT), &youtube_link(id)),
+                    ),
+                    None => out.push_str(&self.markup.escape(VIDEO_NOTE)),
+                },
+                _ => self.render(children, out),
+            }
+        }
+    }
+
+    /// Wraps the rendered children in the markers of `style`, unless that style is already
+    /// applied or the children render to nothing.
+    fn styled(&mut self, style: Style, children: &[Node], out: &mut String) {
+        if self.styles.contains(&style) {
+            return self.render(children, out);
+        }
+        let mut inner = String::new();
+        self.styles.push(style);
+        self.render(children, &mut inner);
+        self.styles.pop();
+        if !inner.is_empty() {
+            let (open, close) = self.markup.markers(style);
+            out.push_str(open);
+            out.push_str(&inner);
+            out.push_str(close);
+        }
+    }
+
+    /// Renders the children as a block quote on lines of their own. Quotes can't be nested, so
+    /// a quote inside a quote is rendered as its content.
+    fn quote(&mut self, children: &[Node], out: &mut String) {
+        if self.quoted {
+            return self.render(children, out);
+        }
+        let mut inner = String::new();
+        self.quoted = true;
+        self.render(children, &mut inner);
+        self.quoted = false;
+        let inner = inner.trim_matches('\n');
+        if inner.is_empty() {
+            return;
+        }
+        start_line(out);
+        match self.markup {
+            Markup::Html => {
+                out.push_str("<blockquote>");
+                out.push_str(inner);
+                out.push_str("</blockquote>");
+            }
+            Markup::MarkdownV2 | Markup::Plain => {
+                let prefix = if self.markup == Markup::Plain {
+                    "> "
+                } else {
+                    ">"
+                };
+                let lines: Vec<String> = inner
+                    .lines()
+                    .map(|line| format!("{}{}", prefix, line))
+                    .collect();
+                out.push_str(&lines.join("\n"));
+            }
+        }
+        out.push('\n');
+    }
+
+    /// Renders the text of the children as a code block on lines of its own, without any markup.
+    fn code(&mut self, children: &[Node], out: &mut String) {
+        let text = text_of(children);
+        let text = text.trim_matches('\n');
+        if text.trim().is_empty() {
+            return;
+        }
+        start_line(out);
+        match self.markup {
+            Markup::MarkdownV2 => {
+                let escaped = text.replace('\\', "\\\\").replace('`', "\\`");
+                out.push_str(&format!("```\n{}\n```", escaped));
+            }
+            Markup::Html => out.push_str(&format!("<pre>{}</pre>", escape_html(text))),
+            Markup::Plain => out.push_str(text),
+        }
+        out.push('\n');
+    }
+
+    /// Renders a link to `url`, or to the link text if the tag has no URL.
+    fn link(&mut self, url: Option<&str>, children: &[Node], out: &mut String) {
+        let url = url.map_or_else(|| text_of(children), str::to_string);
+        let mut text = String::new();
+        self.render(children, &mut text);
+        if url.trim().is_empty() {
+            out.push_str(&text);
+            return;
+        }
+        if text.is_empty() {
+            text = self.markup.escape(&url);
+        }
+        out.push_str(&self.markup.link(&text, &url));
+    }
+}
+
+/// Ends the current line of `out`, unless it is empty or already at the start of a line.
+fn start_line(out: &mut String) {
+    if !out.is_empty() && !out.ends_with('\n') {
+        out.push('\n');
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message_part::validate_markdown_v2;
+
+    fn text(text: &str) -> Node {
+        Node::Text(text.to_string())
+    }
+
+    fn tag(name: &str, args: Option<&str>, children: Vec<Node>) -> Node {
+        Node::Tag {
+            name: name.to_string(),
+            args: args.map(str::to_string),
+            children,
+        }
+    }
+
+    #[test]
+    fn test_parse_builds_a_tree() {
+        assert_eq!(
+            parse("Read [url=https://www.dota2.com][b]this[\\/b][/url]!"),
+            vec![
+                text("Read "),
+                tag(
+                    "url",
+                    Some("https://www.dota2.com"),
+                    vec![tag("b", None, vec![text("this")])]
+                ),
+                text("!"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_list_items_end_at_the_next_item() {
+        assert_eq!(
+            parse("[list][*]One[*]Two[/list]"),
+            vec![tag(
+                "list",
+                None,
+                vec![
+                    tag("*", None, vec![text("One")]),
+                    tag("*", None, vec![text("Two")]),
+                ]
+            )]
+        );
+    }
+
+    #[test]
+    fn test_unclosed_and_stray_tags_are_text() {
+        assert_eq!(
+            parse("[beta] [b]bold[/b] [/i] [1]"),
+            vec![
+                text("[beta] "),
+                tag("b", None, vec![text("bold")]),
+                text(" [/i] [1]"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_render_nested_styles() {
+        let rendered = render_markdown_v2(&parse("[h1][b]Patch 7.36[/b][/h1]"));
+        assert_eq!(rendered, "*Patch 7\\.36*");
+        assert!(validate_markdown_v2(&rendered).is_ok());
+
+        let rendered = render_markdown_v2(&parse(
+            "[list][*][b]Axe[/b] [i]Berserker's [b]Call[/b][/i][*]Fixed a bug[/list]",
+        ));
+        assert_eq!(rendered, "🔸*Axe* _Berserker's *Call*_📌Fixed a bug");
+        assert!(validate_markdown_v2(&rendered).is_ok());
+    }
+
+    #[test]
+    fn test_render_links_and_dropped_tags() {
+        let rendered = render_markdown_v2(&parse(
+            "[img]{STEAM_CLAN_IMAGE}/1.png[/img][url=https://example.com/a_(b)]The [i]notes[/i][/url] [url]https://dota2.com[/url][table][tr][td]x[/td][/tr][/table]",
+        ));
+        assert_eq!(
+            rendered,
+            "[The _notes_](https://example.com/a_(b\\)) [https://dota2\\.com](https://dota2.com)"
+        );
+        assert!(validate_markdown_v2(&rendered).is_ok());
+    }
+
+    #[test]
+    fn test_spoilers_hide_their_content() {
+        let nodes = parse(
+            "Winner: [spoiler]Team [spoiler]Spirit[/spoiler] [b]again![/b][/spoiler]\n[spoiler]Line 1\nLine 2[/spoiler]",
+        );
+
+        let rendered = render_markdown_v2(&nodes);
+        assert_eq!(
+            rendered,
+            "Winner: ||Team Spirit *again\\!*||\n||Line 1\nLine 2||"
+        );
+        assert!(validate_markdown_v2(&rendered).is_ok());
+        assert_eq!(
+            render_html(&nodes),
+            "Winner: <tg-spoiler>Team Spirit <b>again!</b></tg-spoiler>\n<tg-spoiler>Line 1\nLine 2</tg-spoiler>"
+        );
+        assert_eq!(
+            render_plain(&nodes),
+            "Winner: Team Spirit again!\nLine 1\nLine 2"
+        );
+    }
+
+    #[test]
+    fn test_quotes_are_block_quotes() {
+        let nodes = parse(
+            "Gaben said:[quote=Gabe][b]Patch 7.36[/b] is out.\nEnjoy![quote]Nested[/quote][/quote]Thanks.",
+        );
+
+        let rendered = render_markdown_v2(&nodes);
+        assert_eq!(
+            rendered,
+            "Gaben said:\n>*Patch 7\\.36* is out\\.\n>Enjoy\\!Nested\nThanks\\."
+        );
+        assert!(validate_markdown_v2(&rendered).is_ok());
+        assert_eq!(
+            render_html(&nodes),
+            "Gaben said:\n<blockquote><b>Patch 7.36</b> is out.\nEnjoy!Nested</blockquote>\nThanks."
+        );
+        assert_eq!(
+            render_plain(&nodes),
+            "Gaben said:\n> Patch 7.36 is out.\n> Enjoy!Nested\nThanks."
+        );
+        assert_eq!(render_plain(&parse("A[quote]\n[/quote]B")), "AB");
+    }
+
+    #[test]
+    fn test_code_is_shown_as_written() {
+        let nodes = parse("Run:[code]\ndota2.exe -novid [b]`x`[/b] C:\\Games\n[/code]Done.");
+
+        let rendered = render_markdown_v2(&nodes);
+        assert_eq!(
+            rendered,
+            "Run:\n```\ndota2.exe -novid \\`x\\` C:\\\\Games\n```\nDone\\."
+        );
+        assert!(validate_markdown_v2(&rendered).is_ok());
+        assert_eq!(
+            render_html(&nodes),
+            "Run:\n<pre>dota2.exe -novid `x` C:\\Games</pre>\nDone."
+        );
+        assert_eq!(
+            render_plain(&nodes),
+            "Run:\ndota2.exe -novid `x` C:\\Games\nDone."
+        );
+    }
+
+    #[test]
+    fn test_youtube_preview_links_the_video() {
+        let nodes = parse(
+            "Trailer: [previewyoutube=dQw4w9WgXcQ;full][/previewyoutube] Teaser: [previewyoutube=\"a_B-1\";raw][/previewyoutube]",
+        );
+
+        let rendered = render_markdown_v2(&nodes);
+        assert_eq!(
+            rendered,
+            "Trailer: [▶️ Watch the video on YouTube](https://youtu.be/dQw4w9WgXcQ) \
+             Teaser: [▶️ Watch the video on YouTube](https://youtu.be/a_B-1)"
+        );
+        assert!(validate_markdown_v2(&rendered).is_ok());
+        assert!(render_html(&nodes).contains("<a href=\"https://youtu.be/dQw4w9WgXcQ\">"));
+        assert_eq!(
+            render_plain(&parse("[previewyoutube=;full][/previewyoutube]")),
+            VIDEO_NOTE
+        );
+        assert_eq!(
+            render_plain(&parse("[previewyoutube=x/../y;full][/previewyoutube]")),
+            VIDEO_NOTE
+        );
+    }
+
+    #[test]
+    fn test_image_urls_in_order() {
+        let nodes = parse(
+            "[img]{STEAM_CLAN_IMAGE}/3703047/patch.png[/img]Notes[list][*][img src=\"http://example.com/a.jpg\"][/img][/list]\
+             [img]ftp://example.com/b.png[/img][img]javascript:alert(1)[/img][img][/img][img]https://example.com/unclosed.png",
+        );
+
+        assert_eq!(
+            image_urls(&nodes),
+            vec![
+                "https://clan.akamai.steamstatic.com/images/3703047/patch.png",
+                "http://example.com/a.jpg",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_multiline_image_is_dropped() {
+        let nodes = parse(
+            "Before\n[img]\nhttps://clan.akamai.steamstatic.com/images/3703047/patch.png\n[/img]\nAfter",
+        );
+
+        assert_eq!(render_markdown_v2(&nodes), "Before\n\nAfter");
+        assert_eq!(render_html(&nodes), "Before\n\nAfter");
+        assert_eq!(render_plain(&nodes), "Before\n\nAfter");
+    }
+
+    #[test]
+    fn test_render_each_markup() {
+        let nodes = parse(
+            "[h1]Patch 7.36 <beta>[/h1] [i]Axe & co.[/i] [url=https://example.com/?a=1&b=(2)]notes[/url]",
+        );
+
+        assert_eq!(
+            render_markdown_v2(&nodes),
+            "*Patch 7\\.36 <beta\\>* _Axe & co\\._ [notes](https://example.com/?a=1&b=(2\\))"
+        );
+        assert_eq!(
+            render_html(&nodes),
+            "<b>Patch 7.36 &lt;beta&gt;</b> <i>Axe &amp; co.</i> \
+             <a href=\"https://example.com/?a=1&amp;b=(2)\">notes</a>"
+        );
+        assert_eq!(
+            render_plain(&nodes),
+            "Patch 7.36 <beta> Axe & co. notes (https://example.com/?a=1&b=(2))"
+        );
+        assert!(validate_markdown_v2(&render_markdown_v2(&nodes)).is_ok());
+    }
+}
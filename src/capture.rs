@@ -0,0 +1,92 @@
+//! Capture of feed payloads that failed to parse, for diagnosing changes in Steam's schema.
+//!
+//! `SteamEventSource` records every polled body that isn't valid JSON or whose events don't
+//! deserialize, and `/parse_failures` reports them and sends the newest payload back as a file.
+
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+/// Most failures kept; older ones are dropped first.
+const MAX_CAPTURED: usize = 20;
+
+/// Failures older than this aren't counted as recent.
+pub const RECENT_WINDOW: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// A feed payload that failed to parse.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParseFailure {
+    pub at: SystemTime,
+    pub error: String,
+    pub payload: Vec<u8>,
+}
+
+/// The most recent parse failures, newest last.
+#[derive(Default)]
+pub struct ParseFailures {
+    failures: Mutex<VecDeque<ParseFailure>>,
+}
+
+impl ParseFailures {
+    /// Records that `payload` failed to parse with `error`.
+    pub fn record(&self, error: impl ToString, payload: &[u8]) {
+        self.record_at(error, payload, SystemTime::now());
+    }
+
+    fn record_at(&self, error: impl ToString, payload: &[u8], at: SystemTime) {
+        let mut failures = self.failures.lock().unwrap();
+        if failures.len() == MAX_CAPTURED {
+            failures.pop_front();
+        }
+        failures.push_back(ParseFailure {
+            at,
+            error: error.to_string(),
+            payload: payload.to_vec(),
+        });
+    }
+
+    /// Returns the number of failures within `RECENT_WINDOW` before `now`.
+    pub fn recent_count(&self, now: SystemTime) -> usize {
+        let since = now
+            .checked_sub(RECENT_WINDOW)
+            .unwrap_or(SystemTime::UNIX_EPOCH);
+        self.failures
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|failure| failure.at >= since)
+            .count()
+    }
+
+    /// Returns the newest failure, if any was captured.
+    pub fn latest(&self) -> Option<ParseFailure> {
+        self.failures.lock().unwrap().back().cloned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_only_recent_failures_are_counted() {
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(10 * 24 * 60 * 60);
+        let failures = ParseFailures::default();
+        for i in 0..MAX_CAPTURED + 5 {
+            failures.record_at(
+                "old",
+                b"{",
+                now - RECENT_WINDOW * 2 + Duration::from_secs(i as u64),
+            );
+        }
+        failures.record_at(
+            "expected value",
+            b"{\"events\"",
+            now - Duration::from_secs(60),
+        );
+
+        assert_eq!(failures.recent_count(now), 1);
+        assert_eq!(failures.failures.lock().unwrap().len(), MAX_CAPTURED);
+        assert_eq!(failures.latest().unwrap().error, "expected value");
+    }
+}
@@ -0,0 +1,752 @@
+//! Settings from the environment: those read once at startup, and those `/reload` can change
+//! while the bot runs.
+//!
+//! At startup `Config` is read from the environment, into which `dotenv` loads `ENV_FILE`.
+//! `/reload` reads the file again and applies the settings in `ReloadableConfig`. Any other
+//! variable that changed in the file, like the bot token, only takes effect after a restart.
+
+use crate::bbcode::Markup;
+use crate::digest::DigestSchedule;
+use crate::errors::AppError;
+use crate::feeds::{
+    parse_chat_list, parse_feed_base, DEFAULT_FEED_BASE, DEFAULT_LANGUAGE, DOTA_APPID,
+};
+use crate::file_part::{CompareMode, FirstRun, Selection};
+use crate::health::DEFAULT_HEALTH_PORT;
+use crate::json_part::{steam_language, DEFAULT_HTTP_TIMEOUT, DEFAULT_POLL_COUNT, MAX_POLL_COUNT};
+use crate::message_part::WelcomeMode;
+use crate::quiet_hours::QuietHours;
+use crate::sender::{MAX_MEDIA_GROUP_LEN, TELEGRAM_MAX_MESSAGE_LEN};
+use crate::subscribers::PrunePolicy;
+use reqwest::Url;
+use std::collections::HashMap;
+use std::env;
+use std::fmt::Display;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+use teloxide::types::{ChatId, UserId};
+use tracing::{info, warn};
+
+/// The file `dotenv` loads at startup and `/reload` reads again.
+pub const ENV_FILE: &str = ".env";
+
+/// Poll interval used when `SLEEP_DURATION_SECS` isn't set.
+const DEFAULT_SLEEP_DURATION_SECS: u64 = 5;
+
+/// Time of day the daily deliveries go out when `DAILY_DELIVERY_TIME` isn't set.
+const DEFAULT_DAILY_DELIVERY_TIME: &str = "09:00";
+
+/// Settings that are applied again on `/reload`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReloadableConfig {
+    /// How long to wait between polls, set by `SLEEP_DURATION_SECS`.
+    pub sleep_duration: Duration,
+    /// Updates posted longer ago than this are never broadcast, set by `MAX_UPDATE_AGE` in seconds.
+    pub max_update_age: Option<Duration>,
+    /// Whether every update ends with a "View on Steam" link to its post, set by `STEAM_LINKS`.
+    pub steam_links: bool,
+    /// Whether every update names its author on a byline, set by `BYLINES`.
+    pub bylines: bool,
+    /// Whether the images of an update are sent after it in media groups, set by `SEND_IMAGES`.
+    pub send_images: bool,
+    /// Most images per media group, set by `MEDIA_GROUP_SIZE` and clamped to
+    /// `1..=MAX_MEDIA_GROUP_LEN`.
+    pub media_group_size: usize,
+}
+
+/// The variables `ReloadableConfig` is read from, in field order.
+const RELOADABLE_VARS: &[&str] = &[
+    "SLEEP_DURATION_SECS",
+    "MAX_UPDATE_AGE",
+    "STEAM_LINKS",
+    "BYLINES",
+    "SEND_IMAGES",
+    "MEDIA_GROUP_SIZE",
+];
+
+impl ReloadableConfig {
+    /// Reads the settings with `var`, which returns the value of a variable if it is set.
+    ///
+    /// A setting that doesn't parse is a `ConfigError` rather than its default, so `/reload`
+    /// doesn't quietly undo a typo'd change.
+    fn from_vars(var: impl Fn(&str) -> Option<String>) -> Result<Self, AppError> {
+        let secs = |name| Ok::<_, AppError>(parse_var(&var, name)?.map(Duration::from_secs));
+        Ok(Self {
+            sleep_duration: secs("SLEEP_DURATION_SECS")?
+                .unwrap_or(Duration::from_secs(DEFAULT_SLEEP_DURATION_SECS)),
+            max_update_age: secs("MAX_UPDATE_AGE")?,
+            steam_links: parse_flag(&var, "STEAM_LINKS")?,
+            bylines: parse_flag(&var, "BYLINES")?,
+            send_images: parse_flag(&var, "SEND_IMAGES")?,
+            media_group_size: parse_var::<usize>(&var, "MEDIA_GROUP_SIZE")?
+                .map_or(MAX_MEDIA_GROUP_LEN, |size| {
+                    size.clamp(1, MAX_MEDIA_GROUP_LEN)
+                }),
+        })
+    }
+
+    /// Returns the variables whose settings differ between `self` and `other`.
+    fn changes(&self, other: &Self) -> Vec<&'static str> {
+        let changed = [
+            self.sleep_duration != other.sleep_duration,
+            self.max_update_age != other.max_update_age,
+            self.steam_links != other.steam_links,
+            self.bylines != other.bylines,
+            self.send_images != other.send_images,
+            self.media_group_size != other.media_group_size,
+        ];
+        RELOADABLE_VARS
+            .iter()
+            .zip(changed)
+            .filter(|(_, changed)| *changed)
+            .map(|(name, _)| *name)
+            .collect()
+    }
+}
+
+impl Default for ReloadableConfig {
+    fn default() -> Self {
+        Self::from_vars(|_| None).expect("unset variables use the defaults")
+    }
+}
+
+/// Settings read once at startup.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Config {
+    /// The bot token, from `TELOXIDE_TOKEN_FILE` or `TELOXIDE_TOKEN`.
+    pub telegram_token: String,
+    /// The Steam app whose news is polled, set by `APP_ID`.
+    pub app_id: u32,
+    /// Steam's name for the feed language, set by `FEED_LANGUAGE` as a language code. Without it
+    /// the language of the `LANG` locale is used, e.g. `russian` for `ru_RU.UTF-8`.
+    pub lang: &'static str,
+    /// Events fetched per poll unless an admin set another number with `/count`, set by
+    /// `EVENT_COUNT` and clamped to `1..=MAX_POLL_COUNT`.
+    pub event_count: u32,
+    /// Whether messages are only logged instead of sent, set by `DRY_RUN`.
+    pub dry_run: bool,
+    /// How long a request to Steam may take, set by `HTTP_TIMEOUT_SECS`.
+    pub http_timeout: Duration,
+    /// How long to wait before the first poll, set by `STARTUP_DELAY_SECS`.
+    pub startup_delay: Duration,
+    /// Longest message sent to Telegram in one piece, set by `MAX_MESSAGE_LEN`.
+    pub max_message_len: usize,
+    /// What polls compare to detect updates, set by `COMPARE`.
+    pub compare: CompareMode,
+    /// What the first poll of a fresh install does, set by `FIRST_RUN`.
+    pub first_run: FirstRun,
+    /// Which detected events a poll sends, set by `SELECTION`.
+    pub selection: Selection,
+    /// The SQLite database `CompareMode::Gid` keeps seen events in, set by `SEEN_DB`.
+    pub seen_db: Option<PathBuf>,
+    /// Least time between two messages to one chat, set by `CHAT_SEND_INTERVAL_MS`.
+    pub chat_send_interval: Duration,
+    /// Least time between any two messages, set by `BROADCAST_DELAY_MS`.
+    pub broadcast_delay: Duration,
+    /// How long Telegram has to fail before messages are written to `FALLBACK_FILE`, set by
+    /// `OUTAGE_AFTER_SECS`.
+    pub outage_after: Duration,
+    /// What a chat receives right after subscribing, set by `WELCOME`.
+    pub welcome: WelcomeMode,
+    /// Users from `ADMIN_IDS`. They are always admins and can grant admin access to others.
+    pub super_admins: Vec<UserId>,
+    /// Where usage reports are sent, set by `TELEMETRY_URL` when `TELEMETRY` is on.
+    pub telemetry_endpoint: Option<String>,
+    /// How often usage reports are sent, set by `TELEMETRY_INTERVAL_SECS`.
+    pub telemetry_interval: Duration,
+    /// When a subscriber that keeps failing as gone is unsubscribed; `None` keeps it.
+    pub prune_policy: Option<PrunePolicy>,
+    /// Where detected updates are POSTed, set by `UPDATE_WEBHOOK_URL`.
+    pub update_webhook: Option<String>,
+    /// The RSS feed file written after each poll that detects updates, set by `RSS_FILE`.
+    pub rss_file: Option<PathBuf>,
+    /// When the weekly digest is sent, set by `DIGEST_SCHEDULE`; `None` sends none.
+    pub digest_schedule: Option<DigestSchedule>,
+    /// Local hours during which real-time updates are held back, set by `QUIET_HOURS`.
+    pub quiet_hours: Option<QuietHours>,
+    /// When chats with daily delivery get their updates, set by `DAILY_DELIVERY_TIME`.
+    pub daily_delivery: DigestSchedule,
+    /// Clans whose events count as official, set by `OFFICIAL_CLAN_IDS`.
+    pub official_clan_ids: Vec<String>,
+    /// The events endpoint polled instead of `DEFAULT_FEED_BASE`, set by `STEAM_FEED_BASE`.
+    pub feed_base: Option<Url>,
+    /// The only chats updates of `app_id` go to, set by `FEED_<APP_ID>_CHATS`; `None` sends them
+    /// to the subscribers.
+    pub feed_chats: Option<Vec<ChatId>>,
+    /// Markup updates are formatted and sent in, set by `PARSE_MODE`.
+    pub markup: Markup,
+    /// Port health checks are answered on, set by `HEALTH_PORT`.
+    pub health_port: u16,
+    /// The settings `/reload` can change later, as they were at startup.
+    pub reloadable: ReloadableConfig,
+}
+
+impl Config {
+    /// Reads the settings from the process environment.
+    pub fn from_env() -> Result<Self, AppError> {
+        Self::from_vars(|name| env::var(name).ok())
+    }
+
+    /// Returns how long to wait between polls, as set at startup.
+    pub fn sleep_duration(&self) -> Duration {
+        self.reloadable.sleep_duration
+    }
+
+    /// Reads the settings with `var`, which returns the value of a variable if it is set.
+    ///
+    /// Only the bot token is required, and it must look like one; see `is_bot_token()`. An
+    /// invalid language, count, timeout, feed base or chat list is logged and its default used
+    /// instead. Any other setting that doesn't parse, like `APP_ID`, `QUIET_HOURS` or an on/off
+    /// switch, is a `ConfigError`, since falling back could change which updates are sent, and to
+    /// whom.
+    fn from_vars(var: impl Fn(&str) -> Option<String>) -> Result<Self, AppError> {
+        let set = |name| var(name).filter(|val: &String| !val.trim().is_empty());
+        let telegram_token = match token_from_vars(&var).map_err(AppError::ConfigError)? {
+            Some(token) => {
+                info!("Read the bot token from TELOXIDE_TOKEN_FILE.");
+                token
+            }
+            None => set("TELOXIDE_TOKEN").ok_or_else(|| {
+                AppError::ConfigError(
+                    "Neither TELOXIDE_TOKEN nor TELOXIDE_TOKEN_FILE is set".to_string(),
+                )
+            })?,
+        };
+        let telegram_token = telegram_token.trim().to_string();
+        if !is_bot_token(&telegram_token) {
+            // The value isn't logged, since it may be a real token with a typo.
+            return Err(AppError::ConfigError(
+                "TELOXIDE_TOKEN isn't a bot token, which looks like 123456789:AAH-example_secret"
+                    .to_string(),
+            ));
+        }
+        let app_id = parse_var(&var, "APP_ID")?.unwrap_or(DOTA_APPID);
+        let lang = match set("FEED_LANGUAGE") {
+            Some(lang) => steam_language(&lang).unwrap_or_else(|| {
+                warn!(
+                    "Unknown FEED_LANGUAGE {}, using {}.",
+                    lang, DEFAULT_LANGUAGE
+                );
+                DEFAULT_LANGUAGE
+            }),
+            None => set("LANG").map_or(DEFAULT_LANGUAGE, |locale| locale_language(&locale)),
+        };
+        let event_count =
+            set("EVENT_COUNT").map_or(DEFAULT_POLL_COUNT, |val| match val.trim().parse::<u32>() {
+                Ok(count) => count.clamp(1, MAX_POLL_COUNT),
+                Err(_) => {
+                    warn!("Invalid EVENT_COUNT {}, using {}.", val, DEFAULT_POLL_COUNT);
+                    DEFAULT_POLL_COUNT
+                }
+            });
+        let http_timeout =
+            set("HTTP_TIMEOUT_SECS").map_or(DEFAULT_HTTP_TIMEOUT, |val| match val.trim().parse() {
+                Ok(secs) if secs > 0 => Duration::from_secs(secs),
+                _ => {
+                    warn!(
+                        "Invalid HTTP_TIMEOUT_SECS {}, using {:?}.",
+                        val, DEFAULT_HTTP_TIMEOUT
+                    );
+                    DEFAULT_HTTP_TIMEOUT
+                }
+            });
+        let secs = |name| Ok::<_, AppError>(parse_var(&var, name)?.map(Duration::from_secs));
+        let millis = |name| Ok::<_, AppError>(parse_var(&var, name)?.map(Duration::from_millis));
+        let super_admins = set("ADMIN_IDS")
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|id| !id.is_empty())
+            .map(|id| {
+                id.parse().map(UserId).map_err(|err| {
+                    AppError::ConfigError(format!("Invalid ADMIN_IDS entry {:?}: {}", id, err))
+                })
+            })
+            .collect::<Result<_, _>>()?;
+        let telemetry_endpoint = match parse_flag(&var, "TELEMETRY")? {
+            true => {
+                let endpoint = set("TELEMETRY_URL");
+                if endpoint.is_none() {
+                    warn!("TELEMETRY is on but TELEMETRY_URL is not set; telemetry is disabled.");
+                }
+                endpoint
+            }
+            false => None,
+        };
+        let prune_window =
+            secs("PRUNE_FAILURE_WINDOW_SECS")?.unwrap_or(Duration::from_secs(7 * 24 * 60 * 60));
+        let prune_policy = parse_var(&var, "PRUNE_AFTER_FAILURES")?
+            .filter(|&failures| failures > 0)
+            .map(|failures| PrunePolicy {
+                failures,
+                window: prune_window,
+            });
+        let daily_delivery = match parse_var(&var, "DAILY_DELIVERY_TIME")? {
+            Some(time) => time,
+            None => DEFAULT_DAILY_DELIVERY_TIME
+                .parse()
+                .map_err(AppError::ConfigError)?,
+        };
+        let feed_base = set("STEAM_FEED_BASE").and_then(|val| match parse_feed_base(&val) {
+            Ok(base) => Some(base),
+            Err(err) => {
+                warn!("{}; using {}.", err, DEFAULT_FEED_BASE);
+                None
+            }
+        });
+        let feed_chats_var = format!("FEED_{}_CHATS", app_id);
+        let feed_chats = set(&feed_chats_var).and_then(|list| match parse_chat_list(&list) {
+            Ok(chats) => Some(chats),
+            Err(err) => {
+                warn!("{}; {} is ignored.", err, feed_chats_var);
+                None
+            }
+        });
+        Ok(Self {
+            telegram_token,
+            app_id,
+            lang,
+            event_count,
+            dry_run: parse_flag(&var, "DRY_RUN")?,
+            http_timeout,
+            startup_delay: secs("STARTUP_DELAY_SECS")?.unwrap_or_default(),
+            max_message_len: parse_var(&var, "MAX_MESSAGE_LEN")?
+                .unwrap_or(TELEGRAM_MAX_MESSAGE_LEN),
+            compare: parse_var(&var, "COMPARE")?.unwrap_or(CompareMode::Headline),
+            first_run: parse_var(&var, "FIRST_RUN")?.unwrap_or(FirstRun::Baseline),
+            selection: parse_var(&var, "SELECTION")?.unwrap_or(Selection::All),
+            seen_db: set("SEEN_DB").map(PathBuf::from),
+            chat_send_interval: millis("CHAT_SEND_INTERVAL_MS")?
+                .unwrap_or(Duration::from_millis(1000)),
+            broadcast_delay: millis("BROADCAST_DELAY_MS")?.unwrap_or(Duration::from_millis(50)),
+            outage_after: secs("OUTAGE_AFTER_SECS")?.unwrap_or(Duration::from_secs(300)),
+            welcome: parse_var(&var, "WELCOME")?.unwrap_or(WelcomeMode::Latest),
+            super_admins,
+            telemetry_endpoint,
+            telemetry_interval: secs("TELEMETRY_INTERVAL_SECS")?
+                .unwrap_or(Duration::from_secs(24 * 60 * 60)),
+            prune_policy,
+            update_webhook: set("UPDATE_WEBHOOK_URL"),
+            rss_file: set("RSS_FILE").map(PathBuf::from),
+            digest_schedule: parse_var(&var, "DIGEST_SCHEDULE")?,
+            quiet_hours: parse_var(&var, "QUIET_HOURS")?,
+            daily_delivery,
+            official_clan_ids: set("OFFICIAL_CLAN_IDS")
+                .unwrap_or_default()
+                .split(',')
+                .map(|id| id.trim().to_string())
+                .filter(|id| !id.is_empty())
+                .collect(),
+            feed_base,
+            feed_chats,
+            markup: parse_var(&var, "PARSE_MODE")?.unwrap_or(Markup::MarkdownV2),
+            health_port: parse_var(&var, "HEALTH_PORT")?.unwrap_or(DEFAULT_HEALTH_PORT),
+            reloadable: ReloadableConfig::from_vars(&var)?,
+        })
+    }
+}
+
+/// A `Config` read from an environment that only sets a bot token.
+#[cfg(test)]
+pub fn test_config() -> Config {
+    Config::from_vars(|name| (name == "TELOXIDE_TOKEN").then(|| "123456:ABC".to_string())).unwrap()
+}
+
+/// Parses the variable `name`, read with `var`, returning `None` if it isn't set or is blank and
+/// a `ConfigError` naming it if it doesn't parse.
+fn parse_var<T>(var: impl Fn(&str) -> Option<String>, name: &str) -> Result<Option<T>, AppError>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    let Some(val) = var(name).filter(|val| !val.trim().is_empty()) else {
+        return Ok(None);
+    };
+    val.trim()
+        .parse()
+        .map(Some)
+        .map_err(|err| AppError::ConfigError(format!("Invalid {} {:?}: {}", name, val, err)))
+}
+
+/// Reads the on/off switch `name` with `var`: `on`, `true`, `yes` and `1` turn it on, `off`,
+/// `false`, `no` and `0` off, in any case. A switch that isn't set or is blank is off, and any
+/// other value is a `ConfigError` naming it.
+fn parse_flag(var: impl Fn(&str) -> Option<String>, name: &str) -> Result<bool, AppError> {
+    let Some(val) = var(name).filter(|val| !val.trim().is_empty()) else {
+        return Ok(false);
+    };
+    match val.trim().to_lowercase().as_str() {
+        "on" | "true" | "yes" | "1" => Ok(true),
+        "off" | "false" | "no" | "0" => Ok(false),
+        _ => Err(AppError::ConfigError(format!(
+            "Invalid {} {:?}: expected on or off",
+            name, val
+        ))),
+    }
+}
+
+/// Returns Steam's name for the language of the POSIX `locale`, like `ru_RU.UTF-8`, or
+/// `DEFAULT_LANGUAGE` if Steam has no such language. The `C` and `POSIX` locales name none, so
+/// only other unknown locales are logged.
+fn locale_language(locale: &str) -> &'static str {
+    let code = locale.split(['_', '.', '@']).next().unwrap_or_default();
+    steam_language(code).unwrap_or_else(|| {
+        if !matches!(code, "C" | "POSIX") {
+            warn!("Unknown LANG {}, using {}.", locale, DEFAULT_LANGUAGE);
+        }
+        DEFAULT_LANGUAGE
+    })
+}
+
+/// Reads the bot token from the file `TELOXIDE_TOKEN_FILE` names, as mounted by Docker or
+/// Kubernetes secrets, with `var` returning the value of a variable if it is set.
+///
+/// Returns `None` if the variable isn't set, so the token comes from `TELOXIDE_TOKEN`. The file
+/// wins if both are set.
+fn token_from_vars(var: impl Fn(&str) -> Option<String>) -> Result<Option<String>, String> {
+    let Some(path) = var("TELOXIDE_TOKEN_FILE").filter(|path| !path.trim().is_empty()) else {
+        return Ok(None);
+    };
+    let content = fs::read_to_string(path.trim())
+        .map_err(|err| format!("Failed to read TELOXIDE_TOKEN_FILE {}: {}", path, err))?;
+    let token = content.trim();
+    if !is_bot_token(token) {
+        return Err(format!(
+            "TELOXIDE_TOKEN_FILE {} doesn't contain a bot token",
+            path
+        ));
+    }
+    Ok(Some(token.to_string()))
+}
+
+/// Returns `true` if `token` has the shape of a Telegram bot token: the bot's numeric id, a `:`,
+/// and a secret of ASCII letters, digits, `_` and `-`.
+fn is_bot_token(token: &str) -> bool {
+    token.split_once(':').is_some_and(|(id, secret)| {
+        !id.is_empty()
+            && id.chars().all(|c| c.is_ascii_digit())
+            && !secret.is_empty()
+            && secret
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+    })
+}
+
+/// What `reload()` read from the env file.
+#[derive(Debug)]
+pub struct Reload {
+    pub config: ReloadableConfig,
+    /// Variables whose reloadable settings changed.
+    pub changed: Vec<&'static str>,
+    /// Other variables that differ from the running environment, sorted by name.
+    pub needs_restart: Vec<String>,
+}
+
+/// Parses the `NAME=value` lines of an env file, skipping blank lines and `#` comments. Values
+/// may be wrapped in quotes.
+fn parse_env_file(content: &str) -> HashMap<String, String> {
+    content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|line| line.split_once('='))
+        .map(|(name, value)| {
+            let value = value.trim();
+            let unquoted = ['"', '\'']
+                .iter()
+                .find_map(|&quote| value.strip_prefix(quote)?.strip_suffix(quote));
+            (
+                name.trim().to_string(),
+                unquoted.unwrap_or(value).to_string(),
+            )
+        })
+        .collect()
+}
+
+/// Reads the env file at `path` and compares it with the `current` settings and the running
+/// environment. A reloadable setting that doesn't parse is a `ConfigError`.
+pub fn reload(path: &Path, current: &ReloadableConfig) -> Result<Reload, AppError> {
+    let vars = parse_env_file(&fs::read_to_string(path)?);
+    let config = ReloadableConfig::from_vars(|name| vars.get(name).cloned())?;
+    let mut needs_restart: Vec<String> = vars
+        .iter()
+        .filter(|(name, value)| {
+            !RELOADABLE_VARS.contains(&name.as_str())
+                && env::var(name).ok().as_ref() != Some(*value)
+        })
+        .map(|(name, _)| name.clone())
+        .collect();
+    needs_restart.sort();
+    Ok(Reload {
+        changed: current.changes(&config),
+        config,
+        needs_restart,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_token_is_read_from_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("token");
+        std::fs::write(&path, "  123456:ABC-def_ghi\n").unwrap();
+        let path = path.to_str().unwrap().to_string();
+        let var = |value: String| {
+            move |name: &str| (name == "TELOXIDE_TOKEN_FILE").then(|| value.clone())
+        };
+
+        assert_eq!(
+            token_from_vars(var(path.clone())),
+            Ok(Some("123456:ABC-def_ghi".to_string()))
+        );
+        assert_eq!(token_from_vars(|_| None), Ok(None));
+        assert!(token_from_vars(var(format!("{}.missing", path))).is_err());
+        std::fs::write(&path, "not a token").unwrap();
+        assert!(token_from_vars(var(path)).is_err());
+    }
+
+    #[test]
+    fn test_bot_token_shape() {
+        assert!(is_bot_token("123456789:AAH-x_Y9"));
+        for token in [
+            "",
+            "123456789",
+            ":AAH",
+            "123456789:",
+            "12a:AAH",
+            "-1:AAH",
+            "123:AA H",
+            "123:AAH:x",
+            "123:AAH€",
+            "your_token",
+        ] {
+            assert!(!is_bot_token(token), "{:?}", token);
+        }
+    }
+
+    #[test]
+    fn test_config_from_vars() {
+        let vars = |pairs: &'static [(&'static str, &'static str)]| {
+            move |name: &str| {
+                pairs
+                    .iter()
+                    .find(|(key, _)| *key == name)
+                    .map(|(_, val)| val.to_string())
+            }
+        };
+
+        let config = Config::from_vars(vars(&[
+            ("TELOXIDE_TOKEN", "123456:ABC"),
+            ("APP_ID", "730"),
+            ("FEED_LANGUAGE", "ru"),
+            ("EVENT_COUNT", "20"),
+            ("SLEEP_DURATION_SECS", "60"),
+            ("HTTP_TIMEOUT_SECS", "10"),
+        ]))
+        .unwrap();
+        assert_eq!(config.telegram_token, "123456:ABC");
+        assert_eq!(config.app_id, 730);
+        assert_eq!(config.lang, "russian");
+        assert_eq!(config.event_count, 20);
+        assert!(!config.dry_run);
+        assert_eq!(config.http_timeout, Duration::from_secs(10));
+        assert_eq!(config.reloadable.sleep_duration, Duration::from_secs(60));
+
+        let config = Config::from_vars(vars(&[
+            ("TELOXIDE_TOKEN", "123456:ABC"),
+            ("FEED_LANGUAGE", "xx"),
+            ("EVENT_COUNT", "many"),
+            ("HTTP_TIMEOUT_SECS", "0"),
+        ]))
+        .unwrap();
+        assert_eq!((config.app_id, config.lang), (DOTA_APPID, DEFAULT_LANGUAGE));
+        assert_eq!(config.event_count, DEFAULT_POLL_COUNT);
+        assert_eq!(config.http_timeout, DEFAULT_HTTP_TIMEOUT);
+
+        let err = Config::from_vars(vars(&[("TELOXIDE_TOKEN", " ")])).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Invalid configuration: Neither TELOXIDE_TOKEN nor TELOXIDE_TOKEN_FILE is set"
+        );
+        let err = Config::from_vars(vars(&[("TELOXIDE_TOKEN", "12345:hunter2 x")]))
+            .unwrap_err()
+            .to_string();
+        assert!(err.starts_with("Invalid configuration: TELOXIDE_TOKEN isn't a bot token"));
+        assert!(!err.contains("hunter2"));
+        let config = Config::from_vars(vars(&[("TELOXIDE_TOKEN", " 123456:ABC\n")])).unwrap();
+        assert_eq!(config.telegram_token, "123456:ABC");
+    }
+
+    #[test]
+    fn test_config_reads_startup_settings() {
+        let vars = |pairs: &'static [(&'static str, &'static str)]| {
+            move |name: &str| {
+                [("TELOXIDE_TOKEN", "123456:ABC")]
+                    .iter()
+                    .chain(pairs)
+                    .find(|(key, _)| *key == name)
+                    .map(|(_, val)| val.to_string())
+            }
+        };
+
+        let config = Config::from_vars(vars(&[])).unwrap();
+        assert_eq!(config, test_config());
+        assert_eq!(config.compare, CompareMode::Headline);
+        assert_eq!(config.welcome, WelcomeMode::Latest);
+        assert_eq!(config.max_message_len, TELEGRAM_MAX_MESSAGE_LEN);
+        assert_eq!(config.daily_delivery, "09:00".parse().unwrap());
+        assert!(config.super_admins.is_empty());
+        assert_eq!(config.sleep_duration(), Duration::from_secs(5));
+
+        let config = Config::from_vars(vars(&[
+            ("STARTUP_DELAY_SECS", "30"),
+            ("COMPARE", "gid"),
+            ("SELECTION", "newest"),
+            ("SEEN_DB", "seen.db"),
+            ("BROADCAST_DELAY_MS", "20"),
+            ("ADMIN_IDS", "1, 2,"),
+            ("PRUNE_AFTER_FAILURES", "3"),
+            ("PRUNE_FAILURE_WINDOW_SECS", "60"),
+            ("APP_ID", "730"),
+            ("FEED_730_CHATS", "-100"),
+            ("QUIET_HOURS", "23:00-07:00"),
+            ("HEALTH_PORT", "9000"),
+            ("DRY_RUN", "true"),
+            ("STEAM_LINKS", "ON"),
+            ("BYLINES", "0"),
+        ]))
+        .unwrap();
+        assert_eq!(config.startup_delay, Duration::from_secs(30));
+        assert_eq!(config.compare, CompareMode::Gid);
+        assert_eq!(config.selection, Selection::Newest);
+        assert_eq!(config.seen_db, Some(PathBuf::from("seen.db")));
+        assert_eq!(config.broadcast_delay, Duration::from_millis(20));
+        assert_eq!(config.super_admins, vec![UserId(1), UserId(2)]);
+        assert_eq!(
+            config.prune_policy,
+            Some(PrunePolicy {
+                failures: 3,
+                window: Duration::from_secs(60),
+            })
+        );
+        assert_eq!(config.feed_chats, Some(vec![ChatId(-100)]));
+        assert_eq!(config.quiet_hours, Some("23:00-07:00".parse().unwrap()));
+        assert_eq!(config.health_port, 9000);
+        assert!(config.dry_run);
+        assert!(config.reloadable.steam_links);
+        assert!(!config.reloadable.bylines);
+
+        for (name, val, expected) in [
+            (
+                "COMPARE",
+                "gidd",
+                r#"COMPARE "gidd": Unknown compare mode: gidd"#,
+            ),
+            (
+                "MAX_MESSAGE_LEN",
+                "4k",
+                r#"MAX_MESSAGE_LEN "4k": invalid digit"#,
+            ),
+            (
+                "ADMIN_IDS",
+                "1,bob",
+                r#"ADMIN_IDS entry "bob": invalid digit"#,
+            ),
+            (
+                "DAILY_DELIVERY_TIME",
+                "noon",
+                r#"DAILY_DELIVERY_TIME "noon": Invalid"#,
+            ),
+            ("APP_ID", "dota", r#"APP_ID "dota": invalid digit"#),
+            (
+                "QUIET_HOURS",
+                "soon",
+                r#"QUIET_HOURS "soon": Invalid quiet hours"#,
+            ),
+            (
+                "DIGEST_SCHEDULE",
+                "someday",
+                r#"DIGEST_SCHEDULE "someday": Invalid digest schedule"#,
+            ),
+            (
+                "SLEEP_DURATION_SECS",
+                "5m",
+                r#"SLEEP_DURATION_SECS "5m": invalid digit"#,
+            ),
+            (
+                "SEND_IMAGES",
+                "maybe",
+                r#"SEND_IMAGES "maybe": expected on or off"#,
+            ),
+        ] {
+            let err = Config::from_vars(|key| {
+                (key == name)
+                    .then(|| val.to_string())
+                    .or_else(|| (key == "TELOXIDE_TOKEN").then(|| "123456:ABC".to_string()))
+            })
+            .unwrap_err()
+            .to_string();
+            assert!(
+                err.starts_with(&format!("Invalid configuration: Invalid {}", expected)),
+                "{}",
+                err
+            );
+        }
+    }
+
+    #[test]
+    fn test_feed_language_falls_back_to_lang() {
+        let lang = |pairs: &[(&str, &str)]| {
+            let pairs: Vec<_> = [("TELOXIDE_TOKEN", "123456:ABC")]
+                .iter()
+                .chain(pairs)
+                .map(|(key, val)| (key.to_string(), val.to_string()))
+                .collect();
+            Config::from_vars(|name| {
+                pairs
+                    .iter()
+                    .find(|(key, _)| key == name)
+                    .map(|(_, val)| val.clone())
+            })
+            .unwrap()
+            .lang
+        };
+
+        assert_eq!(lang(&[("LANG", "ru_RU.UTF-8")]), "russian");
+        assert_eq!(lang(&[("LANG", "de")]), "german");
+        assert_eq!(
+            lang(&[("LANG", "ru_RU.UTF-8"), ("FEED_LANGUAGE", "pl")]),
+            "polish"
+        );
+        assert_eq!(lang(&[("LANG", "C.UTF-8")]), DEFAULT_LANGUAGE);
+        assert_eq!(lang(&[]), DEFAULT_LANGUAGE);
+    }
+
+    #[test]
+    fn test_reload_reports_changed_settings() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(ENV_FILE);
+        std::fs::write(
+            &path,
+            "SLEEP_DURATION_SECS=60\nSTEAM_LINKS=off\nDOTA_TEST_RESTART_ONLY=1\n",
+        )
+        .unwrap();
+
+        let reload = reload(&path, &ReloadableConfig::default()).unwrap();
+
+        assert_eq!(reload.config.sleep_duration, Duration::from_secs(60));
+        assert_eq!(reload.changed, vec!["SLEEP_DURATION_SECS"]);
+        assert_eq!(reload.needs_restart, vec!["DOTA_TEST_RESTART_ONLY"]);
+
+        std::fs::write(&path, "MEDIA_GROUP_SIZE=ten\n").unwrap();
+        assert!(matches!(
+            super::reload(&path, &ReloadableConfig::default()),
+            Err(AppError::ConfigError(_))
+        ));
+    }
+}
@@ -0,0 +1,250 @@
+//! Scheduled deliveries, independent of the real-time broadcast.
+//!
+//! * The optional weekly digest of all updates from the past 7 days is sent to every subscriber
+//!   at the time given by `DIGEST_SCHEDULE` (e.g. `mon 09:00`, in UTC). It is built from the post
+//!   times in the feed itself: the feed window holds far more than a week of posts, so no
+//!   separate history has to be kept.
+//! * Chats in `DeliveryMode::Digest` get the updates queued for them since the last delivery at
+//!   the daily time given by `DAILY_DELIVERY_TIME` (e.g. `09:00`, in UTC).
+
+use crate::clock::Clock;
+use crate::json_part::Event;
+use crate::message_part::{escape_markdown_v2, send_chunks, send_chunks_as, CommandContext};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use teloxide::types::ChatId;
+use tracing::{error, info};
+
+const DAY_SECS: u64 = 24 * 60 * 60;
+const WEEK_SECS: u64 = 7 * DAY_SECS;
+const WEEKDAYS: [&str; 7] = ["mon", "tue", "wed", "thu", "fri", "sat", "sun"];
+
+/// When a scheduled delivery is sent: a time of day in UTC, either every day or on one weekday.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DigestSchedule {
+    /// Days since Monday, or `None` for every day.
+    weekday: Option<u64>,
+    /// Seconds since midnight.
+    time_of_day: u64,
+}
+
+impl FromStr for DigestSchedule {
+    type Err = String;
+
+    /// Parses a weekly schedule like `mon 09:00` or a daily one like `09:00`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("Invalid digest schedule: {}", s);
+        let (day, time) = match s.trim().split_once(' ') {
+            Some((day, time)) => (Some(day), time),
+            None => (None, s),
+        };
+        let weekday = match day {
+            Some(day) => Some(
+                WEEKDAYS
+                    .iter()
+                    .position(|name| day.trim().to_lowercase().starts_with(name))
+                    .ok_or_else(invalid)? as u64,
+            ),
+            None => None,
+        };
+        Ok(Self {
+            weekday,
+            time_of_day: parse_time_of_day(time).ok_or_else(invalid)?,
+        })
+    }
+}
+
+/// Parses a time of day like `09:00` into seconds since midnight.
+pub fn parse_time_of_day(time: &str) -> Option<u64> {
+    let (hours, minutes) = time.trim().split_once(':')?;
+    let hours: u64 = hours.parse().ok()?;
+    let minutes: u64 = minutes.parse().ok()?;
+    if hours > 23 || minutes > 59 {
+        return None;
+    }
+    Some(hours * 60 * 60 + minutes * 60)
+}
+
+impl DigestSchedule {
+    /// Returns the first scheduled time strictly after `now`.
+    pub fn next_after(&self, now: SystemTime) -> SystemTime {
+        let now_secs = unix_secs(now);
+        let today = now_secs / DAY_SECS;
+        // 1970-01-01 was a Thursday.
+        let today_weekday = (today + 3) % 7;
+        let (days_ahead, period) = match self.weekday {
+            Some(weekday) => ((weekday + 7 - today_weekday) % 7, WEEK_SECS),
+            None => (0, DAY_SECS),
+        };
+        let mut next = (today + days_ahead) * DAY_SECS + self.time_of_day;
+        if next <= now_secs {
+            next += period;
+        }
+        SystemTime::UNIX_EPOCH + Duration::from_secs(next)
+    }
+}
+
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or(Duration::ZERO)
+        .as_secs()
+}
+
+/// Builds a Markdown V2 digest of the events posted in the week before `now`, newest first.
+///
+/// Returns `None` if nothing was posted that week.
+pub fn build_digest(events: &[Event], now: SystemTime) -> Option<String> {
+    let now_secs = unix_secs(now);
+    let since = now_secs.saturating_sub(WEEK_SECS);
+    let lines: Vec<String> = events
+        .iter()
+        .filter(|event| (since..=now_secs).contains(&event.announcement_body.posttime))
+        .filter_map(|event| {
+            Some(format!(
+                "• [{}]({})",
+                escape_markdown_v2(event.headline()?),
+                event.post_link()
+            ))
+        })
+        .collect();
+    if lines.is_empty() {
+        return None;
+    }
+    Some(format!("*Updates of the week*\n\n{}", lines.join("\n")))
+}
+
+/// Builds the digest for the clock's current time and sends it to every subscriber.
+async fn send_digest(ctx: &CommandContext, clock: &dyn Clock) {
+    let events = match ctx.source.fetch_events().await {
+        Ok(events) => events,
+        Err(err) => {
+            error!("Failed to fetch events for the digest: {}", err);
+            return;
+        }
+    };
+    let Some(digest) = build_digest(&events, clock.now()) else {
+        info!("Nothing was posted this week, skipping the digest.");
+        return;
+    };
+    for subscriber in ctx.subscribers.all_subscribers() {
+        let chat_id = ChatId(subscriber.chat_id);
+        if let Err(err) = send_chunks(ctx.sender.as_ref(), chat_id, &digest).await {
+            error!("Failed to send the digest to chat {}: {}", chat_id, err);
+        }
+    }
+    info!("Weekly digest sent.");
+}
+
+/// Sends every chat the updates queued for it, as one message per chat.
+///
+/// A chat's queue is only cleared once its message was sent, so a chat the send fails for keeps
+/// its updates for the next delivery.
+pub async fn deliver_pending(ctx: &CommandContext) {
+    let mut delivered = 0;
+    for (chat_id, updates) in ctx.subscribers.pending_updates() {
+        let text = updates.join("\n");
+        if let Err(err) =
+            send_chunks_as(ctx.sender.as_ref(), chat_id, &text, ctx.startup.markup).await
+        {
+            error!(
+                "Failed to deliver queued updates to chat {}, keeping them for the next delivery: {}",
+                chat_id, err
+            );
+            continue;
+        }
+        match ctx.subscribers.clear_pending(chat_id, updates.len()) {
+            Ok(()) => delivered += 1,
+            Err(err) => error!(
+                "Failed to clear the delivered updates of chat {}: {}",
+                chat_id, err
+            ),
+        }
+    }
+    info!("Delivered queued updates to {} chats.", delivered);
+}
+
+/// Waits for the next scheduled time according to `clock`.
+async fn wait_for(schedule: DigestSchedule, clock: &dyn Clock) {
+    let now = clock.now();
+    let next = schedule.next_after(now);
+    tokio::time::sleep(next.duration_since(now).unwrap_or(Duration::ZERO)).await;
+}
+
+/// Sends the digest at every scheduled time for as long as the bot runs.
+pub async fn run_digest(ctx: Arc<CommandContext>, schedule: DigestSchedule, clock: Arc<dyn Clock>) {
+    info!("Weekly digest is enabled: {:?}.", schedule);
+    loop {
+        wait_for(schedule, clock.as_ref()).await;
+        send_digest(&ctx, clock.as_ref()).await;
+    }
+}
+
+/// Delivers queued updates to digest chats at every scheduled time for as long as the bot runs.
+pub async fn run_daily_delivery(
+    ctx: Arc<CommandContext>,
+    schedule: DigestSchedule,
+    clock: Arc<dyn Clock>,
+) {
+    info!("Queued updates are delivered at {:?}.", schedule);
+    loop {
+        wait_for(schedule, clock.as_ref()).await;
+        deliver_pending(&ctx).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::json_part::test_event;
+
+    /// Thursday, 2024-03-14 12:00 UTC.
+    const NOW: u64 = 1_710_417_600;
+
+    fn posted(headline: &str, gid: &str, secs_ago: u64) -> Event {
+        let mut event = test_event(headline, "body");
+        event.gid = gid.to_string();
+        event.announcement_body.posttime = NOW - secs_ago;
+        event
+    }
+
+    #[test]
+    fn test_build_digest_covers_the_past_week() {
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(NOW);
+        let events = vec![
+            posted("Patch 7.35c", "3", 60),
+            posted("Battle Pass", "2", 6 * DAY_SECS),
+            posted("Patch 7.35b", "1", 8 * DAY_SECS),
+        ];
+
+        assert_eq!(
+            build_digest(&events, now).unwrap(),
+            "*Updates of the week*\n\n\
+             • [Patch 7\\.35c](https://store.steampowered.com/news/app/570/view/3)\n\
+             • [Battle Pass](https://store.steampowered.com/news/app/570/view/2)"
+        );
+        assert_eq!(build_digest(&events[2..], now), None);
+    }
+
+    #[test]
+    fn test_next_digest_time() {
+        let schedule: DigestSchedule = "mon 09:00".parse().unwrap();
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(NOW);
+        // Monday, 2024-03-18 09:00 UTC.
+        let next_monday = SystemTime::UNIX_EPOCH + Duration::from_secs(1_710_752_400);
+
+        assert_eq!(schedule.next_after(now), next_monday);
+        assert_eq!(
+            schedule.next_after(next_monday),
+            next_monday + Duration::from_secs(WEEK_SECS)
+        );
+        assert!("someday 09:00".parse::<DigestSchedule>().is_err());
+
+        let daily: DigestSchedule = "13:30".parse().unwrap();
+        assert_eq!(daily.next_after(now), now + Duration::from_secs(90 * 60));
+        assert_eq!(
+            daily.next_after(now + Duration::from_secs(2 * 60 * 60)),
+            now + Duration::from_secs(DAY_SECS + 90 * 60)
+        );
+    }
+}
@@ -1,3 +1,4 @@
+#[allow(clippy::enum_variant_names)]
 #[derive(thiserror::Error, Debug)]
 pub enum AppError {
     #[error("IO error: {0}")]
@@ -8,4 +9,17 @@ pub enum AppError {
 
     #[error("Failed to fetch URL: {0}")]
     FetchError(#[from] reqwest::Error),
+
+    #[error("Failed to send to Telegram: {0}")]
+    TelegramError(#[from] teloxide::RequestError),
+
+    #[error("Timed out reading the response body")]
+    BodyTimeoutError,
+
+    #[cfg(feature = "sqlite")]
+    #[error("Database error: {0}")]
+    DatabaseError(#[from] rusqlite::Error),
+
+    #[error("Invalid configuration: {0}")]
+    ConfigError(String),
 }
@@ -0,0 +1,340 @@
+use crate::json_part::{build_events_url, build_events_url_on};
+use crate::subscribers::Subscriber;
+use reqwest::Url;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use teloxide::types::ChatId;
+use tracing::warn;
+
+/// Steam app id of Dota 2.
+pub const DOTA_APPID: u32 = 570;
+
+/// Language polled for subscribers that didn't pick one.
+pub const DEFAULT_LANGUAGE: &str = "english";
+
+/// The Steam events endpoint, used when `STEAM_FEED_BASE` isn't set.
+pub const DEFAULT_FEED_BASE: &str =
+    "https://store.steampowered.com/events/ajaxgetpartnereventspageable/";
+
+/// The apps whose own site the bot knows: app id, product name, site and news page.
+const KNOWN_APPS: &[(u32, &str, &str, &str)] = &[
+    (
+        DOTA_APPID,
+        "Dota 2",
+        "https://www.dota2.com",
+        "https://www.dota2.com/news",
+    ),
+    (
+        730,
+        "Counter-Strike 2",
+        "https://www.counter-strike.net",
+        "https://www.counter-strike.net/news",
+    ),
+];
+
+/// A Steam app the bot can follow, with what differs between apps.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct App {
+    pub appid: u32,
+    /// The product name used in messages, e.g. `Dota 2`.
+    pub name: String,
+    /// The site events requests say they come from, sent as `origin`.
+    pub origin: String,
+    /// The news page linked from the footer of every update.
+    pub news_url: String,
+}
+
+impl App {
+    /// Returns the app with id `appid`. Apps not in `KNOWN_APPS` are named after their id and
+    /// use their Steam store pages.
+    pub fn for_appid(appid: u32) -> Self {
+        match KNOWN_APPS.iter().find(|(id, ..)| *id == appid) {
+            Some(&(appid, name, origin, news_url)) => Self {
+                appid,
+                name: name.to_string(),
+                origin: origin.to_string(),
+                news_url: news_url.to_string(),
+            },
+            None => Self {
+                appid,
+                name: format!("Steam app {}", appid),
+                origin: "https://store.steampowered.com".to_string(),
+                news_url: format!("https://store.steampowered.com/news/app/{}", appid),
+            },
+        }
+    }
+}
+
+/// Returns the Steam store page of a single post.
+pub fn post_link(appid: u32, gid: &str) -> String {
+    format!(
+        "https://store.steampowered.com/news/app/{}/view/{}",
+        appid, gid
+    )
+}
+
+/// Parses a `STEAM_FEED_BASE`: an `http` or `https` URL the events query is appended to.
+pub fn parse_feed_base(base: &str) -> Result<Url, String> {
+    let url =
+        Url::parse(base.trim()).map_err(|err| format!("Invalid feed base {}: {}", base, err))?;
+    if !matches!(url.scheme(), "http" | "https") {
+        return Err(format!("Invalid feed base {}: not an http(s) URL", base));
+    }
+    Ok(url)
+}
+
+/// Parses a `FEED_<appid>_CHATS` list of comma separated chat ids, like `-100,-200`.
+pub fn parse_chat_list(list: &str) -> Result<Vec<ChatId>, String> {
+    let chats = list
+        .split(',')
+        .map(str::trim)
+        .filter(|id| !id.is_empty())
+        .map(|id| {
+            id.parse()
+                .map(ChatId)
+                .map_err(|_| format!("Invalid chat id {} in {}", id, list))
+        })
+        .collect::<Result<Vec<ChatId>, String>>()?;
+    if chats.is_empty() {
+        return Err(format!("No chat ids in {}", list));
+    }
+    Ok(chats)
+}
+
+/// A Steam news feed the bot polls, together with its polling state.
+pub struct Feed {
+    pub appid: u32,
+    /// Steam language name, e.g. `english`.
+    pub language: String,
+    app: App,
+    /// The events endpoint, without query parameters of its own unless Steam needs them;
+    /// `None` uses `DEFAULT_FEED_BASE`.
+    base: Option<Url>,
+    /// Chats this feed's updates go to instead of the subscribers, set by `FEED_<appid>_CHATS`.
+    chats: Option<Vec<ChatId>>,
+    last_success: Mutex<Option<SystemTime>>,
+}
+
+impl Feed {
+    pub fn new(appid: u32, language: impl Into<String>) -> Self {
+        Self {
+            appid,
+            language: language.into(),
+            app: App::for_appid(appid),
+            base: None,
+            chats: None,
+            last_success: Mutex::new(None),
+        }
+    }
+
+    /// Fetches the feed's events from `base` instead of `DEFAULT_FEED_BASE`.
+    pub fn with_base(mut self, base: Url) -> Self {
+        self.base = Some(base);
+        self
+    }
+
+    /// Returns the app the feed follows.
+    pub fn app(&self) -> &App {
+        &self.app
+    }
+
+    /// Sends the feed's updates only to `chats` rather than to the subscribers.
+    pub fn with_chats(mut self, chats: Vec<ChatId>) -> Self {
+        self.chats = Some(chats);
+        self
+    }
+
+    /// Returns the chats the feed's updates are routed to, or `None` if they go to the
+    /// subscribers.
+    pub fn chats(&self) -> Option<&[ChatId]> {
+        self.chats.as_deref()
+    }
+
+    /// Returns the URL the newest `count` events of the feed are fetched from.
+    pub fn url(&self, count: u32) -> String {
+        self.url_in(&self.language, count)
+    }
+
+    /// Like `url()`, but in the Steam language `language` instead of the feed's.
+    pub fn url_in(&self, language: &str, count: u32) -> String {
+        match &self.base {
+            Some(base) => build_events_url_on(base.as_str(), self.appid, count, language),
+            None => build_events_url(self.appid, count, language),
+        }
+    }
+
+    /// Returns the news page link for the footer, in the feed's language.
+    ///
+    /// The link is validated as a URL, and it still has to be escaped with `Markup::escape_url()`.
+    /// If the language makes the URL malformed, the English page is linked instead.
+    pub fn news_link(&self) -> String {
+        self.news_link_in(&self.language)
+    }
+
+    /// Like `news_link()`, but in the Steam language `language`, for subscribers who read
+    /// another one.
+    pub fn news_link_in(&self, language: &str) -> String {
+        match Url::parse(&format!("{}?l={}", self.app.news_url, language)) {
+            Ok(url) => url.to_string(),
+            Err(err) => {
+                warn!("Invalid news link for {}: {}", language, err);
+                format!("{}?l={}", self.app.news_url, DEFAULT_LANGUAGE)
+            }
+        }
+    }
+
+    /// Records that the feed was just polled successfully.
+    pub fn record_success(&self) {
+        self.record_success_at(SystemTime::now());
+    }
+
+    fn record_success_at(&self, at: SystemTime) {
+        *self.last_success.lock().unwrap() = Some(at);
+    }
+
+    /// Returns when the feed was last polled successfully, if ever.
+    pub fn last_success(&self) -> Option<SystemTime> {
+        *self.last_success.lock().unwrap()
+    }
+
+    /// Returns `true` if the subscriber reads this feed's language, with subscribers who didn't
+    /// pick one reading `default_language`.
+    fn serves(&self, subscriber: &Subscriber, default_language: &str) -> bool {
+        subscriber.lang.as_deref().unwrap_or(default_language) == self.language
+    }
+}
+
+/// Formats one line per feed with its subscriber count and the time of its last successful poll,
+/// relative to `now`. Subscribers without a language of their own read `default_language`, the
+/// polled one.
+///
+/// The listing is plain text without Markdown V2 reserved characters, so it can be sent as is.
+pub fn feed_listing(
+    feeds: &[Arc<Feed>],
+    subscribers: &[Subscriber],
+    default_language: &str,
+    now: SystemTime,
+) -> String {
+    let mut listing = String::from("Active feeds:");
+    for feed in feeds {
+        let count = subscribers
+            .iter()
+            .filter(|s| feed.serves(s, default_language))
+            .count();
+        let last_success = match feed.last_success() {
+            Some(at) => format!(
+                "{}s ago",
+                now.duration_since(at).unwrap_or(Duration::ZERO).as_secs()
+            ),
+            None => "never".to_string(),
+        };
+        listing += &format!(
+            "\n• appid {}, {}: {} subscribers, last success {}",
+            feed.appid, feed.language, count, last_success
+        );
+    }
+    listing
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::json_part::test_event;
+
+    #[test]
+    fn test_links_follow_feed_language() {
+        let feed = Feed::new(DOTA_APPID, "russian");
+        assert_eq!(feed.news_link(), "https://www.dota2.com/news?l=russian");
+        assert!(feed.url(100).ends_with("&appid=570&l=russian&count=100"));
+    }
+
+    #[test]
+    fn test_url_of_another_app() {
+        let feed = Feed::new(730, "english");
+        assert_eq!(
+            feed.url(20),
+            "https://store.steampowered.com/events/ajaxgetpartnereventspageable/\
+             ?clan_accountid=0&offset=0&origin=https:%2F%2Fwww.counter-strike.net\
+             &appid=730&l=english&count=20"
+        );
+        assert_eq!(feed.app().name, "Counter-Strike 2");
+        assert_eq!(
+            feed.news_link(),
+            "https://www.counter-strike.net/news?l=english"
+        );
+
+        let unknown = App::for_appid(440);
+        assert_eq!(unknown.name, "Steam app 440");
+        assert_eq!(
+            unknown.news_url,
+            "https://store.steampowered.com/news/app/440"
+        );
+        let mut event = test_event("Counter-Strike 2 update", "Notes");
+        event.gid = "42".to_string();
+        event.appid = 730;
+        assert_eq!(
+            event.post_link(),
+            "https://store.steampowered.com/news/app/730/view/42"
+        );
+    }
+
+    #[test]
+    fn test_custom_feed_base() {
+        let base = parse_feed_base("https://store.steampowered.com/events/newendpoint/").unwrap();
+        let feed = Feed::new(DOTA_APPID, "english").with_base(base);
+        assert_eq!(
+            feed.url(100),
+            "https://store.steampowered.com/events/newendpoint/?clan_accountid=0&offset=0\
+             &origin=https:%2F%2Fwww.dota2.com&appid=570&l=english&count=100"
+        );
+        assert!(Feed::new(DOTA_APPID, "english")
+            .url(100)
+            .starts_with(DEFAULT_FEED_BASE));
+        assert!(parse_feed_base("not a url").is_err());
+        assert!(parse_feed_base("ftp://store.steampowered.com/").is_err());
+    }
+
+    #[test]
+    fn test_post_link() {
+        assert_eq!(
+            post_link(DOTA_APPID, "5123456789012345678"),
+            "https://store.steampowered.com/news/app/570/view/5123456789012345678"
+        );
+    }
+
+    #[test]
+    fn test_feed_listing() {
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000);
+        let english = Feed::new(DOTA_APPID, "english");
+        english.record_success_at(now - Duration::from_secs(30));
+        let russian = Feed::new(DOTA_APPID, "russian");
+        let subscribers = vec![
+            Subscriber::new(ChatId(1)),
+            Subscriber::new(ChatId(2)),
+            Subscriber {
+                lang: Some("russian".to_string()),
+                ..Subscriber::new(ChatId(3))
+            },
+        ];
+
+        let (english, russian) = (Arc::new(english), Arc::new(russian));
+
+        assert_eq!(
+            feed_listing(
+                &[Arc::clone(&english), Arc::clone(&russian)],
+                &subscribers,
+                "english",
+                now
+            ),
+            "Active feeds:\n\
+             • appid 570, english: 2 subscribers, last success 30s ago\n\
+             • appid 570, russian: 1 subscribers, last success never"
+        );
+        assert_eq!(
+            feed_listing(&[russian, english], &subscribers, "russian", now),
+            "Active feeds:\n\
+             • appid 570, russian: 3 subscribers, last success never\n\
+             • appid 570, english: 0 subscribers, last success 30s ago"
+        );
+    }
+}
@@ -1,31 +1,283 @@
+use crate::config::Config;
 use crate::errors::AppError;
-use crate::json_part::read_page_to_json_str_headlines;
-use log::{error, info};
-use serde_json::{self, Value};
+use crate::json_part::{extract_headlines, parse_events, Event, EventSource};
+#[cfg(feature = "sqlite")]
+use crate::seen_store::SeenStore;
+use serde::Serialize;
+use serde_json::{self, Map, Value};
+use std::collections::HashSet;
 use std::fs;
 use std::fs::File;
 use std::io::Read;
 use std::io::Write;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Instant, SystemTime};
+use tokio::sync::Mutex;
+use tracing::{error, field, info, info_span, warn, Span};
 
 const FILE1: &str = "temp_new.json";
 const FILE2: &str = "temp_old.json";
 
+/// Most event keys `CompareMode::Gid` remembers as seen, newest first.
+const MAX_SEEN_KEYS: usize = 1000;
+
+/// What `Poller::file_work()` compares between polls to decide whether something changed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompareMode {
+    /// Only headlines are compared, so only new posts are detected: the feed changed when its
+    /// newest headline wasn't stored before. See `new_headlines()`.
+    Headline,
+    /// Full bodies are compared per gid, so silent edits to existing posts are detected too.
+    Body,
+    /// The events are compared with a growing set of `seen_key()`s, so only events that were
+    /// never polled before are sent, however the feed is reordered or its window changes.
+    Gid,
+}
+
+impl FromStr for CompareMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "headline" => Ok(CompareMode::Headline),
+            "body" => Ok(CompareMode::Body),
+            "gid" => Ok(CompareMode::Gid),
+            other => Err(format!("Unknown compare mode: {}", other)),
+        }
+    }
+}
+
+/// What a poll cycle found worth sending.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Broadcast {
+    /// Only the newest update.
+    Latest,
+    /// Every update currently in the feed.
+    All,
+    /// Only the updates with these gids: those never seen by `CompareMode::Gid`, those newer than
+    /// the stored headlines in `CompareMode::Headline`, or those new or edited in
+    /// `CompareMode::Body`.
+    New(Vec<String>),
+}
+
+/// What a poll cycle found, with the events of the feed it compared, so sending them doesn't
+/// fetch the feed again.
+#[derive(Debug, Clone)]
+pub struct Detected {
+    pub broadcast: Broadcast,
+    /// The polled events, newest first.
+    pub events: Vec<Event>,
+}
+
+/// Which of the new events a poll cycle found it sends, in any `CompareMode`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Selection {
+    /// Every new event.
+    All,
+    /// Only the newest new event, so a burst of posts doesn't flood the chats. The others are
+    /// still stored as seen and never sent.
+    Newest,
+}
+
+impl Selection {
+    /// Narrows what `detected` sends to the events this selection sends.
+    fn apply(self, mut detected: Detected) -> Detected {
+        if let (Selection::Newest, Broadcast::New(gids)) = (self, &mut detected.broadcast) {
+            gids.truncate(1);
+        }
+        detected
+    }
+}
+
+impl FromStr for Selection {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "all" => Ok(Selection::All),
+            "newest" => Ok(Selection::Newest),
+            other => Err(format!("Unknown selection: {}", other)),
+        }
+    }
+}
+
+/// What `Poller::file_work()` does on a fresh install, when there is no stored state to compare against.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FirstRun {
+    /// Stores the current feed silently, so only later posts are sent.
+    Baseline,
+    /// Stores the current feed and sends its newest update.
+    BroadcastLatest,
+    /// Stores the current feed and sends every update in it.
+    BroadcastAll,
+}
+
+impl FirstRun {
+    fn broadcast(self) -> Option<Broadcast> {
+        match self {
+            FirstRun::Baseline => None,
+            FirstRun::BroadcastLatest => Some(Broadcast::Latest),
+            FirstRun::BroadcastAll => Some(Broadcast::All),
+        }
+    }
+}
+
+impl FromStr for FirstRun {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "baseline" => Ok(FirstRun::Baseline),
+            "broadcast_latest" => Ok(FirstRun::BroadcastLatest),
+            "broadcast_all" => Ok(FirstRun::BroadcastAll),
+            other => Err(format!("Unknown first run mode: {}", other)),
+        }
+    }
+}
+
 /// Writes headlines to a JSON file.
 ///
 /// This function writes the provided headlines to a JSON file. It converts the headlines into
 /// a JSON string using `serde_json::to_string()` and writes the string to the specified file.
 /// Returns `Ok(())` if the operation succeeds, otherwise returns an `io::Error`.
-pub async fn write_headlines_to_json_file(headlines: Vec<String>) -> Result<(), AppError> {
+pub async fn write_headlines_to_json_file(
+    filename: &str,
+    headlines: Vec<String>,
+) -> Result<(), AppError> {
     info!("Writing headlines to JSON file.");
     let json_str = serde_json::to_string(&headlines).map_err(AppError::ParseJsonError)?;
 
-    let mut file = File::create(FILE1)?;
+    let mut file = File::create(filename)?;
     file.write_all(json_str.as_bytes())?;
     info!("Headlines successfully written to JSON file.");
 
     Ok(())
 }
 
+/// Writes the body of each event, keyed by gid, to a JSON file.
+///
+/// This function is used by `CompareMode::Body`. Returns `Ok(())` if the operation succeeds,
+/// otherwise returns an error.
+pub async fn write_bodies_to_json_file(filename: &str, events: &[Event]) -> Result<(), AppError> {
+    info!("Writing bodies to JSON file.");
+    let json_str = serde_json::to_string(&body_snapshot(events))?;
+
+    let mut file = File::create(filename)?;
+    file.write_all(json_str.as_bytes())?;
+    info!("Bodies successfully written to JSON file.");
+
+    Ok(())
+}
+
+/// Writes the `seen_key()` of each event to a JSON file, followed by the `stored` keys of earlier
+/// polls, for `CompareMode::Gid`.
+///
+/// The set only grows, so an event that drops out of the feed window and comes back, or shows up
+/// once `/count` is raised, isn't sent again. The newest `MAX_SEEN_KEYS` keys are kept.
+fn write_seen_keys_to_json_file(
+    filename: &str,
+    events: &[Event],
+    stored: &[String],
+) -> Result<(), AppError> {
+    let mut keys: Vec<String> = events.iter().map(seen_key).collect();
+    let current: HashSet<String> = keys.iter().cloned().collect();
+    keys.extend(stored.iter().filter(|key| !current.contains(*key)).cloned());
+    keys.truncate(MAX_SEEN_KEYS.max(current.len()));
+    write_json_atomically(Path::new(filename), &keys)
+}
+
+/// Reads the keys `write_seen_keys_to_json_file()` stored, newest first. A file that can't be
+/// read as a list of keys is logged and treated as empty.
+fn read_seen_keys(filename: &str) -> Result<Vec<String>, AppError> {
+    Ok(parse_json(&read_file_content(filename)?)
+        .and_then(serde_json::from_value)
+        .unwrap_or_else(|err| {
+            error!("Failed to read seen events from {}: {}", filename, err);
+            Vec::new()
+        }))
+}
+
+/// Returns the gids of the events whose `seen_key()` isn't among the `seen` ones, in feed order.
+fn unseen_gids(events: &[Event], seen: &HashSet<String>) -> Vec<String> {
+    events
+        .iter()
+        .filter(|event| !seen.contains(&seen_key(event)))
+        .map(|event| event.gid.clone())
+        .collect()
+}
+
+/// Returns the `events` whose body differs from the one stored under their `seen_key()` in the
+/// `stored` body snapshot, or isn't stored at all, in feed order.
+fn changed_bodies<'a>(events: &'a [Event], stored: &Map<String, Value>) -> Vec<&'a Event> {
+    events
+        .iter()
+        .filter(|event| stored.get(&seen_key(event)) != Some(&event.announcement_body.body))
+        .collect()
+}
+
+/// Returns the live `events`, newest first, whose headlines come before the first one in `stored`.
+///
+/// Only the newest headlines count, so a feed that got longer after `/count` was raised, or
+/// shorter, doesn't look like it has new posts just because older ones came or went. As in
+/// `extract_headlines()`, events without a headline are skipped and a reposted headline counts
+/// once, for its newest event.
+fn new_headlines<'a>(events: &'a [Event], stored: &[String]) -> Vec<&'a Event> {
+    let mut seen = HashSet::new();
+    events
+        .iter()
+        .filter(|event| {
+            event
+                .headline()
+                .is_some_and(|headline| seen.insert(headline))
+        })
+        .take_while(|event| {
+            event
+                .headline()
+                .is_some_and(|headline| !stored.iter().any(|stored| stored == headline))
+        })
+        .collect()
+}
+
+/// Maps each event's `seen_key()` to its body.
+fn body_snapshot(events: &[Event]) -> Value {
+    let bodies: Map<String, Value> = events
+        .iter()
+        .map(|event| (seen_key(event), event.announcement_body.body.clone()))
+        .collect();
+    Value::Object(bodies)
+}
+
+/// Returns the key an event is stored under in a body snapshot: its gid followed by a hash of its
+/// headline and post time, e.g. `5123456789012345678:9f3c2a7d1e0b4c58`.
+///
+/// Keying by gid alone would merge every event Steam sends without one, so a new post could hide
+/// behind an older one, and a reused gid would look like an edit of the old post. The tradeoff is
+/// that a post whose headline is edited counts as a new entry rather than an edited one, which
+/// still triggers a broadcast. The hash is FNV-1a so keys stay the same across builds.
+fn seen_key(event: &Event) -> String {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    let headline = event.announcement_body.headline.as_bytes();
+    let posttime = event.announcement_body.posttime.to_le_bytes();
+    for byte in headline.iter().chain(&[0]).chain(&posttime) {
+        hash ^= u64::from(*byte);
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    format!("{}:{:016x}", event.gid, hash)
+}
+
+/// Serializes `value` to JSON and atomically replaces the file at `path` with it.
+///
+/// The JSON is written to a temporary file next to `path` which is then renamed into place, so a
+/// crash never leaves a half-written file behind.
+pub fn write_json_atomically(path: &Path, value: &impl Serialize) -> Result<(), AppError> {
+    let tmp_path = path.with_extension("json.tmp");
+    fs::write(&tmp_path, serde_json::to_string(value)?)?;
+    fs::rename(&tmp_path, path)?;
+    Ok(())
+}
+
 /// Reads the content of a file into a string.
 ///
 /// This function reads the content of the specified file into a string. Returns `Ok(content)`
@@ -68,52 +320,568 @@ fn compare_json_files(file1: &str, file2: &str) -> Result<bool, String> {
     Ok(json_value1 == json_value2)
 }
 
-/// Performs file-related tasks.
+/// The outcome of the last poll cycle, reported by `/last_cycle`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CycleSummary {
+    /// When the cycle finished.
+    pub at: SystemTime,
+    /// What the cycle found worth sending, if anything.
+    pub broadcast: Option<Broadcast>,
+    /// Entries that were new or changed since the last cycle.
+    pub updates_found: usize,
+    /// The error the cycle failed with, if it did.
+    pub error: Option<String>,
+    /// Chats the updates were sent to, or `None` if nothing was sent.
+    pub sent_to: Option<usize>,
+}
+
+/// Runs poll cycles one at a time.
 ///
-/// This function performs file-related tasks including reading headlines from a web page,
-/// writing them to a JSON file, comparing JSON files, removing and renaming files, and sending
-/// updates via Telegram. It logs information about each step and any errors encountered.
-pub async fn file_work(url: &str) -> bool {
-    info!("Starting file work...");
-    let headlines = read_page_to_json_str_headlines(url)
-        .await
-        .expect("Failed to read headlines from page");
-    write_headlines_to_json_file(headlines)
+/// The regular polling loop and `/poll_now` share one `Poller`, so two cycles never read and
+/// rewrite the state files at the same time.
+pub struct Poller {
+    mode: CompareMode,
+    first_run: FirstRun,
+    selection: Selection,
+    new_file: String,
+    old_file: String,
+    cycles: AtomicU64,
+    lock: Mutex<()>,
+    last_cycle: std::sync::Mutex<Option<CycleSummary>>,
+    /// When a cycle last found something to send.
+    last_detected: std::sync::Mutex<Option<SystemTime>>,
+    /// Where `CompareMode::Gid` keeps the events it has seen instead of `old_file`.
+    #[cfg(feature = "sqlite")]
+    seen_store: Option<SeenStore>,
+}
+
+impl Poller {
+    pub fn new(mode: CompareMode, first_run: FirstRun) -> Self {
+        Self::with_files(mode, first_run, FILE1, FILE2)
+    }
+
+    /// Creates a poller that keeps its state in the given files instead of the default ones.
+    pub fn with_files(
+        mode: CompareMode,
+        first_run: FirstRun,
+        new_file: impl Into<String>,
+        old_file: impl Into<String>,
+    ) -> Self {
+        Self {
+            mode,
+            first_run,
+            selection: Selection::All,
+            new_file: new_file.into(),
+            old_file: old_file.into(),
+            cycles: AtomicU64::new(0),
+            lock: Mutex::new(()),
+            last_cycle: std::sync::Mutex::new(None),
+            last_detected: std::sync::Mutex::new(None),
+            #[cfg(feature = "sqlite")]
+            seen_store: None,
+        }
+    }
+
+    /// Creates a poller comparing, starting and selecting as `config` says. With the `sqlite`
+    /// feature and `CompareMode::Gid`, seen events are kept in `config.seen_db` if it is set.
+    pub fn from_config(config: &Config) -> Result<Self, AppError> {
+        let poller = Self::new(config.compare, config.first_run).with_selection(config.selection);
+        #[cfg(feature = "sqlite")]
+        let poller = match &config.seen_db {
+            Some(path) if config.compare == CompareMode::Gid => {
+                info!("Keeping seen events in {}", path.display());
+                poller.with_seen_store(SeenStore::open(path)?)
+            }
+            Some(_) => {
+                warn!("SEEN_DB only applies to COMPARE=gid, ignoring it.");
+                poller
+            }
+            None => poller,
+        };
+        #[cfg(not(feature = "sqlite"))]
+        if config.seen_db.is_some() {
+            warn!("SEEN_DB is set, but the bot was built without the sqlite feature, ignoring it.");
+        }
+        Ok(poller)
+    }
+
+    /// Picks which unseen events each cycle sends; see `Selection`.
+    pub fn with_selection(mut self, selection: Selection) -> Self {
+        self.selection = selection;
+        self
+    }
+
+    /// Keeps the events seen in `CompareMode::Gid` in `store` instead of the state files. Other
+    /// modes still use the files.
+    #[cfg(feature = "sqlite")]
+    pub fn with_seen_store(mut self, store: SeenStore) -> Self {
+        self.seen_store = Some(store);
+        self
+    }
+
+    /// Returns the store seen events are kept in, if this poller uses one.
+    #[cfg(feature = "sqlite")]
+    fn seen_store(&self) -> Option<&SeenStore> {
+        self.seen_store
+            .as_ref()
+            .filter(|_| self.mode == CompareMode::Gid)
+    }
+
+    /// Creates the span for the next poll cycle, numbering cycles from 1.
+    pub fn next_span(&self) -> Span {
+        poll_cycle_span(self.cycles.fetch_add(1, Ordering::Relaxed) + 1)
+    }
+
+    /// Performs file-related tasks, waiting for any cycle already in progress to finish first.
+    ///
+    /// This function performs file-related tasks including reading headlines (or, in
+    /// `CompareMode::Body`, full bodies) from a web page, writing them to a JSON file, comparing
+    /// JSON files, and removing and renaming files. It logs information about each step. Failing to
+    /// fetch the feed or to store it returns the error, so the caller can retry on the next cycle.
+    pub async fn file_work(&self, source: &dyn EventSource) -> Result<Option<Detected>, AppError> {
+        let _guard = self.lock.lock().await;
+        self.detect(source).await
+    }
+
+    /// Like `file_work()`, but returns `None` without polling if a cycle is already in progress.
+    pub async fn try_file_work(
+        &self,
+        source: &dyn EventSource,
+    ) -> Option<Result<Option<Detected>, AppError>> {
+        let _guard = self.lock.try_lock().ok()?;
+        Some(self.detect(source).await)
+    }
+
+    /// Returns the headlines stored by the last poll, or `None` if nothing is stored yet or the
+    /// poller compares bodies, whose snapshots don't keep headlines.
+    pub fn stored_headlines(&self) -> Option<Vec<String>> {
+        if self.mode != CompareMode::Headline {
+            return None;
+        }
+        let content = read_file_content(&self.old_file).ok()?;
+        serde_json::from_str(&content).ok()
+    }
+
+    /// Returns the `events` the next poll would find unseen, in feed order, or `None` if nothing
+    /// is stored yet.
+    ///
+    /// Gid snapshots are compared by `seen_key()`, headline snapshots by headline, and body
+    /// snapshots by body, so an edited post counts as unseen as it does for the poll.
+    pub fn unseen_events<'a>(&self, events: &'a [Event]) -> Option<Vec<&'a Event>> {
+        #[cfg(feature = "sqlite")]
+        if let Some(store) = self.seen_store() {
+            if store.count().ok()? == 0 {
+                return None;
+            }
+            let mut unseen = Vec::new();
+            for event in events {
+                if !store.is_seen(&seen_key(event)).ok()? {
+                    unseen.push(event);
+                }
+            }
+            return Some(unseen);
+        }
+        let content = read_file_content(&self.old_file).ok()?;
+        let stored: Vec<String> = match serde_json::from_str(&content).ok()? {
+            Value::Object(bodies) if self.mode == CompareMode::Body => {
+                return Some(changed_bodies(events, &bodies));
+            }
+            entries @ Value::Array(_) if self.mode != CompareMode::Body => {
+                serde_json::from_value(entries).ok()?
+            }
+            _ => return None,
+        };
+        if self.mode == CompareMode::Headline {
+            return Some(new_headlines(events, &stored));
+        }
+        let seen: HashSet<String> = stored.into_iter().collect();
+        Some(
+            events
+                .iter()
+                .filter(|event| !seen.contains(&seen_key(event)))
+                .collect(),
+        )
+    }
+
+    /// Returns the outcome of the last cycle, or `None` if none has run yet.
+    pub fn last_cycle(&self) -> Option<CycleSummary> {
+        self.last_cycle.lock().unwrap().clone()
+    }
+
+    /// Returns when a cycle last found something to send, or `None` if none has yet.
+    pub fn last_detected(&self) -> Option<SystemTime> {
+        *self.last_detected.lock().unwrap()
+    }
+
+    /// Returns the number of entries in the stored snapshot, or `None` if nothing is stored yet.
+    pub fn tracked_events(&self) -> Option<usize> {
+        #[cfg(feature = "sqlite")]
+        if let Some(store) = self.seen_store() {
+            return store.count().ok().filter(|count| *count > 0);
+        }
+        let content = read_file_content(&self.old_file).ok()?;
+        match serde_json::from_str(&content).ok()? {
+            Value::Array(entries) => Some(entries.len()),
+            Value::Object(entries) => Some(entries.len()),
+            _ => None,
+        }
+    }
+
+    /// Records that the updates found by the last cycle were sent to `chats` chats.
+    pub fn record_sent(&self, chats: usize) {
+        if let Some(summary) = self.last_cycle.lock().unwrap().as_mut() {
+            summary.sent_to = Some(chats);
+        }
+    }
+
+    async fn detect(&self, source: &dyn EventSource) -> Result<Option<Detected>, AppError> {
+        let mut updates_found = 0;
+        #[cfg(feature = "sqlite")]
+        let result = match self.seen_store() {
+            Some(store) => detect_unseen(source, store, self.first_run, &mut updates_found).await,
+            None => self.detect_in_files(source, &mut updates_found).await,
+        };
+        #[cfg(not(feature = "sqlite"))]
+        let result = self.detect_in_files(source, &mut updates_found).await;
+        let result = result.map(|detected| detected.map(|detected| self.selection.apply(detected)));
+        let now = SystemTime::now();
+        if let Ok(Some(_)) = &result {
+            *self.last_detected.lock().unwrap() = Some(now);
+        }
+        *self.last_cycle.lock().unwrap() = Some(CycleSummary {
+            at: now,
+            broadcast: result
+                .as_ref()
+                .ok()
+                .and_then(|detected| Some(detected.as_ref()?.broadcast.clone())),
+            updates_found,
+            error: result.as_ref().err().map(ToString::to_string),
+            sent_to: None,
+        });
+        result
+    }
+
+    async fn detect_in_files(
+        &self,
+        source: &dyn EventSource,
+        updates_found: &mut usize,
+    ) -> Result<Option<Detected>, AppError> {
+        detect_changes(
+            source,
+            self.mode,
+            self.first_run,
+            &self.new_file,
+            &self.old_file,
+            updates_found,
+        )
         .await
-        .expect("Failed to write headlines to JSON file");
+    }
+
+    /// Holds the poll lock, as a running cycle would.
+    #[cfg(test)]
+    pub async fn lock(&self) -> tokio::sync::MutexGuard<'_, ()> {
+        self.lock.lock().await
+    }
+}
+
+/// Compares stored headlines with live ones, returning the live headlines that aren't stored and
+/// the stored ones that are no longer live, each in their original order.
+pub fn headline_diff(stored: &[String], live: &[String]) -> (Vec<String>, Vec<String>) {
+    let missing_from = |headlines: &[String], other: &[String]| {
+        headlines
+            .iter()
+            .filter(|headline| !other.contains(headline))
+            .cloned()
+            .collect()
+    };
+    (missing_from(live, stored), missing_from(stored, live))
+}
+
+/// Creates the span a poll cycle runs in.
+///
+/// `record_cycle()` records `updates_found` and `duration_ms` on the current span once the cycle
+/// finishes, and any broadcast triggered by the cycle opens a child span inside it.
+pub fn poll_cycle_span(cycle: u64) -> Span {
+    info_span!(
+        "poll_cycle",
+        cycle,
+        updates_found = field::Empty,
+        duration_ms = field::Empty
+    )
+}
+
+/// Compares the current feed against the state in `old_file`, using `new_file` as scratch space.
+///
+/// Returns `None` if nothing changed, or an error if the feed can't be fetched or stored. The
+/// number of new or changed entries is reported through `updates_found`. A feed without any events is never treated as a change and
+/// leaves the stored state untouched, since Steam briefly serves an empty `events` array while
+/// its CDN propagates. If `old_file` doesn't exist yet, the feed is stored and `first_run`
+/// decides what is sent.
+async fn detect_changes(
+    source: &dyn EventSource,
+    mode: CompareMode,
+    first_run: FirstRun,
+    new_file: &str,
+    old_file: &str,
+    updates_found: &mut usize,
+) -> Result<Option<Detected>, AppError> {
+    let started = Instant::now();
+    let broadcast =
+        compare_snapshots(source, mode, first_run, new_file, old_file, updates_found).await;
+    record_cycle(started, *updates_found);
+    broadcast
+}
 
-    return match compare_json_files(FILE1, FILE2) {
+/// Records on the current poll cycle span how many updates the cycle found and how long it took
+/// since `started`.
+fn record_cycle(started: Instant, updates_found: usize) {
+    let span = Span::current();
+    span.record("updates_found", updates_found);
+    span.record("duration_ms", started.elapsed().as_millis() as u64);
+}
+
+/// Like `detect_changes()` in `CompareMode::Gid`, but checks the events against `store` rather
+/// than a snapshot file, marking each unseen one seen.
+///
+/// An empty store counts as a first run, so `first_run` decides what is sent.
+#[cfg(feature = "sqlite")]
+async fn detect_unseen(
+    source: &dyn EventSource,
+    store: &SeenStore,
+    first_run: FirstRun,
+    updates_found: &mut usize,
+) -> Result<Option<Detected>, AppError> {
+    let started = Instant::now();
+    let broadcast = compare_with_store(source, store, first_run, updates_found).await;
+    record_cycle(started, *updates_found);
+    broadcast
+}
+
+/// The body of `detect_unseen()`, reporting how many events are new through `updates_found`.
+#[cfg(feature = "sqlite")]
+async fn compare_with_store(
+    source: &dyn EventSource,
+    store: &SeenStore,
+    first_run: FirstRun,
+    updates_found: &mut usize,
+) -> Result<Option<Detected>, AppError> {
+    info!("Starting to check the feed against the seen events...");
+    let Some(feed) = source.fetch_feed_if_changed().await? else {
+        info!("The feed wasn't modified. Nothing new.");
+        return Ok(None);
+    };
+    let events = parse_events(&feed)?;
+    if events.is_empty() {
+        warn!("The feed returned no events, skipping this cycle.");
+        return Ok(None);
+    }
+    let first = store.count()? == 0;
+    let mut gids = Vec::new();
+    for event in &events {
+        let key = seen_key(event);
+        if !store.is_seen(&key)? {
+            store.mark_seen(&key, &event.announcement_body.headline)?;
+            gids.push(event.gid.clone());
+        }
+    }
+    source.commit_poll();
+
+    if first {
+        info!(
+            "No seen events stored yet, first run mode is {:?}.",
+            first_run
+        );
+        if first_run != FirstRun::Baseline {
+            *updates_found = events.len();
+        }
+        return Ok(detected(first_run.broadcast(), events));
+    }
+    *updates_found = gids.len();
+    if gids.is_empty() {
+        info!("Every event was seen before. Nothing new.");
+        return Ok(None);
+    }
+    info!("{} events weren't seen before.", gids.len());
+    Ok(detected(Some(Broadcast::New(gids)), events))
+}
+
+/// Pairs `broadcast`, if there is one, with the `events` it was found in.
+fn detected(broadcast: Option<Broadcast>, events: Vec<Event>) -> Option<Detected> {
+    broadcast.map(|broadcast| Detected { broadcast, events })
+}
+
+/// Replaces the stored state in `old_file` with the snapshot just written to `new_file`, and
+/// once it is stored lets `source` skip the same feed from then on.
+///
+/// Renaming replaces the old file in one step, so a crash leaves either snapshot.
+fn store_snapshot(source: &dyn EventSource, new_file: &str, old_file: &str) {
+    match fs::rename(new_file, old_file) {
+        Ok(()) => source.commit_poll(),
+        Err(err) => error!("Failed to rename file {}: {}", new_file, err),
+    }
+}
+
+/// The body of `detect_changes()`, reporting how many entries are new through `updates_found`.
+async fn compare_snapshots(
+    source: &dyn EventSource,
+    mode: CompareMode,
+    first_run: FirstRun,
+    new_file: &str,
+    old_file: &str,
+    updates_found: &mut usize,
+) -> Result<Option<Detected>, AppError> {
+    info!("Starting file work...");
+    let Some(feed) = source.fetch_feed_if_changed().await? else {
+        info!("The feed wasn't modified. Nothing new.");
+        return Ok(None);
+    };
+    let headlines = extract_headlines(&feed);
+    if headlines.is_empty() {
+        warn!("The feed returned no events, skipping this cycle.");
+        return Ok(None);
+    }
+    let first = !Path::new(old_file).exists();
+    let feed_len = headlines.len();
+    let events = parse_events(&feed)?;
+
+    let mut seen = Vec::new();
+    match mode {
+        CompareMode::Headline => {
+            write_headlines_to_json_file(new_file, headlines.clone()).await?;
+        }
+        CompareMode::Body => {
+            write_bodies_to_json_file(new_file, &events).await?;
+        }
+        CompareMode::Gid => {
+            if !first {
+                seen = read_seen_keys(old_file)?;
+            }
+            write_seen_keys_to_json_file(new_file, &events, &seen)?;
+        }
+    }
+
+    if first {
+        info!("No stored state found, first run mode is {:?}.", first_run);
+        if first_run != FirstRun::Baseline {
+            *updates_found = feed_len;
+        }
+        store_snapshot(source, new_file, old_file);
+        return Ok(detected(first_run.broadcast(), events));
+    }
+
+    if mode == CompareMode::Headline {
+        let stored: Vec<String> = parse_json(&read_file_content(old_file)?)
+            .and_then(serde_json::from_value)
+            .unwrap_or_else(|err| {
+                error!("Failed to read stored headlines from {}: {}", old_file, err);
+                Vec::new()
+            });
+        let gids: Vec<String> = new_headlines(&events, &stored)
+            .into_iter()
+            .map(|event| event.gid.clone())
+            .collect();
+        *updates_found = gids.len();
+        store_snapshot(source, new_file, old_file);
+        if gids.is_empty() {
+            info!("The newest headline was stored before. Nothing new.");
+            return Ok(None);
+        }
+        info!("{} headlines are newer than the stored ones.", gids.len());
+        return Ok(detected(Some(Broadcast::New(gids)), events));
+    }
+
+    if mode == CompareMode::Gid {
+        let gids = unseen_gids(&events, &seen.into_iter().collect());
+        *updates_found = gids.len();
+        store_snapshot(source, new_file, old_file);
+        if gids.is_empty() {
+            info!("Every event was seen before. Nothing new.");
+            return Ok(None);
+        }
+        info!("{} events weren't seen before.", gids.len());
+        return Ok(detected(Some(Broadcast::New(gids)), events));
+    }
+
+    Ok(match compare_json_files(new_file, old_file) {
         Ok(true) => {
             info!("The JSON files are equal. Nothing new.");
-            true
-        },
+            source.commit_poll();
+            None
+        }
         Ok(false) => {
             info!("The JSON files are different.");
-
-            if let Err(err) = fs::remove_file(FILE2) {
-                error!("Failed to remove file {}: {}", FILE2, err);
+            let stored = match parse_json(&read_file_content(old_file)?) {
+                Ok(Value::Object(bodies)) => bodies,
+                _ => {
+                    error!("Failed to read stored bodies from {}", old_file);
+                    Map::new()
+                }
+            };
+            let gids: Vec<String> = changed_bodies(&events, &stored)
+                .into_iter()
+                .map(|event| event.gid.clone())
+                .collect();
+            *updates_found = gids.len();
+            store_snapshot(source, new_file, old_file);
+            if gids.is_empty() {
+                info!("Only events that left the feed changed. Nothing new.");
+                return Ok(None);
             }
-            if let Err(err) = fs::rename(FILE1, FILE2) {
-                error!("Failed to rename file {}: {}", FILE1, err);
-            }
-            false
+            info!("{} events are new or were edited.", gids.len());
+            detected(Some(Broadcast::New(gids)), events)
         }
         Err(err) => {
             error!("Error: {}", err);
-            true
-        },
-    }
+            None
+        }
+    })
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::json_part::{
+        test_event, test_event_with_gid as event, AnnouncementBody, MockEventSource,
+        SteamEventSource,
+    };
     use serde_json::json;
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+    use tracing::field::Visit;
+    use tracing::span::{Attributes, Id, Record};
+    use tracing::Instrument;
+    use tracing_subscriber::layer::{Context, SubscriberExt};
+    use tracing_subscriber::registry::LookupSpan;
+    use tracing_subscriber::{Layer, Registry};
+
+    /// Creates a poller in `mode` that keeps its state files in `dir`.
+    fn poller_in(dir: &tempfile::TempDir, mode: CompareMode) -> Poller {
+        let file = |name| dir.path().join(name).to_str().unwrap().to_string();
+        Poller::with_files(mode, FirstRun::Baseline, file("new.json"), file("old.json"))
+    }
+
+    /// Drops the polled events of a cycle's result, which most tests don't look at.
+    trait BroadcastOnly {
+        fn broadcast(self) -> Option<Broadcast>;
+    }
+
+    impl BroadcastOnly for Option<Detected> {
+        fn broadcast(self) -> Option<Broadcast> {
+            self.map(|detected| detected.broadcast)
+        }
+    }
 
     #[tokio::test]
     async fn test_write_headlines_to_json_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(FILE1);
+        let path = path.to_str().unwrap();
         let headlines = vec!["headline1".to_string(), "headline2".to_string()];
-        assert!(write_headlines_to_json_file(headlines).await.is_ok());
+        assert!(write_headlines_to_json_file(path, headlines).await.is_ok());
+        assert_eq!(
+            read_file_content(path).unwrap(),
+            r#"["headline1","headline2"]"#
+        );
     }
 
     #[test]
@@ -141,4 +909,518 @@ mod tests {
             compare_json_files("test_files/test1_eq.json", "test_files/test_dif.json").unwrap();
         assert!(!result);
     }
+
+    #[test]
+    fn test_compare_modes_on_body_edit() {
+        let before = json!({"events": [
+            {"gid": "1", "announcement_body": {"headline": "Patch 7.35d", "body": "Old notes"}},
+        ]});
+        let after = json!({"events": [
+            {"gid": "1", "announcement_body": {"headline": "Patch 7.35d", "body": "Fixed notes"}},
+        ]});
+
+        assert_eq!(extract_headlines(&before), extract_headlines(&after));
+        assert_ne!(
+            body_snapshot(&parse_events(&before).unwrap()),
+            body_snapshot(&parse_events(&after).unwrap())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_new_post_without_gid_is_detected() {
+        let dir = tempfile::tempdir().unwrap();
+        let new_file = dir.path().join("new.json");
+        let old_file = dir.path().join("old.json");
+        let (new_file, old_file) = (new_file.to_str().unwrap(), old_file.to_str().unwrap());
+        let detect = |events| async {
+            detect_changes(
+                &MockEventSource::new(events),
+                CompareMode::Body,
+                FirstRun::Baseline,
+                new_file,
+                old_file,
+                &mut 0,
+            )
+            .await
+            .unwrap()
+            .broadcast()
+        };
+
+        let old = vec![test_event("Patch 7.35c", "Notes")];
+        assert_eq!(detect(old.clone()).await, None);
+        assert_eq!(detect(old.clone()).await, None);
+
+        let mut new = vec![test_event("Patch 7.35d", "Notes")];
+        new.extend(old);
+        assert_eq!(detect(new).await, Some(Broadcast::New(vec![String::new()])));
+    }
+
+    #[tokio::test]
+    async fn test_body_mode_broadcasts_the_edited_event() {
+        let dir = tempfile::tempdir().unwrap();
+        let poller = poller_in(&dir, CompareMode::Body);
+        let edited = |gid: &str, body: &str| Event {
+            announcement_body: AnnouncementBody {
+                body: Value::String(body.to_string()),
+                ..event(gid).announcement_body
+            },
+            ..event(gid)
+        };
+        let detect = |events| {
+            let poller = &poller;
+            async move {
+                poller
+                    .file_work(&MockEventSource::new(events))
+                    .await
+                    .unwrap()
+            }
+        };
+
+        let old = vec![event("3"), event("2"), event("1")];
+        assert!(detect(old).await.is_none());
+
+        let new = vec![event("3"), edited("2", "Fixed notes"), event("1")];
+        let unseen: Vec<&str> = poller
+            .unseen_events(&new)
+            .unwrap()
+            .iter()
+            .map(|event| event.gid.as_str())
+            .collect();
+        assert_eq!(unseen, vec!["2"]);
+        let detected = detect(new.clone()).await.unwrap();
+        assert_eq!(detected.broadcast, Broadcast::New(vec!["2".to_string()]));
+        assert_eq!(poller.last_cycle().unwrap().updates_found, 1);
+        assert!(detect(new).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_unseen_events_match_what_the_next_poll_sends() {
+        for mode in [CompareMode::Gid, CompareMode::Body, CompareMode::Headline] {
+            let dir = tempfile::tempdir().unwrap();
+            let poller = poller_in(&dir, mode);
+            let old = vec![event("2"), event("1")];
+            assert!(poller.unseen_events(&old).is_none());
+            poller
+                .file_work(&MockEventSource::new(old))
+                .await
+                .unwrap()
+                .broadcast();
+
+            // "0" was never seen, but in `CompareMode::Headline` it comes after a stored
+            // headline, so the poll doesn't count it.
+            for new in [
+                vec![event("3"), event("2"), event("1")],
+                vec![event("4"), event("3"), event("0"), event("2"), event("1")],
+            ] {
+                let unseen: Vec<String> = poller
+                    .unseen_events(&new)
+                    .unwrap()
+                    .iter()
+                    .map(|event| event.gid.clone())
+                    .collect();
+                let sent = poller
+                    .file_work(&MockEventSource::new(new))
+                    .await
+                    .unwrap()
+                    .broadcast();
+                assert_eq!(sent, Some(Broadcast::New(unseen)), "{:?}", mode);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_failed_cycle_is_retried_with_the_same_feed() {
+        use wiremock::matchers::method;
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let server = MockServer::start().await;
+        let feed = json!({"success": 1, "events": [test_event("Patch 7.36", "Notes")]});
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(feed))
+            .expect(3)
+            .mount(&server)
+            .await;
+        let source = SteamEventSource::new(server.uri());
+        let dir = tempfile::tempdir().unwrap();
+        let old_file = dir.path().join("old.json");
+        std::fs::write(&old_file, r#"["Patch 7.35"]"#).unwrap();
+        let old_file = old_file.to_str().unwrap();
+        let detect = |new_file: String| {
+            let source = &source;
+            async move {
+                detect_changes(
+                    source,
+                    CompareMode::Headline,
+                    FirstRun::Baseline,
+                    &new_file,
+                    old_file,
+                    &mut 0,
+                )
+                .await
+            }
+        };
+        let missing_dir = dir.path().join("missing").join("new.json");
+        let new_file = dir.path().join("new.json");
+
+        assert!(detect(missing_dir.to_str().unwrap().to_string())
+            .await
+            .is_err());
+        assert_eq!(
+            detect(new_file.to_str().unwrap().to_string())
+                .await
+                .unwrap()
+                .broadcast(),
+            Some(Broadcast::New(vec![String::new()]))
+        );
+        assert_eq!(
+            detect(new_file.to_str().unwrap().to_string())
+                .await
+                .unwrap()
+                .broadcast(),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn test_changing_the_count_is_not_an_update() {
+        let dir = tempfile::tempdir().unwrap();
+        let new_file = dir.path().join("new.json");
+        let old_file = dir.path().join("old.json");
+        let (new_file, old_file) = (new_file.to_str().unwrap(), old_file.to_str().unwrap());
+        let detect = |gids: &[&str]| {
+            let events = gids.iter().map(|gid| event(gid)).collect();
+            async move {
+                let mut updates_found = 0;
+                let broadcast = detect_changes(
+                    &MockEventSource::new(events),
+                    CompareMode::Headline,
+                    FirstRun::Baseline,
+                    new_file,
+                    old_file,
+                    &mut updates_found,
+                )
+                .await
+                .unwrap()
+                .broadcast();
+                (broadcast, updates_found)
+            }
+        };
+
+        assert_eq!(detect(&["3", "2"]).await, (None, 0));
+        assert_eq!(detect(&["3", "2", "1", "0"]).await, (None, 0));
+        assert_eq!(detect(&["3"]).await, (None, 0));
+        assert_eq!(
+            detect(&["5", "4", "3"]).await,
+            (
+                Some(Broadcast::New(vec!["5".to_string(), "4".to_string()])),
+                2
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn test_only_unseen_gids_are_broadcast() {
+        let dir = tempfile::tempdir().unwrap();
+        let poller = poller_in(&dir, CompareMode::Gid);
+        let detect = |events| {
+            let poller = &poller;
+            async move {
+                poller
+                    .file_work(&MockEventSource::new(events))
+                    .await
+                    .unwrap()
+                    .broadcast()
+            }
+        };
+
+        let old = vec![event("3"), event("2"), event("1")];
+        assert_eq!(detect(old).await, None);
+
+        let new = vec![event("2"), event("4"), event("3"), event("1")];
+        assert_eq!(
+            detect(new.clone()).await,
+            Some(Broadcast::New(vec!["4".to_string()]))
+        );
+        assert_eq!(detect(new).await, None);
+
+        // Events that left the window, or come into it once it is widened, were seen before.
+        assert_eq!(detect(vec![event("4")]).await, None);
+        let widened = vec![event("4"), event("3"), event("2"), event("1"), event("0")];
+        assert_eq!(
+            detect(widened).await,
+            Some(Broadcast::New(vec!["0".to_string()]))
+        );
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[tokio::test]
+    async fn test_gid_mode_keeps_seen_events_in_the_store() {
+        let dir = tempfile::tempdir().unwrap();
+        let poller =
+            poller_in(&dir, CompareMode::Gid).with_seen_store(SeenStore::open_in_memory().unwrap());
+        let detect = |events| {
+            let poller = &poller;
+            async move {
+                poller
+                    .file_work(&MockEventSource::new(events))
+                    .await
+                    .unwrap()
+                    .broadcast()
+            }
+        };
+
+        assert_eq!(poller.tracked_events(), None);
+        assert_eq!(detect(vec![event("2"), event("1")]).await, None);
+        assert_eq!(poller.tracked_events(), Some(2));
+
+        let new = vec![event("3"), event("1")];
+        assert_eq!(
+            poller
+                .unseen_events(&new)
+                .map(|events| events.iter().map(|e| e.gid.clone()).collect::<Vec<_>>()),
+            Some(vec!["3".to_string()])
+        );
+        assert_eq!(
+            detect(new).await,
+            Some(Broadcast::New(vec!["3".to_string()]))
+        );
+        assert_eq!(detect(vec![event("2")]).await, None);
+        assert_eq!(poller.tracked_events(), Some(3));
+        assert!(!dir.path().join("old.json").exists());
+    }
+
+    #[test]
+    fn test_seen_keys_are_capped_newest_first() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("seen.json");
+        let path = path.to_str().unwrap();
+        let event = event("new");
+        let stored: Vec<String> = (0..MAX_SEEN_KEYS).map(|n| n.to_string()).collect();
+
+        write_seen_keys_to_json_file(path, std::slice::from_ref(&event), &stored).unwrap();
+
+        let keys = read_seen_keys(path).unwrap();
+        assert_eq!(keys.len(), MAX_SEEN_KEYS);
+        assert_eq!(keys[0], seen_key(&event));
+        assert_eq!(keys[1], "0");
+        assert!(!keys.contains(&(MAX_SEEN_KEYS - 1).to_string()));
+    }
+
+    async fn select_from_three_unseen(
+        mode: CompareMode,
+        selection: Selection,
+    ) -> Option<Broadcast> {
+        let dir = tempfile::tempdir().unwrap();
+        let poller = poller_in(&dir, mode).with_selection(selection);
+        let old = MockEventSource::new(vec![event("1")]);
+        assert_eq!(poller.file_work(&old).await.unwrap().broadcast(), None);
+
+        let new = MockEventSource::new(vec![event("4"), event("3"), event("2"), event("1")]);
+        let broadcast = poller.file_work(&new).await.unwrap().broadcast();
+        assert_eq!(poller.file_work(&new).await.unwrap().broadcast(), None);
+        broadcast
+    }
+
+    #[tokio::test]
+    async fn test_selection_strategies() {
+        let gids = |gids: &[&str]| {
+            Some(Broadcast::New(
+                gids.iter().map(|gid| gid.to_string()).collect(),
+            ))
+        };
+        for mode in [CompareMode::Gid, CompareMode::Headline] {
+            assert_eq!(
+                select_from_three_unseen(mode, Selection::All).await,
+                gids(&["4", "3", "2"])
+            );
+            assert_eq!(
+                select_from_three_unseen(mode, Selection::Newest).await,
+                gids(&["4"])
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_file_work_detects_updates_across_cycles() {
+        let dir = tempfile::tempdir().unwrap();
+        let poller = poller_in(&dir, CompareMode::Headline);
+        let before = MockEventSource::new(vec![test_event("Old patch", "body")]);
+        let after = MockEventSource::new(vec![
+            test_event("New patch", "body"),
+            test_event("Old patch", "body"),
+        ]);
+
+        assert_eq!(poller.file_work(&before).await.unwrap().broadcast(), None);
+        assert_eq!(poller.file_work(&before).await.unwrap().broadcast(), None);
+        assert_eq!(
+            poller.file_work(&after).await.unwrap().broadcast(),
+            Some(Broadcast::New(vec![String::new()]))
+        );
+        assert_eq!(poller.last_cycle().unwrap().updates_found, 1);
+        assert_eq!(poller.tracked_events(), Some(2));
+        assert_eq!(poller.file_work(&after).await.unwrap().broadcast(), None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_unreachable_feed_is_an_error() {
+        let dir = tempfile::tempdir().unwrap();
+        let poller = poller_in(&dir, CompareMode::Headline);
+
+        let result = poller
+            .file_work(&SteamEventSource::new("http://127.0.0.1:1/events"))
+            .await;
+
+        assert!(matches!(result, Err(AppError::FetchError(_))));
+        assert!(!dir.path().join("old.json").exists());
+    }
+
+    #[tokio::test]
+    async fn test_empty_feed_is_not_a_change() {
+        let dir = tempfile::tempdir().unwrap();
+        let new_file = dir.path().join("new.json");
+        let old_file = dir.path().join("old.json");
+        let (new_file, old_file) = (new_file.to_str().unwrap(), old_file.to_str().unwrap());
+        fs::write(old_file, r#"["Old patch"]"#).unwrap();
+
+        let empty = MockEventSource::new(Vec::new());
+        assert_eq!(
+            detect_changes(
+                &empty,
+                CompareMode::Headline,
+                FirstRun::Baseline,
+                new_file,
+                old_file,
+                &mut 0,
+            )
+            .await
+            .unwrap()
+            .broadcast(),
+            None
+        );
+        assert_eq!(read_file_content(old_file).unwrap(), r#"["Old patch"]"#);
+
+        let populated = MockEventSource::new(vec![
+            test_event("New patch", "body"),
+            test_event("Old patch", "body"),
+        ]);
+        assert_eq!(
+            detect_changes(
+                &populated,
+                CompareMode::Headline,
+                FirstRun::Baseline,
+                new_file,
+                old_file,
+                &mut 0,
+            )
+            .await
+            .unwrap()
+            .broadcast(),
+            Some(Broadcast::New(vec![String::new()]))
+        );
+        assert_eq!(
+            read_file_content(old_file).unwrap(),
+            r#"["New patch","Old patch"]"#
+        );
+    }
+
+    /// Collects the fields recorded on every span, keyed by span name.
+    #[derive(Clone, Default)]
+    struct SpanFields(Arc<Mutex<HashMap<String, HashMap<String, String>>>>);
+
+    struct FieldVisitor<'a>(&'a mut HashMap<String, String>);
+
+    impl Visit for FieldVisitor<'_> {
+        fn record_debug(&mut self, field: &field::Field, value: &dyn std::fmt::Debug) {
+            self.0
+                .insert(field.name().to_string(), format!("{:?}", value));
+        }
+    }
+
+    impl<S: tracing::Subscriber + for<'a> LookupSpan<'a>> Layer<S> for SpanFields {
+        fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+            let name = ctx.span(id).unwrap().name().to_string();
+            let mut spans = self.0.lock().unwrap();
+            attrs.record(&mut FieldVisitor(spans.entry(name).or_default()));
+        }
+
+        fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+            let name = ctx.span(id).unwrap().name().to_string();
+            let mut spans = self.0.lock().unwrap();
+            values.record(&mut FieldVisitor(spans.entry(name).or_default()));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_poll_cycle_span_records_outcome() {
+        let fields = SpanFields::default();
+        let _guard = tracing::subscriber::set_default(Registry::default().with(fields.clone()));
+        let dir = tempfile::tempdir().unwrap();
+        let new_file = dir.path().join("new.json");
+        let old_file = dir.path().join("old.json");
+        let (new_file, old_file) = (new_file.to_str().unwrap(), old_file.to_str().unwrap());
+        fs::write(old_file, r#"["Old patch"]"#).unwrap();
+        let source = MockEventSource::new(vec![
+            test_event("New patch", "body"),
+            test_event("Old patch", "body"),
+        ]);
+
+        detect_changes(
+            &source,
+            CompareMode::Headline,
+            FirstRun::Baseline,
+            new_file,
+            old_file,
+            &mut 0,
+        )
+        .instrument(poll_cycle_span(3))
+        .await
+        .unwrap();
+
+        let spans = fields.0.lock().unwrap();
+        let cycle = &spans["poll_cycle"];
+        assert_eq!(cycle["cycle"], "3");
+        assert_eq!(cycle["updates_found"], "1");
+        assert!(cycle.contains_key("duration_ms"));
+    }
+
+    async fn first_run_with(first_run: FirstRun) -> (Option<Broadcast>, String) {
+        let dir = tempfile::tempdir().unwrap();
+        let new_file = dir.path().join("new.json");
+        let old_file = dir.path().join("old.json");
+        let (new_file, old_file) = (new_file.to_str().unwrap(), old_file.to_str().unwrap());
+        let source = MockEventSource::new(vec![
+            test_event("New patch", "body"),
+            test_event("Old patch", "body"),
+        ]);
+
+        let broadcast = detect_changes(
+            &source,
+            CompareMode::Headline,
+            first_run,
+            new_file,
+            old_file,
+            &mut 0,
+        )
+        .await
+        .unwrap()
+        .broadcast();
+        (broadcast, read_file_content(old_file).unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_first_run_modes() {
+        let state = r#"["New patch","Old patch"]"#.to_string();
+        assert_eq!(
+            first_run_with(FirstRun::Baseline).await,
+            (None, state.clone())
+        );
+        assert_eq!(
+            first_run_with(FirstRun::BroadcastLatest).await,
+            (Some(Broadcast::Latest), state.clone())
+        );
+        assert_eq!(
+            first_run_with(FirstRun::BroadcastAll).await,
+            (Some(Broadcast::All), state)
+        );
+    }
 }
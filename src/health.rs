@@ -0,0 +1,144 @@
+//! Health-check endpoint for container deployments.
+//!
+//! A small HTTP server on `HEALTH_PORT` (8080 by default) answers `GET /health` with the time of
+//! the last successful poll and the number of subscribers, so an orchestrator can restart the bot
+//! once polling has stalled. Any other request gets a 404.
+
+use crate::feeds::Feed;
+use crate::subscribers::SubscriberStore;
+use serde::Serialize;
+use std::sync::Arc;
+use std::time::SystemTime;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tracing::{debug, info};
+
+/// Port the endpoint listens on unless `HEALTH_PORT` is set.
+pub const DEFAULT_HEALTH_PORT: u16 = 8080;
+
+/// Longest request head read before answering.
+const MAX_REQUEST_LEN: usize = 8 * 1024;
+
+/// The JSON body of a `/health` response.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct HealthReport {
+    /// Unix time of the last successful poll, or `None` if none succeeded yet.
+    pub last_success: Option<u64>,
+    pub subscribers: usize,
+}
+
+impl HealthReport {
+    pub fn new(feed: &Feed, subscribers: &SubscriberStore) -> Self {
+        Self {
+            last_success: feed
+                .last_success()
+                .and_then(|at| at.duration_since(SystemTime::UNIX_EPOCH).ok())
+                .map(|since| since.as_secs()),
+            subscribers: subscribers.all_subscribers().len(),
+        }
+    }
+}
+
+/// Builds the HTTP response to a request whose first line is `request_line`.
+fn response(request_line: &str, report: impl FnOnce() -> HealthReport) -> String {
+    let mut parts = request_line.split_whitespace();
+    let (status, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/health")) => (
+            "200 OK",
+            serde_json::to_string(&report()).unwrap_or_default(),
+        ),
+        _ => ("404 Not Found", r#"{"error":"not found"}"#.to_string()),
+    };
+    format!(
+        "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )
+}
+
+/// Reads one request from `stream` and answers it.
+async fn handle(
+    mut stream: TcpStream,
+    feed: &Feed,
+    subscribers: &SubscriberStore,
+) -> std::io::Result<()> {
+    let mut request = Vec::new();
+    let mut buf = [0; 1024];
+    while !request.windows(4).any(|end| end == b"\r\n\r\n") && request.len() < MAX_REQUEST_LEN {
+        let read = stream.read(&mut buf).await?;
+        if read == 0 {
+            break;
+        }
+        request.extend_from_slice(&buf[..read]);
+    }
+    let request = String::from_utf8_lossy(&request);
+    let request_line = request.lines().next().unwrap_or_default();
+    let response = response(request_line, || HealthReport::new(feed, subscribers));
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+/// Answers health checks on `listener` for as long as the bot runs.
+pub async fn serve(listener: TcpListener, feed: Arc<Feed>, subscribers: Arc<SubscriberStore>) {
+    if let Ok(addr) = listener.local_addr() {
+        info!("Serving health checks on {}.", addr);
+    }
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(err) => {
+                debug!("Failed to accept a health check: {}", err);
+                continue;
+            }
+        };
+        let feed = Arc::clone(&feed);
+        let subscribers = Arc::clone(&subscribers);
+        tokio::spawn(async move {
+            if let Err(err) = handle(stream, &feed, &subscribers).await {
+                debug!("Failed to answer a health check: {}", err);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::feeds::{DEFAULT_LANGUAGE, DOTA_APPID};
+    use crate::subscribers::{Subscriber, SUBSCRIBERS_FILE};
+    use teloxide::types::ChatId;
+
+    #[tokio::test]
+    async fn test_health_reports_last_success_and_subscribers() {
+        let dir = tempfile::tempdir().unwrap();
+        let subscribers =
+            Arc::new(SubscriberStore::open(dir.path().join(SUBSCRIBERS_FILE)).unwrap());
+        subscribers
+            .add_subscriber(Subscriber::new(ChatId(1)))
+            .unwrap();
+        let feed = Arc::new(Feed::new(DOTA_APPID, DEFAULT_LANGUAGE));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(serve(listener, Arc::clone(&feed), subscribers));
+
+        let health = reqwest::get(format!("{}/health", url)).await.unwrap();
+        assert_eq!(health.status(), 200);
+        assert_eq!(
+            health.json::<serde_json::Value>().await.unwrap(),
+            serde_json::json!({"last_success": null, "subscribers": 1})
+        );
+
+        feed.record_success();
+        let report: serde_json::Value = reqwest::get(format!("{}/health", url))
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        assert!(report["last_success"].as_u64().unwrap() > 0);
+
+        let missing = reqwest::get(format!("{}/metrics", url)).await.unwrap();
+        assert_eq!(missing.status(), 404);
+    }
+}
@@ -1,68 +1,1068 @@
+use crate::capture::ParseFailures;
 use crate::errors::AppError;
-use log::info;
+use crate::feeds::{post_link, App, Feed, DEFAULT_FEED_BASE, DOTA_APPID};
+use crate::settings::SettingsStore;
+use async_trait::async_trait;
+use reqwest::header::{
+    HeaderMap, HeaderValue, ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED, RETRY_AFTER,
+};
+use reqwest::{Response, StatusCode};
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashSet;
+use std::hash::{Hash, Hasher};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, SystemTime};
+use tracing::{info, info_span, warn, Instrument, Span};
 
-#[derive(Debug, Deserialize, Serialize)]
+/// Number of events fetched per poll unless set with `EVENT_COUNT` or `/count`.
+pub const DEFAULT_POLL_COUNT: u32 = 100;
+
+/// Query parameters sent with every events request, besides `origin`, `appid`, `l` (language)
+/// and `count`.
+const EVENTS_QUERY: &str = "clan_accountid=0&offset=0";
+
+/// Largest number of events `/count` allows fetching per poll.
+pub const MAX_POLL_COUNT: u32 = 200;
+
+/// How long reading a response body may take.
+const BODY_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long a whole request to Steam may take unless set with `HTTP_TIMEOUT_SECS`.
+pub const DEFAULT_HTTP_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The client every event source fetches with, so connections to Steam are pooled.
+static HTTP_CLIENT: OnceLock<reqwest::Client> = OnceLock::new();
+
+/// Builds the shared client with a request timeout of `timeout`.
+///
+/// Only the first call has an effect, so it must come before the first fetch. Without it, the
+/// client uses `DEFAULT_HTTP_TIMEOUT`.
+pub fn init_http_client(timeout: Duration) {
+    if HTTP_CLIENT.set(build_http_client(timeout)).is_err() {
+        warn!(
+            "The HTTP client was already built, ignoring the timeout of {:?}.",
+            timeout
+        );
+    }
+}
+
+/// Returns the shared client, building it with the default timeout if `init_http_client()`
+/// wasn't called.
+fn http_client() -> &'static reqwest::Client {
+    HTTP_CLIENT.get_or_init(|| build_http_client(DEFAULT_HTTP_TIMEOUT))
+}
+
+/// Builds a client that identifies itself as this bot and gives up on requests after `timeout`.
+fn build_http_client(timeout: Duration) -> reqwest::Client {
+    reqwest::Client::builder()
+        .timeout(timeout)
+        .user_agent(concat!(
+            env!("CARGO_PKG_NAME"),
+            "/",
+            env!("CARGO_PKG_VERSION")
+        ))
+        .build()
+        .unwrap_or_else(|err| {
+            warn!(
+                "Failed to build the HTTP client ({}), using the defaults.",
+                err
+            );
+            reqwest::Client::new()
+        })
+}
+
+/// How many times `fetch_with_retry()` tries a request before giving up.
+const MAX_FETCH_ATTEMPTS: u32 = 4;
+
+/// How long `fetch_with_retry()` waits before its first retry when the server doesn't say. Each
+/// later retry waits twice as long as the one before.
+const RETRY_BASE_DELAY: Duration = Duration::from_secs(1);
+
+/// Longest `Retry-After` delay `fetch_with_retry()` waits out. The poller lock is held while it
+/// waits, so a longer delay gives up the cycle instead, and the next poll tries again.
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(60);
+
+/// Steam store languages, keyed by their short codes.
+const STEAM_LANGUAGES: &[(&str, &str)] = &[
+    ("en", "english"),
+    ("ru", "russian"),
+    ("de", "german"),
+    ("fr", "french"),
+    ("es", "spanish"),
+    ("pt", "brazilian"),
+    ("pl", "polish"),
+    ("uk", "ukrainian"),
+    ("zh", "schinese"),
+    ("ja", "japanese"),
+    ("ko", "koreana"),
+];
+
+/// Resolves a short language code (`ru`) or a Steam language name (`russian`) to the Steam name.
+pub fn steam_language(lang: &str) -> Option<&'static str> {
+    let lang = lang.trim().to_lowercase();
+    STEAM_LANGUAGES
+        .iter()
+        .find(|(code, name)| *code == lang || *name == lang)
+        .map(|(_, name)| *name)
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct AnnouncementBody {
     pub body: Value,
     pub headline: String,
+    /// Unix time the announcement was posted at.
+    #[serde(default)]
+    pub posttime: u64,
+    /// The team or person credited with the post, if the feed names one.
+    #[serde(default)]
+    pub author: String,
+    #[serde(default)]
+    pub voteupcount: u64,
+    #[serde(default)]
+    pub commentcount: u64,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct Event {
+    #[serde(default)]
+    pub gid: String,
+    /// Steam app id the event belongs to, used to route it to its feed's chats.
+    #[serde(default)]
+    pub appid: u32,
+    /// Steam id of the group (clan) that published the event.
+    #[serde(default)]
+    pub clan_steamid: String,
+    #[serde(default)]
+    pub event_name: String,
+    #[serde(default)]
+    pub event_type: u32,
+    /// Unix time the event starts at, which `parse_events()` orders the feed by.
+    #[serde(default)]
+    pub rtime32_start_time: u64,
     pub announcement_body: AnnouncementBody,
 }
 
-/// Reads a page to JSON string and extracts events.
+impl Event {
+    /// Returns the headline to show for this event.
+    ///
+    /// Falls back to the event name when the announcement headline is blank, and returns `None`
+    /// if both are blank so the event can be skipped.
+    pub fn headline(&self) -> Option<&str> {
+        [
+            self.announcement_body.headline.as_str(),
+            self.event_name.as_str(),
+        ]
+        .into_iter()
+        .find(|headline| !headline.trim().is_empty())
+    }
+
+    /// Returns the Steam store page of the event's post, under the app the feed reported for it,
+    /// or Dota 2 if it reported none.
+    pub fn post_link(&self) -> String {
+        let appid = if self.appid == 0 {
+            DOTA_APPID
+        } else {
+            self.appid
+        };
+        post_link(appid, &self.gid)
+    }
+}
+
+/// A category of events a chat can limit its updates to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EventFilter {
+    /// Game updates and patch notes.
+    Patches,
+    /// News posts.
+    News,
+}
+
+impl EventFilter {
+    /// Returns `true` if the event belongs to this category.
+    ///
+    /// Steam event types 12-14 are small, regular, and major updates; 28 is a news post.
+    pub fn matches(&self, event: &Event) -> bool {
+        match self {
+            EventFilter::Patches => (12..=14).contains(&event.event_type),
+            EventFilter::News => event.event_type == 28,
+        }
+    }
+}
+
+impl FromStr for EventFilter {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "patches" => Ok(EventFilter::Patches),
+            "news" => Ok(EventFilter::News),
+            other => Err(format!("Unknown event filter: {}", other)),
+        }
+    }
+}
+
+/// Reads the `Retry-After` header of a response as a number of seconds.
+fn retry_after(response: &Response) -> Option<Duration> {
+    let value = response.headers().get(RETRY_AFTER)?.to_str().ok()?;
+    value.trim().parse().ok().map(Duration::from_secs)
+}
+
+/// Returns how long to wait before retrying after failed attempt number `attempt`:
+/// `RETRY_BASE_DELAY` doubled for every earlier attempt, plus up to a quarter of that as jitter,
+/// so bots restarted together don't hit Steam in lockstep.
+fn backoff(attempt: u32) -> Duration {
+    let delay = RETRY_BASE_DELAY * 2u32.pow(attempt.saturating_sub(1));
+    let nanos = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |now| now.subsec_nanos());
+    delay + delay.mul_f64(f64::from(nanos % 1000) / 4000.0)
+}
+
+/// Fetches `url`, retrying up to `MAX_FETCH_ATTEMPTS` times on network errors, `429 Too Many
+/// Requests` and server errors.
+///
+/// A 429 or 5xx response is retried after the delay from its `Retry-After` header, so the bot
+/// backs off as long as Steam asks it to, unless that is longer than `MAX_RETRY_AFTER`, which
+/// fails the fetch. Without the header, and after network errors, it waits as long as
+/// `backoff()` says. Any other response is returned as is. `headers` are sent with
+/// every attempt, all through `client`.
+pub async fn fetch_with_retry(
+    client: &reqwest::Client,
+    url: &str,
+    headers: &HeaderMap,
+) -> Result<Response, AppError> {
+    let mut attempt = 1;
+    loop {
+        let request = client.get(url).headers(headers.clone());
+        let delay = match request.send().await {
+            Ok(response)
+                if response.status() != StatusCode::TOO_MANY_REQUESTS
+                    && !response.status().is_server_error() =>
+            {
+                return Ok(response)
+            }
+            Ok(response) if attempt >= MAX_FETCH_ATTEMPTS => {
+                return Err(AppError::FetchError(
+                    response.error_for_status().unwrap_err(),
+                ))
+            }
+            Err(err) if attempt >= MAX_FETCH_ATTEMPTS => return Err(AppError::FetchError(err)),
+            Ok(response) => {
+                let delay = match retry_after(&response) {
+                    Some(delay) if delay > MAX_RETRY_AFTER => {
+                        warn!(
+                            "Got {} from {} asking to wait {:?}, giving up until the next poll.",
+                            response.status(),
+                            url,
+                            delay
+                        );
+                        return Err(AppError::FetchError(
+                            response.error_for_status().unwrap_err(),
+                        ));
+                    }
+                    Some(delay) => delay,
+                    None => backoff(attempt),
+                };
+                warn!(
+                    "Got {} from {}, retrying in {:?}.",
+                    response.status(),
+                    url,
+                    delay
+                );
+                delay
+            }
+            Err(err) => {
+                let delay = backoff(attempt);
+                warn!("Failed to fetch {}: {}, retrying in {:?}.", url, err, delay);
+                delay
+            }
+        };
+        tokio::time::sleep(delay).await;
+        attempt += 1;
+    }
+}
+
+/// Reads a response body, giving up after `BODY_TIMEOUT`.
+async fn read_body(response: Response) -> Result<Vec<u8>, AppError> {
+    match tokio::time::timeout(BODY_TIMEOUT, response.bytes()).await {
+        Ok(body) => Ok(body.map_err(AppError::FetchError)?.to_vec()),
+        Err(_) => Err(AppError::BodyTimeoutError),
+    }
+}
+
+/// Returns `true` if `body` failed to parse because it ended too early.
+///
+/// That is what a connection dropped mid-response looks like, and fetching again usually helps.
+/// Genuinely malformed JSON fails with a syntax error instead and isn't retried.
+fn is_truncated(body: &[u8], err: &serde_json::Error) -> bool {
+    !body.is_empty() && err.is_eof()
+}
+
+/// Fetches the specified URL with `client` and parses the response body as JSON.
 ///
-/// This function fetches the specified URL, parses the JSON response, and deserializes it into
-/// a vector of `Event` structs. It returns a `Result` containing either the vector of events or
-/// an `std::io::Error` if the operation fails.
-pub async fn read_page_to_json_str_events(url: &str) -> Result<Vec<Event>, AppError> {
-    info!("Fetching URL: {}", url);
-    let response = reqwest::get(url).await.map_err(AppError::FetchError)?;
-    info!("URL fetched successfully");
-    info!("Parse JSON from response");
-    let json: Value = response.json().await.map_err(AppError::FetchError)?;
-    info!("Convert JSON to string");
-    let json_str = serde_json::to_string(&json).map_err(AppError::ParseJsonError)?;
-    let json: Value = serde_json::from_str(&json_str).map_err(AppError::ParseJsonError)?;
+/// A truncated body is fetched once more before giving up.
+pub async fn read_page_to_json(client: &reqwest::Client, url: &str) -> Result<Value, AppError> {
+    let mut retried = false;
+    loop {
+        info!("Fetching URL: {}", url);
+        let response = fetch_with_retry(client, url, &HeaderMap::new()).await?;
+        info!("URL fetched successfully");
+        let body = read_body(response).await?;
+        info!("Parse JSON from response");
+        match serde_json::from_slice(&body) {
+            Ok(json) => return Ok(json),
+            Err(err) if !retried && is_truncated(&body, &err) => {
+                warn!(
+                    "The response from {} was truncated, fetching it again.",
+                    url
+                );
+                retried = true;
+            }
+            Err(err) => return Err(AppError::ParseJsonError(err)),
+        }
+    }
+}
+
+/// Deserializes the `events` array of a feed response into `Event` structs, newest first.
+///
+/// Steam doesn't guarantee the order of the feed, so events are sorted by `rtime32_start_time`.
+/// The sort is stable: events with the same start time keep their feed order. A response
+/// without an `events` array, like an error object or an empty body, has no events.
+pub fn parse_events(json: &Value) -> Result<Vec<Event>, AppError> {
     info!("Deserialize events");
-    let events: Vec<Event> =
+    if json["events"].is_null() {
+        warn!("The feed response has no events, treating it as empty.");
+        return Ok(Vec::new());
+    }
+    let mut events: Vec<Event> =
         serde_json::from_value(json["events"].clone()).map_err(AppError::ParseJsonError)?;
-    info!("Events read successfully");
+    events.sort_by_key(|event| std::cmp::Reverse(event.rtime32_start_time));
     Ok(events)
 }
 
-/// Reads a page to JSON string and extracts headlines.
+/// Extracts the headlines of all events from a feed response, newest first.
 ///
-/// This function fetches the specified URL, parses the JSON response, and extracts the headlines
-/// from the events. It returns a `Result` containing either the vector of headlines or
-/// error if the operation fails.
-pub async fn read_page_to_json_str_headlines(url: &str) -> Result<Vec<String>, AppError> {
-    info!("Fetching URL: {}", url);
-    let response = reqwest::get(url).await.map_err(AppError::FetchError)?;
-    info!("URL fetched successfully");
-    info!("Parse JSON from response");
-    let json: Value = response.json().await.map_err(AppError::FetchError)?;
-    info!("Convert JSON to string");
-    let json_str = serde_json::to_string(&json).map_err(AppError::ParseJsonError)?;
-    let json: Value = serde_json::from_str(&json_str).map_err(AppError::ParseJsonError)?;
-
-    let events_json = &json["events"];
-    let headlines: Vec<String> = events_json
+/// A blank headline falls back to the event name, and events with neither are left out so they
+/// can't make two otherwise identical feeds compare as different. A headline that appears more
+/// than once, as it does when a post is reposted, is kept only for its newest event by
+/// `rtime32_start_time`.
+pub fn extract_headlines(json: &Value) -> Vec<String> {
+    let mut headlines: Vec<(u64, String)> = json["events"]
         .as_array()
         .unwrap_or(&Vec::new())
         .iter()
-        .map(|event_json| {
-            event_json["announcement_body"]["headline"]
-                .as_str()
-                .unwrap_or_default()
-                .to_string()
+        .filter_map(|event_json| {
+            let headline = [
+                &event_json["announcement_body"]["headline"],
+                &event_json["event_name"],
+            ]
+            .into_iter()
+            .filter_map(Value::as_str)
+            .find(|headline| !headline.trim().is_empty())?;
+            let start_time = event_json["rtime32_start_time"].as_u64().unwrap_or(0);
+            Some((start_time, headline.to_string()))
         })
         .collect();
-    info!("Headlines read successfully");
+    headlines.sort_by_key(|(start_time, _)| std::cmp::Reverse(*start_time));
+    let mut seen = HashSet::new();
+    headlines
+        .into_iter()
+        .filter(|(_, headline)| seen.insert(headline.clone()))
+        .map(|(_, headline)| headline)
+        .collect()
+}
+
+/// Removes events that weren't published by one of the given clans.
+///
+/// The partner events endpoint can include posts by third-party partners; keeping only the
+/// official clan ids stops those from being broadcast. An empty list keeps every event.
+pub fn retain_clan_events(json: &mut Value, clan_ids: &[String]) {
+    if clan_ids.is_empty() {
+        return;
+    }
+    if let Some(events) = json["events"].as_array_mut() {
+        events.retain(|event_json| {
+            let clan_id = match &event_json["clan_steamid"] {
+                Value::String(id) => id.clone(),
+                other => other.to_string(),
+            };
+            clan_ids.contains(&clan_id)
+        });
+    }
+}
+
+/// Health summary of a feed response, produced by `diagnose_feed()`.
+#[derive(Debug, PartialEq)]
+pub struct FeedDiagnostics {
+    pub parsed: usize,
+    pub failed: usize,
+    pub success: bool,
+    pub newest_headline: Option<String>,
+}
+
+/// Inspects a feed response without failing on malformed events.
+///
+/// Unlike `parse_events()`, each event is deserialized on its own, so one broken entry is counted
+/// as a failure instead of rejecting the whole feed.
+pub fn diagnose_feed(json: &Value) -> FeedDiagnostics {
+    let (events, failures): (Vec<_>, Vec<_>) = json["events"]
+        .as_array()
+        .unwrap_or(&Vec::new())
+        .iter()
+        .map(|event_json| serde_json::from_value::<Event>(event_json.clone()))
+        .partition(Result::is_ok);
+    let events: Vec<Event> = events.into_iter().map(Result::unwrap).collect();
+
+    FeedDiagnostics {
+        parsed: events.len(),
+        failed: failures.len(),
+        success: json["success"].as_i64() == Some(1),
+        newest_headline: events.iter().find_map(Event::headline).map(str::to_string),
+    }
+}
+
+/// A source of Steam events.
+#[async_trait]
+pub trait EventSource: Send + Sync {
+    /// Fetches the raw feed response.
+    async fn fetch_feed(&self) -> Result<Value, AppError>;
+
+    /// Fetches the raw feed response for a poll, or `None` if it hasn't changed since the last
+    /// poll whose state was stored, see `commit_poll()`.
+    ///
+    /// Sources that can't tell always return the full feed.
+    async fn fetch_feed_if_changed(&self) -> Result<Option<Value>, AppError> {
+        self.fetch_feed().await.map(Some)
+    }
+
+    /// Tells the source that the state of the last `fetch_feed_if_changed()` was stored, so the
+    /// same feed counts as unchanged from now on. Until then it is returned again, so a cycle
+    /// that fails after fetching doesn't lose the update.
+    fn commit_poll(&self) {}
+
+    /// Fetches the current list of events, newest first.
+    async fn fetch_events(&self) -> Result<Vec<Event>, AppError> {
+        parse_events(&self.fetch_feed().await?)
+    }
+
+    /// Fetches the current list of events in the Steam language `language`, newest first.
+    ///
+    /// Sources that serve a single language return the same events as `fetch_events()`.
+    async fn fetch_events_in(&self, _language: &str) -> Result<Vec<Event>, AppError> {
+        self.fetch_events().await
+    }
+}
+
+/// Builds the URL of the newest `count` events of the Steam app `app_id` in the Steam language
+/// `lang`, on `DEFAULT_FEED_BASE`.
+pub fn build_events_url(app_id: u32, count: u32, lang: &str) -> String {
+    build_events_url_on(DEFAULT_FEED_BASE, app_id, count, lang)
+}
+
+/// Like `build_events_url()`, on the events endpoint `base`, which may have a query of its own.
+///
+/// The request says it comes from the app's own site, as Steam's widget on that site does.
+pub fn build_events_url_on(base: &str, app_id: u32, count: u32, lang: &str) -> String {
+    let separator = if base.contains('?') { '&' } else { '?' };
+    format!(
+        "{}{}{}&origin={}&appid={}&l={}&count={}",
+        base,
+        separator,
+        EVENTS_QUERY,
+        App::for_appid(app_id).origin.replace('/', "%2F"),
+        app_id,
+        lang,
+        count
+    )
+}
+
+/// Fetches events from the Steam partner events endpoint.
+///
+/// Polls are conditional: the `ETag` and `Last-Modified` headers of the last polled response are
+/// sent back as `If-None-Match` and `If-Modified-Since`, and a `304 Not Modified` means nothing
+/// new. If Steam doesn't send those headers, every poll is a full fetch. A full response whose
+/// body hashes the same as the last polled one is treated as nothing new too, without parsing it.
+/// Validators and hash only take effect once the poller stored the poll, see `commit_poll()`.
+pub struct SteamEventSource {
+    /// The feed polled, which builds the request URLs.
+    feed: Arc<Feed>,
+    /// Events fetched per poll unless set with `/count`.
+    event_count: u32,
+    /// A handle to the shared `http_client()`.
+    client: reqwest::Client,
+    clan_ids: Vec<String>,
+    settings: Option<Arc<SettingsStore>>,
+    failures: Option<Arc<ParseFailures>>,
+    validators: Mutex<Validators>,
+    body_hash: Mutex<Option<u64>>,
+    /// Validators and body hash of the last poll, until `commit_poll()` applies them.
+    polled: Mutex<Option<(Validators, u64)>>,
+    /// Number of polled responses that were parsed.
+    #[cfg(test)]
+    parses: std::sync::atomic::AtomicUsize,
+}
+
+/// Cache validators from the last polled response.
+#[derive(Default)]
+struct Validators {
+    etag: Option<HeaderValue>,
+    last_modified: Option<HeaderValue>,
+}
+
+impl Validators {
+    fn from_response(response: &Response) -> Self {
+        Self {
+            etag: response.headers().get(ETAG).cloned(),
+            last_modified: response.headers().get(LAST_MODIFIED).cloned(),
+        }
+    }
+
+    fn request_headers(&self) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        if let Some(etag) = &self.etag {
+            headers.insert(IF_NONE_MATCH, etag.clone());
+        }
+        if let Some(last_modified) = &self.last_modified {
+            headers.insert(IF_MODIFIED_SINCE, last_modified.clone());
+        }
+        headers
+    }
+}
+
+impl SteamEventSource {
+    /// Creates a source polling the Dota 2 feed on the events endpoint `url`.
+    #[cfg(test)]
+    pub fn new(url: impl Into<String>) -> Self {
+        let base = reqwest::Url::parse(&url.into()).unwrap();
+        Self::for_feed(Arc::new(
+            Feed::new(DOTA_APPID, crate::feeds::DEFAULT_LANGUAGE).with_base(base),
+        ))
+    }
+
+    /// Creates a source polling `feed`.
+    pub fn for_feed(feed: Arc<Feed>) -> Self {
+        Self {
+            feed,
+            event_count: DEFAULT_POLL_COUNT,
+            client: http_client().clone(),
+            clan_ids: Vec::new(),
+            settings: None,
+            failures: None,
+            validators: Mutex::new(Validators::default()),
+            body_hash: Mutex::new(None),
+            polled: Mutex::new(None),
+            #[cfg(test)]
+            parses: Default::default(),
+        }
+    }
+
+    /// Fetches `count` events per poll unless set with `/count`, instead of `DEFAULT_POLL_COUNT`.
+    pub fn with_event_count(mut self, count: u32) -> Self {
+        self.event_count = count;
+        self
+    }
+
+    /// Keeps only events published by the given clans. See `retain_clan_events()`.
+    pub fn with_clan_ids(mut self, clan_ids: Vec<String>) -> Self {
+        self.clan_ids = clan_ids;
+        self
+    }
+
+    /// Reads the number of events to fetch from the runtime settings, so a `/count` change
+    /// applies from the next fetch on.
+    pub fn with_settings(mut self, settings: Arc<SettingsStore>) -> Self {
+        self.settings = Some(settings);
+        self
+    }
+
+    /// Records polled bodies that fail to parse in `failures`, for `/parse_failures`.
+    pub fn with_failures(mut self, failures: Arc<ParseFailures>) -> Self {
+        self.failures = Some(failures);
+        self
+    }
+
+    /// Records a polled body that failed to parse, if failures are captured.
+    fn capture_failure(&self, err: &serde_json::Error, body: &[u8]) {
+        warn!("Failed to parse the polled feed: {}", err);
+        if let Some(failures) = &self.failures {
+            failures.record(err, body);
+        }
+    }
+
+    /// Returns the URL of the next fetch, including the configured number of events.
+    fn request_url(&self) -> String {
+        self.feed.url(self.count())
+    }
+
+    /// Returns the number of events the next fetch asks for.
+    fn count(&self) -> u32 {
+        self.settings
+            .as_ref()
+            .and_then(|settings| settings.get().poll_count)
+            .unwrap_or(self.event_count)
+    }
+
+    /// Creates the span a fetch of `url` runs in.
+    fn fetch_span(&self, url: &str) -> Span {
+        info_span!("fetch", url = %url, app_id = self.feed.appid)
+    }
+
+    /// Polls `url` once, unless a truncated body needs polling again. See
+    /// `fetch_feed_if_changed()`.
+    async fn poll(&self, url: &str) -> Result<Option<Value>, AppError> {
+        let mut retried = false;
+        loop {
+            info!("Polling URL: {}", url);
+            let headers = self.validators.lock().unwrap().request_headers();
+            let response = fetch_with_retry(&self.client, url, &headers).await?;
+            if response.status() == StatusCode::NOT_MODIFIED {
+                info!("The feed wasn't modified since the last poll.");
+                return Ok(None);
+            }
+            let validators = Validators::from_response(&response);
+            let body = read_body(response).await?;
+            let mut hasher = DefaultHasher::new();
+            body.hash(&mut hasher);
+            let body_hash = hasher.finish();
+            if *self.body_hash.lock().unwrap() == Some(body_hash) {
+                info!("The feed is identical to the last poll, no change.");
+                // The stored state already matches this body, so its validators can be kept.
+                *self.validators.lock().unwrap() = validators;
+                return Ok(None);
+            }
+            #[cfg(test)]
+            self.parses
+                .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            let mut json: Value = match serde_json::from_slice(&body) {
+                Ok(json) => json,
+                Err(err) if !retried && is_truncated(&body, &err) => {
+                    warn!("The response from {} was truncated, polling again.", url);
+                    retried = true;
+                    continue;
+                }
+                Err(err) => {
+                    self.capture_failure(&err, &body);
+                    return Err(AppError::ParseJsonError(err));
+                }
+            };
+            if self.failures.is_some() && !json["events"].is_null() {
+                if let Err(err) = serde_json::from_value::<Vec<Event>>(json["events"].clone()) {
+                    self.capture_failure(&err, &body);
+                }
+            }
+            retain_clan_events(&mut json, &self.clan_ids);
+            *self.polled.lock().unwrap() = Some((validators, body_hash));
+            return Ok(Some(json));
+        }
+    }
+}
+
+#[async_trait]
+impl EventSource for SteamEventSource {
+    async fn fetch_feed(&self) -> Result<Value, AppError> {
+        let url = self.request_url();
+        let mut json = read_page_to_json(&self.client, &url)
+            .instrument(self.fetch_span(&url))
+            .await?;
+        retain_clan_events(&mut json, &self.clan_ids);
+        Ok(json)
+    }
+
+    async fn fetch_feed_if_changed(&self) -> Result<Option<Value>, AppError> {
+        let url = self.request_url();
+        self.poll(&url).instrument(self.fetch_span(&url)).await
+    }
+
+    async fn fetch_events_in(&self, language: &str) -> Result<Vec<Event>, AppError> {
+        let url = self.feed.url_in(language, self.count());
+        let mut json = read_page_to_json(&self.client, &url)
+            .instrument(self.fetch_span(&url))
+            .await?;
+        retain_clan_events(&mut json, &self.clan_ids);
+        parse_events(&json)
+    }
+
+    fn commit_poll(&self) {
+        if let Some((validators, body_hash)) = self.polled.lock().unwrap().take() {
+            *self.validators.lock().unwrap() = validators;
+            *self.body_hash.lock().unwrap() = Some(body_hash);
+        }
+    }
+}
+
+/// An event source that returns a canned feed.
+#[cfg(test)]
+pub struct MockEventSource {
+    pub feed: Value,
+}
+
+#[cfg(test)]
+impl MockEventSource {
+    /// Creates a source whose feed contains the given events.
+    pub fn new(events: Vec<Event>) -> Self {
+        Self {
+            feed: serde_json::json!({ "success": 1, "events": events }),
+        }
+    }
+}
+
+#[cfg(test)]
+#[async_trait]
+impl EventSource for MockEventSource {
+    async fn fetch_feed(&self) -> Result<Value, AppError> {
+        Ok(self.feed.clone())
+    }
+}
+
+/// Builds an event with the given headline and body.
+#[cfg(test)]
+pub fn test_event(headline: &str, body: &str) -> Event {
+    Event {
+        gid: String::new(),
+        appid: 570,
+        clan_steamid: String::new(),
+        event_name: String::new(),
+        event_type: 12,
+        rtime32_start_time: 0,
+        announcement_body: AnnouncementBody {
+            body: Value::String(body.to_string()),
+            headline: headline.to_string(),
+            posttime: 0,
+            author: String::new(),
+            voteupcount: 0,
+            commentcount: 0,
+        },
+    }
+}
+
+/// Builds an event with the given gid, headlined `Post <gid>`.
+#[cfg(test)]
+pub fn test_event_with_gid(gid: &str) -> Event {
+    Event {
+        gid: gid.to_string(),
+        ..test_event(&format!("Post {}", gid), "body")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use wiremock::matchers::{header, method, query_param};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    /// Returns a client for tests on a paused clock. The shared client's timeouts and idle
+    /// connection timers would fire as soon as the clock advances to the next retry.
+    fn paused_client() -> reqwest::Client {
+        reqwest::Client::builder()
+            .pool_max_idle_per_host(0)
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_http_client_is_built_once() {
+        assert!(std::ptr::eq(http_client(), http_client()));
+    }
+
+    #[test]
+    fn test_extract_headlines_skips_empty_headlines() {
+        let stored = json!({"events": [
+            {"announcement_body": {"headline": "Dota 2 Update"}},
+        ]});
+        let fetched = json!({"events": [
+            {"announcement_body": {"headline": ""}},
+            {"announcement_body": {"headline": "Dota 2 Update"}},
+        ]});
+
+        assert_eq!(extract_headlines(&fetched), vec!["Dota 2 Update"]);
+        assert_eq!(extract_headlines(&fetched), extract_headlines(&stored));
+    }
+
+    #[test]
+    fn test_extract_headlines_skips_reposted_headlines() {
+        let fetched = json!({"events": [
+            {"rtime32_start_time": 100, "announcement_body": {"headline": "Patch 7.36"}},
+            {"rtime32_start_time": 200, "announcement_body": {"headline": "Battle Pass"}},
+            {"rtime32_start_time": 300, "announcement_body": {"headline": "Patch 7.36"}},
+        ]});
+        assert_eq!(
+            extract_headlines(&fetched),
+            vec!["Patch 7.36", "Battle Pass"]
+        );
+    }
+
+    #[test]
+    fn test_extract_headlines_falls_back_to_event_name() {
+        let fetched = json!({"events": [
+            {"event_name": "The International", "announcement_body": {"headline": " "}},
+        ]});
+        assert_eq!(extract_headlines(&fetched), vec!["The International"]);
+    }
+
+    #[test]
+    fn test_retain_clan_events_drops_partner_posts() {
+        let mut feed = json!({"events": [
+            {"clan_steamid": "103582791433224455", "announcement_body": {"headline": "Official"}},
+            {"clan_steamid": "103582791470000000", "announcement_body": {"headline": "Partner"}},
+            {"clan_steamid": 103582791433224455u64, "announcement_body": {"headline": "Numeric"}},
+        ]});
+
+        retain_clan_events(&mut feed, &["103582791433224455".to_string()]);
+
+        assert_eq!(extract_headlines(&feed), vec!["Official", "Numeric"]);
+    }
+
+    #[test]
+    fn test_retain_clan_events_keeps_everything_without_filter() {
+        let mut feed = json!({"events": [
+            {"clan_steamid": "1", "announcement_body": {"headline": "Partner"}},
+        ]});
+        retain_clan_events(&mut feed, &[]);
+        assert_eq!(extract_headlines(&feed), vec!["Partner"]);
+    }
+
+    #[test]
+    fn test_diagnose_feed_counts_malformed_events() {
+        let feed = json!({"success": 1, "events": [
+            {"announcement_body": {"headline": "Newest", "body": "text"}},
+            {"announcement_body": {"body": "missing headline"}},
+            {"announcement_body": {"headline": "Older", "body": "text"}},
+        ]});
+
+        assert_eq!(
+            diagnose_feed(&feed),
+            FeedDiagnostics {
+                parsed: 2,
+                failed: 1,
+                success: true,
+                newest_headline: Some("Newest".to_string()),
+            }
+        );
+    }
+
+    #[test]
+    fn test_missing_events_are_empty() {
+        assert!(parse_events(&json!({})).unwrap().is_empty());
+        assert!(parse_events(&json!({"events": null})).unwrap().is_empty());
+        assert!(parse_events(&json!({"events": "none"})).is_err());
+    }
+
+    #[test]
+    fn test_build_events_url() {
+        assert_eq!(
+            build_events_url(730, 20, "english"),
+            "https://store.steampowered.com/events/ajaxgetpartnereventspageable/\
+             ?clan_accountid=0&offset=0&origin=https:%2F%2Fwww.counter-strike.net\
+             &appid=730&l=english&count=20"
+        );
+        assert_eq!(
+            build_events_url_on("https://x.test/events?key=1", 570, 100, "russian"),
+            "https://x.test/events?key=1&clan_accountid=0&offset=0\
+             &origin=https:%2F%2Fwww.dota2.com&appid=570&l=russian&count=100"
+        );
+    }
+
+    #[test]
+    fn test_steam_language() {
+        assert_eq!(steam_language("ru"), Some("russian"));
+        assert_eq!(steam_language("English"), Some("english"));
+        assert_eq!(steam_language("xx"), None);
+    }
+
+    #[test]
+    fn test_event_filter_matches() {
+        let mut event = test_event("Patch", "body");
+        assert!(EventFilter::Patches.matches(&event));
+        event.event_type = 28;
+        assert!(EventFilter::News.matches(&event));
+        assert!(!EventFilter::Patches.matches(&event));
+    }
+
+    #[test]
+    fn test_event_headline() {
+        let mut event = test_event("", "body");
+        assert_eq!(event.headline(), None);
+
+        event.event_name = "Event name".to_string();
+        assert_eq!(event.headline(), Some("Event name"));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_fetch_with_retry_honors_retry_after() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(429).insert_header("Retry-After", "7"))
+            .up_to_n_times(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({"success": 1})))
+            .mount(&server)
+            .await;
+        let start = tokio::time::Instant::now();
+
+        let feed = read_page_to_json(&paused_client(), &server.uri())
+            .await
+            .unwrap();
+
+        assert_eq!(feed, json!({"success": 1}));
+        let waited = start.elapsed();
+        assert!(waited >= Duration::from_secs(7), "waited {:?}", waited);
+        assert!(waited < Duration::from_secs(8), "waited {:?}", waited);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_long_retry_after_gives_up_the_fetch() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(429).insert_header("Retry-After", "86400"))
+            .expect(1)
+            .mount(&server)
+            .await;
+        let start = tokio::time::Instant::now();
+
+        let err = read_page_to_json(&paused_client(), &server.uri())
+            .await
+            .unwrap_err();
+
+        assert!(matches!(err, AppError::FetchError(_)), "{:?}", err);
+        assert!(start.elapsed() < MAX_RETRY_AFTER);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_server_errors_are_retried_with_backoff() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(503))
+            .up_to_n_times(2)
+            .expect(2)
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({"success": 1})))
+            .expect(1)
+            .mount(&server)
+            .await;
+        let start = tokio::time::Instant::now();
+
+        let feed = read_page_to_json(&paused_client(), &server.uri())
+            .await
+            .unwrap();
+
+        assert_eq!(feed, json!({"success": 1}));
+        let waited = start.elapsed();
+        assert!(waited >= Duration::from_secs(3), "waited {:?}", waited);
+        assert!(waited <= Duration::from_millis(3750), "waited {:?}", waited);
+    }
+
+    #[tokio::test]
+    async fn test_not_modified_feed_is_not_parsed() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(header("If-None-Match", "\"v1\""))
+            .respond_with(ResponseTemplate::new(304))
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .insert_header("ETag", "\"v1\"")
+                    .set_body_json(json!({"success": 1, "events": []})),
+            )
+            .expect(1)
+            .mount(&server)
+            .await;
+        let source = SteamEventSource::new(server.uri());
+
+        let first = source.fetch_feed_if_changed().await.unwrap();
+        source.commit_poll();
+        let second = source.fetch_feed_if_changed().await.unwrap();
+
+        assert_eq!(first, Some(json!({"success": 1, "events": []})));
+        assert_eq!(second, None);
+    }
+
+    #[tokio::test]
+    async fn test_identical_feed_is_not_parsed() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(
+                ResponseTemplate::new(200).set_body_json(json!({"success": 1, "events": []})),
+            )
+            .expect(2)
+            .mount(&server)
+            .await;
+        let source = SteamEventSource::new(server.uri());
+
+        let first = source.fetch_feed_if_changed().await.unwrap();
+        source.commit_poll();
+        let second = source.fetch_feed_if_changed().await.unwrap();
+
+        assert_eq!(first, Some(json!({"success": 1, "events": []})));
+        assert_eq!(second, None);
+        assert_eq!(source.parses.load(std::sync::atomic::Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn test_configured_count_is_used_in_fetch_url() {
+        let dir = tempfile::tempdir().unwrap();
+        let settings = Arc::new(SettingsStore::open(dir.path().join("settings.json")).unwrap());
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(query_param("count", "20"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({"success": 1})))
+            .expect(1)
+            .mount(&server)
+            .await;
+        let source = SteamEventSource::new(server.uri()).with_settings(Arc::clone(&settings));
+
+        settings
+            .update(|settings| settings.poll_count = Some(20))
+            .unwrap();
+
+        assert!(source.fetch_feed_if_changed().await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn test_truncated_body_is_fetched_again() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200).set_body_string(r#"{"success": 1, "eve"#))
+            .up_to_n_times(1)
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({"success": 1})))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let feed = read_page_to_json(http_client(), &server.uri())
+            .await
+            .unwrap();
+
+        assert_eq!(feed, json!({"success": 1}));
+    }
+
+    #[tokio::test]
+    async fn test_malformed_body_is_not_fetched_again() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200).set_body_string(r#"{"success": ]"#))
+            .expect(1)
+            .mount(&server)
+            .await;
+        let source = SteamEventSource::new(server.uri());
+
+        let result = source.fetch_feed_if_changed().await;
 
-    Ok(headlines)
+        assert!(matches!(result, Err(AppError::ParseJsonError(_))));
+    }
 }
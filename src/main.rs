@@ -1,55 +1,308 @@
+mod bbcode;
+mod capture;
+mod clock;
+mod config;
+mod digest;
 mod errors;
+mod feeds;
 mod file_part;
+mod health;
 mod json_part;
 mod message_part;
+mod quiet_hours;
+mod rss;
+#[cfg(feature = "sqlite")]
+mod seen_store;
+mod sender;
+mod settings;
+mod shutdown;
+mod subscribers;
+mod telemetry;
+mod template;
+mod webhook;
 
-use crate::file_part::file_work;
+use crate::capture::ParseFailures;
+use crate::clock::SystemClock;
+use crate::config::{Config, ENV_FILE};
+use crate::errors::AppError;
+use crate::feeds::Feed;
+use crate::file_part::Poller;
+use crate::json_part::{init_http_client, SteamEventSource};
+use crate::message_part::{send_chunks, Command, CommandContext, UNKNOWN_COMMAND_TEXT};
+use crate::sender::{
+    run_replay, ChunkSizeSender, DryRunSender, FallbackSender, MessageSender, PacedSender,
+    TelegramSender, FALLBACK_FILE,
+};
+use crate::settings::{SettingsStore, SETTINGS_FILE};
+use crate::shutdown::Shutdown;
+use crate::subscribers::{FailureStreaks, SubscriberStore, SUBSCRIBERS_FILE};
+use crate::telemetry::Telemetry;
+use crate::webhook::UpdateWebhook;
 use dotenv::dotenv;
-use log::{error, info};
-use std::env;
+use std::collections::HashMap;
+use std::future::Future;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
-use teloxide::Bot;
 use teloxide::prelude::*;
+use teloxide::utils::command::{BotCommands, ParseError};
+use teloxide::{ApiError, Bot, RequestError};
+use tracing::{error, info, warn};
+use tracing_subscriber::EnvFilter;
 
-/// The URL used to fetch events related to Dota 2.
-const LINK: &str =
-    "https://store.steampowered.com/events/ajaxgetpartnereventspageable/?clan_accountid=0&appid=570&offset=0&count=100&l=english&origin=https:%2F%2Fwww.dota2.com";
+/// Waits for the startup grace period, then runs `start`.
+///
+/// During rolling deploys this gives the previous instance time to finish draining before this
+/// one polls for the first time.
+async fn after_startup_delay<F: Future>(delay: Duration, start: F) -> F::Output {
+    if !delay.is_zero() {
+        info!("Waiting {:?} before the first poll.", delay);
+        tokio::time::sleep(delay).await;
+    }
+    start.await
+}
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     dotenv().ok();
 
-    env_logger::init();
+    tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::from_default_env())
+        .init();
 
     info!("Starting main function...");
 
-    let sleep_duration_secs = env::var("SLEEP_DURATION_SECS")
-        .ok()
-        .and_then(|val| val.parse().ok())
-        .unwrap_or(5);
+    let config = Config::from_env()?;
+    init_http_client(config.http_timeout);
 
-    let sleep_duration = Duration::from_secs(sleep_duration_secs);
+    let poller = Poller::from_config(&config)?;
+    info!("Polling every {:?}.", config.sleep_duration());
 
-    let bot = Bot::from_env();
+    let subscribers = Arc::new(SubscriberStore::open(SUBSCRIBERS_FILE)?);
+    info!(
+        "Loaded {} subscribers.",
+        subscribers.all_subscribers().len()
+    );
 
-    tokio::spawn(async move {
-        teloxide::repl(bot, move |bot: Bot, msg: Message| async move {
-            while file_work(LINK).await {
-                info!("File work completed.");
+    let appid = config.app_id;
+    let mut feed = Feed::new(appid, config.lang);
+    if let Some(base) = config.feed_base.clone() {
+        feed = feed.with_base(base);
+    }
+    if let Some(chats) = config.feed_chats.clone() {
+        info!("Updates of app {} go to {} chats.", appid, chats.len());
+        feed = feed.with_chats(chats);
+    }
+    let feed = Arc::new(feed);
 
-                tokio::time::sleep(sleep_duration).await;
-            };
+    let settings = Arc::new(SettingsStore::open(SETTINGS_FILE)?);
 
-            if let Err(e) = message_part::handle_message(&bot, &msg).await {
-                error!("Failed to send message: {}", e);
-            }
-            Ok(())
-        }).await;
+    let parse_failures = Arc::new(ParseFailures::default());
+
+    let source = Arc::new(
+        SteamEventSource::for_feed(Arc::clone(&feed))
+            .with_event_count(config.event_count)
+            .with_clan_ids(config.official_clan_ids.clone())
+            .with_settings(Arc::clone(&settings))
+            .with_failures(Arc::clone(&parse_failures)),
+    );
+
+    match tokio::net::TcpListener::bind(("0.0.0.0", config.health_port)).await {
+        Ok(listener) => {
+            tokio::spawn(health::serve(
+                listener,
+                Arc::clone(&feed),
+                Arc::clone(&subscribers),
+            ));
+        }
+        Err(err) => error!(
+            "Failed to listen for health checks on port {}: {}",
+            config.health_port, err
+        ),
+    }
+
+    let telemetry = Arc::new(Telemetry::new(config.telemetry_endpoint.clone()));
+    tokio::spawn(telemetry::run_reporter(
+        Arc::clone(&telemetry),
+        Arc::clone(&subscribers),
+        config.telemetry_interval,
+    ));
+
+    let bot = Bot::with_client(
+        config.telegram_token.clone(),
+        teloxide::net::client_from_env(),
+    );
+    let bot_name = match bot.get_me().await {
+        Ok(me) => me.username().to_string(),
+        // Telegram answers requests with an unknown token with a 404, which teloxide reads as
+        // `NotFound`.
+        Err(RequestError::Api(ApiError::NotFound)) => {
+            return Err(AppError::ConfigError(
+                "Telegram rejected the bot token, check TELOXIDE_TOKEN".to_string(),
+            )
+            .into())
+        }
+        Err(err) => return Err(format!("Failed to reach Telegram: {}", err).into()),
+    };
+    // The command menu is a convenience, so the bot starts even if it can't be set.
+    if let Err(err) = bot.set_my_commands(Command::bot_commands()).await {
+        warn!("Failed to register the command menu: {}", err);
+    }
+    let telegram = TelegramSender::new(bot.clone(), config.max_message_len);
+    let sender: Arc<dyn MessageSender> = if config.dry_run {
+        warn!("DRY_RUN is on, messages are logged instead of sent.");
+        Arc::new(ChunkSizeSender::new(
+            DryRunSender::new(telegram),
+            Arc::clone(&settings),
+        ))
+    } else {
+        let fallback = Arc::new(FallbackSender::open(
+            ChunkSizeSender::new(
+                PacedSender::new(telegram, config.chat_send_interval)
+                    .with_global_interval(config.broadcast_delay),
+                Arc::clone(&settings),
+            ),
+            FALLBACK_FILE,
+            config.outage_after,
+        )?);
+        tokio::spawn(run_replay(Arc::clone(&fallback)));
+        fallback
+    };
+    let ctx = Arc::new(CommandContext {
+        sender,
+        subscribers,
+        source,
+        settings,
+        feeds: vec![Arc::clone(&feed)],
+        poller: Arc::new(poller),
+        config: Mutex::new(config.reloadable.clone()),
+        env_file: PathBuf::from(ENV_FILE),
+        latest_sent: Mutex::new(HashMap::new()),
+        parse_failures,
+        clock: Arc::new(SystemClock),
+        webhook: Arc::new(UpdateWebhook::new(config.update_webhook.clone())),
+        failure_streaks: FailureStreaks::default(),
+        startup: config.clone(),
+    });
+
+    tokio::spawn(digest::run_daily_delivery(
+        Arc::clone(&ctx),
+        config.daily_delivery,
+        Arc::new(SystemClock),
+    ));
+
+    tokio::spawn(quiet_hours::run_held_delivery(Arc::clone(&ctx)));
+
+    if let Some(schedule) = config.digest_schedule {
+        tokio::spawn(digest::run_digest(
+            Arc::clone(&ctx),
+            schedule,
+            Arc::new(SystemClock),
+        ));
+    }
 
-    })
-    .await?;
+    let shutdown = Shutdown::default();
+    tokio::spawn(shutdown::trigger_on_signal(shutdown.clone()));
+
+    let dispatch_shutdown = shutdown.clone();
+    let run = async move {
+        let polling = tokio::spawn(message_part::run_polling(
+            Arc::clone(&ctx),
+            telemetry,
+            dispatch_shutdown.clone(),
+        ));
+        let inline_ctx = Arc::clone(&ctx);
+        let on_message = move |msg: Message| {
+            let ctx = Arc::clone(&ctx);
+            let bot_name = bot_name.clone();
+            async move {
+                let text = msg.text().unwrap_or_default();
+                match Command::parse(text, &bot_name) {
+                    Ok(command) => {
+                        let user_id = msg.from().map(|user| user.id);
+                        if let Err(e) =
+                            message_part::handle_command(&ctx, msg.chat.id, user_id, command).await
+                        {
+                            error!("Failed to handle command: {}", e);
+                        }
+                    }
+                    Err(ParseError::UnknownCommand(_)) => {
+                        if let Err(e) =
+                            send_chunks(ctx.sender.as_ref(), msg.chat.id, UNKNOWN_COMMAND_TEXT)
+                                .await
+                        {
+                            error!("Failed to reply to an unknown command: {}", e);
+                        }
+                    }
+                    // Commands addressed to other bots, and plain text, are ignored.
+                    Err(_) => {}
+                }
+                respond(())
+            }
+        };
+        let on_inline_query = move |bot: Bot, query: InlineQuery| {
+            let ctx = Arc::clone(&inline_ctx);
+            async move {
+                if let Err(e) = message_part::handle_inline_query(&bot, &ctx, &query).await {
+                    error!("Failed to answer inline query: {}", e);
+                }
+                respond(())
+            }
+        };
+        let mut dispatcher = Dispatcher::builder(
+            bot,
+            dptree::entry()
+                .branch(Update::filter_message().endpoint(on_message))
+                .branch(Update::filter_inline_query().endpoint(on_inline_query)),
+        )
+        .build();
+        let token = dispatcher.shutdown_token();
+        tokio::spawn(async move {
+            dispatch_shutdown.triggered().await;
+            // Waits for the handlers in flight, including a poll loop finishing its cycle.
+            match token.shutdown() {
+                Ok(stopped) => stopped.await,
+                Err(err) => warn!("Failed to stop the dispatcher: {}", err),
+            }
+        });
+        dispatcher.dispatch().await;
+        // Lets a broadcast in flight finish before exiting.
+        if let Err(e) = polling.await {
+            error!("The poll loop failed: {}", e);
+        }
+    };
+    let started = tokio::select! {
+        _ = after_startup_delay(config.startup_delay, std::future::ready(())) => true,
+        _ = shutdown.triggered() => false,
+    };
+    // Once started, the dispatcher stops by itself after its handlers are done.
+    if started {
+        run.await;
+    }
 
     info!("Main function completed.");
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    #[tokio::test(start_paused = true)]
+    async fn test_first_poll_waits_for_startup_delay() {
+        let polled = Arc::new(AtomicBool::new(false));
+        let flag = Arc::clone(&polled);
+        let handle = tokio::spawn(after_startup_delay(Duration::from_secs(30), async move {
+            flag.store(true, Ordering::SeqCst);
+        }));
+
+        tokio::time::sleep(Duration::from_secs(29)).await;
+        assert!(!polled.load(Ordering::SeqCst));
+
+        tokio::time::sleep(Duration::from_secs(2)).await;
+        handle.await.unwrap();
+        assert!(polled.load(Ordering::SeqCst));
+    }
+}
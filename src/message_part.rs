@@ -1,171 +1,4340 @@
-use crate::{json_part, LINK};
-use log::{error, info};
-use regex::Regex;
+use crate::bbcode::{self, Markup};
+use crate::capture::ParseFailures;
+use crate::clock::Clock;
+use crate::config::{self, Config, ReloadableConfig};
+use crate::errors::AppError;
+use crate::feeds::{feed_listing, App, Feed, DEFAULT_LANGUAGE, DOTA_APPID};
+use crate::file_part::{headline_diff, Broadcast, Detected, Poller};
+use crate::json_part::{
+    diagnose_feed, extract_headlines, steam_language, Event, EventFilter, EventSource,
+    MAX_POLL_COUNT,
+};
+use crate::quiet_hours::{chat_timezone, parse_timezone, QuietHours};
+use crate::rss::{render_rss, write_rss, RssItem, MAX_RSS_ITEMS, RSS_FILE_NAME};
+use crate::sender::{
+    parse_mode, MessageSender, MAX_MEDIA_GROUP_LEN, MIN_CHUNK_SIZE, TELEGRAM_MAX_MESSAGE_LEN,
+};
+use crate::settings::{Fields, SettingsStore, FIELD_NAMES};
+use crate::shutdown::Shutdown;
+use crate::subscribers::{DeliveryMode, FailureStreaks, Subscriber, SubscriberStore};
+use crate::telemetry::Telemetry;
+use crate::template::{MessageTemplate, PLACEHOLDERS};
+use crate::webhook::UpdateWebhook;
+use chrono::DateTime;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
 use teloxide::prelude::*;
-use teloxide::types::ParseMode;
-use teloxide::Bot;
-
-/// Handles an incoming message.
-///
-/// This asynchronous function handles incoming messages. It first retrieves events using
-/// `json_part::read_page_to_json_str_events()` and processes the first event's body. It constructs
-/// a message body containing the event headline and processed body. The constructed message is then
-/// sent using `send_chunks()`. If there's an error during message handling, it returns an error message.
-pub async fn handle_message(bot: &Bot, msg: &Message) -> Result<(), String> {
-    let mut msg_msg = String::new();
-    info!("Handling incoming message...");
-    if let Ok(events) = json_part::read_page_to_json_str_events(LINK).await {
-        info!("Retrieved events successfully.");
-        if let Some(event) = events.first() {
-            if let Some(body_str) = event.announcement_body.body.as_str() {
-                let processed_body = process_body(body_str);
-                msg_msg += &format!(
-                    "_*To see more updates and news follow this [link](https://www.dota2.com/news?l=english)*_\n\n*{}*\n{}\n\n",
-                    event.announcement_body.headline, processed_body
-                );
-                info!("Prepared message body for sending.");
+use teloxide::types::{
+    InlineQueryResult, InlineQueryResultArticle, InputMessageContent, InputMessageContentText,
+};
+use teloxide::utils::command::BotCommands;
+use teloxide::{ApiError, RequestError};
+use tracing::{error, info, info_span, warn, Instrument};
+
+/// Reply sent to a chat once it has been subscribed to the updates of `app`, the product name.
+fn subscribed_text(app: &str) -> String {
+    format!(
+        "You're subscribed\\! New {} updates will be posted here\\.",
+        escape_markdown_v2(app)
+    )
+}
+
+/// Reply sent to `/subscribe` in a chat that is already subscribed.
+const ALREADY_SUBSCRIBED_TEXT: &str = "This chat is already subscribed\\.";
+
+/// Reply sent to a command the bot doesn't know.
+pub const UNKNOWN_COMMAND_TEXT: &str = "Unknown command\\. Send /help to see what I can do\\.";
+
+/// Reply sent to a chat once it has been unsubscribed.
+const UNSUBSCRIBED_TEXT: &str =
+    "You're unsubscribed\\. Use /subscribe to get updates here again\\.";
+
+/// Message `/add_chat` posts to a chat to check the bot can post there, naming the product `app`.
+fn add_chat_check_text(app: &str) -> String {
+    format!(
+        "This chat will get new {} updates\\.",
+        escape_markdown_v2(app)
+    )
+}
+
+/// Reply sent when a non-admin runs an admin command.
+const ADMIN_ONLY_TEXT: &str = "This command is only available to admins\\.";
+
+/// Reply sent when anyone but a super admin runs a super admin command.
+const SUPER_ADMIN_ONLY_TEXT: &str = "This command is only available to super admins\\.";
+
+/// Usage of the `/admins` command.
+const ADMINS_USAGE_TEXT: &str = "Usage: /admins list, /admins add ID or /admins remove ID";
+
+/// Reply to `/headline_diff` when the poller has no headlines stored.
+const NO_STORED_HEADLINES_TEXT: &str =
+    "No stored headlines to compare with\\. Headlines are only stored with COMPARE\\=headline\\.";
+
+/// Reply to `/diff` when every fetched update was seen before.
+const NO_NEW_UPDATES_TEXT: &str = "No new updates\\.";
+
+/// Most headlines `/diff` lists when nothing is stored yet and every update counts as new.
+const MAX_DIFF_HEADLINES: usize = 10;
+
+/// Name of the file `/parse_failures` sends the latest failing payload as.
+const PARSE_FAILURE_FILE_NAME: &str = "parse_failure.json";
+
+/// Most updates an inline query is answered with.
+const MAX_INLINE_RESULTS: usize = 10;
+
+/// Most updates one `/latest <n>` sends.
+const MAX_LATEST: usize = 10;
+
+/// How long Telegram may cache the answer to an inline query, in seconds, so that typing a query
+/// doesn't fetch the feed on every keystroke.
+const INLINE_CACHE_SECS: u32 = 60;
+
+/// How many times sending one chunk waits out Telegram's flood limit before giving up.
+const MAX_RETRY_AFTER_WAITS: u32 = 3;
+
+/// Commands understood by the bot.
+#[derive(BotCommands, Clone, Debug, PartialEq)]
+#[command(
+    rename_rule = "lowercase",
+    description = "These commands are supported:"
+)]
+pub enum Command {
+    #[command(description = "subscribe this chat to new updates.")]
+    Start,
+    #[command(description = "list the commands.")]
+    Help,
+    #[command(
+        description = "subscribe this chat to new updates. Usage: /subscribe [all|patches|news] [language]"
+    )]
+    Subscribe(String),
+    #[command(description = "stop sending updates to this chat.")]
+    Unsubscribe,
+    #[command(
+        rename = "add_chat",
+        description = "(admin) subscribe another chat or channel. Usage: /add_chat <id>"
+    )]
+    AddChat(String),
+    #[command(
+        rename = "get_recent",
+        description = "show the Nth most recent update. Usage: /get_recent <n>"
+    )]
+    GetRecent(String),
+    #[command(description = "show the newest updates. Usage: /latest [n]")]
+    Latest(String),
+    #[command(description = "get the recent updates as an RSS feed file.")]
+    Rss,
+    #[command(description = "(admin) fetch the feed and report parser diagnostics.")]
+    Diagnose,
+    #[command(
+        description = "(admin) show the message template, or set it. Usage: /template [template]"
+    )]
+    Template(String),
+    #[command(description = "(admin) list the feeds being polled.")]
+    Feeds,
+    #[command(
+        rename = "poll_now",
+        description = "(admin) poll the feed right away and send any new updates."
+    )]
+    PollNow,
+    #[command(
+        description = "(admin) send the latest update to every subscriber again. Usage: /rebroadcast confirm"
+    )]
+    Rebroadcast(String),
+    #[command(description = "(admin) remove subscribers that blocked the bot or no longer exist.")]
+    Prune,
+    #[command(
+        description = "choose how this chat gets updates. Usage: /mode realtime|digest|ping"
+    )]
+    Mode(String),
+    #[command(description = "(super admin) manage admins. Usage: /admins list|add ID|remove ID")]
+    Admins(String),
+    #[command(
+        description = "(admin) escape text for Markdown V2 and check it. Usage: /escape <text>"
+    )]
+    Escape(String),
+    #[command(
+        description = "(admin) show or set how many events each poll fetches. Usage: /count [n]"
+    )]
+    Count(String),
+    #[command(
+        description = "(admin) show or set the longest message chunk. Usage: /chunksize [n]"
+    )]
+    Chunksize(String),
+    #[command(
+        description = "(admin) show the optional parts of updates, or toggle one. Usage: /fields [date|author|engagement|footer|permalink]"
+    )]
+    Fields(String),
+    #[command(description = "show this chat's settings.")]
+    Settings,
+    #[command(
+        description = "show when an update was last detected and how often the feed is polled."
+    )]
+    Status,
+    #[command(
+        description = "(admin) show how an update will be split into messages. Usage: /chunks <gid>"
+    )]
+    Chunks(String),
+    #[command(description = "(admin) reload the configuration from disk.")]
+    Reload,
+    #[command(
+        description = "(admin) send text back as Markdown V2 to see how Telegram renders it. Usage: /echo <markdown>"
+    )]
+    Echo(String),
+    #[command(
+        description = "set this chat's time zone for quiet hours. Usage: /tz <IANA name>, e.g. /tz Europe/Berlin"
+    )]
+    Tz(String),
+    #[command(description = "list the headlines of fetched updates that weren't seen yet.")]
+    Diff,
+    #[command(
+        rename = "headline_diff",
+        description = "(admin) compare the live feed's headlines with the stored ones."
+    )]
+    HeadlineDiff,
+    #[command(
+        rename = "last_cycle",
+        description = "(admin) report the outcome of the last poll cycle."
+    )]
+    LastCycle,
+    #[command(
+        rename = "parse_failures",
+        description = "(admin) report recent feed parse failures and send the latest payload."
+    )]
+    ParseFailures,
+}
+
+impl Command {
+    /// Returns `true` for commands only super admins may run.
+    fn is_super_admin_only(&self) -> bool {
+        matches!(self, Command::Admins(_))
+    }
+
+    /// Returns `true` for commands only admins may run.
+    fn is_admin_only(&self) -> bool {
+        matches!(
+            self,
+            Command::Diagnose
+                | Command::Template(_)
+                | Command::Feeds
+                | Command::PollNow
+                | Command::AddChat(_)
+                | Command::Rebroadcast(_)
+                | Command::Prune
+                | Command::Escape(_)
+                | Command::Count(_)
+                | Command::Chunksize(_)
+                | Command::Fields(_)
+                | Command::Chunks(_)
+                | Command::Reload
+                | Command::ParseFailures
+                | Command::LastCycle
+                | Command::HeadlineDiff
+                | Command::Echo(_)
+        )
+    }
+}
+
+/// Shared state available to every command handler.
+pub struct CommandContext {
+    pub sender: Arc<dyn MessageSender>,
+    pub subscribers: Arc<SubscriberStore>,
+    pub source: Arc<dyn EventSource>,
+    pub settings: Arc<SettingsStore>,
+    /// Feeds being polled, listed by `/feeds`.
+    pub feeds: Vec<Arc<Feed>>,
+    pub poller: Arc<Poller>,
+    /// Settings `/reload` can change, read again from `env_file`.
+    pub config: Mutex<ReloadableConfig>,
+    pub env_file: PathBuf,
+    /// Gid of the update each chat was last sent by `/latest`.
+    pub latest_sent: Mutex<HashMap<ChatId, String>>,
+    /// Polled payloads that failed to parse, reported by `/parse_failures`.
+    pub parse_failures: Arc<ParseFailures>,
+    pub clock: Arc<dyn Clock>,
+    /// Where detected updates are POSTed, set by `UPDATE_WEBHOOK_URL`.
+    pub webhook: Arc<UpdateWebhook>,
+    pub failure_streaks: FailureStreaks,
+    /// Settings read at startup, like the welcome mode and the markup updates are sent in.
+    /// Replies to commands are always Markdown V2, whatever `startup.markup` says.
+    pub startup: Config,
+}
+
+impl CommandContext {
+    /// Returns `true` if the user may manage admins.
+    pub fn is_super_admin(&self, user_id: Option<UserId>) -> bool {
+        user_id.is_some_and(|user_id| self.startup.super_admins.contains(&user_id))
+    }
+
+    /// Returns `true` if the user may run admin commands.
+    pub fn is_admin(&self, user_id: Option<UserId>) -> bool {
+        self.is_super_admin(user_id)
+            || user_id.is_some_and(|user_id| self.settings.get().admins.contains(&user_id.0))
+    }
+
+    /// Returns a snapshot of the reloadable settings.
+    pub fn config(&self) -> ReloadableConfig {
+        self.config.lock().unwrap().clone()
+    }
+
+    /// Returns the footer link to the news page, in the language of the polled feed, escaped for
+    /// `markup`.
+    pub fn news_link(&self) -> String {
+        self.startup.markup.escape_url(&self.raw_news_link())
+    }
+
+    /// Returns the news page link of `news_link()`, unescaped.
+    fn raw_news_link(&self) -> String {
+        match self.feeds.first() {
+            Some(feed) => feed.news_link(),
+            None => Feed::new(DOTA_APPID, DEFAULT_LANGUAGE).news_link(),
+        }
+    }
+
+    /// Like `news_link()`, but in the Steam language `language`.
+    fn news_link_in(&self, language: &str) -> String {
+        let link = match self.feeds.first() {
+            Some(feed) => feed.news_link_in(language),
+            None => Feed::new(DOTA_APPID, DEFAULT_LANGUAGE).news_link_in(language),
+        };
+        self.startup.markup.escape_url(&link)
+    }
+
+    /// Returns the Steam language of the polled feed, which subscribers without a language of
+    /// their own read.
+    fn polled_language(&self) -> &str {
+        self.feeds
+            .first()
+            .map_or(DEFAULT_LANGUAGE, |feed| feed.language.as_str())
+    }
+
+    /// Returns the product name of the polled app, e.g. `Dota 2`, unescaped.
+    pub fn app_name(&self) -> String {
+        match self.feeds.first() {
+            Some(feed) => feed.app().name.clone(),
+            None => App::for_appid(DOTA_APPID).name,
+        }
+    }
+
+    /// Returns the optional parts of updates set with `/fields`, or those `BYLINES` and
+    /// `STEAM_LINKS` turn on if no admin set them.
+    pub fn fields(&self) -> Fields {
+        self.settings.get().fields.unwrap_or_else(|| {
+            let config = self.config();
+            Fields {
+                author: config.bylines,
+                permalink: config.steam_links,
+                ..Fields::default()
             }
+        })
+    }
+
+    /// Returns the template set with `/template`, or the built-in one for `markup`.
+    pub fn template(&self) -> MessageTemplate {
+        self.settings
+            .get()
+            .template
+            .and_then(|source| MessageTemplate::parse(&source).ok())
+            .unwrap_or_else(|| MessageTemplate::default_for(self.startup.markup))
+    }
+}
+
+/// What a chat receives right after subscribing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WelcomeMode {
+    /// Nothing is sent.
+    None,
+    /// Only a short "you're subscribed" confirmation is sent.
+    Confirmation,
+    /// The confirmation is followed by the latest update.
+    Latest,
+}
+
+impl FromStr for WelcomeMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "none" => Ok(WelcomeMode::None),
+            "confirmation" => Ok(WelcomeMode::Confirmation),
+            "latest" => Ok(WelcomeMode::Latest),
+            other => Err(format!("Unknown welcome mode: {}", other)),
         }
-    } else {
-        error!("Failed to retrieve events.");
-        return Ok(());
     }
-    send_chunks(bot, msg.chat.id, &msg_msg)
-        .await
-        .map_err(|err| {
-            error!("Failed to send message: {}", err);
-            err.to_string()
-        })?;
-    info!("Message sent successfully.");
+}
+
+/// Parses the optional `[filter] [language]` arguments of `/subscribe` into a subscriber record.
+///
+/// The filter is `all`, `patches`, or `news`; the language is a short code or Steam language
+/// name. On invalid input it returns a Markdown V2 error reply for the user.
+fn parse_subscribe_args(chat_id: ChatId, args: &str) -> Result<Subscriber, String> {
+    let mut subscriber = Subscriber::new(chat_id);
+    let mut args = args.split_whitespace();
+
+    if let Some(filter) = args.next() {
+        if !filter.eq_ignore_ascii_case("all") {
+            subscriber.filter =
+                Some(filter.parse::<EventFilter>().map_err(|_| {
+                    "Unknown filter\\. Use one of: all, patches, news\\.".to_string()
+                })?);
+        }
+    }
+    if let Some(lang) = args.next() {
+        let lang = steam_language(lang)
+            .ok_or_else(|| "Unknown language\\. Use a code like en or ru\\.".to_string())?;
+        subscriber.lang = Some(lang.to_string());
+    }
+    if args.next().is_some() {
+        return Err(
+            "Too many arguments\\. Usage: /subscribe \\[filter\\] \\[language\\]".to_string(),
+        );
+    }
+    Ok(subscriber)
+}
+
+/// Answers an inline query (`@bot text`) with the newest updates matching its text, as returned
+/// by `inline_results()`.
+pub async fn handle_inline_query(
+    bot: &Bot,
+    ctx: &CommandContext,
+    query: &InlineQuery,
+) -> Result<(), AppError> {
+    let events = ctx.source.fetch_events().await?;
+    let results = inline_results(ctx, &events, &query.query);
+    info!(
+        "Answering inline query {:?} with {} updates",
+        query.query,
+        results.len()
+    );
+    bot.answer_inline_query(query.id.clone(), results)
+        .cache_time(INLINE_CACHE_SECS)
+        .await?;
     Ok(())
 }
 
-/// Processes the body of an event announcement.
+/// Builds the answers to an inline query for `query` from `events`, newest first.
 ///
-/// This function removes certain elements like tables, images, and YouTube video previews using
-/// regular expressions. It replaces URLs with placeholders and modifies some formatting elements.
-/// It returns the processed body text along with a vector containing the found URL fragments.
-fn process_body(body_str: &str) -> String {
-    let body = body_str.to_owned();
-
-    let re_url = Regex::new(r"\[url=([^]]+)]([^\[]+)\[/url]").unwrap();
-    let re_table = Regex::new(r"\[table\].*?\[\/table\]").unwrap();
-    let re_img = Regex::new(r"\[img\].*?\[\\/img\]").unwrap();
-    let re_preview = Regex::new(r"\[previewyoutube.*?\]").unwrap();
-
-    let mut found_fragments = Vec::new();
-
-    let removed_tables = re_table.replace_all(&body, "").as_ref().to_owned();
-    let removed_img = re_img.replace_all(&removed_tables, "").as_ref().to_owned();
-    let removed_preview = re_preview
-        .replace_all(
-            &removed_img,
-            "(This update contains video. To watch the video, go to the official website.)",
-        )
-        .as_ref()
-        .to_owned();
+/// Only events whose headline contains `query`, ignoring case, are returned, at most
+/// `MAX_INLINE_RESULTS` of them. Each is shared as its headline and a link to its post, like
+/// `headline_only()`, since a whole body rarely fits a message.
+pub fn inline_results(
+    ctx: &CommandContext,
+    events: &[Event],
+    query: &str,
+) -> Vec<InlineQueryResult> {
+    let query = query.trim().to_lowercase();
+    let link = ctx.news_link();
+    events
+        .iter()
+        .filter_map(|event| Some((event, event.headline()?)))
+        .filter(|(_, headline)| headline.to_lowercase().contains(&query))
+        .take(MAX_INLINE_RESULTS)
+        .enumerate()
+        .map(|(i, (event, headline))| {
+            let mut content = InputMessageContentText::new(headline_only(
+                event,
+                headline,
+                &link,
+                ctx.fields(),
+                ctx.startup.markup,
+            ));
+            content.parse_mode = parse_mode(ctx.startup.markup);
+            let id = if event.gid.is_empty() {
+                format!("update-{}", i)
+            } else {
+                event.gid.clone()
+            };
+            InlineQueryResult::Article(InlineQueryResultArticle::new(
+                id,
+                headline,
+                InputMessageContent::Text(content),
+            ))
+        })
+        .collect()
+}
+
+/// Orders the items selected by `broadcast` for sending.
+///
+/// `Broadcast::Latest` keeps only the newest item; `Broadcast::All` keeps all of them, oldest
+/// first, so the chat reads in order, and `Broadcast::New` keeps those with its gids in the same
+/// order. Items are keyed by their event's gid.
+fn select<T>(mut items: Vec<(String, T)>, broadcast: &Broadcast) -> Vec<(String, T)> {
+    match broadcast {
+        Broadcast::Latest => items.truncate(1),
+        Broadcast::All => items.reverse(),
+        Broadcast::New(gids) => {
+            items.retain(|(gid, _)| gids.contains(gid));
+            items.reverse();
+        }
+    }
+    items
+}
 
-    let replaced_body = re_url.replace_all(&removed_preview, |captures: &regex::Captures| {
-        let found_fragment = captures.get(0).unwrap().as_str();
-        found_fragments.push(found_fragment.to_string());
-        format!("SomeReplacement{}", found_fragments.len() - 1)
+/// Drops the events posted more than `max_age` before `now`, so updates that came out while the
+/// bot was down aren't broadcast as new.
+///
+/// Events without a post time are kept. Dropped events are still part of the stored snapshot, so
+/// they are never broadcast later either.
+fn fresh_events(events: Vec<Event>, max_age: Option<Duration>, now: SystemTime) -> Vec<Event> {
+    let Some(max_age) = max_age else {
+        return events;
+    };
+    let since = now
+        .checked_sub(max_age)
+        .and_then(|since| since.duration_since(SystemTime::UNIX_EPOCH).ok())
+        .map_or(0, |since| since.as_secs());
+    let (fresh, stale): (Vec<Event>, Vec<Event>) = events.into_iter().partition(|event| {
+        event.announcement_body.posttime == 0 || event.announcement_body.posttime >= since
     });
+    if !stale.is_empty() {
+        info!("Skipping {} updates older than {:?}.", stale.len(), max_age);
+    }
+    fresh
+}
 
-    let mut modified_body = replaced_body.to_string();
-
-    modified_body = modified_body.replace("[/h1]", "*");
-    modified_body = modified_body.replace("[\\/h1]", "*");
-    modified_body = modified_body.replace("[/h2]", "*");
-    modified_body = modified_body.replace("[\\/h2]", "*");
-    modified_body = modified_body.replace("[/h3]", "*");
-    modified_body = modified_body.replace("[\\/h3]", "*");
-    modified_body = modified_body.replace("[/h5]", "*");
-    modified_body = modified_body.replace("[\\/h5]", "*");
-
-    modified_body = modified_body.replace("[list]", "");
-    modified_body = modified_body.replace("[/list]", "");
-    modified_body = modified_body.replace("[*][b]", "🔸*");
-    modified_body = modified_body.replace("[\\/b]", "*");
-    modified_body = modified_body.replace("[*]", "📌");
-    modified_body = modified_body.replace("[strike]", "~");
-    modified_body = modified_body.replace("[\\/strike]", "~");
-    modified_body = modified_body.replace("[\\/previewyoutube]", "");
-
-    let special_chars = "_*()~`>#-|{}.!";
-    let replaced_string: String = modified_body
-        .chars()
-        .map(|c| {
-            if special_chars.contains(c) {
-                format!("\\{}", c)
-            } else {
-                c.to_string()
-            }
+/// Formats the updates selected by `broadcast`, each with its event, with `link` as the news
+/// page.
+///
+/// Only the events kept by `select()` are formatted with `format_event()`, and those without a
+/// headline are dropped. `Broadcast::Latest` sends the newest event that has one.
+fn format_updates<'a>(
+    ctx: &CommandContext,
+    events: &'a [Event],
+    broadcast: &Broadcast,
+    link: &str,
+) -> Vec<(&'a Event, String)> {
+    let template = ctx.template();
+    let format = |event: &'a Event| {
+        let formatted = format_event(event, &template, link, ctx.fields(), ctx.startup.markup)?;
+        Some((event, formatted))
+    };
+    if *broadcast == Broadcast::Latest {
+        return events.iter().find_map(format).into_iter().collect();
+    }
+    let keyed = events
+        .iter()
+        .map(|event| (event.gid.clone(), event))
+        .collect();
+    select(keyed, broadcast)
+        .into_iter()
+        .filter_map(|(_, event)| format(event))
+        .collect()
+}
+
+/// Builds a short notice with a link for each new post selected by `broadcast`, each with its
+/// event.
+///
+/// Only the gid is used, so events whose body can't be formatted still get a notice. `app` is the
+/// product name the notice starts with.
+fn ping_updates<'a>(
+    events: &'a [Event],
+    broadcast: &Broadcast,
+    app: &str,
+) -> Vec<(&'a Event, String)> {
+    let app = escape_markdown_v2(app);
+    let pings = events
+        .iter()
+        .filter(|event| !event.gid.is_empty())
+        .map(|event| {
+            let link = escape_markdown_v2(&event.post_link());
+            let ping = format!("🆕 New {} post\n{}", app, link);
+            (event.gid.clone(), (event, ping))
         })
         .collect();
+    select(pings, broadcast)
+        .into_iter()
+        .map(|(_, ping)| ping)
+        .collect()
+}
 
-    let body_with_links = restore_links(&replaced_string, &found_fragments);
+/// Publishes detected updates outside Telegram: those of the polled `events` selected by
+/// `broadcast` are POSTed to the update webhook, and the RSS file is written again with the
+/// recent updates. Both are optional.
+///
+/// This runs for every poll that detects updates, before and regardless of their delivery to any
+/// chat. Updates too old to broadcast are left out.
+pub async fn publish_updates(ctx: &CommandContext, broadcast: &Broadcast, events: &[Event]) {
+    if !ctx.webhook.is_enabled() && ctx.startup.rss_file.is_none() {
+        return;
+    }
+    let events = fresh_events(
+        events.to_vec(),
+        ctx.config().max_update_age,
+        ctx.clock.now(),
+    );
+    if let Some(path) = &ctx.startup.rss_file {
+        match write_rss(path, &rss_feed(ctx, &events)) {
+            Ok(()) => info!("Wrote the RSS feed to {}", path.display()),
+            Err(err) => error!(
+                "Failed to write the RSS feed to {}: {}",
+                path.display(),
+                err
+            ),
+        }
+    }
+    let items = events
+        .iter()
+        .map(|event| (event.gid.clone(), event))
+        .collect();
+    let selected: Vec<&Event> = select(items, broadcast)
+        .into_iter()
+        .map(|(_, event)| event)
+        .collect();
+    ctx.webhook.post_updates(&selected).await;
+}
 
-    re_url.replace_all(&body_with_links, "[$2]($1)").to_string()
+/// Renders the newest `MAX_RSS_ITEMS` of `events` as an RSS feed, with their bodies as plain
+/// text. Events without a headline are left out.
+fn rss_feed(ctx: &CommandContext, events: &[Event]) -> String {
+    let items: Vec<RssItem> = events
+        .iter()
+        .filter_map(|event| {
+            Some(RssItem {
+                title: event.headline()?.to_string(),
+                link: event.post_link(),
+                pub_date: event.rtime32_start_time,
+                description: event
+                    .announcement_body
+                    .body
+                    .as_str()
+                    .map(|body| process_body(body, Markup::Plain))
+                    .unwrap_or_default(),
+            })
+        })
+        .take(MAX_RSS_ITEMS)
+        .collect();
+    let title = format!("{} updates", ctx.app_name());
+    render_rss(&title, &ctx.raw_news_link(), &items)
 }
 
-/// Restores the replaced URLs back into the processed text.
+/// Sends one update written in `markup` to each of `chats`, logging failures.
 ///
-/// This function replaces the placeholders with the original URL fragments found during processing.
-fn restore_links(replaced_text: &str, found_fragments: &[String]) -> String {
-    let mut restored_text = replaced_text.to_string();
-    found_fragments
-        .iter()
-        .enumerate()
-        .for_each(|(index, fragment)| {
-            let replacement = format!("SomeReplacement{}", index);
-            restored_text = restored_text.replace(&replacement, fragment);
-        });
-    restored_text
+/// A chat that fails because it is gone, as `is_gone()` decides, is unsubscribed once its
+/// failures reach `prune_policy`. Any successful send ends its streak.
+async fn send_to_chats(
+    ctx: &CommandContext,
+    chats: &[ChatId],
+    gid: &str,
+    text: &str,
+    markup: Markup,
+) {
+    let span = info_span!("broadcast", gid = %gid, recipients = chats.len());
+    for &chat_id in chats {
+        match send_chunks_as(ctx.sender.as_ref(), chat_id, text, markup)
+            .instrument(span.clone())
+            .await
+        {
+            Ok(()) => ctx.failure_streaks.record_success(chat_id),
+            Err(err) => {
+                error!("Failed to send updates to chat {}: {}", chat_id, err);
+                let Some(policy) = ctx.startup.prune_policy.filter(|_| is_gone(&err)) else {
+                    continue;
+                };
+                if !ctx
+                    .failure_streaks
+                    .record_failure(chat_id, ctx.clock.now(), policy)
+                {
+                    continue;
+                }
+                match ctx.subscribers.remove_subscriber(chat_id) {
+                    Ok(true) => info!(
+                        "Pruned chat {} after {} failures.",
+                        chat_id, policy.failures
+                    ),
+                    Ok(false) => {}
+                    Err(err) => error!("Failed to unsubscribe chat {}: {}", chat_id, err),
+                }
+            }
+        }
+    }
+}
+
+/// Sends the images in an event's body to `chats`, captioned with its headline.
+async fn send_images_to_chats(ctx: &CommandContext, chats: &[ChatId], event: &Event) {
+    let Some(body) = event.announcement_body.body.as_str() else {
+        return;
+    };
+    let urls = bbcode::image_urls(&bbcode::parse(body));
+    if urls.is_empty() {
+        return;
+    }
+    let caption = ctx
+        .startup
+        .markup
+        .escape(event.headline().unwrap_or_default());
+    let group_size = ctx.config().media_group_size;
+    for &chat_id in chats {
+        if let Err(err) = send_photo_groups(
+            ctx.sender.as_ref(),
+            chat_id,
+            &urls,
+            &caption,
+            ctx.startup.markup,
+            group_size,
+        )
+        .await
+        {
+            error!("Failed to send images to chat {}: {}", chat_id, err);
+        }
+    }
 }
 
-/// Sends the message in chunks to avoid Telegram message size limitations.
+/// Polls the feed every `sleep_duration` and broadcasts what each cycle detects, until `shutdown`
+/// is triggered.
 ///
-/// This asynchronous function splits the message into chunks of 4000 characters each and sends
-/// them individually. It uses Markdown V2 formatting for the messages. If there's an error during
-/// message sending, it returns an error.
-async fn send_chunks(
-    bot: &Bot,
+/// This runs on its own task, apart from command handling, so the feed is polled whether or not
+/// anyone messages the bot. A cycle in progress is always finished before stopping.
+pub async fn run_polling(ctx: Arc<CommandContext>, telemetry: Arc<Telemetry>, shutdown: Shutdown) {
+    while !shutdown.is_triggered() {
+        poll_and_broadcast(&ctx, &telemetry).await;
+        tokio::select! {
+            _ = tokio::time::sleep(ctx.config().sleep_duration) => {}
+            _ = shutdown.triggered() => {}
+        }
+    }
+    info!("Stopped polling.");
+}
+
+/// Runs one poll cycle and broadcasts the updates it detects to the subscribers.
+async fn poll_and_broadcast(ctx: &CommandContext, telemetry: &Telemetry) {
+    let span = ctx.poller.next_span();
+    match ctx
+        .poller
+        .file_work(ctx.source.as_ref())
+        .instrument(span.clone())
+        .await
+    {
+        Ok(changes) => {
+            for feed in &ctx.feeds {
+                feed.record_success();
+            }
+            if let Some(Detected { broadcast, events }) = changes {
+                publish_updates(ctx, &broadcast, &events).await;
+                let subscribers = broadcast_updates(ctx, broadcast, events)
+                    .instrument(span)
+                    .await;
+                ctx.poller.record_sent(subscribers);
+                telemetry.record_update_sent();
+            }
+            info!("File work completed.");
+        }
+        Err(err) => error!("Failed to poll the feed: {}", err),
+    }
+}
+
+/// Delivers the updates of the polled `events` selected by `broadcast` to every subscriber.
+///
+/// Chats in `DeliveryMode::Realtime` get the updates right away, followed by their images if
+/// `SEND_IMAGES` is on, unless their local time is within `QUIET_HOURS`: then the updates are held
+/// back until the window ends for them. Chats in `DeliveryMode::Digest` have them queued for the
+/// next daily delivery instead. Chats in `DeliveryMode::Ping` only get a short notice per post,
+/// and if every chat is in that mode no event body is formatted at all.
+///
+/// Each chat only gets the updates its `/subscribe` filter matches, in its language. The events
+/// are fetched once more for each language subscribers read other than the polled one, and if
+/// that fails they get the polled events instead.
+///
+/// Updates of a feed mapped to chats with `FEED_<appid>_CHATS` go only to those chats, right
+/// away, and never to the subscribers. Returns the number of subscribers.
+pub async fn broadcast_updates(
+    ctx: &CommandContext,
+    broadcast: Broadcast,
+    events: Vec<Event>,
+) -> usize {
+    let now = ctx.clock.now();
+    let max_age = ctx.config().max_update_age;
+    let is_routed = |event: &Event| {
+        ctx.feeds
+            .iter()
+            .any(|feed| feed.appid == event.appid && feed.chats().is_some())
+    };
+    let events = fresh_events(events, max_age, now);
+    let (routed, events): (Vec<Event>, Vec<Event>) = events.into_iter().partition(is_routed);
+    for feed in &ctx.feeds {
+        let Some(chats) = feed.chats() else {
+            continue;
+        };
+        let feed_events: Vec<Event> = routed
+            .iter()
+            .filter(|event| event.appid == feed.appid)
+            .cloned()
+            .collect();
+        for (event, formatted) in format_updates(ctx, &feed_events, &broadcast, &ctx.news_link()) {
+            send_to_chats(ctx, chats, &event.gid, &formatted, ctx.startup.markup).await;
+        }
+    }
+
+    let subscribers = ctx.subscribers.all_subscribers();
+    let polled = ctx.polled_language();
+    let language_of =
+        |subscriber: &Subscriber| subscriber.lang.clone().unwrap_or(polled.to_string());
+    let mut languages: Vec<String> = subscribers.iter().map(language_of).collect();
+    languages.sort();
+    languages.dedup();
+    for language in languages {
+        let readers: Vec<&Subscriber> = subscribers
+            .iter()
+            .filter(|subscriber| language_of(subscriber) == language)
+            .collect();
+        let localized;
+        let language_events = if language == polled {
+            &events
+        } else {
+            match ctx.source.fetch_events_in(&language).await {
+                Ok(fetched) => {
+                    localized = fresh_events(fetched, max_age, now)
+                        .into_iter()
+                        .filter(|event| !is_routed(event))
+                        .collect();
+                    &localized
+                }
+                Err(err) => {
+                    error!(
+                        "Failed to retrieve events in {}, sending them in {}: {}",
+                        language, polled, err
+                    );
+                    &events
+                }
+            }
+        };
+        let link = ctx.news_link_in(&language);
+        deliver_updates(ctx, &readers, language_events, &broadcast, &link, now).await;
+    }
+    subscribers.len()
+}
+
+/// Delivers the updates of `events` selected by `broadcast` to `subscribers`, who all read the
+/// language of `events`, as `broadcast_updates()` describes.
+async fn deliver_updates(
+    ctx: &CommandContext,
+    subscribers: &[&Subscriber],
+    events: &[Event],
+    broadcast: &Broadcast,
+    link: &str,
+    now: SystemTime,
+) {
+    let is_quiet = |subscriber: &Subscriber| {
+        ctx.startup.quiet_hours.is_some_and(|quiet_hours| {
+            quiet_hours.contains(now, chat_timezone(subscriber.tz.as_deref()))
+        })
+    };
+    let chats = |mode, quiet: bool, event: &Event| -> Vec<ChatId> {
+        subscribers
+            .iter()
+            .filter(|subscriber| subscriber.mode == mode)
+            .filter(|subscriber| mode != DeliveryMode::Realtime || is_quiet(subscriber) == quiet)
+            .filter(|subscriber| subscriber.filter.is_none_or(|filter| filter.matches(event)))
+            .map(|subscriber| ChatId(subscriber.chat_id))
+            .collect()
+    };
+    let has_mode = |mode| subscribers.iter().any(|subscriber| subscriber.mode == mode);
+
+    if has_mode(DeliveryMode::Ping) {
+        for (event, text) in ping_updates(events, broadcast, &ctx.app_name()) {
+            let ping = chats(DeliveryMode::Ping, false, event);
+            send_to_chats(ctx, &ping, &event.gid, &text, Markup::MarkdownV2).await;
+        }
+    }
+    if !has_mode(DeliveryMode::Realtime) && !has_mode(DeliveryMode::Digest) {
+        return;
+    }
+
+    let updates = format_updates(ctx, events, broadcast, link);
+    let send_images = ctx.config().send_images;
+    let mut queued: HashMap<ChatId, Vec<String>> = HashMap::new();
+    let mut held: HashMap<ChatId, Vec<String>> = HashMap::new();
+    for (event, formatted) in &updates {
+        let realtime = chats(DeliveryMode::Realtime, false, event);
+        send_to_chats(ctx, &realtime, &event.gid, formatted, ctx.startup.markup).await;
+        if send_images {
+            send_images_to_chats(ctx, &realtime, event).await;
+        }
+        for chat_id in chats(DeliveryMode::Realtime, true, event) {
+            held.entry(chat_id).or_default().push(formatted.clone());
+        }
+        for chat_id in chats(DeliveryMode::Digest, false, event) {
+            queued.entry(chat_id).or_default().push(formatted.clone());
+        }
+    }
+    for (chat_id, updates) in queued {
+        if let Err(err) = ctx.subscribers.queue_updates(chat_id, &updates) {
+            error!("Failed to queue updates for chat {}: {}", chat_id, err);
+        }
+    }
+    for (chat_id, updates) in held {
+        if let Err(err) = ctx.subscribers.hold_updates(chat_id, &updates) {
+            error!("Failed to hold updates back for chat {}: {}", chat_id, err);
+        }
+    }
+}
+
+/// Builds the reply to `/escape`: the escaped form of `text`, shown literally, and whether the
+/// input and the escaped form pass `validate_markdown_v2()`.
+fn escape_report(text: &str) -> String {
+    let escaped = escape_markdown_v2(text);
+    let verdict = |text: &str| match validate_markdown_v2(text) {
+        Ok(()) => "valid".to_string(),
+        Err(err) => escape_markdown_v2(&err),
+    };
+    format!(
+        "Escaped:\n{}\n\nInput: {}\nEscaped: {}",
+        escape_markdown_v2(&escaped),
+        verdict(text),
+        verdict(&escaped)
+    )
+}
+
+/// Handles the super admin `/admins list|add ID|remove ID` command.
+///
+/// Added admins are stored in the settings, so they keep their access across restarts. Super
+/// admins come from `ADMIN_IDS` and can't be removed here.
+async fn handle_admins(ctx: &CommandContext, chat_id: ChatId, args: &str) -> Result<(), AppError> {
+    let mut args = args.split_whitespace();
+    let action = args.next().unwrap_or_default().to_lowercase();
+    let user_id = args.next().and_then(|id| id.parse::<u64>().ok());
+    let reply = match (action.as_str(), user_id, args.next()) {
+        ("list", None, None) => {
+            let list = |ids: Vec<u64>| {
+                if ids.is_empty() {
+                    return "none".to_string();
+                }
+                ids.iter()
+                    .map(|id| id.to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            };
+            let super_admins = ctx.startup.super_admins.iter().map(|id| id.0).collect();
+            format!(
+                "Super admins: {}\nAdmins: {}",
+                list(super_admins),
+                list(ctx.settings.get().admins)
+            )
+        }
+        ("add", Some(id), None) if ctx.is_admin(Some(UserId(id))) => {
+            format!("User {} is already an admin\\.", id)
+        }
+        ("add", Some(id), None) => {
+            ctx.settings.update(|settings| settings.admins.push(id))?;
+            info!("User {} was made an admin.", id);
+            format!("User {} is now an admin\\.", id)
+        }
+        ("remove", Some(id), None) if ctx.is_super_admin(Some(UserId(id))) => format!(
+            "User {} is a super admin and can only be removed from ADMIN\\_IDS\\.",
+            id
+        ),
+        ("remove", Some(id), None) if !ctx.is_admin(Some(UserId(id))) => {
+            format!("User {} isn't an admin\\.", id)
+        }
+        ("remove", Some(id), None) => {
+            ctx.settings
+                .update(|settings| settings.admins.retain(|&admin| admin != id))?;
+            info!("User {} is no longer an admin.", id);
+            format!("User {} is no longer an admin\\.", id)
+        }
+        _ => ADMINS_USAGE_TEXT.to_string(),
+    };
+    send_reply(ctx, chat_id, &reply).await
+}
+
+/// Handles the admin `/count [n]` command.
+///
+/// Without arguments it replies with the number of events fetched per poll. Otherwise the number
+/// is clamped to `1..=MAX_POLL_COUNT` and stored in the settings, and the next poll uses it.
+async fn handle_count(ctx: &CommandContext, chat_id: ChatId, args: &str) -> Result<(), AppError> {
+    let args = args.trim();
+    let reply = if args.is_empty() {
+        let count = ctx
+            .settings
+            .get()
+            .poll_count
+            .unwrap_or(ctx.startup.event_count);
+        format!("Each poll fetches {} events\\.", count)
+    } else {
+        match args.parse::<u32>() {
+            Err(_) => "Usage: /count \\[n\\]".to_string(),
+            Ok(count) => {
+                let count = count.clamp(1, MAX_POLL_COUNT);
+                ctx.settings
+                    .update(|settings| settings.poll_count = Some(count))?;
+                info!("Poll count set to {}.", count);
+                format!("Each poll fetches {} events from now on\\.", count)
+            }
+        }
+    };
+    send_reply(ctx, chat_id, &reply).await
+}
+
+/// Handles the admin `/chunksize [n]` command.
+///
+/// Without arguments it replies with the current chunk size. Otherwise the size is clamped to
+/// `MIN_CHUNK_SIZE..=TELEGRAM_MAX_MESSAGE_LEN` and stored in the settings, and every later send
+/// is split to it, up to the sender's own limit.
+async fn handle_chunksize(
+    ctx: &CommandContext,
     chat_id: ChatId,
-    msg: &str,
-) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    let chunks = msg
-        .chars()
-        .collect::<Vec<_>>()
-        .chunks(4000)
-        .map(|chunk| chunk.iter().collect::<String>())
-        .collect::<Vec<_>>();
-    for chunk in chunks {
-        bot.send_message(chat_id, &chunk)
-            .parse_mode(ParseMode::MarkdownV2)
-            .await?;
-        info!("Chunk sent successfully.");
+    args: &str,
+) -> Result<(), AppError> {
+    let args = args.trim();
+    let reply = if args.is_empty() {
+        format!(
+            "Messages are split into chunks of up to {} characters\\.",
+            ctx.sender.max_message_len()
+        )
+    } else {
+        match args.parse::<usize>() {
+            Err(_) => "Usage: /chunksize \\[n\\]".to_string(),
+            Ok(size) => {
+                let size = size.clamp(MIN_CHUNK_SIZE, TELEGRAM_MAX_MESSAGE_LEN);
+                ctx.settings
+                    .update(|settings| settings.chunk_size = Some(size))?;
+                info!("Chunk size set to {}.", size);
+                format!(
+                    "Messages are split into chunks of up to {} characters from now on\\.",
+                    size
+                )
+            }
+        }
+    };
+    send_reply(ctx, chat_id, &reply).await
+}
+
+/// Handles the admin `/fields [name]` command.
+///
+/// Without arguments it lists the optional parts of updates with whether each is on. With the
+/// name of one it turns that part on or off and stores every flag in the settings, after which
+/// `BYLINES` and `STEAM_LINKS` no longer apply.
+async fn handle_fields(ctx: &CommandContext, chat_id: ChatId, args: &str) -> Result<(), AppError> {
+    let name = args.trim().to_lowercase();
+    let mut fields = ctx.fields();
+    let summary = |fields: Fields| {
+        fields
+            .flags()
+            .iter()
+            .map(|(name, on)| format!("{}: {}", name, if *on { "on" } else { "off" }))
+            .collect::<Vec<_>>()
+            .join("\n")
+    };
+    let reply = if name.is_empty() {
+        summary(fields)
+    } else if let Some(flag) = fields.flag_mut(&name) {
+        *flag = !*flag;
+        ctx.settings
+            .update(|settings| settings.fields = Some(fields))?;
+        info!("Field {} toggled.", name);
+        summary(fields)
+    } else {
+        format!(
+            "Unknown field: {}\nFields: {}",
+            escape_markdown_v2(&name),
+            FIELD_NAMES.join(", ")
+        )
+    };
+    send_reply(ctx, chat_id, &reply).await
+}
+
+/// Builds the reply to `/settings` from a chat's subscriber record, which is `None` if the chat
+/// isn't subscribed.
+///
+/// The message template and `SEND_IMAGES` are shared by all chats, so only whether a custom
+/// template is set and whether images are sent is shown. `quiet_hours` is the bot's window, if it
+/// has one, and whether the chat is inside it at the moment, so real-time updates are held back.
+/// `default_language` is the one chats that didn't pick a language read.
+fn settings_summary(
+    subscriber: Option<&Subscriber>,
+    default_language: &str,
+    custom_template: bool,
+    send_images: bool,
+    quiet_hours: Option<(QuietHours, bool)>,
+) -> String {
+    let Some(subscriber) = subscriber else {
+        return "Subscribed: no\nUse /subscribe to get updates here\\.".to_string();
+    };
+    let language = match &subscriber.lang {
+        Some(lang) => escape_markdown_v2(lang),
+        None => format!("{} \\(default\\)", escape_markdown_v2(default_language)),
+    };
+    let filter = match subscriber.filter {
+        None => "all",
+        Some(EventFilter::Patches) => "patches",
+        Some(EventFilter::News) => "news",
+    };
+    let mode = match subscriber.mode {
+        DeliveryMode::Realtime => "realtime",
+        DeliveryMode::Digest => "digest",
+        DeliveryMode::Ping => "ping",
+    };
+    let format = if custom_template { "custom" } else { "default" };
+    let images = if send_images { "on" } else { "off" };
+    let time_zone = match &subscriber.tz {
+        Some(tz) => escape_markdown_v2(tz),
+        None => "UTC \\(default\\)".to_string(),
+    };
+    let muted = match quiet_hours {
+        None => "no quiet hours".to_string(),
+        Some((window, muted)) => format!(
+            "{}, quiet hours {}",
+            if muted { "yes" } else { "no" },
+            escape_markdown_v2(&window.to_string())
+        ),
+    };
+    format!(
+        "Subscribed: yes\nLanguage: {}\nFilter: {}\nMode: {}\nTemplate: {}\nImages: {}\n\
+         Time zone: {}\nMuted: {}",
+        language, filter, mode, format, images, time_zone, muted
+    )
+}
+
+/// Handles the admin `/reload` command.
+///
+/// The env file is read again with `config::reload()` and its reloadable settings applied, and
+/// the settings file is read again too, so a template edited on disk is used. The reply lists
+/// what changed and which variables only take effect after a restart. If a setting doesn't parse,
+/// the reply is the error and nothing is applied.
+async fn handle_reload(ctx: &CommandContext, chat_id: ChatId) -> Result<(), AppError> {
+    let template = ctx.settings.get().template;
+    let reload = match config::reload(&ctx.env_file, &ctx.config()) {
+        Err(AppError::ConfigError(err)) => {
+            let reply = format!("Configuration not reloaded: {}", escape_markdown_v2(&err));
+            return send_reply(ctx, chat_id, &reply).await;
+        }
+        reload => reload.and_then(|reload| ctx.settings.reload().map(|()| reload))?,
+    };
+    *ctx.config.lock().unwrap() = reload.config;
+    let mut changed: Vec<&str> = reload.changed;
+    if ctx.settings.get().template != template {
+        changed.push("template");
     }
-    Ok(())
+    info!(
+        "Configuration reloaded, changed: {:?}, needs a restart: {:?}.",
+        changed, reload.needs_restart
+    );
+    let list = |names: Vec<String>| escape_markdown_v2(&names.join(", "));
+    let mut reply = "Configuration reloaded\\.".to_string();
+    if !changed.is_empty() {
+        reply += &format!(
+            "\nChanged: {}",
+            list(changed.iter().map(|name| name.to_string()).collect())
+        );
+    }
+    if !reload.needs_restart.is_empty() {
+        reply += &format!("\nNeeds a restart: {}", list(reload.needs_restart));
+    }
+    send_reply(ctx, chat_id, &reply).await
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+/// Handles the `/mode <realtime|digest|ping>` command.
+async fn handle_mode(ctx: &CommandContext, chat_id: ChatId, args: &str) -> Result<(), AppError> {
+    let reply = match args.parse::<DeliveryMode>() {
+        Err(_) => "Unknown mode\\. Usage: /mode realtime\\|digest\\|ping".to_string(),
+        Ok(mode) => {
+            let subscribed = ctx.subscribers.set_mode(chat_id, mode)?;
+            match (subscribed, mode) {
+                (false, _) => "This chat isn't subscribed\\. Use /subscribe first\\.".to_string(),
+                (true, DeliveryMode::Realtime) => {
+                    "Updates will be sent as they come out\\.".to_string()
+                }
+                (true, DeliveryMode::Digest) => "Updates will be sent once a day\\.".to_string(),
+                (true, DeliveryMode::Ping) => {
+                    "Only a short notice will be sent for new posts\\.".to_string()
+                }
+            }
+        }
+    };
+    send_reply(ctx, chat_id, &reply).await
+}
 
-    #[test]
-    fn test_process_body() {
-        let input = "[url=https://www.dota2.com]Dota 2[/url]";
-        let processed_body = process_body(input);
+/// Handles the admin `/echo <markdown>` command.
+///
+/// The text is sent back as is with the Markdown V2 parse mode. If Telegram rejects it, the reply
+/// is Telegram's error instead, so operators can try out markup without breaking a broadcast.
+async fn handle_echo(ctx: &CommandContext, chat_id: ChatId, text: &str) -> Result<(), AppError> {
+    let sender = ctx.sender.as_ref();
+    if text.trim().is_empty() {
+        return send_reply(ctx, chat_id, "Usage: /echo <markdown>").await;
+    }
+    match sender.send_text(chat_id, text, Markup::MarkdownV2).await {
+        Err(RequestError::Api(err)) => {
+            let reply = format!(
+                "Telegram rejected the markup: {}",
+                escape_markdown_v2(&err.to_string())
+            );
+            send_reply(ctx, chat_id, &reply).await
+        }
+        result => Ok(result?),
+    }
+}
 
-        assert_eq!(processed_body, "[Dota 2](https://www.dota2.com)");
+/// Handles the `/tz <IANA name>` command.
+async fn handle_tz(ctx: &CommandContext, chat_id: ChatId, name: &str) -> Result<(), AppError> {
+    let reply = match parse_timezone(name) {
+        Err(err) => format!(
+            "{}\\. Usage: /tz <IANA name>, e\\.g\\. /tz Europe/Berlin",
+            escape_markdown_v2(&err)
+        ),
+        Ok(tz) => {
+            let subscribed = ctx.subscribers.set_timezone(chat_id, tz.name())?;
+            if subscribed {
+                format!("Time zone set to {}\\.", escape_markdown_v2(tz.name()))
+            } else {
+                "This chat isn't subscribed\\. Use /subscribe first\\.".to_string()
+            }
+        }
+    };
+    send_reply(ctx, chat_id, &reply).await
+}
+
+/// Replies to a command sent in `chat_id` with the Markdown V2 `text`, split like `send_chunks()`.
+async fn send_reply(ctx: &CommandContext, chat_id: ChatId, text: &str) -> Result<(), AppError> {
+    Ok(send_chunks(ctx.sender.as_ref(), chat_id, text).await?)
+}
+
+/// Handles a parsed bot command by dispatching it to the matching handler.
+///
+/// Admin commands from users who aren't admins, and super admin commands from anyone outside
+/// `Config::super_admins`, are answered with a refusal.
+pub async fn handle_command(
+    ctx: &CommandContext,
+    chat_id: ChatId,
+    user_id: Option<UserId>,
+    command: Command,
+) -> Result<(), AppError> {
+    if command.is_super_admin_only() && !ctx.is_super_admin(user_id) {
+        return send_reply(ctx, chat_id, SUPER_ADMIN_ONLY_TEXT).await;
+    }
+    if command.is_admin_only() && !ctx.is_admin(user_id) {
+        return send_reply(ctx, chat_id, ADMIN_ONLY_TEXT).await;
+    }
+    match command {
+        Command::Subscribe(args) => handle_subscribe(ctx, chat_id, &args).await,
+        Command::Start => handle_subscribe(ctx, chat_id, "").await,
+        Command::Help => {
+            let help = escape_markdown_v2(&Command::descriptions().to_string());
+            send_reply(ctx, chat_id, &help).await
+        }
+        Command::Unsubscribe => {
+            let removed = ctx.subscribers.remove_subscriber(chat_id)?;
+            let reply = if removed {
+                UNSUBSCRIBED_TEXT
+            } else {
+                "This chat isn't subscribed\\."
+            };
+            send_reply(ctx, chat_id, reply).await
+        }
+        Command::GetRecent(args) => handle_get_recent(ctx, chat_id, &args).await,
+        Command::Latest(args) => match parse_latest_count(&args) {
+            Ok(1) => handle_latest(ctx, chat_id).await,
+            Ok(count) => handle_latest_many(ctx, chat_id, count).await,
+            Err(usage) => send_reply(ctx, chat_id, &usage).await,
+        },
+        Command::Rss => handle_rss(ctx, chat_id).await,
+        Command::Diagnose => handle_diagnose(ctx, chat_id).await,
+        Command::Template(args) => handle_template(ctx, chat_id, &args).await,
+        Command::PollNow => handle_poll_now(ctx, chat_id).await,
+        Command::Rebroadcast(args) => handle_rebroadcast(ctx, chat_id, &args).await,
+        Command::Prune => handle_prune(ctx, chat_id).await,
+        Command::AddChat(args) => handle_add_chat(ctx, chat_id, &args).await,
+        Command::Mode(args) => handle_mode(ctx, chat_id, &args).await,
+        Command::Admins(args) => handle_admins(ctx, chat_id, &args).await,
+        Command::Count(args) => handle_count(ctx, chat_id, &args).await,
+        Command::Chunksize(args) => handle_chunksize(ctx, chat_id, &args).await,
+        Command::Fields(args) => handle_fields(ctx, chat_id, &args).await,
+        Command::Reload => handle_reload(ctx, chat_id).await,
+        Command::ParseFailures => handle_parse_failures(ctx, chat_id).await,
+        Command::LastCycle => handle_last_cycle(ctx, chat_id).await,
+        Command::Status => handle_status(ctx, chat_id).await,
+        Command::Diff => handle_diff(ctx, chat_id).await,
+        Command::HeadlineDiff => handle_headline_diff(ctx, chat_id).await,
+        Command::Tz(name) => handle_tz(ctx, chat_id, &name).await,
+        Command::Echo(text) => handle_echo(ctx, chat_id, &text).await,
+        Command::Chunks(gid) => handle_chunks(ctx, chat_id, gid.trim()).await,
+        Command::Settings => {
+            let subscriber = ctx.subscribers.subscriber(chat_id);
+            let tz = chat_timezone(subscriber.as_ref().and_then(|s| s.tz.as_deref()));
+            let quiet_hours = ctx
+                .startup
+                .quiet_hours
+                .map(|window| (window, window.contains(ctx.clock.now(), tz)));
+            let summary = settings_summary(
+                subscriber.as_ref(),
+                ctx.polled_language(),
+                ctx.settings.get().template.is_some(),
+                ctx.config().send_images,
+                quiet_hours,
+            );
+            send_reply(ctx, chat_id, &summary).await
+        }
+        Command::Escape(text) => send_reply(ctx, chat_id, &escape_report(&text)).await,
+        Command::Feeds => {
+            let listing = feed_listing(
+                &ctx.feeds,
+                &ctx.subscribers.all_subscribers(),
+                ctx.polled_language(),
+                SystemTime::now(),
+            );
+            send_reply(ctx, chat_id, &listing).await
+        }
     }
+}
 
-    #[test]
-    fn test_restore_links() {
-        let replaced_text = "SomeReplacement0";
-        let found_fragments = vec!["[url=https://www.dota2.com]Dota 2[/url]".to_string()];
-        let restored_text = restore_links(replaced_text, &found_fragments);
+/// Handles the `/get_recent <n>` command.
+///
+/// This asynchronous function fetches the feed and sends the `n`-th most recent update, where
+/// `1` is the newest. If `n` isn't a number or is outside the fetched range, it replies with an
+/// error instead.
+async fn handle_get_recent(
+    ctx: &CommandContext,
+    chat_id: ChatId,
+    args: &str,
+) -> Result<(), AppError> {
+    let (reply, markup) = match args.trim().parse::<usize>() {
+        Ok(n) if n >= 1 => {
+            let events = ctx.source.fetch_events().await?;
+            let template = ctx.template();
+            let link = ctx.news_link();
+            let mut updates: Vec<String> = events
+                .iter()
+                .filter_map(|event| {
+                    format_event(event, &template, &link, ctx.fields(), ctx.startup.markup)
+                })
+                .collect();
+            if n <= updates.len() {
+                (updates.swap_remove(n - 1), ctx.startup.markup)
+            } else {
+                let reply = format!(
+                    "Only {} updates are available\\. Pick a number from 1 to {}\\.",
+                    updates.len(),
+                    updates.len()
+                );
+                (reply, Markup::MarkdownV2)
+            }
+        }
+        _ => (
+            "Usage: /get\\_recent <n\\>, where 1 is the newest update\\.".to_string(),
+            Markup::MarkdownV2,
+        ),
+    };
+    Ok(send_chunks_as(ctx.sender.as_ref(), chat_id, &reply, markup).await?)
+}
+
+/// Parses the arguments of `/latest [n]` into how many updates to send.
+///
+/// No argument means `1`, and numbers are clamped to `1..=MAX_LATEST`. Anything else is an error
+/// holding the usage to reply with.
+fn parse_latest_count(args: &str) -> Result<usize, String> {
+    let args = args.trim();
+    if args.is_empty() {
+        return Ok(1);
+    }
+    args.parse::<usize>()
+        .map(|count| count.clamp(1, MAX_LATEST))
+        .map_err(|_| {
+            format!(
+                "Usage: /latest \\[n\\], where n is at most {}\\.",
+                MAX_LATEST
+            )
+        })
+}
+
+/// Handles `/latest <n>` for more than one update.
+///
+/// Sends the `count` newest updates, oldest first so the chat reads in order, each as its own
+/// message.
+async fn handle_latest_many(
+    ctx: &CommandContext,
+    chat_id: ChatId,
+    count: usize,
+) -> Result<(), AppError> {
+    let events = ctx.source.fetch_events().await?;
+    let template = ctx.template();
+    let link = ctx.news_link();
+    let mut updates: Vec<(&Event, String)> = events
+        .iter()
+        .filter_map(|event| {
+            Some((
+                event,
+                format_event(event, &template, &link, ctx.fields(), ctx.startup.markup)?,
+            ))
+        })
+        .take(count)
+        .collect();
+    let Some((newest, _)) = updates.first() else {
+        return send_reply(ctx, chat_id, "No updates are available yet\\.").await;
+    };
+    ctx.latest_sent
+        .lock()
+        .unwrap()
+        .insert(chat_id, newest.gid.clone());
+    updates.reverse();
+    for (_, formatted) in updates {
+        send_chunks_as(ctx.sender.as_ref(), chat_id, &formatted, ctx.startup.markup).await?;
+    }
+    Ok(())
+}
+
+/// Handles the `/latest` command.
+///
+/// Sends the newest update, unless it is the one this chat was already sent by `/latest`. Then
+/// only a short note with its headline is sent, so running the command again doesn't repeat the
+/// whole body.
+async fn handle_latest(ctx: &CommandContext, chat_id: ChatId) -> Result<(), AppError> {
+    let events = ctx.source.fetch_events().await?;
+    let template = ctx.template();
+    let link = ctx.news_link();
+    let latest = events.iter().find_map(|event| {
+        Some((
+            event,
+            format_event(event, &template, &link, ctx.fields(), ctx.startup.markup)?,
+        ))
+    });
+    let (reply, markup) = match latest {
+        None => (
+            "No updates are available yet\\.".to_string(),
+            Markup::MarkdownV2,
+        ),
+        Some((event, formatted)) => {
+            let mut latest_sent = ctx.latest_sent.lock().unwrap();
+            if !event.gid.is_empty() && latest_sent.get(&chat_id) == Some(&event.gid) {
+                let note = format!(
+                    "Still the latest: *{}*",
+                    escape_markdown_v2(event.headline().unwrap_or_default())
+                );
+                (note, Markup::MarkdownV2)
+            } else {
+                latest_sent.insert(chat_id, event.gid.clone());
+                (formatted, ctx.startup.markup)
+            }
+        }
+    };
+    Ok(send_chunks_as(ctx.sender.as_ref(), chat_id, &reply, markup).await?)
+}
+
+/// Handles the admin `/chunks <gid>` command.
+///
+/// The event with the given gid is formatted like a broadcast and split with
+/// `split_into_chunks()` for the current sender, and the reply lists the length of each chunk.
+/// Nothing is sent to subscribers.
+async fn handle_chunks(ctx: &CommandContext, chat_id: ChatId, gid: &str) -> Result<(), AppError> {
+    let reply = if gid.is_empty() {
+        "Usage: /chunks <gid\\>".to_string()
+    } else {
+        let events = ctx.source.fetch_events().await?;
+        let formatted = events
+            .iter()
+            .find(|event| event.gid == gid)
+            .and_then(|event| {
+                format_event(
+                    event,
+                    &ctx.template(),
+                    &ctx.news_link(),
+                    ctx.fields(),
+                    ctx.startup.markup,
+                )
+            });
+        match formatted {
+            None => format!(
+                "No update with gid {} in the feed\\.",
+                escape_markdown_v2(gid)
+            ),
+            Some(formatted) => {
+                let lengths: Vec<String> =
+                    split_into_chunks(&formatted, ctx.sender.max_message_len(), ctx.startup.markup)
+                        .iter()
+                        .map(|chunk| chunk.chars().count().to_string())
+                        .collect();
+                format!(
+                    "This update is sent as {} messages of {} characters\\.",
+                    lengths.len(),
+                    lengths.join(", ")
+                )
+            }
+        }
+    };
+    send_reply(ctx, chat_id, &reply).await
+}
+
+/// Handles the admin `/diagnose` command.
+///
+/// This asynchronous function fetches the raw feed, runs it through the tolerant
+/// `diagnose_feed()` parser, and replies with the number of parsed and malformed events, whether
+/// the feed reported `success`, and the newest headline.
+async fn handle_diagnose(ctx: &CommandContext, chat_id: ChatId) -> Result<(), AppError> {
+    let reply = match ctx.source.fetch_feed().await {
+        Ok(feed) => {
+            let diagnostics = diagnose_feed(&feed);
+            format!(
+                "*Feed diagnostics*\nEvents parsed: {}\nEvents failed to parse: {}\nSuccess flag: {}\nNewest headline: {}",
+                diagnostics.parsed,
+                diagnostics.failed,
+                if diagnostics.success { "yes" } else { "no" },
+                diagnostics
+                    .newest_headline
+                    .as_deref()
+                    .map_or_else(|| "none".to_string(), escape_markdown_v2),
+            )
+        }
+        Err(err) => format!(
+            "Failed to fetch the feed: {}",
+            escape_markdown_v2(&err.to_string())
+        ),
+    };
+    send_reply(ctx, chat_id, &reply).await
+}
+
+/// Handles the admin `/headline_diff` command.
+///
+/// Fetches the live feed and replies with its headlines that the poller hasn't stored yet, which
+/// the next poll would treat as new, and the stored headlines that left the feed.
+async fn handle_headline_diff(ctx: &CommandContext, chat_id: ChatId) -> Result<(), AppError> {
+    let Some(stored) = ctx.poller.stored_headlines() else {
+        return send_reply(ctx, chat_id, NO_STORED_HEADLINES_TEXT).await;
+    };
+    let reply = match ctx.source.fetch_feed().await {
+        Err(err) => format!(
+            "Failed to fetch the feed: {}",
+            escape_markdown_v2(&err.to_string())
+        ),
+        Ok(feed) => {
+            let (added, removed) = headline_diff(&stored, &extract_headlines(&feed));
+            let list = |headlines: Vec<String>| {
+                if headlines.is_empty() {
+                    return "none".to_string();
+                }
+                headlines
+                    .iter()
+                    .map(|headline| format!("• {}", escape_markdown_v2(headline)))
+                    .collect::<Vec<_>>()
+                    .join("\n")
+            };
+            format!(
+                "*Headline diff*\nNew in the feed:\n{}\nNo longer in the feed:\n{}",
+                list(added),
+                list(removed)
+            )
+        }
+    };
+    send_reply(ctx, chat_id, &reply).await
+}
+
+/// Handles the `/diff` command.
+///
+/// Fetches the feed and replies with the headlines of the events the poller hasn't seen, which
+/// the next poll would send. If nothing is stored yet every event is new, and only the first
+/// `MAX_DIFF_HEADLINES` are listed.
+async fn handle_diff(ctx: &CommandContext, chat_id: ChatId) -> Result<(), AppError> {
+    let reply = match ctx.source.fetch_events().await {
+        Err(err) => format!(
+            "Failed to fetch the feed: {}",
+            escape_markdown_v2(&err.to_string())
+        ),
+        Ok(events) => {
+            let list = |events: &[&Event]| {
+                events
+                    .iter()
+                    .map(|event| {
+                        format!(
+                            "• {}",
+                            escape_markdown_v2(&event.announcement_body.headline)
+                        )
+                    })
+                    .collect::<Vec<_>>()
+                    .join("\n")
+            };
+            match ctx.poller.unseen_events(&events) {
+                Some(unseen) if unseen.is_empty() => NO_NEW_UPDATES_TEXT.to_string(),
+                Some(unseen) => format!("*New updates*\n{}", list(&unseen)),
+                None => {
+                    let all: Vec<&Event> = events.iter().collect();
+                    let shown = &all[..all.len().min(MAX_DIFF_HEADLINES)];
+                    format!(
+                        "*New updates*\nNothing was stored yet, so all {} fetched updates are new\\. The newest:\n{}",
+                        all.len(),
+                        list(shown)
+                    )
+                }
+            }
+        }
+    };
+    send_reply(ctx, chat_id, &reply).await
+}
+
+/// Handles the admin `/parse_failures` command.
+///
+/// Replies with the number of polled payloads that failed to parse within
+/// `capture::RECENT_WINDOW`, and the error of the latest one, whose payload is then sent as a file.
+async fn handle_parse_failures(ctx: &CommandContext, chat_id: ChatId) -> Result<(), AppError> {
+    let now = SystemTime::now();
+    let Some(latest) = ctx.parse_failures.latest() else {
+        return send_reply(ctx, chat_id, "No parse failures captured\\.").await;
+    };
+    let reply = format!(
+        "Parse failures in the last 24 hours: {}\nLatest, {}s ago: {}",
+        ctx.parse_failures.recent_count(now),
+        now.duration_since(latest.at)
+            .unwrap_or(Duration::ZERO)
+            .as_secs(),
+        escape_markdown_v2(&latest.error)
+    );
+    send_chunks(ctx.sender.as_ref(), chat_id, &reply).await?;
+    Ok(ctx
+        .sender
+        .send_document(chat_id, PARSE_FAILURE_FILE_NAME, &latest.payload)
+        .await?)
+}
+
+/// Handles the admin `/last_cycle` command.
+///
+/// Reports when the last poll cycle ran, what it found and whether anything was sent, for
+/// debugging an update that wasn't broadcast.
+async fn handle_last_cycle(ctx: &CommandContext, chat_id: ChatId) -> Result<(), AppError> {
+    let reply = match ctx.poller.last_cycle() {
+        None => "No poll cycle has run yet\\.".to_string(),
+        Some(summary) => {
+            let ago = ctx
+                .clock
+                .now()
+                .duration_since(summary.at)
+                .unwrap_or(Duration::ZERO)
+                .as_secs();
+            let outcome = match (&summary.error, &summary.broadcast) {
+                (Some(err), _) => format!("failed: {}", escape_markdown_v2(err)),
+                (None, None) => "no change".to_string(),
+                (None, Some(Broadcast::Latest)) => "change detected, the latest update".to_string(),
+                (None, Some(Broadcast::All)) => "change detected, every update".to_string(),
+                (None, Some(Broadcast::New(gids))) => {
+                    format!("change detected, {} new gids", gids.len())
+                }
+            };
+            let sent = match summary.sent_to {
+                Some(chats) => format!("sent to {} chats", chats),
+                None => "nothing sent".to_string(),
+            };
+            format!(
+                "Last poll cycle, {}s ago: {}\nNew or changed entries: {}\nBroadcast: {}",
+                ago, outcome, summary.updates_found, sent
+            )
+        }
+    };
+    send_reply(ctx, chat_id, &reply).await
+}
+
+/// What `/status` reports about polling.
+struct Status {
+    /// When a poll last found something to send.
+    last_detected: Option<SystemTime>,
+    /// Entries in the stored snapshot.
+    tracked_events: Option<usize>,
+    poll_interval: Duration,
+}
+
+/// Formats `status` as of `now` as a Markdown V2 reply.
+fn status_text(status: &Status, now: SystemTime) -> String {
+    let last_detected = match status.last_detected {
+        Some(at) => format!(
+            "{}s ago",
+            now.duration_since(at).unwrap_or(Duration::ZERO).as_secs()
+        ),
+        None => "not since startup".to_string(),
+    };
+    let tracked_events = status
+        .tracked_events
+        .map_or("none stored yet".to_string(), |count| count.to_string());
+    format!(
+        "Last update detected: {}\nEvents tracked: {}\nPoll interval: {}s",
+        last_detected,
+        tracked_events,
+        status.poll_interval.as_secs()
+    )
+}
+
+/// Handles the `/status` command, replying with `status_text()`.
+async fn handle_status(ctx: &CommandContext, chat_id: ChatId) -> Result<(), AppError> {
+    let status = Status {
+        last_detected: ctx.poller.last_detected(),
+        tracked_events: ctx.poller.tracked_events(),
+        poll_interval: ctx.config().sleep_duration,
+    };
+    send_reply(ctx, chat_id, &status_text(&status, ctx.clock.now())).await
+}
+
+/// Handles the admin `/template [template]` command.
+///
+/// Without arguments it replies with the current template. Otherwise the argument is validated
+/// with `MessageTemplate::parse()` and stored in the settings, so it applies to every update sent
+/// afterwards. Templates with unknown placeholders are rejected, and so are templates that
+/// `check_template()` finds Telegram wouldn't parse.
+async fn handle_template(
+    ctx: &CommandContext,
+    chat_id: ChatId,
+    args: &str,
+) -> Result<(), AppError> {
+    let args = args.trim();
+    let reply = if args.is_empty() {
+        format!(
+            "Current template:\n{}",
+            escape_markdown_v2(ctx.template().source())
+        )
+    } else {
+        let parsed = MessageTemplate::parse(args).map_err(|err| {
+            format!(
+                "{}\\. Available placeholders: {}",
+                escape_markdown_v2(&err),
+                escape_markdown_v2(
+                    &PLACEHOLDERS
+                        .iter()
+                        .map(|name| format!("{{{}}}", name))
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                )
+            )
+        });
+        let checked = parsed.and_then(|template| {
+            check_template(&template, ctx.startup.markup, &ctx.news_link())
+                .map(|()| template)
+                .map_err(|err| format!("{}\\.", escape_markdown_v2(&err)))
+        });
+        match checked {
+            Ok(template) => {
+                ctx.settings
+                    .update(|settings| settings.template = Some(template.source().to_string()))?;
+                info!("Message template updated.");
+                "Template updated\\.".to_string()
+            }
+            Err(reply) => reply,
+        }
+    };
+    send_reply(ctx, chat_id, &reply).await
+}
+
+/// Renders a sample update with `template` and checks that the result is valid in `markup`, so a
+/// template with, say, an unescaped `.` isn't stored only to have Telegram reject every update
+/// sent with it. Only Markdown V2 can be checked.
+fn check_template(template: &MessageTemplate, markup: Markup, link: &str) -> Result<(), String> {
+    if markup != Markup::MarkdownV2 {
+        return Ok(());
+    }
+    let sample = template.render(
+        &escape_headline("Patch 7.36 (beta)!", markup),
+        &process_body("[b]Notes[/b] for 7.36.", markup),
+        link,
+    );
+    validate_markdown_v2(&sample)
+        .map_err(|err| format!("The template renders to invalid Markdown V2: {}", err))
+}
+
+/// Handles the `/rss` command by sending the recent updates as an RSS feed file, the same one
+/// `RSS_FILE` is written with.
+async fn handle_rss(ctx: &CommandContext, chat_id: ChatId) -> Result<(), AppError> {
+    let events = ctx.source.fetch_events().await?;
+    let events = fresh_events(events, ctx.config().max_update_age, ctx.clock.now());
+    Ok(ctx
+        .sender
+        .send_document(chat_id, RSS_FILE_NAME, rss_feed(ctx, &events).as_bytes())
+        .await?)
+}
+
+/// Handles the admin `/poll_now` command.
+///
+/// Runs one poll cycle right away and sends any new updates to every subscriber. The cycle goes
+/// through the shared `Poller`, so if the regular loop is polling at the moment the command is
+/// refused instead of running a second cycle alongside it. The regular interval is unaffected.
+async fn handle_poll_now(ctx: &CommandContext, chat_id: ChatId) -> Result<(), AppError> {
+    let span = ctx.poller.next_span();
+    let reply = match ctx
+        .poller
+        .try_file_work(ctx.source.as_ref())
+        .instrument(span.clone())
+        .await
+    {
+        None => "A poll is already running, try again later\\.".to_string(),
+        Some(Err(err)) => format!("The poll failed: {}", escape_markdown_v2(&err.to_string())),
+        Some(Ok(changes)) => {
+            for feed in &ctx.feeds {
+                feed.record_success();
+            }
+            match changes {
+                None => "Nothing new\\.".to_string(),
+                Some(Detected { broadcast, events }) => {
+                    publish_updates(ctx, &broadcast, &events).await;
+                    let subscribers = broadcast_updates(ctx, broadcast, events)
+                        .instrument(span)
+                        .await;
+                    ctx.poller.record_sent(subscribers);
+                    format!("Sent new updates to {} subscribers\\.", subscribers)
+                }
+            }
+        }
+    };
+    send_reply(ctx, chat_id, &reply).await
+}
+
+/// Handles the admin `/add_chat <id>` command.
+///
+/// Subscribes a chat the admin can't easily run `/subscribe` in, like a channel, with the default
+/// preferences. The bot first posts `add_chat_check_text()` there, and the chat is only added if
+/// that succeeds, so a wrong id or missing post rights are reported right away.
+async fn handle_add_chat(
+    ctx: &CommandContext,
+    chat_id: ChatId,
+    args: &str,
+) -> Result<(), AppError> {
+    let reply = match args.trim().parse::<i64>().map(ChatId) {
+        Err(_) => "Usage: /add\\_chat <id\\>".to_string(),
+        Ok(target) if ctx.subscribers.subscriber(target).is_some() => {
+            format!(
+                "Chat {} is already subscribed\\.",
+                escape_markdown_v2(&target.to_string())
+            )
+        }
+        Ok(target) => {
+            let id = escape_markdown_v2(&target.to_string());
+            match send_chunks(
+                ctx.sender.as_ref(),
+                target,
+                &add_chat_check_text(&ctx.app_name()),
+            )
+            .await
+            {
+                Err(err) => {
+                    warn!("Failed to post to chat {}: {}", target, err);
+                    format!(
+                        "Couldn't post to chat {}, so it wasn't added: {}",
+                        id,
+                        escape_markdown_v2(&err.to_string())
+                    )
+                }
+                Ok(()) => {
+                    ctx.subscribers.add_subscriber(Subscriber::new(target))?;
+                    info!("Chat {} added by an admin.", target);
+                    format!("Chat {} is subscribed\\.", id)
+                }
+            }
+        }
+    };
+    send_reply(ctx, chat_id, &reply).await
+}
+
+/// Returns `true` if the error means the chat is gone or the bot was blocked or removed there,
+/// rather than that the request failed for now.
+fn is_gone(err: &RequestError) -> bool {
+    matches!(
+        err,
+        RequestError::Api(
+            ApiError::ChatNotFound
+                | ApiError::BotBlocked
+                | ApiError::BotKicked
+                | ApiError::BotKickedFromSupergroup
+                | ApiError::UserDeactivated
+                | ApiError::GroupDeactivated
+                | ApiError::CantInitiateConversation
+        )
+    )
+}
+
+/// Handles the admin `/prune` command.
+///
+/// Checks every subscriber with `check_chat()`, which posts nothing, and removes the chats that
+/// are gone. A chat whose check fails for another reason, like a network error, stays subscribed.
+/// The reply says how many chats were removed.
+async fn handle_prune(ctx: &CommandContext, chat_id: ChatId) -> Result<(), AppError> {
+    let subscribers = ctx.subscribers.all_subscribers();
+    let mut pruned = 0;
+    for subscriber in &subscribers {
+        let target = ChatId(subscriber.chat_id);
+        match ctx.sender.check_chat(target).await {
+            Ok(()) => {}
+            Err(err) if is_gone(&err) => {
+                ctx.subscribers.remove_subscriber(target)?;
+                info!("Pruned chat {}: {}", target, err);
+                pruned += 1;
+            }
+            Err(err) => warn!("Failed to check chat {}: {}", target, err),
+        }
+    }
+    let reply = format!(
+        "Removed {} of {} subscribers that can't be reached\\.",
+        pruned,
+        subscribers.len()
+    );
+    send_reply(ctx, chat_id, &reply).await
+}
+
+/// Handles the admin `/rebroadcast` command.
+///
+/// Sends the latest update to every subscriber again, as a new poll would, even to chats that
+/// already got it, e.g. after a formatting fix is deployed. Without `confirm` it only replies with
+/// how many subscribers would get it, so a stray command doesn't spam every chat.
+async fn handle_rebroadcast(
+    ctx: &CommandContext,
+    chat_id: ChatId,
+    args: &str,
+) -> Result<(), AppError> {
+    let reply = if args.trim() == "confirm" {
+        let events = ctx.source.fetch_events().await?;
+        let subscribers = broadcast_updates(ctx, Broadcast::Latest, events).await;
+        info!(
+            "Rebroadcast the latest update to {} subscribers.",
+            subscribers
+        );
+        format!(
+            "Sent the latest update to {} subscribers again\\.",
+            subscribers
+        )
+    } else {
+        format!(
+            "This sends the latest update to all {} subscribers again\\. Send /rebroadcast confirm to go ahead\\.",
+            ctx.subscribers.all_subscribers().len()
+        )
+    };
+    send_reply(ctx, chat_id, &reply).await
+}
+
+/// Handles the `/subscribe` command.
+///
+/// This asynchronous function parses the optional filter and language arguments, adds the chat to
+/// the subscriber store and, depending on the configured `WelcomeMode`, replies with nothing, a
+/// confirmation, or a confirmation followed by the latest matching update. Invalid arguments are
+/// answered with an error reply and nothing is stored.
+///
+/// Subscribing again is answered with `ALREADY_SUBSCRIBED_TEXT` instead of a welcome. Without
+/// arguments the record is left alone, with them its filter and language are replaced.
+async fn handle_subscribe(
+    ctx: &CommandContext,
+    chat_id: ChatId,
+    args: &str,
+) -> Result<(), AppError> {
+    let sender = ctx.sender.as_ref();
+    if args.trim().is_empty() && ctx.subscribers.subscriber(chat_id).is_some() {
+        return send_reply(ctx, chat_id, ALREADY_SUBSCRIBED_TEXT).await;
+    }
+    let subscriber = match parse_subscribe_args(chat_id, args) {
+        Ok(subscriber) => subscriber,
+        Err(reply) => {
+            return send_reply(ctx, chat_id, &reply).await;
+        }
+    };
+    let filter = subscriber.filter;
+    let added = ctx.subscribers.add_subscriber(subscriber)?;
+    if !added {
+        let reply = format!(
+            "{} Its preferences were updated\\.",
+            ALREADY_SUBSCRIBED_TEXT
+        );
+        return send_reply(ctx, chat_id, &reply).await;
+    }
+
+    if ctx.startup.welcome == WelcomeMode::None {
+        return Ok(());
+    }
+    send_chunks(sender, chat_id, &subscribed_text(&ctx.app_name())).await?;
+
+    if ctx.startup.welcome == WelcomeMode::Latest {
+        let events = ctx.source.fetch_events().await?;
+        let template = ctx.template();
+        let link = ctx.news_link();
+        let formatted = events
+            .iter()
+            .filter(|event| filter.is_none_or(|filter| filter.matches(event)))
+            .find_map(|event| {
+                format_event(event, &template, &link, ctx.fields(), ctx.startup.markup)
+            });
+        if let Some(formatted) = formatted {
+            send_chunks_as(sender, chat_id, &formatted, ctx.startup.markup).await?;
+        }
+    }
+    info!("Welcome sent to chat {}", chat_id);
+    Ok(())
+}
+
+/// Formats an event as a message in `markup` using `template`, with `link` as the news page.
+///
+/// `fields` picks the optional parts. With `date` the message starts with `published_line()`.
+/// Without `footer` the template's `{link}` lines are left out. A "— posted by" byline, the
+/// upvote and comment counts and a "View on Steam" link to the post are added on their own lines
+/// at the end, in that order, when their flags are on and the feed has them. If the body isn't a
+/// string or renders to invalid Markdown V2, the update is sent as `headline_only()` instead of
+/// being dropped. Returns `None` if the event has no headline, so such events are never sent.
+fn format_event(
+    event: &Event,
+    template: &MessageTemplate,
+    link: &str,
+    fields: Fields,
+    markup: Markup,
+) -> Option<String> {
+    let raw_headline = event.headline()?;
+    let headline = escape_headline(raw_headline, markup);
+    let Some(body_str) = event.announcement_body.body.as_str() else {
+        warn!(
+            "The body of {} isn't text, sending the headline only.",
+            raw_headline
+        );
+        return Some(headline_only(event, raw_headline, link, fields, markup));
+    };
+    let processed_body = process_body(body_str, markup);
+    if markup == Markup::MarkdownV2 {
+        if let Err(err) = validate_markdown_v2(&processed_body) {
+            warn!(
+                "The body of {} renders to invalid Markdown V2 ({}), sending the headline only.",
+                raw_headline, err
+            );
+            return Some(headline_only(event, raw_headline, link, fields, markup));
+        }
+    }
+    let rendered = if fields.footer {
+        template.render(&headline, &processed_body, link)
+    } else {
+        template
+            .without_footer()
+            .render(&headline, &processed_body, link)
+    };
+    let mut formatted = match published_line(event, fields, markup) {
+        Some(published) => format!("{}\n{}", published, rendered),
+        None => rendered,
+    };
+    let mut push_line = |line: String| {
+        if !formatted.ends_with('\n') {
+            formatted.push('\n');
+        }
+        formatted += &line;
+    };
+    let body = &event.announcement_body;
+    let author = body.author.trim();
+    if fields.author && !author.is_empty() {
+        push_line(markup.escape(&format!("— posted by {}", author)));
+    }
+    if fields.engagement && (body.voteupcount > 0 || body.commentcount > 0) {
+        push_line(format!(
+            "👍 {} · 💬 {}",
+            body.voteupcount, body.commentcount
+        ));
+    }
+    if fields.permalink && !event.gid.is_empty() {
+        push_line(markup.link("View on Steam", &event.post_link()));
+    }
+    Some(formatted)
+}
+
+/// Formats an event as just its escaped headline and a link to its post, or to the news page
+/// `link` if it has no gid, after its publish time if `fields` has `date` on.
+fn headline_only(
+    event: &Event,
+    headline: &str,
+    link: &str,
+    fields: Fields,
+    markup: Markup,
+) -> String {
+    let link = if event.gid.is_empty() {
+        markup.escape(link)
+    } else {
+        markup.link("View on Steam", &event.post_link())
+    };
+    let message = format!("{}\n{}", markup.escape(headline), link);
+    match published_line(event, fields, markup) {
+        Some(published) => format!("{}\n{}", published, message),
+        None => message,
+    }
+}
+
+/// Returns the line showing when `event` was published, like "🕒 28 Mar 2024, 00:00 UTC", or
+/// `None` if `fields` has `date` off.
+///
+/// The time is the event's `rtime32_start_time`, or the announcement's `posttime` if the feed
+/// left that out. If it has neither, there is no line rather than a bogus date.
+fn published_line(event: &Event, fields: Fields, markup: Markup) -> Option<String> {
+    if !fields.date {
+        return None;
+    }
+    let published = [event.rtime32_start_time, event.announcement_body.posttime]
+        .into_iter()
+        .find(|&secs| secs > 0)
+        .and_then(|secs| i64::try_from(secs).ok())
+        .and_then(|secs| DateTime::from_timestamp(secs, 0))?;
+    Some(markup.escape(&format!("🕒 {}", published.format("%-d %b %Y, %H:%M UTC"))))
+}
+
+/// Processes the body of an event announcement.
+///
+/// The BBCode body is parsed with `bbcode::parse()` and rendered in `markup`; see
+/// `bbcode::render_markdown_v2()` for how each tag is shown.
+fn process_body(body_str: &str, markup: Markup) -> String {
+    markup.render(&bbcode::parse(body_str))
+}
+
+/// Escapes a headline for `markup`.
+///
+/// Headlines are plain text, so unlike bodies they aren't read as BBCode: only the characters
+/// `markup` reserves are escaped, and everything else, like a `[` in "[Hotfix]", is kept as
+/// written.
+fn escape_headline(headline: &str, markup: Markup) -> String {
+    markup.escape(headline)
+}
+
+/// Escapes every character that is reserved in Telegram's Markdown V2.
+pub fn escape_markdown_v2(text: &str) -> String {
+    let special_chars = "\\_*[]()~`>#+-=|{}.!";
+    text.chars()
+        .fold(String::with_capacity(text.len()), |mut escaped, c| {
+            if special_chars.contains(c) {
+                escaped.push('\\');
+            }
+            escaped.push(c);
+            escaped
+        })
+}
+
+/// Checks that `text` would be accepted by Telegram's Markdown V2 parser.
+///
+/// Reserved characters must be escaped unless they delimit an entity: `*`, `_`, `~`, `||` and
+/// `` ` `` must come in pairs, `[text](url)` links must be complete, and `>` may only start a
+/// quote at the beginning of a line. Inside a link URL, and inside `` `code` `` or a ```` ``` ````
+/// block, only the delimiter and `\` need escaping. Returns a description of the first problem
+/// found.
+pub fn validate_markdown_v2(text: &str) -> Result<(), String> {
+    let mut open = Vec::new();
+    let mut in_link_text = false;
+    let mut chars = text.char_indices().peekable();
+    let mut line_start = true;
+    while let Some((i, c)) = chars.next() {
+        let at_line_start = line_start;
+        line_start = c == '\n';
+        match c {
+            '\\' => {
+                chars
+                    .next()
+                    .ok_or_else(|| format!("Dangling '\\' at position {}", i))?;
+            }
+            '`' => {
+                let fence = code_fence(&text[i..]);
+                for _ in 1..fence.len() {
+                    chars.next();
+                }
+                loop {
+                    match chars.next() {
+                        Some((_, '\\')) => {
+                            chars.next();
+                        }
+                        Some((end, '`')) if text[end..].starts_with(fence) => {
+                            for _ in 1..fence.len() {
+                                chars.next();
+                            }
+                            break;
+                        }
+                        Some(_) => {}
+                        None => return Err("Unclosed '`' entity".to_string()),
+                    }
+                }
+            }
+            '*' | '_' | '~' => toggle(&mut open, c),
+            '|' if chars.next_if(|&(_, next)| next == '|').is_some() => toggle(&mut open, c),
+            '[' if !in_link_text => in_link_text = true,
+            ']' if in_link_text => {
+                in_link_text = false;
+                if chars.next_if(|&(_, next)| next == '(').is_none() {
+                    return Err(format!("Link without a URL at position {}", i));
+                }
+                loop {
+                    match chars.next() {
+                        Some((_, ')')) => break,
+                        Some((_, '\\')) => {
+                            chars.next();
+                        }
+                        Some(_) => {}
+                        None => return Err(format!("Unterminated link URL at position {}", i)),
+                    }
+                }
+            }
+            '>' if at_line_start => {}
+            '[' | ']' | '(' | ')' | '>' | '#' | '+' | '-' | '=' | '|' | '{' | '}' | '.' | '!' => {
+                return Err(format!("Unescaped '{}' at position {}", c, i));
+            }
+            _ => {}
+        }
+    }
+    if in_link_text {
+        return Err("Unterminated link text".to_string());
+    }
+    if let Some(c) = open.first() {
+        return Err(format!("Unclosed '{}' entity", c));
+    }
+    Ok(())
+}
+
+/// Returns the delimiter of the code entity `text` starts with: ```` ``` ```` for a block, or
+/// `` ` `` for inline code.
+fn code_fence(text: &str) -> &'static str {
+    if text.starts_with("```") {
+        "```"
+    } else {
+        "`"
+    }
+}
+
+/// Opens the entity delimited by `c`, or closes it if it is open.
+fn toggle(open: &mut Vec<char>, c: char) {
+    match open.iter().position(|&o| o == c) {
+        Some(index) => {
+            open.remove(index);
+        }
+        None => open.push(c),
+    }
+}
+
+/// Returns for each position in `chars`, and the end, whether a chunk may end there.
+///
+/// In Markdown V2 a chunk may not end inside an entity or a `[text](url)` link, or between a `\`
+/// and the character it escapes, since each chunk is parsed as a message of its own. In HTML it
+/// may not end inside an element, a tag or a character reference like `&amp;`.
+fn split_points(chars: &[char], markup: Markup) -> Vec<bool> {
+    let mut safe = vec![true; chars.len() + 1];
+    if markup == Markup::Html {
+        let mut depth = 0usize;
+        let mut i = 0;
+        while i < chars.len() {
+            safe[i] = depth == 0;
+            let end = match chars[i] {
+                '<' => chars[i..].iter().position(|&c| c == '>'),
+                '&' => chars[i..].iter().take(10).position(|&c| c == ';'),
+                _ => None,
+            };
+            let Some(end) = end.map(|len| i + len) else {
+                i += 1;
+                continue;
+            };
+            if chars[i] == '<' {
+                if chars.get(i + 1) == Some(&'/') {
+                    depth = depth.saturating_sub(1);
+                } else if chars[end - 1] != '/' {
+                    depth += 1;
+                }
+            }
+            for point in &mut safe[i + 1..=end] {
+                *point = false;
+            }
+            i = end + 1;
+        }
+        safe[chars.len()] = true;
+        return safe;
+    }
+    if markup != Markup::MarkdownV2 {
+        return safe;
+    }
+    let mut open = Vec::new();
+    let mut in_link = false;
+    let mut in_url = false;
+    let mut i = 0;
+    while i < chars.len() {
+        safe[i] = open.is_empty() && !in_link;
+        match chars[i] {
+            '\\' => {
+                if let Some(escaped) = safe.get_mut(i + 1) {
+                    *escaped = false;
+                }
+                i += 1;
+            }
+            ')' if in_url => {
+                in_link = false;
+                in_url = false;
+            }
+            _ if in_url => {}
+            '`' => {
+                let rest: String = chars[i..chars.len().min(i + 3)].iter().collect();
+                let fence: Vec<char> = code_fence(&rest).chars().collect();
+                let mut end = i + fence.len();
+                while end < chars.len() && !chars[end..].starts_with(&fence) {
+                    end += if chars[end] == '\\' { 2 } else { 1 };
+                }
+                let end = (end + fence.len()).min(chars.len());
+                for point in &mut safe[i + 1..end] {
+                    *point = false;
+                }
+                i = end;
+                continue;
+            }
+            '*' | '_' | '~' => toggle(&mut open, chars[i]),
+            '|' if chars.get(i + 1) == Some(&'|') => {
+                toggle(&mut open, '|');
+                i += 1;
+                safe[i] = false;
+            }
+            '[' if !in_link => in_link = true,
+            ']' if in_link && chars.get(i + 1) == Some(&'(') => {
+                in_url = true;
+                i += 1;
+                safe[i] = false;
+            }
+            _ => {}
+        }
+        i += 1;
+    }
+    safe
+}
+
+/// Splits the message into chunks of at most `max_len` characters each.
+///
+/// A chunk ends after the last paragraph break that fits, or failing that the last line break or
+/// space, as long as that keeps it at least half full. Otherwise it is cut as late as possible.
+/// Chunks never end where `split_points()` says the rest wouldn't parse on its own, unless a
+/// single entity is longer than `max_len`.
+fn split_into_chunks(msg: &str, max_len: usize, markup: Markup) -> Vec<String> {
+    let chars: Vec<char> = msg.chars().collect();
+    let safe = split_points(&chars, markup);
+    let max_len = max_len.max(1);
+    let mut chunks = Vec::new();
+    let mut start = 0;
+    while chars.len() - start > max_len {
+        let end = start + max_len;
+        let last_after = |min: usize, ends: &dyn Fn(&[char]) -> bool| {
+            (min.max(start + 1)..=end)
+                .rev()
+                .find(|&i| safe[i] && ends(&chars[start..i]))
+        };
+        let half = start + max_len / 2;
+        let split = last_after(half, &|chunk| chunk.ends_with(&['\n', '\n']))
+            .or_else(|| last_after(half, &|chunk| chunk.ends_with(&['\n'])))
+            .or_else(|| last_after(half, &|chunk| chunk.ends_with(&[' '])))
+            .or_else(|| last_after(start, &|_| true))
+            .unwrap_or(end);
+        chunks.push(chars[start..split].iter().collect());
+        start = split;
+    }
+    if start < chars.len() {
+        chunks.push(chars[start..].iter().collect());
+    }
+    chunks
+}
+
+/// Sends the Markdown V2 message in chunks to avoid message size limitations.
+///
+/// This asynchronous function splits the message into chunks no longer than the sender's
+/// `max_message_len()` and sends them individually using `send_chunk()`. If there's an error
+/// during message sending, it returns an error.
+pub async fn send_chunks(
+    sender: &dyn MessageSender,
+    chat_id: ChatId,
+    msg: &str,
+) -> Result<(), RequestError> {
+    send_chunks_as(sender, chat_id, msg, Markup::MarkdownV2).await
+}
+
+/// Sends a message written in `markup` in chunks, like `send_chunks()`, in a `send` span with
+/// the chat id.
+pub async fn send_chunks_as(
+    sender: &dyn MessageSender,
+    chat_id: ChatId,
+    msg: &str,
+    markup: Markup,
+) -> Result<(), RequestError> {
+    async {
+        for chunk in split_into_chunks(msg, sender.max_message_len(), markup) {
+            send_chunk(sender, chat_id, &chunk, markup).await?;
+            info!("Chunk sent successfully.");
+        }
+        Ok(())
+    }
+    .instrument(info_span!("send", chat_id = %chat_id))
+    .await
+}
+
+/// Sends photos in media groups of at most `group_size`, which is capped at Telegram's
+/// `MAX_MEDIA_GROUP_LEN`, with `caption` under the first photo of the first group. A paced sender
+/// spaces out the groups like any other message.
+pub async fn send_photo_groups(
+    sender: &dyn MessageSender,
+    chat_id: ChatId,
+    urls: &[String],
+    caption: &str,
+    markup: Markup,
+    group_size: usize,
+) -> Result<(), RequestError> {
+    for (i, group) in urls
+        .chunks(group_size.clamp(1, MAX_MEDIA_GROUP_LEN))
+        .enumerate()
+    {
+        let caption = if i == 0 { caption } else { "" };
+        sender.send_photos(chat_id, group, caption, markup).await?;
+        info!("Sent a group of {} photos.", group.len());
+    }
+    Ok(())
+}
+
+/// Sends a single chunk, hard-splitting it if Telegram still says it's too long.
+///
+/// Telegram measures length after escaping and in UTF-16 code units, so a chunk that fits the
+/// character limit can still be rejected with "message is too long". As a last resort the chunk
+/// is split at `hard_split_point()` and each half is sent the same way. When Telegram's
+/// flood limit is hit, the piece is sent again after the wait it asks for, at most
+/// `MAX_RETRY_AFTER_WAITS` times per chunk.
+async fn send_chunk(
+    sender: &dyn MessageSender,
+    chat_id: ChatId,
+    chunk: &str,
+    markup: Markup,
+) -> Result<(), RequestError> {
+    let mut pending = vec![chunk.to_string()];
+    let mut waits = 0;
+    while let Some(piece) = pending.pop() {
+        match sender.send_text(chat_id, &piece, markup).await {
+            Err(RequestError::RetryAfter(delay)) if waits < MAX_RETRY_AFTER_WAITS => {
+                warn!(
+                    "Hit the flood limit sending to chat {}, retrying in {:?}.",
+                    chat_id, delay
+                );
+                waits += 1;
+                tokio::time::sleep(delay).await;
+                pending.push(piece);
+            }
+            Err(RequestError::Api(ApiError::MessageIsTooLong)) => {
+                let chars: Vec<char> = piece.chars().collect();
+                let Some(split) = hard_split_point(&chars, markup) else {
+                    return Err(RequestError::Api(ApiError::MessageIsTooLong));
+                };
+                let (head, tail) = chars.split_at(split);
+                warn!(
+                    "Message is too long, hard-splitting a {}-character chunk.",
+                    chars.len()
+                );
+                pending.push(tail.iter().collect());
+                pending.push(head.iter().collect());
+            }
+            result => result?,
+        }
+    }
+    Ok(())
+}
+
+/// Returns where to hard-split `chars`: the point closest to the middle that `split_points()`
+/// allows, so both halves still parse, or `None` if there is none, as for a single entity.
+fn hard_split_point(chars: &[char], markup: Markup) -> Option<usize> {
+    let safe = split_points(chars, markup);
+    let middle = chars.len() / 2;
+    (1..chars.len())
+        .filter(|&i| safe[i])
+        .min_by_key(|&i| i.abs_diff(middle))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::{FixedClock, SystemClock};
+    use crate::file_part::{CompareMode, FirstRun};
+    use crate::json_part::{test_event, MockEventSource, SteamEventSource};
+    use crate::sender::{ChunkSizeSender, RecordingSender};
+    use crate::subscribers::PrunePolicy;
+
+    /// A new patch and the one before it, as the poller sees them in `old.json`.
+    fn patch_events() -> Vec<Event> {
+        vec![
+            Event {
+                gid: "736".to_string(),
+                ..test_event("Patch 7.36", "New")
+            },
+            Event {
+                gid: "735".to_string(),
+                ..test_event("Patch 7.35", "Old")
+            },
+        ]
+    }
+
+    #[test]
+    fn test_process_body() {
+        let input = "[url=https://www.dota2.com]Dota 2[/url]";
+        let processed_body = process_body(input, Markup::MarkdownV2);
+
+        assert_eq!(processed_body, "[Dota 2](https://www.dota2.com)");
+    }
+
+    #[tokio::test]
+    async fn test_send_chunks_respects_sender_limit() {
+        let body = "a".repeat(5000);
+        let discord_like = RecordingSender::new(2000);
+        let telegram_like = RecordingSender::new(4096);
+
+        send_chunks(&discord_like, ChatId(1), &body).await.unwrap();
+        send_chunks(&telegram_like, ChatId(1), &body).await.unwrap();
+
+        let short_lens: Vec<usize> = discord_like.sent().iter().map(|(_, c)| c.len()).collect();
+        let long_lens: Vec<usize> = telegram_like.sent().iter().map(|(_, c)| c.len()).collect();
+        assert_eq!(short_lens, vec![2000, 2000, 1000]);
+        assert_eq!(long_lens, vec![4096, 904]);
+    }
+
+    #[test]
+    fn test_chunks_split_at_paragraphs_outside_entities() {
+        let paragraph = format!("{}\n\n", "word ".repeat(10));
+        let msg = format!("{}{}", paragraph, paragraph);
+        let chunks = split_into_chunks(&msg, 80, Markup::MarkdownV2);
+        assert_eq!(chunks, vec![paragraph.clone(), paragraph]);
+
+        let msg = (0..40)
+            .map(|i| {
+                format!("See *bold {i}* and [the post {i}](https://example.com/{i}_a) now\\. ")
+            })
+            .collect::<String>();
+        for max_len in [50, 64, 100] {
+            let chunks = split_into_chunks(&msg, max_len, Markup::MarkdownV2);
+            assert_eq!(chunks.concat(), msg);
+            for chunk in &chunks {
+                assert!(chunk.chars().count() <= max_len);
+                assert_eq!(validate_markdown_v2(chunk), Ok(()), "{chunk:?}");
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_send_chunks_hard_splits_too_long_chunks() {
+        let body = "x".repeat(100);
+        let sender = RecordingSender::rejecting(100, |_, text| {
+            (text.chars().count() > 30).then(|| RequestError::Api(ApiError::MessageIsTooLong))
+        });
+
+        send_chunks(&sender, ChatId(1), &body).await.unwrap();
+
+        let sent: Vec<String> = sender.sent().into_iter().map(|(_, text)| text).collect();
+        assert!(sent.len() > 1);
+        assert!(sent.iter().all(|piece| piece.chars().count() <= 30));
+        assert_eq!(sent.concat(), body);
+    }
+
+    #[tokio::test]
+    async fn test_hard_splits_keep_entities_whole() {
+        let body = format!("{}*{}*\\.{}", "a".repeat(9), "b".repeat(15), "c".repeat(12));
+        let sender = RecordingSender::rejecting(100, |_, text| {
+            (text.chars().count() > 20).then(|| RequestError::Api(ApiError::MessageIsTooLong))
+        });
+
+        send_chunks(&sender, ChatId(1), &body).await.unwrap();
+
+        let sent: Vec<String> = sender.sent().into_iter().map(|(_, text)| text).collect();
+        assert!(sent.len() > 1);
+        for piece in &sent {
+            assert_eq!(validate_markdown_v2(piece), Ok(()), "{piece:?}");
+        }
+        assert_eq!(sent.concat(), body);
+
+        let html = "<b>bold &amp; long</b> tail";
+        let chars: Vec<char> = html.chars().collect();
+        assert_eq!(hard_split_point(&chars, Markup::Html), Some(22));
+        let entity: Vec<char> = "*one long entity*".chars().collect();
+        assert_eq!(hard_split_point(&entity, Markup::MarkdownV2), None);
+        let unsplittable = RecordingSender::rejecting(100, |_, _| {
+            Some(RequestError::Api(ApiError::MessageIsTooLong))
+        });
+        assert!(send_chunks(&unsplittable, ChatId(1), "*bold*")
+            .await
+            .is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_send_chunks_waits_out_flood_limits() {
+        let limited = std::sync::atomic::AtomicBool::new(true);
+        let sender = RecordingSender::rejecting(4096, move |_, _| {
+            limited
+                .swap(false, std::sync::atomic::Ordering::Relaxed)
+                .then_some(RequestError::RetryAfter(Duration::from_secs(5)))
+        });
+        let start = tokio::time::Instant::now();
+
+        send_chunks(&sender, ChatId(1), "Patch").await.unwrap();
+
+        assert_eq!(sender.sent(), vec![(ChatId(1), "Patch".to_string())]);
+        assert!(start.elapsed() >= Duration::from_secs(5));
+
+        let flooded = RecordingSender::rejecting(4096, |_, _| {
+            Some(RequestError::RetryAfter(Duration::from_secs(5)))
+        });
+        let start = tokio::time::Instant::now();
+        let err = send_chunks(&flooded, ChatId(1), "Patch").await.unwrap_err();
+        assert!(matches!(err, RequestError::RetryAfter(_)));
+        assert_eq!(start.elapsed(), Duration::from_secs(15));
+    }
+
+    #[tokio::test]
+    async fn test_send_photo_groups_batches_by_ten() {
+        let sender = RecordingSender::new(4096);
+        let body: String = (0..15)
+            .map(|i| format!("[img]{{STEAM_CLAN_IMAGE}}/{}.png[/img]", i))
+            .collect();
+        let urls = bbcode::image_urls(&bbcode::parse(&body));
+        assert_eq!(urls[0], "https://clan.akamai.steamstatic.com/images/0.png");
+
+        send_photo_groups(
+            &sender,
+            ChatId(1),
+            &urls,
+            "Patch",
+            Markup::MarkdownV2,
+            MAX_MEDIA_GROUP_LEN + 1,
+        )
+        .await
+        .unwrap();
+
+        let photos = sender.photos.lock().unwrap();
+        let groups: Vec<(usize, &str)> = photos
+            .iter()
+            .map(|(_, urls, caption)| (urls.len(), caption.as_str()))
+            .collect();
+        assert_eq!(groups, vec![(10, "Patch"), (5, "")]);
+        assert_eq!(photos[1].1[0], urls[10]);
+    }
+
+    #[tokio::test]
+    async fn test_media_group_size_is_configurable() {
+        let mut event = test_event(
+            "Patch",
+            &(0..12)
+                .map(|i| format!("[img]{{STEAM_CLAN_IMAGE}}/{}.png[/img]", i))
+                .collect::<String>(),
+        );
+        event.gid = "1".to_string();
+        let test = TestContext::with_events(Vec::new());
+        test.ctx.config.lock().unwrap().media_group_size = 4;
+
+        send_images_to_chats(&test.ctx, &[ChatId(1)], &event).await;
+
+        let sizes: Vec<usize> = test
+            .sender
+            .photos
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(_, urls, _)| urls.len())
+            .collect();
+        assert_eq!(sizes, vec![4, 4, 4]);
+    }
+
+    #[tokio::test]
+    async fn test_images_follow_the_update_they_belong_to() {
+        let mut event = test_event(
+            "Patch",
+            "Notes[img]{STEAM_CLAN_IMAGE}/1.png[/img][img]file:///etc/passwd[/img]",
+        );
+        event.gid = "1".to_string();
+        let test = TestContext::with_events(vec![event]);
+        test.ctx
+            .subscribers
+            .add_subscriber(Subscriber::new(ChatId(7)))
+            .unwrap();
+
+        broadcast_polled(&test, Broadcast::Latest).await;
+        assert!(test.sender.photos.lock().unwrap().is_empty());
+
+        test.ctx.config.lock().unwrap().send_images = true;
+        broadcast_polled(&test, Broadcast::Latest).await;
+        assert_eq!(
+            *test.sender.photos.lock().unwrap(),
+            vec![(
+                ChatId(7),
+                vec!["https://clan.akamai.steamstatic.com/images/1.png".to_string()],
+                "Patch".to_string()
+            )]
+        );
+    }
+
+    #[test]
+    fn test_headline_is_escaped_without_bbcode() {
+        assert_eq!(
+            escape_headline("Patch 7.36a (Hotfix)", Markup::MarkdownV2),
+            "Patch 7\\.36a \\(Hotfix\\)"
+        );
+        assert_eq!(
+            escape_headline("[b]Patch[/b] & 7.36", Markup::Html),
+            "[b]Patch[/b] &amp; 7.36"
+        );
+
+        let event = test_event("Patch 7.36a (Hotfix)", "Fixes");
+        let formatted = format_event(
+            &event,
+            &MessageTemplate::default(),
+            "https://www.dota2.com/news",
+            Fields::default(),
+            Markup::MarkdownV2,
+        )
+        .unwrap();
+        assert!(formatted.contains("*Patch 7\\.36a \\(Hotfix\\)*"));
+        assert_eq!(validate_markdown_v2(&formatted), Ok(()));
+    }
+
+    #[tokio::test]
+    async fn test_sent_headlines_are_valid_markdown_v2() {
+        let test = TestContext::with_events(vec![test_event("Patch 7.35d (Balance!)", "Notes")]);
+        test.ctx
+            .subscribers
+            .add_subscriber(Subscriber::new(ChatId(7)))
+            .unwrap();
+
+        broadcast_polled(&test, Broadcast::Latest).await;
+
+        let sent = test.sender.sent();
+        assert!(sent[0].1.contains("*Patch 7\\.35d \\(Balance\\!\\)*"));
+        assert_eq!(validate_markdown_v2(&sent[0].1), Ok(()));
+    }
+
+    #[test]
+    fn test_updates_are_formatted_after_selection() {
+        let test = TestContext::with_events(Vec::new());
+        let events = vec![
+            Event {
+                gid: "2".to_string(),
+                ..test_event("", "body")
+            },
+            Event {
+                gid: "1".to_string(),
+                ..test_event("Patch", "body")
+            },
+        ];
+        let gids = |broadcast: Broadcast| {
+            format_updates(&test.ctx, &events, &broadcast, "")
+                .iter()
+                .map(|(event, _)| event.gid.as_str())
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(gids(Broadcast::Latest), vec!["1"]);
+        assert_eq!(gids(Broadcast::All), vec!["1"]);
+        assert!(gids(Broadcast::New(vec!["2".to_string()])).is_empty());
+    }
+
+    #[test]
+    fn test_format_event_skips_empty_headline() {
+        let template = MessageTemplate::default();
+        assert_eq!(
+            format_event(
+                &test_event("", "body"),
+                &template,
+                "",
+                Fields::default(),
+                Markup::MarkdownV2
+            ),
+            None
+        );
+        assert!(format_event(
+            &test_event("Patch", "body"),
+            &template,
+            "",
+            Fields::default(),
+            Markup::MarkdownV2
+        )
+        .is_some());
+    }
+
+    #[test]
+    fn test_invalid_body_falls_back_to_headline() {
+        let mut event = test_event(
+            "Patch 7.36",
+            "[url=https://a.b][url=https://c.d]notes[/url][/url]",
+        );
+        event.gid = "42".to_string();
+
+        assert_eq!(
+            format_event(
+                &event,
+                &MessageTemplate::default(),
+                "",
+                Fields::default(),
+                Markup::MarkdownV2
+            )
+            .unwrap(),
+            "Patch 7\\.36\n[View on Steam](https://store.steampowered.com/news/app/570/view/42)"
+        );
+    }
+
+    /// A command context backed by a recording sender and a temporary subscriber store.
+    struct TestContext {
+        ctx: CommandContext,
+        sender: Arc<RecordingSender>,
+        _dir: tempfile::TempDir,
+    }
+
+    const ADMIN: UserId = UserId(1);
+
+    impl TestContext {
+        fn new(source: MockEventSource) -> Self {
+            let dir = tempfile::tempdir().unwrap();
+            let sender = Arc::new(RecordingSender::new(4096));
+            let ctx = CommandContext {
+                sender: sender.clone(),
+                subscribers: Arc::new(
+                    SubscriberStore::open(dir.path().join("subscribers.json")).unwrap(),
+                ),
+                source: Arc::new(source),
+                settings: Arc::new(SettingsStore::open(dir.path().join("settings.json")).unwrap()),
+                feeds: vec![Arc::new(Feed::new(DOTA_APPID, DEFAULT_LANGUAGE))],
+                poller: Arc::new(Poller::with_files(
+                    CompareMode::Headline,
+                    FirstRun::Baseline,
+                    dir.path().join("new.json").to_str().unwrap(),
+                    dir.path().join("old.json").to_str().unwrap(),
+                )),
+                config: Mutex::new(ReloadableConfig::default()),
+                env_file: dir.path().join(config::ENV_FILE),
+                latest_sent: Mutex::new(HashMap::new()),
+                parse_failures: Arc::new(ParseFailures::default()),
+                clock: Arc::new(SystemClock),
+                webhook: Arc::new(UpdateWebhook::new(None)),
+                failure_streaks: FailureStreaks::default(),
+                startup: Config {
+                    welcome: WelcomeMode::None,
+                    super_admins: vec![ADMIN],
+                    ..config::test_config()
+                },
+            };
+            Self {
+                ctx,
+                sender,
+                _dir: dir,
+            }
+        }
+
+        fn with_events(events: Vec<Event>) -> Self {
+            Self::new(MockEventSource::new(events))
+        }
+
+        /// Runs a command as `user` from chat 7 and returns the replies.
+        async fn run(&self, text: &str, user: UserId) -> Vec<String> {
+            let command = Command::parse(text, "bot").unwrap();
+            handle_command(&self.ctx, ChatId(7), Some(user), command)
+                .await
+                .unwrap();
+            self.sender
+                .sent()
+                .into_iter()
+                .map(|(_, text)| text)
+                .collect()
+        }
+    }
+
+    /// Broadcasts the events `test`'s source serves, as a poll that found `broadcast` would.
+    async fn broadcast_polled(test: &TestContext, broadcast: Broadcast) -> usize {
+        let events = test.ctx.source.fetch_events().await.unwrap();
+        broadcast_updates(&test.ctx, broadcast, events).await
+    }
+
+    /// Publishes the events `test`'s source serves, as a poll that found `broadcast` would.
+    async fn publish_polled(test: &TestContext, broadcast: &Broadcast) {
+        let events = test.ctx.source.fetch_events().await.unwrap();
+        publish_updates(&test.ctx, broadcast, &events).await
+    }
+
+    async fn subscribe_with(welcome: WelcomeMode) -> Vec<String> {
+        let mut test = TestContext::with_events(vec![test_event("Gameplay Patch", "New heroes")]);
+        test.ctx.startup.welcome = welcome;
+
+        let sent = test.run("/subscribe", UserId(7)).await;
+
+        assert_eq!(
+            test.ctx.subscribers.all_subscribers(),
+            vec![Subscriber::new(ChatId(7))]
+        );
+        sent
+    }
+
+    #[tokio::test]
+    async fn test_start_subscribes_and_welcomes() {
+        let mut test = TestContext::with_events(vec![test_event("Gameplay Patch", "New heroes")]);
+        test.ctx.startup.welcome = WelcomeMode::Latest;
+
+        let sent = test.run("/start", UserId(7)).await;
+
+        assert_eq!(sent[0], subscribed_text("Dota 2"));
+        assert!(sent[1].contains("Gameplay Patch"));
+        assert_eq!(
+            test.ctx.subscribers.all_subscribers(),
+            vec![Subscriber::new(ChatId(7))]
+        );
+        let help = test.run("/help", UserId(7)).await;
+        assert!(validate_markdown_v2(&help[2]).is_ok());
+        assert!(help[2].contains("/latest"));
+    }
+
+    #[tokio::test]
+    async fn test_subscribe_welcome_none() {
+        assert!(subscribe_with(WelcomeMode::None).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_subscribe_welcome_confirmation() {
+        assert_eq!(
+            subscribe_with(WelcomeMode::Confirmation).await,
+            vec![subscribed_text("Dota 2")]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_subscribe_welcome_latest() {
+        let sent = subscribe_with(WelcomeMode::Latest).await;
+        assert_eq!(sent.len(), 2);
+        assert_eq!(sent[0], subscribed_text("Dota 2"));
+        assert!(sent[1].contains("*Gameplay Patch*"));
+    }
+
+    #[test]
+    fn test_command_menu_matches_help() {
+        let help = Command::descriptions().to_string();
+        for command in Command::bot_commands() {
+            let name = command.command.trim_start_matches('/');
+            assert!(help.contains(&format!("/{} ", name)), "{}", name);
+            assert!(name
+                .chars()
+                .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_'));
+            assert!((1..=256).contains(&command.description.chars().count()));
+        }
+    }
+
+    #[test]
+    fn test_parse_subscribe_args() {
+        assert_eq!(
+            Command::parse("/subscribe", "bot").unwrap(),
+            Command::Subscribe(String::new())
+        );
+        assert_eq!(
+            parse_subscribe_args(ChatId(7), ""),
+            Ok(Subscriber::new(ChatId(7)))
+        );
+        assert!(parse_subscribe_args(ChatId(7), "bogus").is_err());
+        assert!(parse_subscribe_args(ChatId(7), "patches xx").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_subscribe_with_filter_and_language() {
+        let test = TestContext::with_events(Vec::new());
+
+        test.run("/subscribe patches ru", UserId(7)).await;
+
+        let subscriber = &test.ctx.subscribers.all_subscribers()[0];
+        assert_eq!(subscriber.filter, Some(EventFilter::Patches));
+        assert_eq!(subscriber.lang.as_deref(), Some("russian"));
+    }
+
+    #[tokio::test]
+    async fn test_subscribing_twice_is_idempotent() {
+        let test = TestContext::with_events(Vec::new());
+
+        test.run("/subscribe", UserId(7)).await;
+        let again = test.run("/subscribe", UserId(7)).await;
+        let updated = test.run("/subscribe patches", UserId(7)).await;
+
+        assert_eq!(again, vec![ALREADY_SUBSCRIBED_TEXT.to_string()]);
+        assert!(updated[0].starts_with(ALREADY_SUBSCRIBED_TEXT));
+        let subscribers = test.ctx.subscribers.all_subscribers();
+        assert_eq!(subscribers.len(), 1);
+        assert_eq!(subscribers[0].filter, Some(EventFilter::Patches));
+
+        test.run("/unsubscribe", UserId(7)).await;
+        assert!(test.ctx.subscribers.all_subscribers().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_subscribe_rejects_invalid_language() {
+        let test = TestContext::with_events(Vec::new());
+
+        let sent = test.run("/subscribe patches xx", UserId(7)).await;
+
+        assert!(test.ctx.subscribers.all_subscribers().is_empty());
+        assert!(sent[0].starts_with("Unknown language"));
+    }
+
+    async fn get_recent(args: &str) -> String {
+        let test = TestContext::with_events(vec![
+            test_event("Newest", "First body"),
+            test_event("Older", "Second body"),
+        ]);
+        test.run(&format!("/get_recent {}", args), UserId(7))
+            .await
+            .remove(0)
+    }
+
+    #[tokio::test]
+    async fn test_get_recent_sends_nth_update() {
+        assert_eq!(
+            Command::parse("/get_recent 2", "bot").unwrap(),
+            Command::GetRecent("2".to_string())
+        );
+        assert!(get_recent("2").await.contains("*Older*"));
+        assert!(get_recent("1").await.contains("*Newest*"));
+    }
+
+    #[tokio::test]
+    async fn test_get_recent_rejects_out_of_range() {
+        assert!(get_recent("3").await.starts_with("Only 2 updates"));
+        assert!(get_recent("0").await.starts_with("Usage"));
+        assert!(get_recent("two").await.starts_with("Usage"));
+    }
+
+    #[test]
+    fn test_escape_markdown_v2() {
+        assert_eq!(
+            escape_markdown_v2("Patch 7.35d (Balance!)"),
+            "Patch 7\\.35d \\(Balance\\!\\)"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_diagnose_reports_partially_malformed_feed() {
+        let test = TestContext::new(MockEventSource {
+            feed: serde_json::json!({"success": 1, "events": [
+                {"announcement_body": {"headline": "Patch 7.35d", "body": "text"}},
+                {"announcement_body": {"headline": 42}},
+            ]}),
+        });
+
+        let sent = test.run("/diagnose", ADMIN).await;
+
+        assert_eq!(
+            sent,
+            vec![
+                "*Feed diagnostics*\nEvents parsed: 1\nEvents failed to parse: 1\nSuccess flag: yes\nNewest headline: Patch 7\\.35d"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_headline_diff_reports_added_and_removed() {
+        let test = TestContext::with_events(vec![
+            test_event("Patch 7.36", "Notes"),
+            test_event("Patch 7.35d", "Notes"),
+        ]);
+        assert!(test.run("/headline_diff", ADMIN).await[0].starts_with("No stored headlines"));
+
+        std::fs::write(
+            test._dir.path().join("old.json"),
+            r#"["Patch 7.35d","Battle Pass"]"#,
+        )
+        .unwrap();
+        let sent = test.run("/headline_diff", ADMIN).await;
+
+        assert_eq!(
+            sent[1],
+            "*Headline diff*\nNew in the feed:\n• Patch 7\\.36\nNo longer in the feed:\n• Battle Pass"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_diff_lists_only_unseen_updates() {
+        let test = TestContext::with_events(
+            (1..=12)
+                .rev()
+                .map(|n| test_event(&format!("Patch 7.{}", n), "Notes"))
+                .collect(),
+        );
+
+        let sent = test.run("/diff", UserId(7)).await;
+        let fresh = sent[0].lines().collect::<Vec<_>>();
+        assert_eq!(
+            fresh[1],
+            "Nothing was stored yet, so all 12 fetched updates are new\\. The newest:"
+        );
+        assert_eq!(fresh[2], "• Patch 7\\.12");
+        assert_eq!(fresh.len(), 2 + MAX_DIFF_HEADLINES);
+
+        let stored: Vec<String> = (1..=10).map(|n| format!("Patch 7.{}", n)).collect();
+        std::fs::write(
+            test._dir.path().join("old.json"),
+            serde_json::to_string(&stored).unwrap(),
+        )
+        .unwrap();
+        let sent = test.run("/diff", UserId(7)).await;
+        assert_eq!(sent[1], "*New updates*\n• Patch 7\\.12\n• Patch 7\\.11");
+
+        let stored: Vec<String> = (1..=12).map(|n| format!("Patch 7.{}", n)).collect();
+        std::fs::write(
+            test._dir.path().join("old.json"),
+            serde_json::to_string(&stored).unwrap(),
+        )
+        .unwrap();
+        let sent = test.run("/diff", UserId(7)).await;
+        assert_eq!(sent[2], NO_NEW_UPDATES_TEXT);
+    }
+
+    #[tokio::test]
+    async fn test_parse_failures_reports_and_attaches_latest_payload() {
+        let test = TestContext::with_events(Vec::new());
+        assert_eq!(
+            test.run("/parse_failures", ADMIN).await,
+            vec!["No parse failures captured\\."]
+        );
+
+        test.ctx
+            .parse_failures
+            .record("missing field `gid`", b"{\"events\":[{}]}");
+        let sent = test.run("/parse_failures", ADMIN).await;
+
+        assert_eq!(
+            sent[1],
+            "Parse failures in the last 24 hours: 1\nLatest, 0s ago: missing field \\`gid\\`"
+        );
+        assert_eq!(
+            *test.sender.documents.lock().unwrap(),
+            vec![(
+                ChatId(7),
+                PARSE_FAILURE_FILE_NAME.to_string(),
+                b"{\"events\":[{}]}".to_vec()
+            )]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_rss_lists_recent_updates() {
+        let mut event = test_event("Patch 7.36", "[b]New[/b] heroes");
+        event.gid = "736".to_string();
+        let mut test = TestContext::with_events(vec![event, test_event("", "No headline")]);
+        let path = test._dir.path().join(RSS_FILE_NAME);
+        test.ctx.startup.rss_file = Some(path.clone());
+
+        test.run("/rss", UserId(7)).await;
+        publish_polled(&test, &Broadcast::Latest).await;
+
+        let documents = test.sender.documents.lock().unwrap().clone();
+        assert_eq!(documents.len(), 1);
+        assert_eq!(documents[0].1, RSS_FILE_NAME);
+        let xml = String::from_utf8(documents[0].2.clone()).unwrap();
+        assert_eq!(xml.matches("<item>").count(), 1);
+        assert!(xml.contains("<title>Patch 7.36</title>"));
+        assert!(xml.contains("<link>https://store.steampowered.com/news/app/570/view/736</link>"));
+        assert!(xml.contains("<description>New heroes</description>"));
+        assert_eq!(std::fs::read_to_string(path).unwrap(), xml);
+    }
+
+    #[tokio::test]
+    async fn test_diagnose_requires_admin() {
+        let test = TestContext::with_events(Vec::new());
+        assert_eq!(
+            test.run("/diagnose", UserId(7)).await,
+            vec![ADMIN_ONLY_TEXT.to_string()]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_template_set_and_show() {
+        let test = TestContext::with_events(vec![test_event("Patch 7.36", "Notes")]);
+
+        test.run("/template 🆕 *{headline}*\n{body}", ADMIN).await;
+        let sent = test.run("/get_recent 1", UserId(7)).await;
+        assert_eq!(sent[0], "Template updated\\.");
+        assert_eq!(sent[1], "🆕 *Patch 7\\.36*\nNotes");
+
+        let sent = test.run("/template", ADMIN).await;
+        assert_eq!(
+            sent[2],
+            "Current template:\n🆕 \\*\\{headline\\}\\*\n\\{body\\}"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_template_rejects_unknown_placeholder() {
+        let test = TestContext::with_events(Vec::new());
+
+        let sent = test.run("/template {headline} {author}", ADMIN).await;
+
+        assert!(sent[0].starts_with("Unknown placeholder: \\{author\\}"));
+        assert_eq!(test.ctx.settings.get().template, None);
+    }
+
+    #[tokio::test]
+    async fn test_template_rejects_invalid_markdown() {
+        let test = TestContext::with_events(Vec::new());
+
+        let sent = test.run("/template New update! *{headline}*", ADMIN).await;
+        assert_eq!(
+            sent[0],
+            "The template renders to invalid Markdown V2: Unescaped '\\!' at position 10\\."
+        );
+        let sent = test.run("/template *{headline}\n{body}", ADMIN).await;
+        assert!(sent[1].starts_with("The template renders to invalid Markdown V2"));
+        assert_eq!(test.ctx.settings.get().template, None);
+
+        test.run("/template New update\\! *{headline}*", ADMIN)
+            .await;
+        assert_eq!(
+            test.ctx.settings.get().template.as_deref(),
+            Some("New update\\! *{headline}*")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_broadcast_selects_updates() {
+        let mut old = test_event("Patch 7.35", "Old");
+        old.gid = "735".to_string();
+        let test = TestContext::with_events(vec![test_event("Patch 7.36", "New"), old]);
+        test.ctx
+            .subscribers
+            .add_subscriber(Subscriber::new(ChatId(7)))
+            .unwrap();
+
+        broadcast_polled(&test, Broadcast::Latest).await;
+        broadcast_polled(&test, Broadcast::All).await;
+        broadcast_polled(&test, Broadcast::New(vec!["735".to_string()])).await;
+
+        let sent: Vec<String> = test.sender.sent().into_iter().map(|(_, t)| t).collect();
+        assert_eq!(sent.len(), 4);
+        assert!(sent[0].contains("Patch 7\\.36"));
+        assert!(sent[1].contains("Patch 7\\.35"));
+        assert!(sent[2].contains("Patch 7\\.36"));
+        assert!(sent[3].contains("Patch 7\\.35"));
+    }
+
+    #[tokio::test]
+    async fn test_feeds_lists_active_feeds() {
+        let test = TestContext::with_events(Vec::new());
+
+        let sent = test.run("/feeds", ADMIN).await;
+
+        assert_eq!(
+            sent,
+            vec!["Active feeds:\n• appid 570, english: 0 subscribers, last success never"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_footer_link_follows_feed_language() {
+        let mut test = TestContext::with_events(vec![test_event("Patch 7.36", "Notes")]);
+        test.ctx.feeds = vec![Arc::new(Feed::new(DOTA_APPID, "russian"))];
+
+        let sent = test.run("/get_recent 1", UserId(7)).await;
+
+        assert!(sent[0].contains("[link](https://www.dota2.com/news?l=russian)"));
+    }
+
+    #[tokio::test]
+    async fn test_poll_now_broadcasts_new_updates() {
+        let test = TestContext::with_events(patch_events());
+        std::fs::write(test._dir.path().join("old.json"), r#"["Patch 7.35"]"#).unwrap();
+        test.ctx
+            .subscribers
+            .add_subscriber(Subscriber::new(ChatId(42)))
+            .unwrap();
+
+        let sent = test.run("/poll_now", ADMIN).await;
+
+        assert_eq!(sent.len(), 2);
+        assert!(sent[0].contains("Patch 7\\.36"));
+        assert_eq!(sent[1], "Sent new updates to 1 subscribers\\.");
+        assert_eq!(test.sender.sent()[0].0, ChatId(42));
+        let sent = test.run("/poll_now", ADMIN).await;
+        assert_eq!(sent[2], "Nothing new\\.");
+    }
+
+    #[tokio::test]
+    async fn test_poll_sends_the_events_it_compared() {
+        let server = wiremock::MockServer::start().await;
+        let mut new = test_event("Patch 7.36", "New");
+        new.gid = "736".to_string();
+        let feed = serde_json::json!({"events": [new, test_event("Patch 7.35", "Old")]});
+        wiremock::Mock::given(wiremock::matchers::method("GET"))
+            .respond_with(wiremock::ResponseTemplate::new(200).set_body_json(feed))
+            .expect(1)
+            .mount(&server)
+            .await;
+        let mut test = TestContext::with_events(Vec::new());
+        test.ctx.source = Arc::new(SteamEventSource::new(format!("{}/events", server.uri())));
+        std::fs::write(test._dir.path().join("old.json"), r#"["Patch 7.35"]"#).unwrap();
+        for chat in [ChatId(1), ChatId(2)] {
+            test.ctx
+                .subscribers
+                .add_subscriber(Subscriber::new(chat))
+                .unwrap();
+        }
+        test.ctx
+            .subscribers
+            .set_mode(ChatId(2), DeliveryMode::Ping)
+            .unwrap();
+
+        let sent = test.run("/poll_now", ADMIN).await;
+
+        assert_eq!(sent.len(), 3);
+        assert!(sent[0].starts_with("🆕 New Dota 2 post"));
+        assert!(sent[1].contains("Patch 7\\.36"));
+        assert_eq!(sent[2], "Sent new updates to 2 subscribers\\.");
+    }
+
+    #[tokio::test]
+    async fn test_add_chat_checks_the_bot_can_post() {
+        let mut test = TestContext::with_events(Vec::new());
+        let sender = Arc::new(RecordingSender::rejecting(4096, |chat, _| {
+            (chat == ChatId(-200)).then(|| RequestError::Api(ApiError::ChatNotFound))
+        }));
+        test.ctx.sender = sender.clone();
+
+        for text in ["/add_chat -100", "/add_chat -200", "/add_chat -100"] {
+            let command = Command::parse(text, "bot").unwrap();
+            handle_command(&test.ctx, ChatId(7), Some(ADMIN), command)
+                .await
+                .unwrap();
+        }
+
+        let sent = sender.sent();
+        assert_eq!(sent[0], (ChatId(-100), add_chat_check_text("Dota 2")));
+        assert_eq!(
+            sent[1],
+            (ChatId(7), "Chat \\-100 is subscribed\\.".to_string())
+        );
+        assert!(sent[2]
+            .1
+            .starts_with("Couldn't post to chat \\-200, so it wasn't added"));
+        assert_eq!(sent[3].1, "Chat \\-100 is already subscribed\\.");
+        let chats: Vec<i64> = test
+            .ctx
+            .subscribers
+            .all_subscribers()
+            .iter()
+            .map(|subscriber| subscriber.chat_id)
+            .collect();
+        assert_eq!(chats, vec![-100]);
+    }
+
+    #[tokio::test]
+    async fn test_prune_removes_unreachable_chats() {
+        let mut test = TestContext::with_events(Vec::new());
+        let sender = Arc::new(RecordingSender::rejecting(4096, |chat, _| match chat.0 {
+            2 => Some(RequestError::Api(ApiError::ChatNotFound)),
+            3 => Some(RequestError::Api(ApiError::BotBlocked)),
+            4 => Some(RequestError::Io(std::io::Error::other("timed out"))),
+            _ => None,
+        }));
+        test.ctx.sender = sender.clone();
+        for chat in 1..=4 {
+            test.ctx
+                .subscribers
+                .add_subscriber(Subscriber::new(ChatId(chat)))
+                .unwrap();
+        }
+
+        let command = Command::parse("/prune", "bot").unwrap();
+        handle_command(&test.ctx, ChatId(7), Some(ADMIN), command)
+            .await
+            .unwrap();
+
+        let remaining: Vec<i64> = test
+            .ctx
+            .subscribers
+            .all_subscribers()
+            .iter()
+            .map(|subscriber| subscriber.chat_id)
+            .collect();
+        assert_eq!(remaining, vec![1, 4]);
+        assert_eq!(
+            sender.sent(),
+            vec![(
+                ChatId(7),
+                "Removed 2 of 4 subscribers that can't be reached\\.".to_string()
+            )]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_failing_chat_is_pruned_after_the_grace_period() {
+        let mut event = test_event("Patch 7.36", "New");
+        event.gid = "736".to_string();
+        let mut test = TestContext::with_events(vec![event]);
+        test.ctx.sender = Arc::new(RecordingSender::rejecting(4096, |chat, _| {
+            (chat == ChatId(2)).then(|| RequestError::Api(ApiError::BotBlocked))
+        }));
+        test.ctx.startup.prune_policy = Some(PrunePolicy {
+            failures: 3,
+            window: Duration::from_secs(60 * 60),
+        });
+        for chat in [ChatId(1), ChatId(2)] {
+            test.ctx
+                .subscribers
+                .add_subscriber(Subscriber::new(chat))
+                .unwrap();
+        }
+
+        for expected in [2, 2, 1] {
+            broadcast_polled(&test, Broadcast::Latest).await;
+            assert_eq!(test.ctx.subscribers.all_subscribers().len(), expected);
+        }
+        assert_eq!(test.ctx.subscribers.all_subscribers()[0].chat_id, 1);
+    }
+
+    #[tokio::test]
+    async fn test_rebroadcast_resends_to_every_subscriber() {
+        let mut event = test_event("Patch 7.36", "Fixed notes");
+        event.gid = "736".to_string();
+        let test = TestContext::with_events(vec![event]);
+        for chat in [ChatId(1), ChatId(2)] {
+            test.ctx
+                .subscribers
+                .add_subscriber(Subscriber::new(chat))
+                .unwrap();
+            test.ctx
+                .latest_sent
+                .lock()
+                .unwrap()
+                .insert(chat, "736".to_string());
+        }
+
+        let sent = test.run("/rebroadcast", ADMIN).await;
+        assert_eq!(
+            sent,
+            vec!["This sends the latest update to all 2 subscribers again\\. Send /rebroadcast confirm to go ahead\\."]
+        );
+
+        let sent = test.run("/rebroadcast confirm", ADMIN).await;
+        assert_eq!(
+            sent.last().unwrap(),
+            "Sent the latest update to 2 subscribers again\\."
+        );
+        for chat in [ChatId(1), ChatId(2)] {
+            assert!(test
+                .sender
+                .sent()
+                .iter()
+                .any(|(to, text)| *to == chat && text.contains("Fixed notes")));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_detected_updates_are_posted_to_the_webhook() {
+        let server = wiremock::MockServer::start().await;
+        wiremock::Mock::given(wiremock::matchers::method("POST"))
+            .and(wiremock::matchers::body_json(serde_json::json!({
+                "gid": "736",
+                "headline": "Patch 7.36",
+                "timestamp": 0,
+                "permalink": "https://store.steampowered.com/news/app/570/view/736",
+            })))
+            .respond_with(wiremock::ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&server)
+            .await;
+        let mut event = test_event("Patch 7.36", "New");
+        event.gid = "736".to_string();
+        let mut test = TestContext::with_events(vec![event, test_event("Patch 7.35", "Old")]);
+        test.ctx.webhook = Arc::new(UpdateWebhook::new(Some(server.uri())));
+        std::fs::write(test._dir.path().join("old.json"), r#"["Patch 7.35"]"#).unwrap();
+
+        test.run("/poll_now", ADMIN).await;
+        let sent = test.run("/poll_now", ADMIN).await;
+
+        assert_eq!(sent.last().unwrap(), "Nothing new\\.");
+    }
+
+    #[tokio::test]
+    async fn test_last_cycle_reports_a_detected_update() {
+        let test = TestContext::with_events(patch_events());
+        assert_eq!(
+            test.run("/last_cycle", ADMIN).await,
+            vec!["No poll cycle has run yet\\."]
+        );
+        std::fs::write(test._dir.path().join("old.json"), r#"["Patch 7.35"]"#).unwrap();
+        test.ctx
+            .subscribers
+            .add_subscriber(Subscriber::new(ChatId(42)))
+            .unwrap();
+
+        test.run("/poll_now", ADMIN).await;
+        let sent = test.run("/last_cycle", ADMIN).await;
+
+        assert_eq!(
+            sent.last().unwrap(),
+            "Last poll cycle, 0s ago: change detected, 1 new gids\nNew or changed entries: 1\nBroadcast: sent to 1 chats"
+        );
+    }
+
+    #[test]
+    fn test_status_text() {
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let status = Status {
+            last_detected: Some(now - Duration::from_secs(90)),
+            tracked_events: Some(100),
+            poll_interval: Duration::from_secs(300),
+        };
+        assert_eq!(
+            status_text(&status, now),
+            "Last update detected: 90s ago\nEvents tracked: 100\nPoll interval: 300s"
+        );
+
+        let status = Status {
+            last_detected: None,
+            tracked_events: None,
+            poll_interval: Duration::from_secs(300),
+        };
+        let text = status_text(&status, now);
+        assert_eq!(
+            text,
+            "Last update detected: not since startup\nEvents tracked: none stored yet\nPoll interval: 300s"
+        );
+        assert!(validate_markdown_v2(&text).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_status_reflects_the_last_poll() {
+        let test = TestContext::with_events(patch_events());
+        std::fs::write(test._dir.path().join("old.json"), r#"["Patch 7.35"]"#).unwrap();
+
+        test.run("/poll_now", ADMIN).await;
+        let sent = test.run("/status", UserId(7)).await;
+
+        assert!(sent
+            .last()
+            .unwrap()
+            .starts_with("Last update detected: 0s ago\nEvents tracked: 2\n"));
+    }
+
+    #[tokio::test]
+    async fn test_poll_cycle_broadcasts_to_subscribers() {
+        let test = TestContext::with_events(patch_events());
+        std::fs::write(test._dir.path().join("old.json"), r#"["Patch 7.35"]"#).unwrap();
+        test.ctx
+            .subscribers
+            .add_subscriber(Subscriber::new(ChatId(42)))
+            .unwrap();
+
+        poll_and_broadcast(&test.ctx, &Telemetry::new(None)).await;
+
+        let sent = test.sender.sent();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].0, ChatId(42));
+        assert!(sent[0].1.contains("Patch 7\\.36"));
+        assert_eq!(test.ctx.poller.last_cycle().unwrap().sent_to, Some(1));
+        assert!(test.ctx.feeds[0].last_success().is_some());
+
+        poll_and_broadcast(&test.ctx, &Telemetry::new(None)).await;
+        assert_eq!(test.sender.sent().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_poll_now_is_refused_while_a_cycle_runs() {
+        let test = TestContext::with_events(vec![test_event("Patch 7.36", "New")]);
+        let _running = test.ctx.poller.lock().await;
+
+        let sent = test.run("/poll_now", ADMIN).await;
+
+        assert_eq!(sent, vec!["A poll is already running, try again later\\."]);
+    }
+
+    #[tokio::test]
+    async fn test_digest_chats_are_deferred() {
+        let test = TestContext::with_events(patch_events());
+        std::fs::write(test._dir.path().join("old.json"), r#"["Patch 7.35"]"#).unwrap();
+        for chat in [ChatId(1), ChatId(2)] {
+            test.ctx
+                .subscribers
+                .add_subscriber(Subscriber::new(chat))
+                .unwrap();
+        }
+        test.ctx
+            .subscribers
+            .set_mode(ChatId(2), DeliveryMode::Digest)
+            .unwrap();
+
+        test.run("/poll_now", ADMIN).await;
+
+        let chats: Vec<ChatId> = test.sender.sent().iter().map(|(chat, _)| *chat).collect();
+        assert_eq!(chats, vec![ChatId(1), ChatId(7)]);
+        let pending = test.ctx.subscribers.pending_updates();
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].0, ChatId(2));
+        assert!(pending[0].1[0].contains("Patch 7\\.36"));
+    }
+
+    #[tokio::test]
+    async fn test_failed_delivery_keeps_the_queued_updates() {
+        let mut test = TestContext::with_events(Vec::new());
+        for chat in [ChatId(1), ChatId(2)] {
+            test.ctx
+                .subscribers
+                .add_subscriber(Subscriber::new(chat))
+                .unwrap();
+            test.ctx
+                .subscribers
+                .queue_updates(chat, &["Patch 7\\.36".to_string()])
+                .unwrap();
+        }
+        let failing = Arc::new(RecordingSender::rejecting(4096, |chat_id, _| {
+            (chat_id == ChatId(1)).then(|| RequestError::Api(ApiError::BotBlocked))
+        }));
+        test.ctx.sender = failing.clone();
+
+        crate::digest::deliver_pending(&test.ctx).await;
+
+        assert_eq!(
+            failing.sent(),
+            vec![(ChatId(2), "Patch 7\\.36".to_string())]
+        );
+        assert_eq!(
+            test.ctx.subscribers.pending_updates(),
+            vec![(ChatId(1), vec!["Patch 7\\.36".to_string()])]
+        );
+
+        test.ctx.sender = test.sender.clone();
+        crate::digest::deliver_pending(&test.ctx).await;
+
+        assert_eq!(
+            test.sender.sent(),
+            vec![(ChatId(1), "Patch 7\\.36".to_string())]
+        );
+        assert!(test.ctx.subscribers.pending_updates().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_stale_updates_are_not_broadcast() {
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let posted = |headline, secs_ago| {
+            let mut event = test_event(headline, "body");
+            event.announcement_body.posttime = now - secs_ago;
+            event
+        };
+        let test = TestContext::with_events(vec![
+            posted("Patch 7.36b", 60),
+            posted("Patch 7.36a", 2 * 60 * 60),
+            posted("Patch 7.36", 7 * 24 * 60 * 60),
+        ]);
+        test.ctx.config.lock().unwrap().max_update_age = Some(Duration::from_secs(24 * 60 * 60));
+        test.ctx
+            .subscribers
+            .add_subscriber(Subscriber::new(ChatId(1)))
+            .unwrap();
+
+        broadcast_polled(&test, Broadcast::All).await;
+
+        let sent = test.sender.sent();
+        assert_eq!(sent.len(), 2);
+        assert!(sent[0].1.contains("Patch 7\\.36a"));
+        assert!(sent[1].1.contains("Patch 7\\.36b"));
+    }
+
+    #[test]
+    fn test_updates_start_with_the_publish_time() {
+        let template = MessageTemplate::default();
+        let fields = Fields {
+            date: true,
+            footer: false,
+            ..Fields::default()
+        };
+        let mut event = test_event("Patch", "Notes");
+        let format = |event: &Event, fields| {
+            format_event(event, &template, "", fields, Markup::MarkdownV2).unwrap()
+        };
+        assert!(format(&event, fields).starts_with("*Patch*"));
+
+        event.announcement_body.posttime = 1_711_584_000;
+        assert!(format(&event, fields).starts_with("🕒 28 Mar 2024, 00:00 UTC\n*Patch*\n"));
+        event.rtime32_start_time = 1_711_589_400;
+        assert!(format(&event, fields).starts_with("🕒 28 Mar 2024, 01:30 UTC\n*Patch*\n"));
+        let without_date = Fields {
+            date: false,
+            ..fields
+        };
+        assert!(format(&event, without_date).starts_with("*Patch*\nNotes"));
+
+        event.announcement_body.body = serde_json::Value::Null;
+        assert_eq!(format(&event, fields), "🕒 28 Mar 2024, 01:30 UTC\nPatch\n");
+        assert_eq!(format(&event, without_date), "Patch\n");
+    }
+
+    #[tokio::test]
+    async fn test_events_are_ordered_by_start_time() {
+        let started = |headline, start| {
+            let mut event = test_event(headline, "body");
+            event.rtime32_start_time = start;
+            event
+        };
+        let test = TestContext::with_events(vec![
+            started("Middle", 200),
+            started("Newest", 300),
+            started("Oldest", 100),
+        ]);
+        test.ctx
+            .subscribers
+            .add_subscriber(Subscriber::new(ChatId(1)))
+            .unwrap();
+
+        broadcast_polled(&test, Broadcast::Latest).await;
+        broadcast_polled(&test, Broadcast::All).await;
+
+        let headlines: Vec<&str> = test
+            .sender
+            .sent()
+            .iter()
+            .map(|(_, text)| {
+                ["Oldest", "Middle", "Newest"]
+                    .into_iter()
+                    .find(|headline| text.contains(headline))
+                    .unwrap()
+            })
+            .collect();
+        assert_eq!(headlines, vec!["Newest", "Oldest", "Middle", "Newest"]);
+    }
+
+    #[test]
+    fn test_inline_query_matches_headlines() {
+        let mut patch = test_event("Gameplay Patch 7.35", "Notes");
+        patch.gid = "42".to_string();
+        let test = TestContext::with_events(vec![]);
+        let events = vec![
+            patch,
+            test_event("The International", "Tickets"),
+            test_event("Hotfix for the patch", "Fixes"),
+        ];
+
+        let results = inline_results(&test.ctx, &events, " PATCH ");
+
+        let articles: Vec<(&str, &str, &str)> = results
+            .iter()
+            .map(|result| match result {
+                InlineQueryResult::Article(article) => match &article.input_message_content {
+                    InputMessageContent::Text(content) => (
+                        article.id.as_str(),
+                        article.title.as_str(),
+                        content.message_text.as_str(),
+                    ),
+                    other => panic!("unexpected content {:?}", other),
+                },
+                other => panic!("unexpected result {:?}", other),
+            })
+            .collect();
+        assert_eq!(
+            articles,
+            vec![
+                (
+                    "42",
+                    "Gameplay Patch 7.35",
+                    "Gameplay Patch 7\\.35\n[View on Steam](https://store.steampowered.com/news/app/570/view/42)"
+                ),
+                (
+                    "update-1",
+                    "Hotfix for the patch",
+                    "Hotfix for the patch\nhttps://www\\.dota2\\.com/news?l\\=english"
+                ),
+            ]
+        );
+        assert_eq!(inline_results(&test.ctx, &events, "").len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_mapped_feeds_route_to_their_chats() {
+        let for_app = |headline, appid| {
+            let mut event = test_event(headline, "Notes");
+            event.appid = appid;
+            event
+        };
+        let mut test = TestContext::with_events(vec![
+            for_app("Dota news", DOTA_APPID),
+            for_app("Underlords news", 1046930),
+            for_app("Artifact news", 583950),
+        ]);
+        test.ctx.feeds = vec![
+            Arc::new(Feed::new(DOTA_APPID, DEFAULT_LANGUAGE).with_chats(vec![ChatId(-100)])),
+            Arc::new(Feed::new(1046930, DEFAULT_LANGUAGE).with_chats(vec![ChatId(-200)])),
+        ];
+        test.ctx
+            .subscribers
+            .add_subscriber(Subscriber::new(ChatId(1)))
+            .unwrap();
+
+        broadcast_polled(&test, Broadcast::All).await;
+
+        let sent = test.sender.sent();
+        let routed = |headline| -> Vec<ChatId> {
+            sent.iter()
+                .filter(|(_, text)| text.contains(headline))
+                .map(|(chat, _)| *chat)
+                .collect()
+        };
+        assert_eq!(routed("Dota news"), vec![ChatId(-100)]);
+        assert_eq!(routed("Underlords news"), vec![ChatId(-200)]);
+        assert_eq!(routed("Artifact news"), vec![ChatId(1)]);
+    }
+
+    #[tokio::test]
+    async fn test_quiet_hours_follow_each_chat_timezone() {
+        let mut test = TestContext::with_events(vec![test_event("Patch 7.36", "Notes")]);
+        // Thursday, 2024-03-14 12:00 UTC: 08:00 in New York and 15:00 in Moscow.
+        let noon = SystemTime::UNIX_EPOCH + Duration::from_secs(1_710_417_600);
+        test.ctx.clock = Arc::new(FixedClock(noon));
+        test.ctx.startup.quiet_hours = Some("07:00-09:00".parse().unwrap());
+        for (chat_id, tz) in [(1, "America/New_York"), (2, "Europe/Moscow")] {
+            let chat_id = ChatId(chat_id);
+            test.ctx
+                .subscribers
+                .add_subscriber(Subscriber::new(chat_id))
+                .unwrap();
+            test.ctx.subscribers.set_timezone(chat_id, tz).unwrap();
+        }
+
+        broadcast_polled(&test, Broadcast::Latest).await;
+
+        let chats = |test: &TestContext| -> Vec<ChatId> {
+            test.sender.sent().iter().map(|(chat, _)| *chat).collect()
+        };
+        assert_eq!(chats(&test), vec![ChatId(2)]);
+        assert!(test.ctx.subscribers.pending_updates().is_empty());
+        let held = test.ctx.subscribers.held_updates();
+        assert_eq!(held.len(), 1);
+        assert_eq!(held[0].chat_id, 1);
+
+        crate::quiet_hours::deliver_held(&test.ctx).await;
+        assert_eq!(chats(&test), vec![ChatId(2)]);
+
+        // 09:00 in New York, when its quiet hours end.
+        test.ctx.clock = Arc::new(FixedClock(noon + Duration::from_secs(60 * 60)));
+        crate::quiet_hours::deliver_held(&test.ctx).await;
+        assert_eq!(chats(&test), vec![ChatId(2), ChatId(1)]);
+        assert!(test.sender.sent()[1].1.contains("Patch 7\\.36"));
+        assert!(test.ctx.subscribers.held_updates().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_chunksize_applies_to_later_updates() {
+        let mut test = TestContext::with_events(vec![test_event("Patch", &"x".repeat(1000))]);
+        let sender = Arc::new(ChunkSizeSender::new(
+            RecordingSender::new(4096),
+            Arc::clone(&test.ctx.settings),
+        ));
+        test.ctx.sender = sender.clone();
+        test.ctx
+            .subscribers
+            .add_subscriber(Subscriber::new(ChatId(1)))
+            .unwrap();
+
+        let command = Command::parse("/chunksize 300", "bot").unwrap();
+        handle_command(&test.ctx, ChatId(7), Some(ADMIN), command)
+            .await
+            .unwrap();
+        broadcast_polled(&test, Broadcast::Latest).await;
+
+        let sent = sender.inner().sent();
+        assert_eq!(
+            sent[0].1,
+            "Messages are split into chunks of up to 300 characters from now on\\."
+        );
+        let lens: Vec<usize> = sent[1..]
+            .iter()
+            .map(|(_, text)| text.chars().count())
+            .collect();
+        assert!(lens.len() > 1);
+        assert!(lens.iter().all(|len| *len <= 300));
+        assert_eq!(test.ctx.settings.get().chunk_size, Some(300));
+    }
+
+    #[tokio::test]
+    async fn test_echo_reports_rejected_markup() {
+        let mut test = TestContext::with_events(Vec::new());
+        let sender = Arc::new(RecordingSender::rejecting(4096, |_, text| {
+            validate_markdown_v2(text).err().map(|err| {
+                RequestError::Api(ApiError::Unknown(format!(
+                    "Bad Request: can't parse entities: {}",
+                    err
+                )))
+            })
+        }));
+        test.ctx.sender = sender.clone();
+
+        for text in ["/echo *bold* \\.", "/echo *bold"] {
+            let command = Command::parse(text, "bot").unwrap();
+            handle_command(&test.ctx, ChatId(7), Some(ADMIN), command)
+                .await
+                .unwrap();
+        }
+
+        let sent: Vec<String> = sender.sent().into_iter().map(|(_, text)| text).collect();
+        assert_eq!(sent[0], "*bold* \\.");
+        assert!(sent[1].starts_with(
+            "Telegram rejected the markup: Unknown error: \"Bad Request: can't parse entities: "
+        ));
+        assert_eq!(sent.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_tz_rejects_unknown_zone() {
+        let test = TestContext::with_events(Vec::new());
+        test.ctx
+            .subscribers
+            .add_subscriber(Subscriber::new(ChatId(7)))
+            .unwrap();
+
+        let sent = test.run("/tz Mars/Olympus", UserId(7)).await;
+        assert!(sent[0].starts_with("Unknown time zone: Mars/Olympus\\."));
+
+        let sent = test.run("/tz America/New_York", UserId(7)).await;
+        assert_eq!(sent[1], "Time zone set to America/New\\_York\\.");
+        assert_eq!(
+            test.ctx
+                .subscribers
+                .subscriber(ChatId(7))
+                .unwrap()
+                .tz
+                .as_deref(),
+            Some("America/New_York")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_mode_requires_subscription() {
+        let test = TestContext::with_events(Vec::new());
+
+        let sent = test.run("/mode digest", UserId(7)).await;
+        assert_eq!(
+            sent[0],
+            "This chat isn't subscribed\\. Use /subscribe first\\."
+        );
+
+        test.run("/subscribe", UserId(7)).await;
+        let sent = test.run("/mode digest", UserId(7)).await;
+        assert_eq!(sent[1], "Updates will be sent once a day\\.");
+        assert_eq!(
+            test.ctx.subscribers.all_subscribers()[0].mode,
+            DeliveryMode::Digest
+        );
+    }
+
+    #[tokio::test]
+    async fn test_admins_add_list_remove() {
+        let test = TestContext::with_events(Vec::new());
+
+        test.run("/admins add 5", ADMIN).await;
+        assert!(test.ctx.is_admin(Some(UserId(5))));
+        let sent = test.run("/admins list", ADMIN).await;
+        assert_eq!(sent[1], "Super admins: 1\nAdmins: 5");
+
+        let sent = test.run("/admins remove 1", ADMIN).await;
+        assert_eq!(
+            sent[2],
+            "User 1 is a super admin and can only be removed from ADMIN\\_IDS\\."
+        );
+        let sent = test.run("/admins remove 5", ADMIN).await;
+        assert_eq!(sent[3], "User 5 is no longer an admin\\.");
+        assert!(!test.ctx.is_admin(Some(UserId(5))));
+    }
+
+    #[tokio::test]
+    async fn test_admins_requires_super_admin() {
+        let test = TestContext::with_events(Vec::new());
+        test.run("/admins add 5", ADMIN).await;
+
+        let sent = test.run("/admins add 6", UserId(5)).await;
+
+        assert_eq!(sent[1], SUPER_ADMIN_ONLY_TEXT);
+        assert!(!test.ctx.is_admin(Some(UserId(6))));
+        let sent = test.run("/feeds", UserId(5)).await;
+        assert!(sent[2].starts_with("Active feeds:"));
+    }
+
+    #[test]
+    fn test_validate_markdown_v2() {
+        assert!(validate_markdown_v2("*Patch 7\\.35* [link](https://a.b/c?d=(e\\))").is_ok());
+        assert!(validate_markdown_v2("> quote ||spoiler||").is_ok());
+        assert!(validate_markdown_v2("`a.b` ```\nfn main() { \\` }\n```").is_ok());
+        assert_eq!(
+            validate_markdown_v2("```\nnot closed"),
+            Err("Unclosed '`' entity".to_string())
+        );
+        assert_eq!(
+            validate_markdown_v2("Patch 7.35"),
+            Err("Unescaped '.' at position 7".to_string())
+        );
+        assert_eq!(
+            validate_markdown_v2("*bold"),
+            Err("Unclosed '*' entity".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_escape_command() {
+        let test = TestContext::with_events(Vec::new());
+
+        let sent = test.run("/escape Patch 7.35c (beta)!", ADMIN).await;
+
+        assert_eq!(
+            sent,
+            vec![
+                "Escaped:\nPatch 7\\\\\\.35c \\\\\\(beta\\\\\\)\\\\\\!\n\n\
+                 Input: Unescaped '\\.' at position 7\nEscaped: valid"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_broadcast_follows_each_chat_filter() {
+        let mut news = test_event("Community news", "Body");
+        news.event_type = 28;
+        let test = TestContext::with_events(vec![news]);
+        for (chat, filter) in [
+            (1, Some(EventFilter::Patches)),
+            (2, None),
+            (3, Some(EventFilter::News)),
+        ] {
+            test.ctx
+                .subscribers
+                .add_subscriber(Subscriber {
+                    filter,
+                    ..Subscriber::new(ChatId(chat))
+                })
+                .unwrap();
+        }
+
+        broadcast_polled(&test, Broadcast::Latest).await;
+
+        let chats: Vec<ChatId> = test
+            .sender
+            .sent()
+            .into_iter()
+            .map(|(chat, _)| chat)
+            .collect();
+        assert_eq!(chats, vec![ChatId(2), ChatId(3)]);
+    }
+
+    /// Serves `english` as the polled feed, and `russian` when the events are fetched in Russian.
+    struct LocalizedSource {
+        english: Vec<Event>,
+        russian: Vec<Event>,
+    }
+
+    #[async_trait::async_trait]
+    impl EventSource for LocalizedSource {
+        async fn fetch_feed(&self) -> Result<serde_json::Value, AppError> {
+            Ok(serde_json::json!({ "events": self.english }))
+        }
+
+        async fn fetch_events_in(&self, language: &str) -> Result<Vec<Event>, AppError> {
+            Ok(match language {
+                "russian" => self.russian.clone(),
+                _ => self.english.clone(),
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_broadcast_renders_each_chat_language() {
+        let mut test = TestContext::with_events(Vec::new());
+        test.ctx.source = Arc::new(LocalizedSource {
+            english: vec![test_event("Patch 7.36", "New heroes")],
+            russian: vec![test_event("Патч 7.36", "Новые герои")],
+        });
+        test.ctx
+            .subscribers
+            .add_subscriber(Subscriber::new(ChatId(1)))
+            .unwrap();
+        test.ctx
+            .subscribers
+            .add_subscriber(Subscriber {
+                lang: Some("russian".to_string()),
+                ..Subscriber::new(ChatId(2))
+            })
+            .unwrap();
+
+        broadcast_polled(&test, Broadcast::Latest).await;
+
+        let sent = test.sender.sent();
+        assert_eq!(sent.len(), 2);
+        assert_eq!(sent[0].0, ChatId(1));
+        assert!(sent[0].1.contains("Patch 7\\.36"));
+        assert!(sent[0].1.contains("l=english"));
+        assert_eq!(sent[1].0, ChatId(2));
+        assert!(sent[1].1.contains("Патч 7\\.36"));
+        assert!(sent[1].1.contains("l=russian"));
+    }
+
+    #[tokio::test]
+    async fn test_ping_chats_get_notices_without_bodies() {
+        let mut unformattable = test_event("Patch 7.36", "");
+        unformattable.gid = "42".to_string();
+        unformattable.announcement_body.body = serde_json::json!({"not": "a string"});
+        let test = TestContext::with_events(vec![unformattable]);
+        test.ctx
+            .subscribers
+            .add_subscriber(Subscriber::new(ChatId(1)))
+            .unwrap();
+        test.ctx
+            .subscribers
+            .set_mode(ChatId(1), DeliveryMode::Ping)
+            .unwrap();
+
+        broadcast_polled(&test, Broadcast::Latest).await;
+
+        assert_eq!(
+            test.sender.sent(),
+            vec![(
+                ChatId(1),
+                "🆕 New Dota 2 post\nhttps://store\\.steampowered\\.com/news/app/570/view/42"
+                    .to_string()
+            )]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_count_is_clamped_and_stored() {
+        let test = TestContext::with_events(Vec::new());
+
+        let sent = test.run("/count 1000", ADMIN).await;
+
+        assert_eq!(sent[0], "Each poll fetches 200 events from now on\\.");
+        assert_eq!(test.ctx.settings.get().poll_count, Some(MAX_POLL_COUNT));
+    }
+
+    #[tokio::test]
+    async fn test_settings_summarizes_the_chat_record() {
+        let test = TestContext::with_events(Vec::new());
+
+        let sent = test.run("/settings", UserId(7)).await;
+        assert_eq!(
+            sent[0],
+            "Subscribed: no\nUse /subscribe to get updates here\\."
+        );
+
+        test.run("/subscribe news ru", UserId(7)).await;
+        test.run("/mode ping", UserId(7)).await;
+        let sent = test.run("/settings", UserId(7)).await;
+        let subscriber = test.ctx.subscribers.subscriber(ChatId(7)).unwrap();
+        assert_eq!(
+            sent[2],
+            settings_summary(Some(&subscriber), DEFAULT_LANGUAGE, false, false, None)
+        );
+        assert_eq!(
+            sent[2],
+            "Subscribed: yes\nLanguage: russian\nFilter: news\nMode: ping\nTemplate: default\n\
+             Images: off\nTime zone: UTC \\(default\\)\nMuted: no quiet hours"
+        );
+
+        test.run("/tz Europe/Berlin", UserId(7)).await;
+        let mut test = test;
+        test.ctx.startup.quiet_hours = Some("07:00-09:00".parse().unwrap());
+        // 06:30 UTC, 08:30 in Berlin.
+        test.ctx.clock = Arc::new(FixedClock(
+            SystemTime::UNIX_EPOCH + Duration::from_secs(1_717_223_400),
+        ));
+        test.ctx.config.lock().unwrap().send_images = true;
+        let sent = test.run("/settings", UserId(7)).await;
+        assert!(sent.last().unwrap().ends_with(
+            "Images: on\nTime zone: Europe/Berlin\nMuted: yes, quiet hours 07:00\\-09:00"
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_default_language_is_the_polled_one() {
+        let mut test = TestContext::with_events(Vec::new());
+        test.ctx.feeds = vec![Arc::new(Feed::new(DOTA_APPID, "russian"))];
+        test.run("/subscribe", UserId(7)).await;
+
+        let sent = test.run("/settings", UserId(7)).await;
+        assert!(sent
+            .last()
+            .unwrap()
+            .contains("\nLanguage: russian \\(default\\)\n"));
+        let sent = test.run("/feeds", ADMIN).await;
+        assert!(sent
+            .last()
+            .unwrap()
+            .contains("appid 570, russian: 1 subscribers"));
+    }
+
+    #[test]
+    fn test_format_event_adds_steam_link() {
+        let template = MessageTemplate::parse("*{headline}*").unwrap();
+        let mut event = test_event("Patch", "body");
+        event.gid = "42".to_string();
+        let permalink = Fields {
+            permalink: true,
+            ..Fields::default()
+        };
+
+        assert_eq!(
+            format_event(&event, &template, "", permalink, Markup::MarkdownV2).unwrap(),
+            "*Patch*\n[View on Steam](https://store.steampowered.com/news/app/570/view/42)"
+        );
+        assert_eq!(
+            format_event(&event, &template, "", Fields::default(), Markup::MarkdownV2).unwrap(),
+            "*Patch*"
+        );
+    }
+
+    #[test]
+    fn test_format_event_adds_byline() {
+        let template = MessageTemplate::parse("*{headline}*").unwrap();
+        let mut event = test_event("Patch", "body");
+        event.announcement_body.author = "The Dota Team".to_string();
+        let author = Fields {
+            author: true,
+            ..Fields::default()
+        };
+
+        assert_eq!(
+            format_event(&event, &template, "", author, Markup::MarkdownV2).unwrap(),
+            "*Patch*\n— posted by The Dota Team"
+        );
+        assert_eq!(
+            format_event(&event, &template, "", Fields::default(), Markup::MarkdownV2).unwrap(),
+            "*Patch*"
+        );
+        event.announcement_body.author = " ".to_string();
+        assert_eq!(
+            format_event(&event, &template, "", author, Markup::MarkdownV2).unwrap(),
+            "*Patch*"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_fields_toggle_parts_of_updates() {
+        let mut event = test_event("Patch", "Notes");
+        event.gid = "42".to_string();
+        event.announcement_body.posttime = 1_711_584_000;
+        event.announcement_body.author = "The Dota Team".to_string();
+        event.announcement_body.voteupcount = 12;
+        event.announcement_body.commentcount = 3;
+        let test = TestContext::with_events(vec![event.clone()]);
+        let render = || {
+            format_event(
+                &event,
+                &MessageTemplate::default(),
+                "https://example.com",
+                test.ctx.fields(),
+                Markup::MarkdownV2,
+            )
+            .unwrap()
+        };
+        let footer = "follow this [link](https://example.com)";
+        assert!(render().contains(footer));
+        assert!(!render().contains("🕒"));
+
+        let sent = test.run("/fields date", ADMIN).await;
+        assert_eq!(
+            sent[0],
+            "date: on\nauthor: off\nengagement: off\nfooter: on\npermalink: off"
+        );
+        for name in ["author", "engagement", "footer", "permalink"] {
+            test.run(&format!("/fields {}", name), ADMIN).await;
+        }
+
+        assert_eq!(
+            render(),
+            "🕒 28 Mar 2024, 00:00 UTC\n*Patch*\nNotes\n\n— posted by The Dota Team\n👍 12 · 💬 3\n\
+             [View on Steam](https://store.steampowered.com/news/app/570/view/42)"
+        );
+        test.run("/fields date", ADMIN).await;
+        test.run("/fields footer", ADMIN).await;
+        assert!(render().contains(footer));
+        assert!(!render().contains("🕒"));
+        let sent = test.run("/fields color", ADMIN).await;
+        assert_eq!(
+            sent.last().unwrap(),
+            "Unknown field: color\nFields: date, author, engagement, footer, permalink"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_chunks_matches_actual_chunking() {
+        let mut event = test_event("Big Patch", &"a".repeat(10_000));
+        event.gid = "42".to_string();
+        let test = TestContext::with_events(vec![event.clone()]);
+
+        let sent = test.run("/chunks 42", ADMIN).await;
+
+        let formatted = format_event(
+            &event,
+            &MessageTemplate::default(),
+            &test.ctx.news_link(),
+            Fields::default(),
+            Markup::MarkdownV2,
+        )
+        .unwrap();
+        let chunks = split_into_chunks(&formatted, 4096, Markup::MarkdownV2);
+        assert_eq!(chunks.len(), 3);
+        let last = chunks[2].chars().count();
+        assert_eq!(
+            sent,
+            vec![format!(
+                "This update is sent as 3 messages of 4096, 4096, {} characters\\.",
+                last
+            )]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_repeated_latest_sends_a_short_note() {
+        let mut event = test_event("Patch 7.36", "New heroes");
+        event.gid = "42".to_string();
+        let test = TestContext::with_events(vec![event]);
+
+        test.run("/latest", UserId(7)).await;
+        let sent = test.run("/latest", UserId(7)).await;
+
+        assert_eq!(sent.len(), 2);
+        assert!(sent[0].contains("New heroes"));
+        assert_eq!(sent[1], "Still the latest: *Patch 7\\.36*");
+    }
+
+    #[test]
+    fn test_latest_count_is_parsed_and_clamped() {
+        assert_eq!(parse_latest_count(""), Ok(1));
+        assert_eq!(parse_latest_count(" 5 "), Ok(5));
+        assert_eq!(parse_latest_count("0"), Ok(1));
+        assert_eq!(parse_latest_count("500"), Ok(MAX_LATEST));
+        assert!(parse_latest_count("five").is_err());
+
+        let command = |text| Command::parse(text, "bot").unwrap();
+        assert_eq!(command("/latest 5"), Command::Latest("5".to_string()));
+        assert_eq!(command("/latest"), Command::Latest(String::new()));
+    }
+
+    #[tokio::test]
+    async fn test_latest_sends_each_update_on_its_own() {
+        let test = TestContext::with_events(vec![
+            test_event("Patch 7.36b", "Third"),
+            test_event("Patch 7.36a", "Second"),
+            test_event("Patch 7.36", "First"),
+        ]);
+
+        let sent = test.run("/latest 2", UserId(7)).await;
+
+        assert_eq!(sent.len(), 2);
+        assert!(sent[0].contains("Second"));
+        assert!(sent[1].contains("Third"));
+        let sent = test.run("/latest two", UserId(7)).await;
+        assert_eq!(
+            sent.last().unwrap(),
+            "Usage: /latest \\[n\\], where n is at most 10\\."
+        );
+    }
+
+    /// A feed that never answers in time.
+    struct TimingOutSource;
+
+    #[async_trait::async_trait]
+    impl EventSource for TimingOutSource {
+        async fn fetch_feed(&self) -> Result<serde_json::Value, AppError> {
+            Err(AppError::BodyTimeoutError)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_command_returns_the_fetch_error() {
+        let mut test = TestContext::with_events(Vec::new());
+        test.ctx.source = Arc::new(TimingOutSource);
+
+        let command = Command::parse("/latest", "bot").unwrap();
+        let err = handle_command(&test.ctx, ChatId(7), Some(UserId(7)), command)
+            .await
+            .unwrap_err();
+
+        assert!(matches!(err, AppError::BodyTimeoutError));
+        assert!(test.sender.sent().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_reload_applies_the_new_interval() {
+        let test = TestContext::with_events(Vec::new());
+        std::fs::write(&test.ctx.env_file, "SLEEP_DURATION_SECS=60\n").unwrap();
+
+        let sent = test.run("/reload", ADMIN).await;
+
+        assert_eq!(test.ctx.config().sleep_duration, Duration::from_secs(60));
+        assert_eq!(
+            sent[0],
+            "Configuration reloaded\\.\nChanged: SLEEP\\_DURATION\\_SECS"
+        );
+
+        std::fs::write(&test.ctx.env_file, "SLEEP_DURATION_SECS=2m\n").unwrap();
+        let sent = test.run("/reload", ADMIN).await;
 
-        assert_eq!(restored_text, found_fragments[0]);
+        assert_eq!(test.ctx.config().sleep_duration, Duration::from_secs(60));
+        assert_eq!(
+            sent[1],
+            "Configuration not reloaded: Invalid SLEEP\\_DURATION\\_SECS \"2m\": invalid digit found in string"
+        );
     }
 }
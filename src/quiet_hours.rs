@@ -0,0 +1,144 @@
+//! Quiet hours, during which real-time updates are held back from a chat.
+//!
+//! The window from `QUIET_HOURS` (e.g. `23:00-07:00`) is read in each chat's own time zone, set
+//! with `/tz`, so a chat elsewhere in the world isn't silenced by the operator's night. Chats that
+//! didn't set a time zone use UTC.
+//!
+//! Updates held back from a chat are sent as one message by the first check after its window
+//! ends, made every `HELD_CHECK_INTERVAL`.
+
+use crate::digest::parse_time_of_day;
+use crate::message_part::{send_chunks_as, CommandContext};
+use chrono::{DateTime, Timelike, Utc};
+use chrono_tz::Tz;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use teloxide::types::ChatId;
+use tracing::{error, info};
+
+/// How often chats are checked for quiet hours that ended.
+const HELD_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// A daily window of local time, which may wrap past midnight.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QuietHours {
+    /// Seconds since midnight the window starts at.
+    start: u64,
+    /// Seconds since midnight the window ends at, exclusive.
+    end: u64,
+}
+
+impl FromStr for QuietHours {
+    type Err = String;
+
+    /// Parses a window like `23:00-07:00`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("Invalid quiet hours: {}", s);
+        let (start, end) = s.split_once('-').ok_or_else(invalid)?;
+        Ok(Self {
+            start: parse_time_of_day(start).ok_or_else(invalid)?,
+            end: parse_time_of_day(end).ok_or_else(invalid)?,
+        })
+    }
+}
+
+impl fmt::Display for QuietHours {
+    /// Writes the window as it is parsed, like `23:00-07:00`.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let time = |secs: u64| format!("{:02}:{:02}", secs / 3600, secs % 3600 / 60);
+        write!(f, "{}-{}", time(self.start), time(self.end))
+    }
+}
+
+impl QuietHours {
+    /// Returns `true` if `now` falls inside the window in time zone `tz`.
+    pub fn contains(&self, now: SystemTime, tz: Tz) -> bool {
+        let local = DateTime::<Utc>::from(now).with_timezone(&tz);
+        let time_of_day = u64::from(local.num_seconds_from_midnight());
+        if self.start <= self.end {
+            (self.start..self.end).contains(&time_of_day)
+        } else {
+            time_of_day >= self.start || time_of_day < self.end
+        }
+    }
+}
+
+/// Parses an IANA time zone name like `Europe/Berlin`.
+pub fn parse_timezone(name: &str) -> Result<Tz, String> {
+    name.trim()
+        .parse()
+        .map_err(|_| format!("Unknown time zone: {}", name.trim()))
+}
+
+/// Returns the time zone of a chat that set `tz`, or UTC if it didn't or the name is unknown.
+pub fn chat_timezone(tz: Option<&str>) -> Tz {
+    tz.and_then(|name| parse_timezone(name).ok())
+        .unwrap_or(Tz::UTC)
+}
+
+/// Sends every chat whose quiet hours are over the updates held back from it, as one message.
+///
+/// Without `QUIET_HOURS`, every held update is sent, so none are left behind after the window is
+/// removed. As with `deliver_pending()`, a chat's updates stay held if the send fails.
+pub async fn deliver_held(ctx: &CommandContext) {
+    let now = ctx.clock.now();
+    for subscriber in ctx.subscribers.held_updates() {
+        let chat_id = ChatId(subscriber.chat_id);
+        let quiet = ctx.startup.quiet_hours.is_some_and(|quiet_hours| {
+            quiet_hours.contains(now, chat_timezone(subscriber.tz.as_deref()))
+        });
+        if quiet {
+            continue;
+        }
+        let text = subscriber.held.join("\n");
+        if let Err(err) =
+            send_chunks_as(ctx.sender.as_ref(), chat_id, &text, ctx.startup.markup).await
+        {
+            error!(
+                "Failed to send the held updates to chat {}, keeping them: {}",
+                chat_id, err
+            );
+            continue;
+        }
+        match ctx.subscribers.clear_held(chat_id, subscriber.held.len()) {
+            Ok(()) => info!(
+                "Quiet hours ended for chat {}, sent its held updates.",
+                chat_id
+            ),
+            Err(err) => error!(
+                "Failed to clear the held updates of chat {}: {}",
+                chat_id, err
+            ),
+        }
+    }
+}
+
+/// Sends held updates whenever a chat's quiet hours end, for as long as the bot runs.
+pub async fn run_held_delivery(ctx: Arc<CommandContext>) {
+    let mut interval = tokio::time::interval(HELD_CHECK_INTERVAL);
+    loop {
+        interval.tick().await;
+        deliver_held(&ctx).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_quiet_hours_wrap_past_midnight() {
+        let quiet: QuietHours = "23:00-07:00".parse().unwrap();
+        let at = |hours: u64| SystemTime::UNIX_EPOCH + Duration::from_secs(hours * 60 * 60);
+
+        assert!(quiet.contains(at(23), Tz::UTC));
+        assert!(quiet.contains(at(6), Tz::UTC));
+        assert!(!quiet.contains(at(7), Tz::UTC));
+        assert!(!quiet.contains(at(12), Tz::UTC));
+        assert!("23:00".parse::<QuietHours>().is_err());
+        assert!(parse_timezone("Mars/Olympus").is_err());
+    }
+}
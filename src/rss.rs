@@ -0,0 +1,142 @@
+//! Export of recent updates as an RSS 2.0 feed, for feed readers.
+//!
+//! When `RSS_FILE` is set, the file is written again after every poll that detects updates, so
+//! any web server can serve it. `/rss` sends the same feed as a file.
+
+use crate::bbcode::escape_html;
+use crate::errors::AppError;
+use chrono::DateTime;
+use std::fs;
+use std::path::Path;
+
+/// Name of the file `/rss` sends.
+pub const RSS_FILE_NAME: &str = "updates.xml";
+
+/// Most updates a feed lists, newest first.
+pub const MAX_RSS_ITEMS: usize = 20;
+
+/// One update in the feed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RssItem {
+    pub title: String,
+    /// The permalink of the update's post.
+    pub link: String,
+    /// Unix time the update was posted at.
+    pub pub_date: u64,
+    /// The body as plain text.
+    pub description: String,
+}
+
+/// Formats a Unix time as an RFC 2822 date, as RSS requires.
+fn rfc2822(timestamp: u64) -> String {
+    i64::try_from(timestamp)
+        .ok()
+        .and_then(|secs| DateTime::from_timestamp(secs, 0))
+        .unwrap_or_default()
+        .to_rfc2822()
+}
+
+/// Renders an RSS 2.0 document for a channel named `title` that links to `link`.
+pub fn render_rss(title: &str, link: &str, items: &[RssItem]) -> String {
+    let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    xml.push_str("<rss version=\"2.0\">\n<channel>\n");
+    xml.push_str(&format!("<title>{}</title>\n", escape_html(title)));
+    xml.push_str(&format!("<link>{}</link>\n", escape_html(link)));
+    xml.push_str(&format!(
+        "<description>{}</description>\n",
+        escape_html(title)
+    ));
+    for item in items {
+        xml.push_str("<item>\n");
+        xml.push_str(&format!("<title>{}</title>\n", escape_html(&item.title)));
+        xml.push_str(&format!("<link>{}</link>\n", escape_html(&item.link)));
+        xml.push_str(&format!(
+            "<guid isPermaLink=\"true\">{}</guid>\n",
+            escape_html(&item.link)
+        ));
+        xml.push_str(&format!("<pubDate>{}</pubDate>\n", rfc2822(item.pub_date)));
+        xml.push_str(&format!(
+            "<description>{}</description>\n",
+            escape_html(&item.description)
+        ));
+        xml.push_str("</item>\n");
+    }
+    xml.push_str("</channel>\n</rss>\n");
+    xml
+}
+
+/// Atomically replaces the file at `path` with `xml`, like `write_json_atomically()`.
+pub fn write_rss(path: &Path, xml: &str) -> Result<(), AppError> {
+    let tmp_path = path.with_extension("xml.tmp");
+    fs::write(&tmp_path, xml)?;
+    fs::rename(&tmp_path, path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns the names of the elements in `xml`, in order, checking every tag is closed in the
+    /// right order and nothing but entities follows a `&`.
+    fn element_names(xml: &str) -> Vec<String> {
+        let mut open = Vec::new();
+        let mut names = Vec::new();
+        let mut rest = xml.strip_prefix("<?xml").unwrap();
+        rest = &rest[rest.find("?>").unwrap() + 2..];
+        while let Some(start) = rest.find(['<', '&']) {
+            let text = &rest[start..];
+            if let Some(after) = text.strip_prefix('&') {
+                assert!(["amp;", "lt;", "gt;", "quot;"]
+                    .iter()
+                    .any(|entity| after.starts_with(entity)));
+                rest = after;
+                continue;
+            }
+            let end = text.find('>').unwrap();
+            let tag = &text[1..end];
+            match tag.strip_prefix('/') {
+                Some(name) => assert_eq!(open.pop().as_deref(), Some(name)),
+                None => {
+                    let name = tag.split(' ').next().unwrap().to_string();
+                    names.push(name.clone());
+                    open.push(name);
+                }
+            }
+            rest = &text[end + 1..];
+        }
+        assert!(open.is_empty());
+        names
+    }
+
+    #[test]
+    fn test_rss_is_well_formed() {
+        let items = [
+            RssItem {
+                title: "Patch 7.36 <Fixes> & more".to_string(),
+                link: "https://store.steampowered.com/news/app/570/view/736".to_string(),
+                pub_date: 1_700_000_000,
+                description: "Heroes \"rebalanced\"".to_string(),
+            },
+            RssItem {
+                title: "Patch 7.35".to_string(),
+                link: "https://store.steampowered.com/news/app/570/view/735".to_string(),
+                pub_date: 0,
+                description: String::new(),
+            },
+        ];
+
+        let xml = render_rss("Dota 2 updates", "https://www.dota2.com/news", &items);
+
+        let names = element_names(&xml);
+        assert_eq!(names.iter().filter(|name| *name == "item").count(), 2);
+        assert!(xml.contains("<title>Patch 7.36 &lt;Fixes&gt; &amp; more</title>"));
+        assert!(xml.contains("<pubDate>Tue, 14 Nov 2023 22:13:20 +0000</pubDate>"));
+        assert!(xml.contains("<description>Heroes &quot;rebalanced&quot;</description>"));
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(RSS_FILE_NAME);
+        write_rss(&path, &xml).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), xml);
+    }
+}
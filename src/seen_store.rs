@@ -0,0 +1,114 @@
+use crate::errors::AppError;
+use rusqlite::{params, Connection, OptionalExtension};
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Schema changes, applied in order. `PRAGMA user_version` records how many have run, so each
+/// one runs once per database.
+const MIGRATIONS: &[&str] = &["CREATE TABLE seen_events (
+        gid TEXT PRIMARY KEY,
+        headline TEXT NOT NULL,
+        seen_at INTEGER NOT NULL
+    )"];
+
+/// The events `CompareMode::Gid` has seen, kept in SQLite instead of a JSON snapshot.
+///
+/// Unlike the snapshot, which is capped to the newest `MAX_SEEN_KEYS` keys, the table keeps every
+/// event ever seen, so a post that drops out of the feed and comes back is never sent twice.
+pub struct SeenStore {
+    conn: Mutex<Connection>,
+}
+
+impl SeenStore {
+    /// Opens the database at `path`, creating it if needed, and brings its schema up to date.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, AppError> {
+        Self::migrate(Connection::open(path)?)
+    }
+
+    /// Opens a database that lives only as long as the store.
+    #[cfg(test)]
+    pub fn open_in_memory() -> Result<Self, AppError> {
+        Self::migrate(Connection::open_in_memory()?)
+    }
+
+    fn migrate(mut conn: Connection) -> Result<Self, AppError> {
+        let version: usize = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+        let tx = conn.transaction()?;
+        for migration in MIGRATIONS.iter().skip(version) {
+            tx.execute_batch(migration)?;
+        }
+        tx.pragma_update(None, "user_version", MIGRATIONS.len())?;
+        tx.commit()?;
+        Ok(Self {
+            conn: Mutex::new(conn),
+        })
+    }
+
+    /// Returns whether the event stored under `gid` was marked seen.
+    pub fn is_seen(&self, gid: &str) -> Result<bool, AppError> {
+        let conn = self.conn.lock().unwrap();
+        let found = conn
+            .query_row(
+                "SELECT 1 FROM seen_events WHERE gid = ?1",
+                params![gid],
+                |_| Ok(()),
+            )
+            .optional()?;
+        Ok(found.is_some())
+    }
+
+    /// Marks the event stored under `gid` seen. Marking it again keeps when it was first seen.
+    pub fn mark_seen(&self, gid: &str, headline: &str) -> Result<(), AppError> {
+        let seen_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_secs() as i64);
+        self.conn.lock().unwrap().execute(
+            "INSERT OR IGNORE INTO seen_events (gid, headline, seen_at) VALUES (?1, ?2, ?3)",
+            params![gid, headline, seen_at],
+        )?;
+        Ok(())
+    }
+
+    /// Returns the number of events marked seen.
+    pub fn count(&self) -> Result<usize, AppError> {
+        let conn = self.conn.lock().unwrap();
+        Ok(conn.query_row("SELECT COUNT(*) FROM seen_events", [], |row| row.get(0))?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_marked_events_are_seen() {
+        let store = SeenStore::open_in_memory().unwrap();
+        assert!(!store.is_seen("1:abc").unwrap());
+        assert_eq!(store.count().unwrap(), 0);
+
+        store.mark_seen("1:abc", "Patch 7.36").unwrap();
+        store.mark_seen("1:abc", "Patch 7.36").unwrap();
+        assert!(store.is_seen("1:abc").unwrap());
+        assert!(!store.is_seen("2:def").unwrap());
+        assert_eq!(store.count().unwrap(), 1);
+    }
+
+    #[test]
+    fn test_reopening_keeps_seen_events() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("seen.db");
+        SeenStore::open(&path)
+            .unwrap()
+            .mark_seen("1:abc", "Patch 7.36")
+            .unwrap();
+
+        let store = SeenStore::open(&path).unwrap();
+        assert!(store.is_seen("1:abc").unwrap());
+        let conn = store.conn.lock().unwrap();
+        let version: usize = conn
+            .query_row("PRAGMA user_version", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(version, MIGRATIONS.len());
+    }
+}
@@ -0,0 +1,891 @@
+use crate::bbcode::Markup;
+use crate::errors::AppError;
+use crate::file_part::write_json_atomically;
+use crate::settings::SettingsStore;
+use async_trait::async_trait;
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use teloxide::prelude::*;
+use teloxide::types::{InputFile, InputMedia, InputMediaPhoto, ParseMode};
+use teloxide::{Bot, RequestError};
+use tokio::time::Instant;
+use tracing::{error, info, warn};
+
+/// File undelivered messages are kept in while Telegram is unreachable.
+pub const FALLBACK_FILE: &str = "undelivered.json";
+
+/// How often `run_replay()` retries the messages kept during an outage.
+const REPLAY_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Maximum length of a single Telegram text message.
+pub const TELEGRAM_MAX_MESSAGE_LEN: usize = 4096;
+
+/// Smallest chunk size `/chunksize` allows.
+pub const MIN_CHUNK_SIZE: usize = 256;
+
+/// Most photos Telegram accepts in one media group.
+pub const MAX_MEDIA_GROUP_LEN: usize = 10;
+
+/// Most messages a `PacedSender` with a global interval sends per second, across all chats.
+/// Telegram's limit is about 30.
+pub const MAX_MESSAGES_PER_SECOND: u32 = 25;
+
+/// A destination that formatted updates can be delivered to.
+///
+/// Each platform has its own limit on how long a single message may be, so the chunking logic
+/// asks the sender for `max_message_len()` instead of assuming Telegram's limit.
+#[async_trait]
+pub trait MessageSender: Send + Sync {
+    /// Returns the maximum number of characters a single message may contain.
+    fn max_message_len(&self) -> usize;
+
+    /// Sends one already-chunked message written in `markup` to the given chat.
+    async fn send_text(
+        &self,
+        chat_id: ChatId,
+        text: &str,
+        markup: Markup,
+    ) -> Result<(), RequestError>;
+
+    /// Sends up to `MAX_MEDIA_GROUP_LEN` photos as one group, with `caption` written in `markup`
+    /// under the first. An empty caption is left out.
+    async fn send_photos(
+        &self,
+        chat_id: ChatId,
+        urls: &[String],
+        caption: &str,
+        markup: Markup,
+    ) -> Result<(), RequestError>;
+
+    /// Sends `content` as a file named `file_name`.
+    async fn send_document(
+        &self,
+        chat_id: ChatId,
+        file_name: &str,
+        content: &[u8],
+    ) -> Result<(), RequestError>;
+
+    /// Checks that the chat still exists and the bot may post there, without sending anything.
+    async fn check_chat(&self, chat_id: ChatId) -> Result<(), RequestError>;
+}
+
+/// Returns the Telegram parse mode of `markup`, or `None` for plain text.
+pub fn parse_mode(markup: Markup) -> Option<ParseMode> {
+    match markup {
+        Markup::MarkdownV2 => Some(ParseMode::MarkdownV2),
+        Markup::Html => Some(ParseMode::Html),
+        Markup::Plain => None,
+    }
+}
+
+/// Sends messages through the Telegram Bot API, with the parse mode matching their markup.
+pub struct TelegramSender {
+    bot: Bot,
+    max_message_len: usize,
+}
+
+impl TelegramSender {
+    /// Creates a sender for the given bot. The length limit is capped at Telegram's maximum.
+    pub fn new(bot: Bot, max_message_len: usize) -> Self {
+        Self {
+            bot,
+            max_message_len: max_message_len.min(TELEGRAM_MAX_MESSAGE_LEN),
+        }
+    }
+}
+
+#[async_trait]
+impl MessageSender for TelegramSender {
+    fn max_message_len(&self) -> usize {
+        self.max_message_len
+    }
+
+    async fn send_text(
+        &self,
+        chat_id: ChatId,
+        text: &str,
+        markup: Markup,
+    ) -> Result<(), RequestError> {
+        let request = self.bot.send_message(chat_id, text);
+        match parse_mode(markup) {
+            Some(mode) => request.parse_mode(mode).await?,
+            None => request.await?,
+        };
+        Ok(())
+    }
+
+    /// Photo URLs that don't parse are skipped. A single photo is sent on its own, since a
+    /// media group needs at least two.
+    async fn send_photos(
+        &self,
+        chat_id: ChatId,
+        urls: &[String],
+        caption: &str,
+        markup: Markup,
+    ) -> Result<(), RequestError> {
+        let mut photos: Vec<InputMediaPhoto> = urls
+            .iter()
+            .filter_map(|url| Url::parse(url).ok())
+            .map(|url| InputMediaPhoto::new(InputFile::url(url)))
+            .collect();
+        if let Some(first) = photos.first_mut().filter(|_| !caption.is_empty()) {
+            first.caption = Some(caption.to_string());
+            first.parse_mode = parse_mode(markup);
+        }
+        match photos.len() {
+            0 => {}
+            1 => {
+                let photo = photos.remove(0);
+                let mut request = self.bot.send_photo(chat_id, photo.media);
+                if let Some(caption) = photo.caption {
+                    request = request.caption(caption);
+                }
+                if let Some(mode) = photo.parse_mode {
+                    request = request.parse_mode(mode);
+                }
+                request.await?;
+            }
+            _ => {
+                self.bot
+                    .send_media_group(chat_id, photos.into_iter().map(InputMedia::Photo))
+                    .await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn send_document(
+        &self,
+        chat_id: ChatId,
+        file_name: &str,
+        content: &[u8],
+    ) -> Result<(), RequestError> {
+        let file = InputFile::memory(content.to_vec()).file_name(file_name.to_string());
+        self.bot.send_document(chat_id, file).await?;
+        Ok(())
+    }
+
+    /// Looks the chat up with `getChat`, which fails once the bot was removed or blocked.
+    async fn check_chat(&self, chat_id: ChatId) -> Result<(), RequestError> {
+        self.bot.get_chat(chat_id).await?;
+        Ok(())
+    }
+}
+
+/// Caps the message length of the inner sender at the chunk size set with `/chunksize`.
+///
+/// The setting is read on every call, so a change applies from the next send on. Without one,
+/// the inner sender's limit is used.
+pub struct ChunkSizeSender<S> {
+    inner: S,
+    settings: Arc<SettingsStore>,
+}
+
+impl<S: MessageSender> ChunkSizeSender<S> {
+    pub fn new(inner: S, settings: Arc<SettingsStore>) -> Self {
+        Self { inner, settings }
+    }
+
+    #[cfg(test)]
+    pub fn inner(&self) -> &S {
+        &self.inner
+    }
+}
+
+#[async_trait]
+impl<S: MessageSender> MessageSender for ChunkSizeSender<S> {
+    fn max_message_len(&self) -> usize {
+        let limit = self.inner.max_message_len();
+        self.settings
+            .get()
+            .chunk_size
+            .map_or(limit, |chunk_size| chunk_size.min(limit))
+    }
+
+    async fn send_text(
+        &self,
+        chat_id: ChatId,
+        text: &str,
+        markup: Markup,
+    ) -> Result<(), RequestError> {
+        self.inner.send_text(chat_id, text, markup).await
+    }
+
+    async fn send_photos(
+        &self,
+        chat_id: ChatId,
+        urls: &[String],
+        caption: &str,
+        markup: Markup,
+    ) -> Result<(), RequestError> {
+        self.inner.send_photos(chat_id, urls, caption, markup).await
+    }
+
+    async fn send_document(
+        &self,
+        chat_id: ChatId,
+        file_name: &str,
+        content: &[u8],
+    ) -> Result<(), RequestError> {
+        self.inner.send_document(chat_id, file_name, content).await
+    }
+
+    async fn check_chat(&self, chat_id: ChatId) -> Result<(), RequestError> {
+        self.inner.check_chat(chat_id).await
+    }
+}
+
+/// Spaces consecutive messages to the same chat by a minimum interval.
+///
+/// Telegram allows roughly one message per second per chat for some chat types, so multi-chunk
+/// updates are paced per chat to avoid flood control. Different chats don't wait on each other,
+/// unless a global interval is set with `with_global_interval()`.
+pub struct PacedSender<S> {
+    inner: S,
+    interval: Duration,
+    global_interval: Duration,
+    next_slot: Mutex<HashMap<ChatId, Instant>>,
+    next_global_slot: Mutex<Option<Instant>>,
+}
+
+impl<S: MessageSender> PacedSender<S> {
+    pub fn new(inner: S, interval: Duration) -> Self {
+        Self {
+            inner,
+            interval,
+            global_interval: Duration::ZERO,
+            next_slot: Mutex::new(HashMap::new()),
+            next_global_slot: Mutex::new(None),
+        }
+    }
+
+    /// Also spaces messages to different chats by `interval`, so a broadcast to many
+    /// subscribers stays under Telegram's overall limit. The interval is raised to keep the rate
+    /// at most `MAX_MESSAGES_PER_SECOND`.
+    pub fn with_global_interval(mut self, interval: Duration) -> Self {
+        self.global_interval = interval.max(Duration::from_secs(1) / MAX_MESSAGES_PER_SECOND);
+        self
+    }
+
+    /// Waits until the chat's next free slot, and the next global one, and reserves the slots
+    /// after it.
+    async fn wait_for_slot(&self, chat_id: ChatId) {
+        let slot = {
+            let mut next_slot = self.next_slot.lock().unwrap();
+            let mut next_global_slot = self.next_global_slot.lock().unwrap();
+            let now = Instant::now();
+            let slot = next_slot
+                .get(&chat_id)
+                .into_iter()
+                .chain(next_global_slot.as_ref())
+                .fold(now, |slot, &next| slot.max(next));
+            next_slot.insert(chat_id, slot + self.interval);
+            *next_global_slot = Some(slot + self.global_interval);
+            slot
+        };
+        tokio::time::sleep_until(slot).await;
+    }
+}
+
+#[async_trait]
+impl<S: MessageSender> MessageSender for PacedSender<S> {
+    fn max_message_len(&self) -> usize {
+        self.inner.max_message_len()
+    }
+
+    async fn send_text(
+        &self,
+        chat_id: ChatId,
+        text: &str,
+        markup: Markup,
+    ) -> Result<(), RequestError> {
+        self.wait_for_slot(chat_id).await;
+        self.inner.send_text(chat_id, text, markup).await
+    }
+
+    async fn send_photos(
+        &self,
+        chat_id: ChatId,
+        urls: &[String],
+        caption: &str,
+        markup: Markup,
+    ) -> Result<(), RequestError> {
+        self.wait_for_slot(chat_id).await;
+        self.inner.send_photos(chat_id, urls, caption, markup).await
+    }
+
+    async fn send_document(
+        &self,
+        chat_id: ChatId,
+        file_name: &str,
+        content: &[u8],
+    ) -> Result<(), RequestError> {
+        self.wait_for_slot(chat_id).await;
+        self.inner.send_document(chat_id, file_name, content).await
+    }
+
+    async fn check_chat(&self, chat_id: ChatId) -> Result<(), RequestError> {
+        self.inner.check_chat(chat_id).await
+    }
+}
+
+/// Logs every message instead of sending it, for trying out formatting against the live feed.
+///
+/// The inner sender is never called; only its `max_message_len()` is used, so messages are
+/// chunked as they would be for real.
+pub struct DryRunSender<S> {
+    inner: S,
+}
+
+impl<S: MessageSender> DryRunSender<S> {
+    pub fn new(inner: S) -> Self {
+        Self { inner }
+    }
+}
+
+#[async_trait]
+impl<S: MessageSender> MessageSender for DryRunSender<S> {
+    fn max_message_len(&self) -> usize {
+        self.inner.max_message_len()
+    }
+
+    async fn send_text(
+        &self,
+        chat_id: ChatId,
+        text: &str,
+        markup: Markup,
+    ) -> Result<(), RequestError> {
+        info!(
+            "Dry run, not sending to chat {} ({:?}):\n{}",
+            chat_id, markup, text
+        );
+        Ok(())
+    }
+
+    async fn send_photos(
+        &self,
+        chat_id: ChatId,
+        urls: &[String],
+        caption: &str,
+        _markup: Markup,
+    ) -> Result<(), RequestError> {
+        info!(
+            "Dry run, not sending {} photos to chat {} captioned {:?}: {:?}",
+            urls.len(),
+            chat_id,
+            caption,
+            urls
+        );
+        Ok(())
+    }
+
+    async fn send_document(
+        &self,
+        chat_id: ChatId,
+        file_name: &str,
+        content: &[u8],
+    ) -> Result<(), RequestError> {
+        info!(
+            "Dry run, not sending {} ({} bytes) to chat {}",
+            file_name,
+            content.len(),
+            chat_id
+        );
+        Ok(())
+    }
+
+    async fn check_chat(&self, _chat_id: ChatId) -> Result<(), RequestError> {
+        Ok(())
+    }
+}
+
+/// A message that couldn't be delivered during an outage.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+struct Undelivered {
+    chat_id: i64,
+    text: String,
+    #[serde(default)]
+    markup: Markup,
+}
+
+/// Returns `true` if the error means Telegram couldn't be reached at all, rather than that it
+/// refused this one message.
+fn is_unreachable(err: &RequestError) -> bool {
+    matches!(err, RequestError::Network(_) | RequestError::Io(_))
+}
+
+/// Keeps messages in a file when Telegram has been unreachable for a while, and replays them
+/// once it is back.
+///
+/// Until sends have failed for `outage_after`, errors are returned as usual. After that, every
+/// message that fails to send is appended to the file and counted as sent, so an outage loses
+/// nothing even across restarts. The kept messages are retried, oldest first, before each send and
+/// by `run_replay()`, and the first one that still fails stops the replay.
+pub struct FallbackSender<S> {
+    inner: S,
+    path: PathBuf,
+    outage_after: Duration,
+    failing_since: Mutex<Option<Instant>>,
+    undelivered: Mutex<Vec<Undelivered>>,
+}
+
+impl<S: MessageSender> FallbackSender<S> {
+    /// Wraps `inner`, loading messages kept at `path` by an earlier run.
+    pub fn open(
+        inner: S,
+        path: impl Into<PathBuf>,
+        outage_after: Duration,
+    ) -> Result<Self, AppError> {
+        let path = path.into();
+        let undelivered: Vec<Undelivered> = if path.exists() {
+            serde_json::from_str(&fs::read_to_string(&path)?)?
+        } else {
+            Vec::new()
+        };
+        if !undelivered.is_empty() {
+            info!(
+                "{} undelivered messages will be replayed.",
+                undelivered.len()
+            );
+        }
+        Ok(Self {
+            inner,
+            path,
+            outage_after,
+            failing_since: Mutex::new(None),
+            undelivered: Mutex::new(undelivered),
+        })
+    }
+
+    /// Appends messages to the file, after the ones already kept if `at_end`, before them
+    /// otherwise.
+    fn keep(&self, messages: Vec<Undelivered>, at_end: bool) -> Result<(), AppError> {
+        let mut undelivered = self.undelivered.lock().unwrap();
+        if at_end {
+            undelivered.extend(messages);
+        } else {
+            undelivered.splice(0..0, messages);
+        }
+        write_json_atomically(&self.path, &*undelivered)
+    }
+
+    /// Sends the kept messages until one fails, and keeps that one and the rest.
+    async fn replay(&self) {
+        let messages = {
+            let mut undelivered = self.undelivered.lock().unwrap();
+            if undelivered.is_empty() {
+                return;
+            }
+            std::mem::take(&mut *undelivered)
+        };
+        let mut messages = messages.into_iter();
+        let mut replayed = 0;
+        while let Some(message) = messages.next() {
+            if self
+                .inner
+                .send_text(ChatId(message.chat_id), &message.text, message.markup)
+                .await
+                .is_err()
+            {
+                let rest = std::iter::once(message).chain(messages).collect();
+                if let Err(err) = self.keep(rest, false) {
+                    error!("Failed to keep undelivered messages: {}", err);
+                }
+                return;
+            }
+            replayed += 1;
+        }
+        *self.failing_since.lock().unwrap() = None;
+        if let Err(err) = self.keep(Vec::new(), true) {
+            error!("Failed to clear undelivered messages: {}", err);
+        }
+        info!("Replayed {} undelivered messages.", replayed);
+    }
+}
+
+/// Replays the messages `sender` kept right away, so those of an earlier run go out at startup,
+/// and then every `REPLAY_INTERVAL` for as long as the bot runs, so they don't wait for the next
+/// update to be sent.
+pub async fn run_replay<S: MessageSender>(sender: Arc<FallbackSender<S>>) {
+    let mut interval = tokio::time::interval(REPLAY_INTERVAL);
+    loop {
+        interval.tick().await;
+        sender.replay().await;
+    }
+}
+
+#[async_trait]
+impl<S: MessageSender> MessageSender for FallbackSender<S> {
+    fn max_message_len(&self) -> usize {
+        self.inner.max_message_len()
+    }
+
+    async fn send_text(
+        &self,
+        chat_id: ChatId,
+        text: &str,
+        markup: Markup,
+    ) -> Result<(), RequestError> {
+        self.replay().await;
+        let err = match self.inner.send_text(chat_id, text, markup).await {
+            Ok(()) => {
+                *self.failing_since.lock().unwrap() = None;
+                return Ok(());
+            }
+            Err(err) if is_unreachable(&err) => err,
+            Err(err) => return Err(err),
+        };
+        let failing_since = *self
+            .failing_since
+            .lock()
+            .unwrap()
+            .get_or_insert_with(Instant::now);
+        if failing_since.elapsed() < self.outage_after {
+            return Err(err);
+        }
+        warn!(
+            "Telegram is unreachable, keeping the message to chat {} for later: {}",
+            chat_id, err
+        );
+        let message = Undelivered {
+            chat_id: chat_id.0,
+            text: text.to_string(),
+            markup,
+        };
+        self.keep(vec![message], true)
+            .map_err(|err| RequestError::Io(io::Error::other(err.to_string())))
+    }
+
+    /// Photos aren't kept during an outage, since the text of the update already is.
+    async fn send_photos(
+        &self,
+        chat_id: ChatId,
+        urls: &[String],
+        caption: &str,
+        markup: Markup,
+    ) -> Result<(), RequestError> {
+        self.inner.send_photos(chat_id, urls, caption, markup).await
+    }
+
+    async fn send_document(
+        &self,
+        chat_id: ChatId,
+        file_name: &str,
+        content: &[u8],
+    ) -> Result<(), RequestError> {
+        self.inner.send_document(chat_id, file_name, content).await
+    }
+
+    async fn check_chat(&self, chat_id: ChatId) -> Result<(), RequestError> {
+        self.inner.check_chat(chat_id).await
+    }
+}
+
+/// Decides whether a test sender rejects a message, and with which error.
+#[cfg(test)]
+type RejectFn = dyn Fn(ChatId, &str) -> Option<RequestError> + Send + Sync;
+
+/// A sender that records every message instead of delivering it.
+#[cfg(test)]
+pub struct RecordingSender {
+    pub max_message_len: usize,
+    pub sent: Mutex<Vec<(ChatId, String)>>,
+    pub sent_at: Mutex<Vec<Instant>>,
+    /// Photo groups sent, with their captions.
+    pub photos: Mutex<Vec<(ChatId, Vec<String>, String)>>,
+    /// Files sent, by name.
+    pub documents: Mutex<Vec<(ChatId, String, Vec<u8>)>>,
+    reject: Box<RejectFn>,
+}
+
+#[cfg(test)]
+impl RecordingSender {
+    pub fn new(max_message_len: usize) -> Self {
+        Self::rejecting(max_message_len, |_, _| None)
+    }
+
+    /// Creates a sender that fails a message whenever `reject` returns an error for it.
+    pub fn rejecting(
+        max_message_len: usize,
+        reject: impl Fn(ChatId, &str) -> Option<RequestError> + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            max_message_len,
+            sent: Mutex::new(Vec::new()),
+            sent_at: Mutex::new(Vec::new()),
+            photos: Mutex::new(Vec::new()),
+            documents: Mutex::new(Vec::new()),
+            reject: Box::new(reject),
+        }
+    }
+
+    pub fn sent(&self) -> Vec<(ChatId, String)> {
+        self.sent.lock().unwrap().clone()
+    }
+}
+
+#[cfg(test)]
+#[async_trait]
+impl MessageSender for RecordingSender {
+    fn max_message_len(&self) -> usize {
+        self.max_message_len
+    }
+
+    async fn send_text(
+        &self,
+        chat_id: ChatId,
+        text: &str,
+        _markup: Markup,
+    ) -> Result<(), RequestError> {
+        if let Some(err) = (self.reject)(chat_id, text) {
+            return Err(err);
+        }
+        self.sent.lock().unwrap().push((chat_id, text.to_string()));
+        self.sent_at.lock().unwrap().push(Instant::now());
+        Ok(())
+    }
+
+    async fn send_photos(
+        &self,
+        chat_id: ChatId,
+        urls: &[String],
+        caption: &str,
+        _markup: Markup,
+    ) -> Result<(), RequestError> {
+        self.photos
+            .lock()
+            .unwrap()
+            .push((chat_id, urls.to_vec(), caption.to_string()));
+        Ok(())
+    }
+
+    async fn send_document(
+        &self,
+        chat_id: ChatId,
+        file_name: &str,
+        content: &[u8],
+    ) -> Result<(), RequestError> {
+        self.documents
+            .lock()
+            .unwrap()
+            .push((chat_id, file_name.to_string(), content.to_vec()));
+        Ok(())
+    }
+
+    /// Fails the check whenever `reject` returns an error for an empty message to the chat.
+    async fn check_chat(&self, chat_id: ChatId) -> Result<(), RequestError> {
+        match (self.reject)(chat_id, "") {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn test_dry_run_sends_nothing() {
+        let server = wiremock::MockServer::start().await;
+        wiremock::Mock::given(wiremock::matchers::any())
+            .respond_with(wiremock::ResponseTemplate::new(200))
+            .expect(0)
+            .mount(&server)
+            .await;
+        let bot = Bot::new("1:token").set_api_url(server.uri().parse().unwrap());
+        let sender = DryRunSender::new(TelegramSender::new(bot, 100));
+
+        sender
+            .send_text(ChatId(1), "*Patch*", Markup::MarkdownV2)
+            .await
+            .unwrap();
+        sender
+            .send_photos(
+                ChatId(1),
+                &["https://example.com/a.png".to_string()],
+                "",
+                Markup::Plain,
+            )
+            .await
+            .unwrap();
+        sender
+            .send_document(ChatId(1), "a.txt", b"a")
+            .await
+            .unwrap();
+        assert_eq!(sender.max_message_len(), 100);
+    }
+
+    #[test]
+    fn test_telegram_sender_caps_max_message_len() {
+        let sender = TelegramSender::new(Bot::new("1:token"), 10_000);
+        assert_eq!(sender.max_message_len(), TELEGRAM_MAX_MESSAGE_LEN);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_paced_sender_spaces_messages_to_the_same_chat() {
+        let interval = Duration::from_secs(1);
+        let sender = PacedSender::new(RecordingSender::new(4096), interval);
+        let start = Instant::now();
+
+        sender
+            .send_text(ChatId(1), "chunk 1", Markup::MarkdownV2)
+            .await
+            .unwrap();
+        sender
+            .send_text(ChatId(1), "chunk 2", Markup::MarkdownV2)
+            .await
+            .unwrap();
+        sender
+            .send_text(ChatId(2), "other chat", Markup::MarkdownV2)
+            .await
+            .unwrap();
+        sender
+            .send_text(ChatId(1), "chunk 3", Markup::MarkdownV2)
+            .await
+            .unwrap();
+
+        let sent_at = sender.inner.sent_at.lock().unwrap().clone();
+        let offsets: Vec<Duration> = sent_at.iter().map(|at| *at - start).collect();
+        assert_eq!(
+            offsets,
+            vec![Duration::ZERO, interval, interval, interval * 2,]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_paced_sender_limits_the_overall_rate() {
+        let interval = Duration::from_millis(50);
+        let sender = PacedSender::new(RecordingSender::new(4096), Duration::from_secs(1))
+            .with_global_interval(interval);
+        let start = Instant::now();
+
+        for chat in 0..30 {
+            sender
+                .send_text(ChatId(chat), "update", Markup::MarkdownV2)
+                .await
+                .unwrap();
+        }
+
+        assert!(start.elapsed() >= interval * 29);
+        let fast = PacedSender::new(RecordingSender::new(4096), Duration::ZERO)
+            .with_global_interval(Duration::ZERO);
+        let start = Instant::now();
+        for chat in 0..26 {
+            fast.send_text(ChatId(chat), "update", Markup::MarkdownV2)
+                .await
+                .unwrap();
+        }
+        assert!(start.elapsed() >= Duration::from_secs(1));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_fallback_sender_keeps_and_replays_messages() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(FALLBACK_FILE);
+        let down = Arc::new(AtomicBool::new(true));
+        let inner = RecordingSender::rejecting(4096, {
+            let down = Arc::clone(&down);
+            move |_, _| {
+                down.load(Ordering::Relaxed)
+                    .then(|| RequestError::Io(io::Error::other("unreachable")))
+            }
+        });
+        let outage_after = Duration::from_secs(60);
+        let sender = FallbackSender::open(inner, &path, outage_after).unwrap();
+
+        assert!(sender
+            .send_text(ChatId(1), "early", Markup::MarkdownV2)
+            .await
+            .is_err());
+        tokio::time::advance(outage_after).await;
+        sender
+            .send_text(ChatId(1), "update 1", Markup::MarkdownV2)
+            .await
+            .unwrap();
+        sender
+            .send_text(ChatId(2), "update 2", Markup::MarkdownV2)
+            .await
+            .unwrap();
+
+        let kept: Vec<Undelivered> =
+            serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+        let texts: Vec<&str> = kept.iter().map(|message| message.text.as_str()).collect();
+        assert_eq!(texts, vec!["update 1", "update 2"]);
+        assert!(sender.inner.sent().is_empty());
+
+        down.store(false, Ordering::Relaxed);
+        sender
+            .send_text(ChatId(3), "update 3", Markup::MarkdownV2)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            sender.inner.sent(),
+            vec![
+                (ChatId(1), "update 1".to_string()),
+                (ChatId(2), "update 2".to_string()),
+                (ChatId(3), "update 3".to_string()),
+            ]
+        );
+        assert_eq!(fs::read_to_string(&path).unwrap(), "[]");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_kept_messages_are_replayed_without_a_new_send() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(FALLBACK_FILE);
+        let kept = |text: &str| Undelivered {
+            chat_id: 1,
+            text: text.to_string(),
+            markup: Markup::MarkdownV2,
+        };
+        fs::write(
+            &path,
+            serde_json::to_string(&[kept("from the last run")]).unwrap(),
+        )
+        .unwrap();
+        let down = Arc::new(AtomicBool::new(false));
+        let inner = RecordingSender::rejecting(4096, {
+            let down = Arc::clone(&down);
+            move |_, _| {
+                down.load(Ordering::Relaxed)
+                    .then(|| RequestError::Io(io::Error::other("unreachable")))
+            }
+        });
+        let sender = Arc::new(FallbackSender::open(inner, &path, Duration::ZERO).unwrap());
+        tokio::spawn(run_replay(Arc::clone(&sender)));
+
+        tokio::task::yield_now().await;
+        assert_eq!(
+            sender.inner.sent(),
+            vec![(ChatId(1), "from the last run".to_string())]
+        );
+
+        down.store(true, Ordering::Relaxed);
+        sender
+            .send_text(ChatId(1), "during the outage", Markup::MarkdownV2)
+            .await
+            .unwrap();
+        down.store(false, Ordering::Relaxed);
+        tokio::time::advance(REPLAY_INTERVAL).await;
+        tokio::task::yield_now().await;
+
+        assert_eq!(
+            sender.inner.sent(),
+            vec![
+                (ChatId(1), "from the last run".to_string()),
+                (ChatId(1), "during the outage".to_string()),
+            ]
+        );
+        assert_eq!(fs::read_to_string(&path).unwrap(), "[]");
+    }
+}
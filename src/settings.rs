@@ -0,0 +1,150 @@
+use crate::errors::AppError;
+use crate::file_part::write_json_atomically;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+/// File used to persist settings changed at runtime through admin commands.
+pub const SETTINGS_FILE: &str = "settings.json";
+
+/// Settings that admins can change while the bot is running.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+pub struct Settings {
+    /// Message template set with `/template`; `None` uses the built-in one.
+    #[serde(default)]
+    pub template: Option<String>,
+    /// User ids granted admin access with `/admins add`, on top of the super admins.
+    #[serde(default)]
+    pub admins: Vec<u64>,
+    /// Number of events fetched per poll, set with `/count`; `None` uses `DEFAULT_POLL_COUNT`.
+    #[serde(default)]
+    pub poll_count: Option<u32>,
+    /// Longest message chunk, set with `/chunksize`; `None` uses the sender's limit.
+    #[serde(default)]
+    pub chunk_size: Option<usize>,
+    /// Optional parts of updates, toggled with `/fields`; `None` follows `BYLINES` and
+    /// `STEAM_LINKS`.
+    #[serde(default)]
+    pub fields: Option<Fields>,
+}
+
+/// Names of the `Fields` flags, in the order `/fields` lists them.
+pub const FIELD_NAMES: &[&str] = &["date", "author", "engagement", "footer", "permalink"];
+
+/// The optional parts of an update, each shown when its flag is on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Fields {
+    /// When the post was published, in UTC, at the top of the update.
+    pub date: bool,
+    /// A byline naming the post's author.
+    pub author: bool,
+    /// The post's upvote and comment counts.
+    pub engagement: bool,
+    /// The template's lines with the news page `{link}`.
+    pub footer: bool,
+    /// A "View on Steam" link to the post.
+    pub permalink: bool,
+}
+
+impl Default for Fields {
+    /// Only the footer, as updates were always sent.
+    fn default() -> Self {
+        Self {
+            date: false,
+            author: false,
+            engagement: false,
+            footer: true,
+            permalink: false,
+        }
+    }
+}
+
+impl Fields {
+    /// Returns the flag called `name`, one of `FIELD_NAMES`.
+    pub fn flag_mut(&mut self, name: &str) -> Option<&mut bool> {
+        match name {
+            "date" => Some(&mut self.date),
+            "author" => Some(&mut self.author),
+            "engagement" => Some(&mut self.engagement),
+            "footer" => Some(&mut self.footer),
+            "permalink" => Some(&mut self.permalink),
+            _ => None,
+        }
+    }
+
+    /// Returns each flag with its name, in `FIELD_NAMES` order.
+    pub fn flags(&self) -> [(&'static str, bool); 5] {
+        [
+            ("date", self.date),
+            ("author", self.author),
+            ("engagement", self.engagement),
+            ("footer", self.footer),
+            ("permalink", self.permalink),
+        ]
+    }
+}
+
+/// Runtime settings backed by a JSON file.
+pub struct SettingsStore {
+    path: PathBuf,
+    settings: Mutex<Settings>,
+}
+
+impl SettingsStore {
+    /// Opens the store at `path`, loading existing settings if the file exists.
+    pub fn open(path: impl Into<PathBuf>) -> Result<Self, AppError> {
+        let path = path.into();
+        let settings = if path.exists() {
+            serde_json::from_str(&fs::read_to_string(&path)?)?
+        } else {
+            Settings::default()
+        };
+        Ok(Self {
+            path,
+            settings: Mutex::new(settings),
+        })
+    }
+
+    /// Returns a snapshot of the current settings.
+    pub fn get(&self) -> Settings {
+        self.settings.lock().unwrap().clone()
+    }
+
+    /// Reads the settings file again, keeping the current settings if it doesn't exist.
+    pub fn reload(&self) -> Result<(), AppError> {
+        if self.path.exists() {
+            *self.settings.lock().unwrap() =
+                serde_json::from_str(&fs::read_to_string(&self.path)?)?;
+        }
+        Ok(())
+    }
+
+    /// Applies `change` to the settings and persists the result.
+    pub fn update(&self, change: impl FnOnce(&mut Settings)) -> Result<(), AppError> {
+        let mut settings = self.settings.lock().unwrap();
+        change(&mut settings);
+        write_json_atomically(&self.path, &*settings)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_settings_are_persisted() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(SETTINGS_FILE);
+        let store = SettingsStore::open(&path).unwrap();
+        assert_eq!(store.get(), Settings::default());
+
+        store
+            .update(|settings| settings.template = Some("{headline}".to_string()))
+            .unwrap();
+
+        let reopened = SettingsStore::open(&path).unwrap();
+        assert_eq!(reopened.get().template.as_deref(), Some("{headline}"));
+    }
+}
@@ -0,0 +1,98 @@
+//! Graceful shutdown on SIGTERM and SIGINT.
+//!
+//! The signal handler calls `Shutdown::trigger()`. The poll loop checks for it only between cycles,
+//! so a broadcast in flight is finished and the snapshot files are never left half renamed.
+
+use tokio::sync::watch;
+use tracing::{error, info};
+
+/// A flag that is set once the bot should stop, and that tasks can wait on.
+#[derive(Clone)]
+pub struct Shutdown {
+    sender: watch::Sender<bool>,
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Self {
+            sender: watch::channel(false).0,
+        }
+    }
+}
+
+impl Shutdown {
+    /// Asks every task waiting on this flag to stop.
+    pub fn trigger(&self) {
+        self.sender.send_replace(true);
+    }
+
+    /// Returns `true` once `trigger()` was called.
+    pub fn is_triggered(&self) -> bool {
+        *self.sender.borrow()
+    }
+
+    /// Waits until `trigger()` is called, returning right away if it already was.
+    pub async fn triggered(&self) {
+        let mut receiver = self.sender.subscribe();
+        // The sender lives in `self`, so waiting can't fail.
+        let _ = receiver.wait_for(|&triggered| triggered).await;
+    }
+}
+
+/// Waits for SIGTERM or SIGINT (Ctrl+C), then triggers `shutdown`.
+pub async fn trigger_on_signal(shutdown: Shutdown) {
+    let interrupt = tokio::signal::ctrl_c();
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                tokio::select! {
+                    _ = interrupt => info!("Got SIGINT, shutting down."),
+                    _ = terminate.recv() => info!("Got SIGTERM, shutting down."),
+                }
+            }
+            Err(err) => {
+                error!("Failed to listen for SIGTERM: {}", err);
+                let _ = interrupt.await;
+                info!("Got SIGINT, shutting down.");
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = interrupt.await;
+        info!("Got Ctrl+C, shutting down.");
+    }
+    shutdown.trigger();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_trigger_stops_waiting_tasks() {
+        let shutdown = Shutdown::default();
+        let waiter = tokio::spawn({
+            let shutdown = shutdown.clone();
+            async move {
+                tokio::select! {
+                    _ = tokio::time::sleep(Duration::from_secs(300)) => false,
+                    _ = shutdown.triggered() => true,
+                }
+            }
+        });
+        tokio::task::yield_now().await;
+        assert!(!shutdown.is_triggered());
+
+        shutdown.trigger();
+
+        assert!(waiter.await.unwrap());
+        assert!(shutdown.is_triggered());
+        tokio::time::timeout(Duration::from_secs(1), shutdown.triggered())
+            .await
+            .unwrap();
+    }
+}
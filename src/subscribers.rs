@@ -0,0 +1,354 @@
+use crate::errors::AppError;
+use crate::file_part::write_json_atomically;
+use crate::json_part::EventFilter;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+use teloxide::types::ChatId;
+use tracing::info;
+
+/// File used to persist subscribed chats between restarts.
+pub const SUBSCRIBERS_FILE: &str = "subscribers.json";
+
+/// How a chat receives new updates.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DeliveryMode {
+    /// Each update is sent as soon as it is found.
+    #[default]
+    Realtime,
+    /// Updates are queued and sent together once a day.
+    Digest,
+    /// Only a short notice with a link is sent for each new post.
+    Ping,
+}
+
+impl FromStr for DeliveryMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "realtime" => Ok(DeliveryMode::Realtime),
+            "digest" => Ok(DeliveryMode::Digest),
+            "ping" => Ok(DeliveryMode::Ping),
+            other => Err(format!("Unknown delivery mode: {}", other)),
+        }
+    }
+}
+
+/// A chat that receives new updates.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct Subscriber {
+    pub chat_id: i64,
+    /// Only events in this category are sent; `None` means all events.
+    #[serde(default)]
+    pub filter: Option<EventFilter>,
+    /// Steam language name (e.g. `russian`); `None` means the language of the polled feed.
+    #[serde(default)]
+    pub lang: Option<String>,
+    #[serde(default)]
+    pub mode: DeliveryMode,
+    /// Formatted updates waiting for the next daily delivery in `DeliveryMode::Digest`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub pending: Vec<String>,
+    /// Formatted real-time updates held back during quiet hours, sent once they end.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub held: Vec<String>,
+    /// IANA time zone name (e.g. `Europe/Berlin`) quiet hours are read in; `None` means UTC.
+    #[serde(default)]
+    pub tz: Option<String>,
+}
+
+impl Subscriber {
+    /// Creates a subscriber that receives all events in the default language.
+    pub fn new(chat_id: ChatId) -> Self {
+        Self {
+            chat_id: chat_id.0,
+            filter: None,
+            lang: None,
+            mode: DeliveryMode::Realtime,
+            pending: Vec::new(),
+            held: Vec::new(),
+            tz: None,
+        }
+    }
+}
+
+/// Subscribed chats backed by a JSON file.
+///
+/// Every change is written with `write_json_atomically()` while the in-memory list is locked, so
+/// concurrent writers never leave a half-written file.
+pub struct SubscriberStore {
+    path: PathBuf,
+    subscribers: Mutex<Vec<Subscriber>>,
+}
+
+impl SubscriberStore {
+    /// Opens the store at `path`, loading existing subscribers if the file exists.
+    pub fn open(path: impl Into<PathBuf>) -> Result<Self, AppError> {
+        let path = path.into();
+        let subscribers = if path.exists() {
+            serde_json::from_str(&fs::read_to_string(&path)?)?
+        } else {
+            Vec::new()
+        };
+        Ok(Self {
+            path,
+            subscribers: Mutex::new(subscribers),
+        })
+    }
+
+    /// Adds a subscriber to the store, replacing the preferences of an existing one.
+    /// Returns `Ok(false)` if the chat was already subscribed.
+    pub fn add_subscriber(&self, subscriber: Subscriber) -> Result<bool, AppError> {
+        let mut subscribers = self.subscribers.lock().unwrap();
+        let chat_id = subscriber.chat_id;
+        let added = match subscribers.iter_mut().find(|s| s.chat_id == chat_id) {
+            Some(existing) => {
+                *existing = subscriber;
+                false
+            }
+            None => {
+                subscribers.push(subscriber);
+                true
+            }
+        };
+        write_json_atomically(&self.path, &*subscribers)?;
+        info!("Subscribed chat {}", chat_id);
+        Ok(added)
+    }
+
+    /// Removes a chat from the store. Returns `Ok(false)` if the chat wasn't subscribed.
+    pub fn remove_subscriber(&self, chat_id: ChatId) -> Result<bool, AppError> {
+        let mut subscribers = self.subscribers.lock().unwrap();
+        let count = subscribers.len();
+        subscribers.retain(|s| s.chat_id != chat_id.0);
+        if subscribers.len() == count {
+            return Ok(false);
+        }
+        write_json_atomically(&self.path, &*subscribers)?;
+        info!("Unsubscribed chat {}", chat_id);
+        Ok(true)
+    }
+
+    /// Sets how a subscribed chat receives updates. Returns `Ok(false)` if the chat isn't
+    /// subscribed.
+    pub fn set_mode(&self, chat_id: ChatId, mode: DeliveryMode) -> Result<bool, AppError> {
+        let mut subscribers = self.subscribers.lock().unwrap();
+        let Some(subscriber) = subscribers.iter_mut().find(|s| s.chat_id == chat_id.0) else {
+            return Ok(false);
+        };
+        subscriber.mode = mode;
+        write_json_atomically(&self.path, &*subscribers)?;
+        info!("Chat {} switched to {:?} delivery", chat_id, mode);
+        Ok(true)
+    }
+
+    /// Sets the time zone of a subscribed chat. Returns `Ok(false)` if the chat isn't subscribed.
+    pub fn set_timezone(&self, chat_id: ChatId, tz: &str) -> Result<bool, AppError> {
+        let mut subscribers = self.subscribers.lock().unwrap();
+        let Some(subscriber) = subscribers.iter_mut().find(|s| s.chat_id == chat_id.0) else {
+            return Ok(false);
+        };
+        subscriber.tz = Some(tz.to_string());
+        write_json_atomically(&self.path, &*subscribers)?;
+        info!("Chat {} set its time zone to {}", chat_id, tz);
+        Ok(true)
+    }
+
+    /// Queues updates for a chat's next daily delivery.
+    pub fn queue_updates(&self, chat_id: ChatId, updates: &[String]) -> Result<(), AppError> {
+        let mut subscribers = self.subscribers.lock().unwrap();
+        if let Some(subscriber) = subscribers.iter_mut().find(|s| s.chat_id == chat_id.0) {
+            subscriber.pending.extend_from_slice(updates);
+            write_json_atomically(&self.path, &*subscribers)?;
+        }
+        Ok(())
+    }
+
+    /// Returns the queued updates of every chat that has some. They stay queued until
+    /// `clear_pending()` is called for the chat.
+    pub fn pending_updates(&self) -> Vec<(ChatId, Vec<String>)> {
+        self.subscribers
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|s| !s.pending.is_empty())
+            .map(|s| (ChatId(s.chat_id), s.pending.clone()))
+            .collect()
+    }
+
+    /// Removes the first `delivered` queued updates of a chat, once they were sent. Updates
+    /// queued since `pending_updates()` was read stay queued.
+    pub fn clear_pending(&self, chat_id: ChatId, delivered: usize) -> Result<(), AppError> {
+        let mut subscribers = self.subscribers.lock().unwrap();
+        if let Some(subscriber) = subscribers.iter_mut().find(|s| s.chat_id == chat_id.0) {
+            let delivered = delivered.min(subscriber.pending.len());
+            subscriber.pending.drain(..delivered);
+            write_json_atomically(&self.path, &*subscribers)?;
+        }
+        Ok(())
+    }
+
+    /// Holds real-time updates back from a chat until its quiet hours end.
+    pub fn hold_updates(&self, chat_id: ChatId, updates: &[String]) -> Result<(), AppError> {
+        let mut subscribers = self.subscribers.lock().unwrap();
+        if let Some(subscriber) = subscribers.iter_mut().find(|s| s.chat_id == chat_id.0) {
+            subscriber.held.extend_from_slice(updates);
+            write_json_atomically(&self.path, &*subscribers)?;
+        }
+        Ok(())
+    }
+
+    /// Returns every chat that has updates held back. They stay held until `clear_held()` is
+    /// called for the chat.
+    pub fn held_updates(&self) -> Vec<Subscriber> {
+        self.subscribers
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|s| !s.held.is_empty())
+            .cloned()
+            .collect()
+    }
+
+    /// Removes the first `delivered` held updates of a chat, once they were sent.
+    pub fn clear_held(&self, chat_id: ChatId, delivered: usize) -> Result<(), AppError> {
+        let mut subscribers = self.subscribers.lock().unwrap();
+        if let Some(subscriber) = subscribers.iter_mut().find(|s| s.chat_id == chat_id.0) {
+            let delivered = delivered.min(subscriber.held.len());
+            subscriber.held.drain(..delivered);
+            write_json_atomically(&self.path, &*subscribers)?;
+        }
+        Ok(())
+    }
+
+    /// Returns the record of a subscribed chat, or `None` if it isn't subscribed.
+    pub fn subscriber(&self, chat_id: ChatId) -> Option<Subscriber> {
+        self.subscribers
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|s| s.chat_id == chat_id.0)
+            .cloned()
+    }
+
+    /// Returns a snapshot of all subscribed chats.
+    pub fn all_subscribers(&self) -> Vec<Subscriber> {
+        self.subscribers.lock().unwrap().clone()
+    }
+}
+
+/// How many failures meaning a chat is gone unsubscribe it, set by `PRUNE_AFTER_FAILURES` and
+/// `PRUNE_FAILURE_WINDOW_SECS`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PrunePolicy {
+    /// Failures in a row a chat is unsubscribed after.
+    pub failures: u32,
+    /// A streak whose first failure is older than this starts over.
+    pub window: Duration,
+}
+
+/// Failures in a row of each chat, kept in memory, so a chat that restricts the bot for a moment
+/// isn't unsubscribed on the first error.
+#[derive(Default)]
+pub struct FailureStreaks {
+    /// Number of failures and the time of the first, by chat.
+    streaks: Mutex<HashMap<ChatId, (u32, SystemTime)>>,
+}
+
+impl FailureStreaks {
+    /// Counts a failure of `chat_id` at `now`. Returns `true` once the chat has failed
+    /// `policy.failures` times in a row within `policy.window`, and forgets its streak.
+    pub fn record_failure(&self, chat_id: ChatId, now: SystemTime, policy: PrunePolicy) -> bool {
+        let mut streaks = self.streaks.lock().unwrap();
+        let (count, since) = streaks.entry(chat_id).or_insert((0, now));
+        if now.duration_since(*since).unwrap_or_default() > policy.window {
+            *count = 0;
+            *since = now;
+        }
+        *count += 1;
+        if *count < policy.failures {
+            return false;
+        }
+        streaks.remove(&chat_id);
+        true
+    }
+
+    /// Ends the streak of a chat that was delivered to.
+    pub fn record_success(&self, chat_id: ChatId) {
+        self.streaks.lock().unwrap().remove(&chat_id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_failure_streaks_reach_the_policy() {
+        let policy = PrunePolicy {
+            failures: 3,
+            window: Duration::from_secs(60),
+        };
+        let at = |secs| SystemTime::UNIX_EPOCH + Duration::from_secs(secs);
+        let streaks = FailureStreaks::default();
+
+        assert!(!streaks.record_failure(ChatId(1), at(0), policy));
+        assert!(!streaks.record_failure(ChatId(1), at(10), policy));
+        streaks.record_success(ChatId(1));
+        assert!(!streaks.record_failure(ChatId(1), at(20), policy));
+        assert!(!streaks.record_failure(ChatId(1), at(30), policy));
+        assert!(!streaks.record_failure(ChatId(1), at(100), policy));
+        assert!(!streaks.record_failure(ChatId(1), at(110), policy));
+        assert!(streaks.record_failure(ChatId(1), at(120), policy));
+    }
+
+    #[test]
+    fn test_add_subscriber_is_idempotent() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = SubscriberStore::open(dir.path().join(SUBSCRIBERS_FILE)).unwrap();
+
+        assert!(store.add_subscriber(Subscriber::new(ChatId(1))).unwrap());
+        assert!(!store.add_subscriber(Subscriber::new(ChatId(1))).unwrap());
+        assert_eq!(store.all_subscribers(), vec![Subscriber::new(ChatId(1))]);
+    }
+
+    #[test]
+    fn test_remove_subscriber() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(SUBSCRIBERS_FILE);
+        let store = SubscriberStore::open(&path).unwrap();
+        store.add_subscriber(Subscriber::new(ChatId(1))).unwrap();
+        store.add_subscriber(Subscriber::new(ChatId(2))).unwrap();
+
+        assert!(store.remove_subscriber(ChatId(1)).unwrap());
+        assert!(!store.remove_subscriber(ChatId(1)).unwrap());
+        let reopened = SubscriberStore::open(&path).unwrap();
+        assert_eq!(reopened.all_subscribers(), vec![Subscriber::new(ChatId(2))]);
+    }
+
+    #[test]
+    fn test_subscribers_are_persisted() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(SUBSCRIBERS_FILE);
+        let store = SubscriberStore::open(&path).unwrap();
+        let subscriber = Subscriber {
+            chat_id: 1,
+            filter: Some(EventFilter::News),
+            lang: Some("russian".to_string()),
+            mode: DeliveryMode::Digest,
+            pending: vec!["update".to_string()],
+            held: vec!["quiet update".to_string()],
+            tz: Some("Europe/Moscow".to_string()),
+        };
+        store.add_subscriber(subscriber.clone()).unwrap();
+
+        let reopened = SubscriberStore::open(&path).unwrap();
+        assert_eq!(reopened.all_subscribers(), vec![subscriber]);
+    }
+}
@@ -0,0 +1,158 @@
+//! Opt-in anonymous usage telemetry.
+//!
+//! Telemetry is off unless `TELEMETRY=on` is set. When enabled, the bot periodically POSTs a small
+//! JSON report to `TELEMETRY_URL` containing only a coarse subscriber count bucket, the number of
+//! updates sent since startup, and the crate version. No chat ids, usernames, or message content
+//! are ever included. Failures to reach the endpoint are logged at debug level and otherwise
+//! ignored.
+
+use crate::subscribers::SubscriberStore;
+use serde::Serialize;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{debug, info};
+
+/// Anonymous counters sent in each telemetry report.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct TelemetryReport {
+    pub subscribers: &'static str,
+    pub updates_sent: u64,
+    pub version: &'static str,
+}
+
+/// Rounds a subscriber count into a coarse bucket so exact numbers are never reported.
+pub fn subscriber_bucket(count: usize) -> &'static str {
+    match count {
+        0 => "0",
+        1..=10 => "1-10",
+        11..=100 => "11-100",
+        101..=1000 => "101-1000",
+        _ => "1000+",
+    }
+}
+
+/// Collects usage counters and reports them when telemetry is enabled.
+pub struct Telemetry {
+    endpoint: Option<String>,
+    updates_sent: AtomicU64,
+    client: reqwest::Client,
+}
+
+impl Telemetry {
+    /// Creates a telemetry collector. Passing `None` disables reporting entirely.
+    pub fn new(endpoint: Option<String>) -> Self {
+        Self {
+            endpoint,
+            updates_sent: AtomicU64::new(0),
+            client: reqwest::Client::new(),
+        }
+    }
+
+    /// Returns `true` if reports are sent.
+    pub fn is_enabled(&self) -> bool {
+        self.endpoint.is_some()
+    }
+
+    /// Counts one update delivered to a chat.
+    pub fn record_update_sent(&self) {
+        self.updates_sent.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Builds the report for the given number of subscribers.
+    pub fn report(&self, subscriber_count: usize) -> TelemetryReport {
+        TelemetryReport {
+            subscribers: subscriber_bucket(subscriber_count),
+            updates_sent: self.updates_sent.load(Ordering::Relaxed),
+            version: env!("CARGO_PKG_VERSION"),
+        }
+    }
+
+    /// POSTs a report to the endpoint. Does nothing when telemetry is disabled and never fails.
+    pub async fn send_report(&self, subscriber_count: usize) {
+        let Some(endpoint) = &self.endpoint else {
+            return;
+        };
+        let result = self
+            .client
+            .post(endpoint)
+            .json(&self.report(subscriber_count))
+            .send()
+            .await
+            .and_then(|response| response.error_for_status());
+        match result {
+            Ok(_) => debug!("Telemetry report sent."),
+            Err(err) => debug!("Failed to send telemetry report: {}", err),
+        }
+    }
+}
+
+/// Sends a telemetry report every `interval` for as long as the bot runs.
+pub async fn run_reporter(
+    telemetry: Arc<Telemetry>,
+    subscribers: Arc<SubscriberStore>,
+    interval: Duration,
+) {
+    if !telemetry.is_enabled() {
+        return;
+    }
+    info!("Telemetry is enabled, reporting every {:?}.", interval);
+    loop {
+        tokio::time::sleep(interval).await;
+        telemetry
+            .send_report(subscribers.all_subscribers().len())
+            .await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wiremock::matchers::{body_json, method};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    #[test]
+    fn test_subscriber_bucket() {
+        assert_eq!(subscriber_bucket(0), "0");
+        assert_eq!(subscriber_bucket(7), "1-10");
+        assert_eq!(subscriber_bucket(5000), "1000+");
+    }
+
+    #[tokio::test]
+    async fn test_no_report_when_disabled() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(0)
+            .mount(&server)
+            .await;
+
+        Telemetry::new(None).send_report(3).await;
+    }
+
+    #[tokio::test]
+    async fn test_report_is_posted_when_enabled() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(body_json(serde_json::json!({
+                "subscribers": "1-10",
+                "updates_sent": 2,
+                "version": env!("CARGO_PKG_VERSION"),
+            })))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let telemetry = Telemetry::new(Some(server.uri()));
+        telemetry.record_update_sent();
+        telemetry.record_update_sent();
+        telemetry.send_report(3).await;
+    }
+
+    #[tokio::test]
+    async fn test_unreachable_endpoint_is_ignored() {
+        let telemetry = Telemetry::new(Some("http://127.0.0.1:9".to_string()));
+        telemetry.send_report(3).await;
+    }
+}
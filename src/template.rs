@@ -0,0 +1,168 @@
+use crate::bbcode::Markup;
+
+/// Placeholders that can appear in a message template as `{name}`.
+pub const PLACEHOLDERS: &[&str] = &["headline", "body", "link"];
+
+/// The built-in template used until an admin sets a different one.
+pub const DEFAULT_TEMPLATE: &str =
+    "_*To see more updates and news follow this [link]({link})*_\n\n*{headline}*\n{body}\n\n";
+
+/// `DEFAULT_TEMPLATE` for `Markup::Html`.
+const DEFAULT_HTML_TEMPLATE: &str =
+    "<i><b>To see more updates and news follow this <a href=\"{link}\">link</a></b></i>\n\n<b>{headline}</b>\n{body}\n\n";
+
+/// `DEFAULT_TEMPLATE` for `Markup::Plain`.
+const DEFAULT_PLAIN_TEMPLATE: &str =
+    "To see more updates and news follow this link: {link}\n\n{headline}\n{body}\n\n";
+
+/// A message layout with `{placeholder}` slots for the parts of an update, usually in Markdown
+/// V2.
+///
+/// A literal brace can be written as `\{` or `\}`, which is also how Markdown V2 escapes it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MessageTemplate {
+    source: String,
+}
+
+/// A piece of a template: literal text or a placeholder name.
+enum Segment<'a> {
+    Text(&'a str),
+    Placeholder(&'a str),
+}
+
+impl MessageTemplate {
+    /// Parses a template, rejecting unknown or unterminated placeholders.
+    pub fn parse(source: &str) -> Result<Self, String> {
+        for segment in segments(source)? {
+            if let Segment::Placeholder(name) = segment {
+                if !PLACEHOLDERS.contains(&name) {
+                    return Err(format!("Unknown placeholder: {{{}}}", name));
+                }
+            }
+        }
+        Ok(Self {
+            source: source.to_string(),
+        })
+    }
+
+    /// Returns the template as it was written.
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    /// Returns the built-in template for `markup`.
+    pub fn default_for(markup: Markup) -> Self {
+        let source = match markup {
+            Markup::MarkdownV2 => DEFAULT_TEMPLATE,
+            Markup::Html => DEFAULT_HTML_TEMPLATE,
+            Markup::Plain => DEFAULT_PLAIN_TEMPLATE,
+        };
+        Self {
+            source: source.to_string(),
+        }
+    }
+
+    /// Returns the template without its footer: every line with a `{link}` placeholder is dropped,
+    /// along with the blank lines the template then starts with.
+    pub fn without_footer(&self) -> Self {
+        let source = self
+            .source
+            .split('\n')
+            .filter(|line| !line.contains("{link}"))
+            .collect::<Vec<_>>()
+            .join("\n");
+        Self {
+            source: source.trim_start_matches('\n').to_string(),
+        }
+    }
+
+    /// Fills in the placeholders. The values must already be escaped for the template's markup.
+    pub fn render(&self, headline: &str, body: &str, link: &str) -> String {
+        let segments = segments(&self.source).unwrap_or_default();
+        segments
+            .into_iter()
+            .map(|segment| match segment {
+                Segment::Text(text) => text,
+                Segment::Placeholder("headline") => headline,
+                Segment::Placeholder("body") => body,
+                Segment::Placeholder("link") => link,
+                Segment::Placeholder(_) => "",
+            })
+            .collect()
+    }
+}
+
+impl Default for MessageTemplate {
+    fn default() -> Self {
+        Self::default_for(Markup::MarkdownV2)
+    }
+}
+
+/// Splits a template into literal text and placeholders.
+fn segments(source: &str) -> Result<Vec<Segment<'_>>, String> {
+    let mut segments = Vec::new();
+    let mut text_start = 0;
+    let mut chars = source.char_indices();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '\\' => {
+                chars.next();
+            }
+            '{' => {
+                let end = source[i..]
+                    .find('}')
+                    .map(|offset| i + offset)
+                    .ok_or_else(|| "Unterminated placeholder".to_string())?;
+                segments.push(Segment::Text(&source[text_start..i]));
+                segments.push(Segment::Placeholder(&source[i + 1..end]));
+                text_start = end + 1;
+                while chars.next().is_some_and(|(j, _)| j < end) {}
+            }
+            _ => {}
+        }
+    }
+    segments.push(Segment::Text(&source[text_start..]));
+    Ok(segments)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_default_template() {
+        let rendered = MessageTemplate::default().render("Patch", "Notes", "https://example.com");
+        assert_eq!(
+            rendered,
+            "_*To see more updates and news follow this [link](https://example.com)*_\n\n*Patch*\nNotes\n\n"
+        );
+    }
+
+    #[test]
+    fn test_default_template_without_footer() {
+        let rendered = MessageTemplate::default().without_footer().render(
+            "Patch",
+            "Notes",
+            "https://example.com",
+        );
+        assert_eq!(rendered, "*Patch*\nNotes\n\n");
+    }
+
+    #[test]
+    fn test_escaped_braces_are_literal() {
+        let template = MessageTemplate::parse("\\{not a placeholder\\} {headline}").unwrap();
+        assert_eq!(
+            template.render("Patch", "", ""),
+            "\\{not a placeholder\\} Patch"
+        );
+    }
+
+    #[test]
+    fn test_parse_rejects_unknown_placeholders() {
+        assert_eq!(
+            MessageTemplate::parse("{headline} {author}"),
+            Err("Unknown placeholder: {author}".to_string())
+        );
+        assert!(MessageTemplate::parse("{headline").is_err());
+    }
+}
@@ -0,0 +1,110 @@
+//! Optional outgoing webhook for detected updates.
+//!
+//! When `UPDATE_WEBHOOK_URL` is set, every update a poll detects is POSTed there as a small JSON
+//! object, whether or not it reaches any chat, so operators can feed updates into their own
+//! pipelines. Failures to reach the endpoint are logged and otherwise ignored.
+
+use crate::json_part::Event;
+use serde::Serialize;
+use tracing::{debug, warn};
+
+/// The JSON object POSTed for each detected update.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct UpdatePayload {
+    pub gid: String,
+    pub headline: String,
+    /// Unix time the update was posted at.
+    pub timestamp: u64,
+    pub permalink: String,
+}
+
+impl UpdatePayload {
+    pub fn new(event: &Event) -> Self {
+        Self {
+            gid: event.gid.clone(),
+            headline: event.headline().unwrap_or_default().to_string(),
+            timestamp: event.rtime32_start_time,
+            permalink: event.post_link(),
+        }
+    }
+}
+
+/// POSTs detected updates to an endpoint when one is configured.
+pub struct UpdateWebhook {
+    endpoint: Option<String>,
+    client: reqwest::Client,
+}
+
+impl UpdateWebhook {
+    /// Creates a webhook for the given endpoint. Passing `None` disables it entirely.
+    pub fn new(endpoint: Option<String>) -> Self {
+        Self {
+            endpoint,
+            client: reqwest::Client::new(),
+        }
+    }
+
+    /// Returns `true` if updates are POSTed.
+    pub fn is_enabled(&self) -> bool {
+        self.endpoint.is_some()
+    }
+
+    /// POSTs one payload per event, in order. Does nothing when the webhook is disabled and never
+    /// fails.
+    pub async fn post_updates(&self, events: &[&Event]) {
+        let Some(endpoint) = &self.endpoint else {
+            return;
+        };
+        for event in events {
+            let result = self
+                .client
+                .post(endpoint)
+                .json(&UpdatePayload::new(event))
+                .send()
+                .await
+                .and_then(|response| response.error_for_status());
+            match result {
+                Ok(_) => debug!("Posted update {} to the webhook.", event.gid),
+                Err(err) => warn!(
+                    "Failed to post update {} to the webhook: {}",
+                    event.gid, err
+                ),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::json_part::test_event;
+    use wiremock::matchers::{body_json, method};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    #[tokio::test]
+    async fn test_updates_are_posted_as_json() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(body_json(serde_json::json!({
+                "gid": "736",
+                "headline": "Patch 7.36",
+                "timestamp": 1_700_000_000,
+                "permalink": "https://store.steampowered.com/news/app/570/view/736",
+            })))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&server)
+            .await;
+        let mut event = test_event("Patch 7.36", "New heroes");
+        event.gid = "736".to_string();
+        event.rtime32_start_time = 1_700_000_000;
+
+        UpdateWebhook::new(Some(server.uri()))
+            .post_updates(&[&event])
+            .await;
+        UpdateWebhook::new(None).post_updates(&[&event]).await;
+        UpdateWebhook::new(Some("http://127.0.0.1:9".to_string()))
+            .post_updates(&[&event])
+            .await;
+    }
+}